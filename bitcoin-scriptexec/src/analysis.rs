@@ -0,0 +1,620 @@
+//! Static analysis of the stack usage of scripts.
+//!
+//! [analyze] walks a script without executing it and tracks the depth of the
+//! stack and the altstack, relative to their depth at the start of the
+//! script. Both branches of every conditional are followed, so depths are
+//! bounds rather than exact values. Conditionals are analyzed independently,
+//! so scripts that balance the stack over several conditionals on the same
+//! value get wider bounds than they have in practice.
+//!
+//! The values of pushed numbers are tracked as far as possible, so that the
+//! common `<n> OP_PICK` and `<n> OP_ROLL` patterns have a known effect. When
+//! the effect of an opcode depends on values that are only known at runtime,
+//! like OP_PICK with a computed index, an [Issue::DataDependent] is reported
+//! and the bounds after it are approximations.
+
+use std::fmt;
+
+use bitcoin::opcodes::{all::*, Class, ClassifyContext, Opcode};
+use bitcoin::script::{self, Instruction, Script};
+
+use crate::{read_scriptint, MAX_PUBKEYS_PER_MULTISIG, MAX_STACK_SIZE};
+
+/// Bounds on the depth of a stack, relative to the start of the script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DepthBounds {
+    pub min: isize,
+    pub max: isize,
+}
+
+impl DepthBounds {
+    fn exact(depth: isize) -> DepthBounds {
+        DepthBounds {
+            min: depth,
+            max: depth,
+        }
+    }
+
+    /// Whether the depth is the same on all paths.
+    pub fn is_exact(&self) -> bool {
+        self.min == self.max
+    }
+
+    fn shift(&mut self, n: isize) {
+        self.min += n;
+        self.max += n;
+    }
+
+    fn union(self, other: DepthBounds) -> DepthBounds {
+        DepthBounds {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+}
+
+impl fmt::Display for DepthBounds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_exact() {
+            write!(f, "{:+}", self.min)
+        } else {
+            write!(f, "{:+}..{:+}", self.min, self.max)
+        }
+    }
+}
+
+/// The analysis of a single instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepAnalysis {
+    /// The position of the instruction in the script.
+    pub position: usize,
+    /// The opcode, or [None] for data pushes.
+    pub opcode: Option<Opcode>,
+    /// The stack depth before the instruction.
+    pub stack: DepthBounds,
+    /// The altstack depth before the instruction.
+    pub altstack: DepthBounds,
+    /// The number of stack items that must be present at the start of the
+    /// script for this instruction not to underflow.
+    pub stack_required: usize,
+    /// Same as [StepAnalysis::stack_required], for the altstack.
+    pub altstack_required: usize,
+}
+
+/// A problem found in the script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Issue {
+    /// An OP_ELSE or OP_ENDIF without OP_IF, or an OP_IF that isn't closed,
+    /// reported at the end of the script.
+    UnbalancedConditional { position: usize },
+    /// The stack effect of the opcode depends on runtime values, the bounds
+    /// after it are approximations.
+    DataDependent { position: usize, opcode: Opcode },
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Issue::UnbalancedConditional { position } => {
+                write!(f, "unbalanced conditional at position {}", position)
+            }
+            Issue::DataDependent { position, opcode } => write!(
+                f,
+                "data-dependent stack effect of {} at position {}",
+                opcode, position,
+            ),
+        }
+    }
+}
+
+/// An instruction that underflows the stack or the altstack.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Underflow {
+    pub position: usize,
+    pub opcode: Option<Opcode>,
+    /// The number of missing stack items.
+    pub missing: usize,
+    /// The number of missing altstack items.
+    pub missing_altstack: usize,
+}
+
+/// The stack effect of a script as a whole.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StackEffect {
+    /// The number of stack items the script uses from the stack it starts
+    /// with.
+    pub inputs: usize,
+    /// The change of the stack depth.
+    pub delta: DepthBounds,
+    /// The highest stack depth during the script.
+    pub max_depth: isize,
+    /// The number of altstack items the script uses from the altstack it
+    /// starts with.
+    pub altstack_inputs: usize,
+    /// The change of the altstack depth.
+    pub altstack_delta: DepthBounds,
+    /// The highest altstack depth during the script.
+    pub altstack_max_depth: isize,
+}
+
+impl StackEffect {
+    /// The number of stack items the script leaves in place of its inputs,
+    /// if it's the same on all paths.
+    pub fn outputs(&self) -> Option<usize> {
+        if self.delta.is_exact() {
+            Some((self.inputs as isize + self.delta.min).max(0) as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Same as [StackEffect::outputs], for the altstack.
+    pub fn altstack_outputs(&self) -> Option<usize> {
+        if self.altstack_delta.is_exact() {
+            Some((self.altstack_inputs as isize + self.altstack_delta.min).max(0) as usize)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for StackEffect {
+    /// Formats the effect like a Forth stack comment, `( inputs -- outputs )`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn side(f: &mut fmt::Formatter, inputs: usize, delta: DepthBounds) -> fmt::Result {
+            let min = inputs as isize + delta.min;
+            let max = inputs as isize + delta.max;
+            if delta.is_exact() {
+                write!(f, "( {} -- {} )", inputs, min)
+            } else {
+                write!(f, "( {} -- {}..{} )", inputs, min, max)
+            }
+        }
+        side(f, self.inputs, self.delta)?;
+        if self.altstack_inputs > 0 || self.altstack_delta != DepthBounds::default() {
+            write!(f, " altstack ")?;
+            side(f, self.altstack_inputs, self.altstack_delta)?;
+        }
+        Ok(())
+    }
+}
+
+/// The result of analyzing a script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Analysis {
+    /// The analysis of each instruction, in script order.
+    pub steps: Vec<StepAnalysis>,
+    /// The problems found in the script, in script order.
+    pub issues: Vec<Issue>,
+    /// The stack effect of the whole script.
+    pub effect: StackEffect,
+}
+
+impl Analysis {
+    /// Returns the instructions that underflow when the script starts with
+    /// the given number of stack and altstack items.
+    pub fn underflows(&self, nb_stack: usize, nb_altstack: usize) -> Vec<Underflow> {
+        self.steps
+            .iter()
+            .filter(|s| s.stack_required > nb_stack || s.altstack_required > nb_altstack)
+            .map(|s| Underflow {
+                position: s.position,
+                opcode: s.opcode,
+                missing: s.stack_required.saturating_sub(nb_stack),
+                missing_altstack: s.altstack_required.saturating_sub(nb_altstack),
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Stack effect: {}", self.effect)?;
+        writeln!(f, "Max stack depth: {:+}", self.effect.max_depth)?;
+        writeln!(
+            f,
+            "Max altstack depth: {:+}",
+            self.effect.altstack_max_depth
+        )?;
+        for issue in &self.issues {
+            writeln!(f, "Warning: {}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+/// The abstract state of one of the stacks.
+#[derive(Debug, Clone)]
+struct AbstractStack {
+    depth: DepthBounds,
+    /// The known values of the top items, top last. Items below are unknown.
+    top: Vec<Option<i64>>,
+}
+
+impl AbstractStack {
+    fn new() -> AbstractStack {
+        AbstractStack {
+            depth: DepthBounds::exact(0),
+            top: Vec::new(),
+        }
+    }
+
+    /// Makes sure the top `n` items are represented in `top`.
+    fn materialize(&mut self, n: usize) {
+        if self.top.len() < n {
+            let missing = n - self.top.len();
+            self.top.splice(0..0, std::iter::repeat_n(None, missing));
+        }
+    }
+
+    /// The value of the item at the given depth, 0 is the top.
+    fn value(&self, i: usize) -> Option<i64> {
+        self.top
+            .len()
+            .checked_sub(i + 1)
+            .and_then(|idx| self.top[idx])
+    }
+
+    fn pop(&mut self) -> Option<i64> {
+        self.depth.shift(-1);
+        self.top.pop().flatten()
+    }
+
+    fn push(&mut self, value: Option<i64>) {
+        self.depth.shift(1);
+        self.top.push(value);
+    }
+
+    /// Removes the item at the given depth and returns its value.
+    fn remove(&mut self, i: usize) -> Option<i64> {
+        self.materialize(i + 1);
+        self.depth.shift(-1);
+        let idx = self.top.len() - 1 - i;
+        self.top.remove(idx)
+    }
+
+    /// Merges the state at the end of the other branch of a conditional.
+    fn merge(&mut self, other: AbstractStack) {
+        if self.depth == other.depth {
+            // Only the items tracked in both branches are kept.
+            let len = self.top.len().min(other.top.len());
+            self.top.drain(..self.top.len() - len);
+            let other_top = &other.top[other.top.len() - len..];
+            for (a, b) in self.top.iter_mut().zip(other_top) {
+                if a != b {
+                    *a = None;
+                }
+            }
+        } else {
+            self.top.clear();
+        }
+        self.depth = self.depth.union(other.depth);
+    }
+}
+
+struct Analyzer {
+    stack: AbstractStack,
+    altstack: AbstractStack,
+    /// For each open conditional, the state of the path not currently
+    /// followed.
+    cond_stack: Vec<(AbstractStack, AbstractStack)>,
+
+    position: usize,
+    step_required: (usize, usize),
+    effect: StackEffect,
+    issues: Vec<Issue>,
+}
+
+impl Analyzer {
+    /// Requires `n` items on the stack for the current instruction.
+    fn need(&mut self, n: usize) {
+        let required = (n as isize - self.stack.depth.min).max(0) as usize;
+        self.step_required.0 = self.step_required.0.max(required);
+        self.stack.materialize(n);
+    }
+
+    /// Requires `n` items on the altstack for the current instruction.
+    fn need_alt(&mut self, n: usize) {
+        let required = (n as isize - self.altstack.depth.min).max(0) as usize;
+        self.step_required.1 = self.step_required.1.max(required);
+        self.altstack.materialize(n);
+    }
+
+    /// Replaces the top `pops` items with `pushes` unknown items.
+    fn replace(&mut self, pops: usize, pushes: usize) {
+        self.need(pops);
+        for _ in 0..pops {
+            self.stack.pop();
+        }
+        for _ in 0..pushes {
+            self.stack.push(None);
+        }
+    }
+
+    /// Copies the item at the given depth to the top.
+    fn copy(&mut self, i: usize) {
+        self.need(i + 1);
+        let value = self.stack.value(i);
+        self.stack.push(value);
+    }
+
+    /// Moves the item at the given depth to the top.
+    fn roll(&mut self, i: usize) {
+        self.need(i + 1);
+        let value = self.stack.remove(i);
+        self.stack.push(value);
+    }
+
+    /// Applies a numeric operation on the top `N` items.
+    fn numeric<const N: usize>(&mut self, f: impl Fn([i64; N]) -> Option<i64>) {
+        self.need(N);
+        let mut args = [0; N];
+        let mut known = true;
+        for i in (0..N).rev() {
+            match self.stack.pop() {
+                Some(v) => args[i] = v,
+                None => known = false,
+            }
+        }
+        self.stack.push(if known { f(args) } else { None });
+    }
+
+    fn data_dependent(&mut self, opcode: Opcode) {
+        self.issues.push(Issue::DataDependent {
+            position: self.position,
+            opcode,
+        });
+    }
+
+    fn unbalanced(&mut self) {
+        self.issues.push(Issue::UnbalancedConditional {
+            position: self.position,
+        });
+    }
+
+    fn push_bytes(&mut self, data: &[u8]) {
+        self.stack.push(read_scriptint(data, 4, false).ok());
+    }
+
+    fn opcode(&mut self, op: Opcode) {
+        if let Class::PushNum(n) = op.classify(ClassifyContext::TapScript) {
+            self.stack.push(Some(n as i64));
+            return;
+        }
+
+        match op {
+            OP_IF | OP_NOTIF => {
+                self.replace(1, 0);
+                self.cond_stack
+                    .push((self.stack.clone(), self.altstack.clone()));
+            }
+            OP_ELSE => match self.cond_stack.last_mut() {
+                Some((stack, altstack)) => {
+                    std::mem::swap(stack, &mut self.stack);
+                    std::mem::swap(altstack, &mut self.altstack);
+                }
+                None => self.unbalanced(),
+            },
+            OP_ENDIF => match self.cond_stack.pop() {
+                Some((stack, altstack)) => {
+                    self.stack.merge(stack);
+                    self.altstack.merge(altstack);
+                }
+                None => self.unbalanced(),
+            },
+
+            OP_TOALTSTACK => {
+                self.need(1);
+                let value = self.stack.pop();
+                self.altstack.push(value);
+            }
+            OP_FROMALTSTACK => {
+                self.need_alt(1);
+                let value = self.altstack.pop();
+                self.stack.push(value);
+            }
+
+            OP_VERIFY | OP_DROP => self.replace(1, 0),
+            OP_2DROP => self.replace(2, 0),
+            OP_DUP => self.copy(0),
+            OP_2DUP => {
+                self.copy(1);
+                self.copy(1);
+            }
+            OP_3DUP => {
+                self.copy(2);
+                self.copy(2);
+                self.copy(2);
+            }
+            OP_OVER => self.copy(1),
+            OP_2OVER => {
+                self.copy(3);
+                self.copy(3);
+            }
+            OP_SWAP => self.roll(1),
+            OP_2SWAP => {
+                self.roll(3);
+                self.roll(3);
+            }
+            OP_ROT => self.roll(2),
+            OP_2ROT => {
+                self.roll(5);
+                self.roll(5);
+            }
+            OP_NIP => {
+                self.need(2);
+                self.stack.remove(1);
+            }
+            OP_TUCK => {
+                // (x1 x2 -- x2 x1 x2)
+                self.copy(0);
+                self.roll(2);
+                self.roll(1);
+            }
+            OP_IFDUP => {
+                self.need(1);
+                match self.stack.value(0) {
+                    Some(0) => {}
+                    Some(v) => self.stack.push(Some(v)),
+                    None => {
+                        self.data_dependent(op);
+                        self.stack.depth.max += 1;
+                        self.stack.top.clear();
+                    }
+                }
+            }
+            OP_DEPTH => self.stack.push(None),
+            OP_PICK | OP_ROLL => {
+                self.need(1);
+                match self.stack.pop() {
+                    // Larger indices always fail because of the stack limit.
+                    Some(n) if (0..MAX_STACK_SIZE as i64).contains(&n) => {
+                        if op == OP_PICK {
+                            self.copy(n as usize);
+                        } else {
+                            self.roll(n as usize);
+                        }
+                    }
+                    _ => {
+                        self.data_dependent(op);
+                        self.need(1);
+                        if op == OP_PICK {
+                            self.stack.push(None);
+                        } else {
+                            // The depth doesn't change, but any item might
+                            // have moved.
+                            self.stack.top.clear();
+                        }
+                    }
+                }
+            }
+
+            OP_SIZE => {
+                self.need(1);
+                self.stack.push(None);
+            }
+            OP_CAT | OP_EQUAL => self.replace(2, 1),
+            OP_EQUALVERIFY => self.replace(2, 0),
+
+            OP_1ADD => self.numeric(|[a]| a.checked_add(1)),
+            OP_1SUB => self.numeric(|[a]| a.checked_sub(1)),
+            OP_NEGATE => self.numeric(|[a]| a.checked_neg()),
+            OP_ABS | OP_NOT | OP_0NOTEQUAL => self.replace(1, 1),
+            OP_ADD => self.numeric(|[a, b]| a.checked_add(b)),
+            OP_SUB => self.numeric(|[a, b]| a.checked_sub(b)),
+            OP_BOOLAND
+            | OP_BOOLOR
+            | OP_NUMEQUAL
+            | OP_NUMNOTEQUAL
+            | OP_LESSTHAN
+            | OP_GREATERTHAN
+            | OP_LESSTHANOREQUAL
+            | OP_GREATERTHANOREQUAL
+            | OP_MIN
+            | OP_MAX => self.replace(2, 1),
+            OP_NUMEQUALVERIFY => self.replace(2, 0),
+            OP_WITHIN => self.replace(3, 1),
+
+            OP_RIPEMD160 | OP_SHA1 | OP_SHA256 | OP_HASH160 | OP_HASH256 => self.replace(1, 1),
+            OP_CHECKSIG => self.replace(2, 1),
+            OP_CHECKSIGVERIFY => self.replace(2, 0),
+            OP_CHECKSIGADD => self.replace(3, 1),
+            OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+                // ([dummy] [sig ...] nb_sigs [pubkey ...] nb_pubkeys -- bool)
+                let pushes = if op == OP_CHECKMULTISIG { 1 } else { 0 };
+                self.need(1);
+                let nb_keys = self
+                    .stack
+                    .value(0)
+                    .filter(|n| (0..=MAX_PUBKEYS_PER_MULTISIG).contains(n));
+                if let Some(nb_keys) = nb_keys {
+                    self.need(nb_keys as usize + 2);
+                    let nb_sigs = self
+                        .stack
+                        .value(nb_keys as usize + 1)
+                        .filter(|n| (0..=nb_keys).contains(n));
+                    if let Some(nb_sigs) = nb_sigs {
+                        self.replace((nb_keys + nb_sigs) as usize + 3, pushes);
+                        return;
+                    }
+                }
+                self.data_dependent(op);
+                self.replace(1, pushes);
+            }
+            OP_CLTV | OP_CSV => self.need(1),
+
+            // Nops, opcodes that always fail and OP_SUCCESSx, none of which
+            // change the stack.
+            _ => {}
+        }
+    }
+
+    fn step(&mut self, position: usize, instruction: Instruction) -> StepAnalysis {
+        self.position = position;
+        self.step_required = (0, 0);
+        let stack = self.stack.depth;
+        let altstack = self.altstack.depth;
+
+        let opcode = match instruction {
+            Instruction::PushBytes(push) => {
+                self.push_bytes(push.as_bytes());
+                None
+            }
+            Instruction::Op(op) => {
+                self.opcode(op);
+                Some(op)
+            }
+        };
+
+        let effect = &mut self.effect;
+        effect.inputs = effect.inputs.max(self.step_required.0);
+        effect.altstack_inputs = effect.altstack_inputs.max(self.step_required.1);
+        effect.max_depth = effect.max_depth.max(self.stack.depth.max);
+        effect.altstack_max_depth = effect.altstack_max_depth.max(self.altstack.depth.max);
+
+        StepAnalysis {
+            position,
+            opcode,
+            stack,
+            altstack,
+            stack_required: self.step_required.0,
+            altstack_required: self.step_required.1,
+        }
+    }
+}
+
+/// Analyzes the stack usage of the script without executing it.
+///
+/// Fails only if the script can't be parsed.
+pub fn analyze(script: &Script) -> Result<Analysis, script::Error> {
+    let mut analyzer = Analyzer {
+        stack: AbstractStack::new(),
+        altstack: AbstractStack::new(),
+        cond_stack: Vec::new(),
+        position: 0,
+        step_required: (0, 0),
+        effect: StackEffect::default(),
+        issues: Vec::new(),
+    };
+
+    let mut steps = Vec::new();
+    for res in script.instruction_indices() {
+        let (position, instruction) = res?;
+        steps.push(analyzer.step(position, instruction));
+    }
+
+    if !analyzer.cond_stack.is_empty() {
+        analyzer.position = script.len();
+        analyzer.unbalanced();
+    }
+    analyzer.effect.delta = analyzer.stack.depth;
+    analyzer.effect.altstack_delta = analyzer.altstack.depth;
+
+    Ok(Analysis {
+        steps,
+        issues: analyzer.issues,
+        effect: analyzer.effect,
+    })
+}
@@ -0,0 +1,277 @@
+//! Consensus and policy limits of tapscript leaves.
+//!
+//! [check_leaf] executes a leaf script with its witness and reports how much
+//! of every limit the spend uses, so that leaves can be checked before they
+//! are committed to in a taproot output. Tapscript has no limit on the size
+//! of the script or on the number of opcodes, large scripts are only limited
+//! by the weight of the transaction spending them.
+//!
+//! The weight of the spending transaction is calculated for a transaction
+//! with a single input and a single P2TR output, the smallest transaction
+//! that spends the leaf.
+
+use std::fmt;
+
+use bitcoin::consensus::Encodable;
+use bitcoin::script::{Instruction, ScriptBuf};
+
+use crate::*;
+
+/// Maximum weight of a block, and thus of a transaction.
+pub const MAX_BLOCK_WEIGHT: usize = 4_000_000;
+
+/// Maximum weight of a transaction relayed by default.
+pub const MAX_STANDARD_TX_WEIGHT: usize = 400_000;
+
+/// Maximum size of the witness stack items of a tapscript spend relayed by
+/// default.
+pub const MAX_STANDARD_TAPSCRIPT_STACK_ITEM_SIZE: usize = 80;
+
+/// Size of the control block of a leaf that is the only leaf of its tree.
+pub const MIN_CONTROL_BLOCK_SIZE: usize = bitcoin::taproot::TAPROOT_CONTROL_BASE_SIZE;
+
+/// Size of a transaction with a single input and a single P2TR output,
+/// without the witness.
+const SPEND_TX_BASE_SIZE: usize = 94;
+
+/// Size of the segwit marker and flag.
+const SEGWIT_MARKER_SIZE: usize = 2;
+
+/// A limit of tapscript spends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Limit {
+    /// The size of the items pushed or created on the stack.
+    ElementSize,
+    /// The number of items on the stack and the altstack together.
+    StackSize,
+    /// The validation weight used by signature checks, which is budgeted
+    /// from the size of the witness (BIP 342).
+    ValidationWeight,
+    /// The weight of the spending transaction, which can't exceed the
+    /// weight of a block.
+    BlockWeight,
+    /// The size of the witness stack items, not counting the script and the
+    /// control block.
+    StandardWitnessItemSize,
+    /// The weight of the spending transaction.
+    StandardTxWeight,
+}
+
+impl Limit {
+    /// Whether the limit is enforced by consensus, or only by the default
+    /// relay policy.
+    pub fn is_consensus(&self) -> bool {
+        match self {
+            Limit::ElementSize
+            | Limit::StackSize
+            | Limit::ValidationWeight
+            | Limit::BlockWeight => true,
+            Limit::StandardWitnessItemSize | Limit::StandardTxWeight => false,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Limit::ElementSize => "element_size",
+            Limit::StackSize => "stack_size",
+            Limit::ValidationWeight => "validation_weight",
+            Limit::BlockWeight => "block_weight",
+            Limit::StandardWitnessItemSize => "standard_witness_item_size",
+            Limit::StandardTxWeight => "standard_tx_weight",
+        }
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+/// How much of a limit a spend uses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitUsage {
+    pub limit: Limit,
+    pub used: usize,
+    pub max: usize,
+}
+
+impl LimitUsage {
+    /// How much more the spend could use, negative if the limit is exceeded.
+    pub fn headroom(&self) -> i64 {
+        self.max as i64 - self.used as i64
+    }
+
+    pub fn is_exceeded(&self) -> bool {
+        self.used > self.max
+    }
+}
+
+/// The limits used by a spend of a leaf, see [check_leaf].
+#[derive(Debug, Clone)]
+pub struct BudgetReport {
+    /// The usage of every limit, consensus limits first.
+    pub usages: Vec<LimitUsage>,
+    /// The result of the execution the usage was measured on. Usage is
+    /// measured up to the failure if the execution failed.
+    pub result: ExecutionResult,
+}
+
+impl BudgetReport {
+    /// The usage of the given limit.
+    pub fn usage(&self, limit: Limit) -> &LimitUsage {
+        self.usages
+            .iter()
+            .find(|u| u.limit == limit)
+            .expect("all limits are reported")
+    }
+
+    /// The limits that are exceeded.
+    pub fn violations(&self) -> impl Iterator<Item = &LimitUsage> {
+        self.usages.iter().filter(|u| u.is_exceeded())
+    }
+
+    /// Whether no consensus limit is exceeded.
+    pub fn is_consensus_valid(&self) -> bool {
+        self.violations().all(|u| !u.limit.is_consensus())
+    }
+
+    /// Whether no limit is exceeded, including the policy limits.
+    pub fn is_standard(&self) -> bool {
+        self.violations().next().is_none()
+    }
+}
+
+impl fmt::Display for BudgetReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<10} {:<28} {:>10} {:>10} {:>10}",
+            "KIND", "LIMIT", "USED", "MAX", "HEADROOM"
+        )?;
+        for usage in &self.usages {
+            writeln!(
+                f,
+                "{:<10} {:<28} {:>10} {:>10} {:>10}{}",
+                if usage.limit.is_consensus() {
+                    "consensus"
+                } else {
+                    "policy"
+                },
+                usage.limit,
+                usage.used,
+                usage.max,
+                usage.headroom(),
+                if usage.is_exceeded() { " EXCEEDED" } else { "" },
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Tracks the largest item created on the stacks.
+///
+/// New items are always created on top of a stack, so only the top items
+/// are checked after every step.
+struct ElementSizes {
+    max: usize,
+}
+
+impl ExecObserver for ElementSizes {
+    fn wants_stack_diffs(&self) -> bool {
+        false
+    }
+
+    fn post_step(&mut self, exec: &Exec, _step: &StepInfo, _diff: Option<&StepDiff>) {
+        for stack in [exec.stack(), exec.altstack()] {
+            if let Ok(top) = stack.last() {
+                self.max = self.max.max(top.len());
+            }
+        }
+    }
+}
+
+/// Executes the leaf script with the given witness stack, bottom first, and
+/// reports the usage of all consensus and policy limits of the spend.
+///
+/// The size of the control block depends on the depth of the leaf in the
+/// tree, it's [MIN_CONTROL_BLOCK_SIZE] for a tree with a single leaf. The
+/// stack limit is never enforced during the execution, so that the usage
+/// can be reported when it's exceeded.
+pub fn check_leaf(
+    opt: Options,
+    tx: TxTemplate,
+    script: ScriptBuf,
+    witness: Vec<Vec<u8>>,
+    control_block_size: usize,
+) -> Result<BudgetReport, Error> {
+    // The full witness of the input, with the script and the control block.
+    let mut full_witness = witness.clone();
+    full_witness.push(script.to_bytes());
+    full_witness.push(vec![0; control_block_size]);
+    let witness_size =
+        Encodable::consensus_encode(&full_witness, &mut bitcoin::io::sink()).unwrap();
+    let tx_weight = SPEND_TX_BASE_SIZE * 4 + SEGWIT_MARKER_SIZE + witness_size;
+
+    let max_witness_item = witness.iter().map(|i| i.len()).max().unwrap_or(0);
+    // Pushes are limited in non-executed branches as well.
+    let max_push = script
+        .instructions()
+        .filter_map(|i| match i {
+            Ok(Instruction::PushBytes(b)) => Some(b.len()),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+
+    let opt = Options {
+        enforce_stack_limit: false,
+        ..opt
+    };
+    let mut exec = Exec::new(ExecCtx::Tapscript, opt, tx, script, witness)?;
+    // Start with an unlimited budget to measure the validation weight used.
+    exec.set_start_validation_weight(i64::MAX);
+    let mut sizes = ElementSizes {
+        max: max_witness_item.max(max_push),
+    };
+    while exec.exec_next_observed(&mut sizes).is_ok() {}
+    let stats = exec.stats();
+
+    let usages = vec![
+        LimitUsage {
+            limit: Limit::ElementSize,
+            used: sizes.max,
+            max: MAX_SCRIPT_ELEMENT_SIZE,
+        },
+        LimitUsage {
+            limit: Limit::StackSize,
+            used: stats.max_nb_stack_items,
+            max: MAX_STACK_SIZE,
+        },
+        LimitUsage {
+            limit: Limit::ValidationWeight,
+            used: (stats.start_validation_weight - stats.validation_weight) as usize,
+            max: VALIDATION_WEIGHT_OFFSET as usize + witness_size,
+        },
+        LimitUsage {
+            limit: Limit::BlockWeight,
+            used: tx_weight,
+            max: MAX_BLOCK_WEIGHT,
+        },
+        LimitUsage {
+            limit: Limit::StandardWitnessItemSize,
+            used: max_witness_item,
+            max: MAX_STANDARD_TAPSCRIPT_STACK_ITEM_SIZE,
+        },
+        LimitUsage {
+            limit: Limit::StandardTxWeight,
+            used: tx_weight,
+            max: MAX_STANDARD_TX_WEIGHT,
+        },
+    ];
+
+    Ok(BudgetReport {
+        usages,
+        result: exec.result().unwrap().clone(),
+    })
+}
@@ -0,0 +1,140 @@
+//! Experimental covenant opcodes: OP_CHECKTEMPLATEVERIFY (BIP 119) and
+//! OP_CHECKSIGFROMSTACK (BIP 348).
+//!
+//! Both are only active when enabled in [Experimental].
+
+use bitcoin::consensus::Encodable;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::opcodes::{all::*, Opcode};
+use bitcoin::secp256k1::ffi::{self, CPtr};
+use bitcoin::secp256k1::XOnlyPublicKey;
+use bitcoin::transaction::Transaction;
+
+use crate::signatures::SECP;
+use crate::*;
+
+/// OP_CHECKTEMPLATEVERIFY, redefining OP_NOP4.
+pub const OP_CHECKTEMPLATEVERIFY: Opcode = OP_NOP4;
+
+/// OP_CHECKSIGFROMSTACK, redefining OP_SUCCESS204 in tapscript.
+pub const OP_CHECKSIGFROMSTACK: Opcode = OP_RETURN_204;
+
+/// Calculates the BIP 119 default template hash of the transaction for the
+/// given input.
+///
+/// This is the hash an OP_CHECKTEMPLATEVERIFY in the script of the input
+/// needs to commit to for the transaction to be valid.
+pub fn default_template_hash(tx: &Transaction, input_idx: u32) -> sha256::Hash {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&tx.version.0.to_le_bytes());
+    buf.extend_from_slice(&tx.lock_time.to_consensus_u32().to_le_bytes());
+
+    // The scriptSigs are only committed to if any of them is non-empty.
+    if tx.input.iter().any(|txin| !txin.script_sig.is_empty()) {
+        let mut script_sigs = Vec::new();
+        for txin in &tx.input {
+            txin.script_sig.consensus_encode(&mut script_sigs).unwrap();
+        }
+        buf.extend_from_slice(sha256::Hash::hash(&script_sigs).as_byte_array());
+    }
+
+    buf.extend_from_slice(&(tx.input.len() as u32).to_le_bytes());
+    let sequences = tx
+        .input
+        .iter()
+        .flat_map(|txin| txin.sequence.0.to_le_bytes())
+        .collect::<Vec<u8>>();
+    buf.extend_from_slice(sha256::Hash::hash(&sequences).as_byte_array());
+
+    buf.extend_from_slice(&(tx.output.len() as u32).to_le_bytes());
+    let mut outputs = Vec::new();
+    for txout in &tx.output {
+        txout.consensus_encode(&mut outputs).unwrap();
+    }
+    buf.extend_from_slice(sha256::Hash::hash(&outputs).as_byte_array());
+
+    buf.extend_from_slice(&input_idx.to_le_bytes());
+    sha256::Hash::hash(&buf)
+}
+
+/// Verifies a BIP 340 signature over a message of arbitrary length.
+fn verify_schnorr_msg(sig: &[u8], msg: &[u8], pk: &XOnlyPublicKey) -> bool {
+    assert_eq!(sig.len(), 64);
+    // The safe API only accepts 32-byte messages, while BIP 348 allows
+    // messages of any size, which libsecp256k1 supports.
+    // SAFETY: the signature is 64 bytes, the message pointer is valid for
+    // msg.len() bytes and the context and public key are valid objects.
+    let ret = unsafe {
+        ffi::secp256k1_schnorrsig_verify(
+            SECP.ctx().as_ptr(),
+            sig.as_ptr(),
+            msg.as_ptr(),
+            msg.len(),
+            pk.as_c_ptr(),
+        )
+    };
+    ret == 1
+}
+
+impl Exec {
+    /// Executes OP_CHECKTEMPLATEVERIFY.
+    ///
+    /// Like the other NOPs turned into soft-fork opcodes, the argument is
+    /// left on the stack.
+    pub(crate) fn check_template_verify(&mut self) -> Result<(), ExecError> {
+        let template = self.stack.topstr(-1)?;
+        if template.len() == 32 {
+            let hash = default_template_hash(&self.tx.tx, self.tx.input_idx as u32);
+            if template[..] != hash.as_byte_array()[..] {
+                return Err(ExecError::TemplateMismatch);
+            }
+        } else if self.opt.discourage_upgradable_nops {
+            // Other template types are reserved for future upgrades.
+            return Err(ExecError::DiscourageUpgradableNops);
+        }
+        Ok(())
+    }
+
+    /// Executes OP_CHECKSIGFROMSTACK.
+    ///
+    /// (sig msg pubkey -- bool)
+    pub(crate) fn check_sig_from_stack(&mut self) -> Result<(), ExecError> {
+        let sig = self.stack.topstr(-3)?;
+        let msg = self.stack.topstr(-2)?;
+        let pk = self.stack.topstr(-1)?;
+
+        if !sig.is_empty() {
+            self.validation_weight -= VALIDATION_WEIGHT_PER_SIGOP_PASSED;
+            if self.validation_weight < 0 {
+                return Err(ExecError::TapscriptValidationWeight);
+            }
+        }
+
+        if pk.is_empty() {
+            return Err(ExecError::PubkeyType);
+        }
+        let success = if pk.len() == 32 {
+            if !sig.is_empty() {
+                if sig.len() != 64 {
+                    return Err(ExecError::SchnorrSigSize);
+                }
+                let pk = XOnlyPublicKey::from_slice(&pk).map_err(|_| ExecError::SchnorrSig)?;
+                if !verify_schnorr_msg(&sig, &msg, &pk) {
+                    return Err(ExecError::SchnorrSig);
+                }
+                true
+            } else {
+                false
+            }
+        } else if self.opt.discourage_upgradable_pubkey_type {
+            return Err(ExecError::DiscourageUpgradablePubkeyType);
+        } else {
+            // Unknown public key types succeed for any non-empty signature.
+            !sig.is_empty()
+        };
+
+        self.stack.popn(3).unwrap();
+        self.stack.pushnum(if success { 1 } else { 0 });
+        Ok(())
+    }
+}
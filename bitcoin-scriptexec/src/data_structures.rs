@@ -1,13 +1,13 @@
 use crate::{read_scriptint, ExecError};
-use alloc::rc::Rc;
+use alloc::sync::Arc;
 use bitcoin::script;
-use core::cell::RefCell;
 use core::cmp::PartialEq;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum StackEntry {
     Num(i64),
-    StrRef(Rc<RefCell<Vec<u8>>>),
+    StrRef(Arc<Vec<u8>>),
 }
 
 impl StackEntry {
@@ -24,7 +24,7 @@ impl StackEntry {
                 num.to_le_bytes().to_vec()
             }
             StackEntry::StrRef(v) => {
-                let mut v = v.borrow().to_vec();
+                let mut v = v.to_vec();
                 assert!(
                     v.len() <= 4,
                     "There should not be entries with more than 32 bits on the stack at this point"
@@ -39,6 +39,7 @@ impl StackEntry {
 }
 
 #[derive(Clone, Eq, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Stack(Vec<StackEntry>);
 
 impl Stack {
@@ -57,7 +58,7 @@ impl Stack {
     pub fn from_u8_vec(v: Vec<Vec<u8>>) -> Self {
         let mut res = Self::new();
         for entry in v {
-            res.0.push(StackEntry::StrRef(Rc::new(RefCell::new(entry))));
+            res.0.push(StackEntry::StrRef(Arc::new(entry)));
         }
         res
     }
@@ -75,7 +76,7 @@ impl Stack {
         let entry = self.top(offset)?;
         match entry {
             StackEntry::Num(v) => Ok(script::scriptint_vec(*v)),
-            StackEntry::StrRef(v) => Ok(v.borrow().to_vec()),
+            StackEntry::StrRef(v) => Ok(v.to_vec()),
         }
     }
 
@@ -83,13 +84,13 @@ impl Stack {
         let entry = self.top(offset)?;
         match entry {
             StackEntry::Num(v) => {
-                if *v <= i32::MAX as i64 {
+                if v.unsigned_abs() <= i32::MAX as u64 {
                     Ok(*v)
                 } else {
                     Err(ExecError::ScriptIntNumericOverflow)
                 }
             }
-            StackEntry::StrRef(v) => Ok(read_scriptint(v.borrow().as_slice(), 4, require_minimal)?),
+            StackEntry::StrRef(v) => Ok(read_scriptint(v.as_slice(), 4, require_minimal)?),
         }
     }
 
@@ -99,7 +100,7 @@ impl Stack {
 
     pub fn pushstr(&mut self, v: &[u8]) {
         self.0
-            .push(StackEntry::StrRef(Rc::new(RefCell::new(v.to_vec()))));
+            .push(StackEntry::StrRef(Arc::new(v.to_vec())));
     }
 
     pub fn push(&mut self, v: StackEntry) {
@@ -129,7 +130,7 @@ impl Stack {
         let entry = self.0.pop().ok_or(ExecError::InvalidStackOperation)?;
         match entry {
             StackEntry::Num(v) => Ok(script::scriptint_vec(v)),
-            StackEntry::StrRef(v) => Ok(v.borrow().to_vec()),
+            StackEntry::StrRef(v) => Ok(v.to_vec()),
         }
     }
 
@@ -137,13 +138,13 @@ impl Stack {
         let entry = self.0.pop().ok_or(ExecError::InvalidStackOperation)?;
         match entry {
             StackEntry::Num(v) => {
-                if v <= i32::MAX as i64 {
+                if v.unsigned_abs() <= i32::MAX as u64 {
                     Ok(v)
                 } else {
                     Err(ExecError::ScriptIntNumericOverflow)
                 }
             }
-            StackEntry::StrRef(v) => Ok(read_scriptint(v.borrow().as_slice(), 4, require_minimal)?),
+            StackEntry::StrRef(v) => Ok(read_scriptint(v.as_slice(), 4, require_minimal)?),
         }
     }
 
@@ -151,6 +152,11 @@ impl Stack {
         self.0.len()
     }
 
+    /// Replaces the item at the given index, counted from the bottom.
+    pub fn set(&mut self, index: usize, v: &[u8]) {
+        self.0[index] = StackEntry::StrRef(Arc::new(v.to_vec()));
+    }
+
     pub fn remove(&mut self, v: usize) {
         self.0.remove(v);
     }
@@ -158,14 +164,27 @@ impl Stack {
     pub fn iter_str(&self) -> impl DoubleEndedIterator<Item = Vec<u8>> + '_ {
         self.0.iter().map(|v| match v {
             StackEntry::Num(v) => script::scriptint_vec(*v),
-            StackEntry::StrRef(v) => v.borrow().to_vec(),
+            StackEntry::StrRef(v) => v.to_vec(),
         })
     }
 
+    /// Returns the number of items at the bottom of both stacks that are equal.
+    pub fn common_prefix_len(&self, other: &Stack) -> usize {
+        self.0
+            .iter()
+            .zip(other.0.iter())
+            .take_while(|(a, b)| match (a, b) {
+                // Shared entries are equal without having to compare the contents.
+                (StackEntry::StrRef(a), StackEntry::StrRef(b)) if Arc::ptr_eq(a, b) => true,
+                (a, b) => a == b,
+            })
+            .count()
+    }
+
     pub fn get(&self, index: usize) -> Vec<u8> {
         match &self.0[index] {
             StackEntry::Num(v) => script::scriptint_vec(*v),
-            StackEntry::StrRef(v) => v.borrow().to_vec(),
+            StackEntry::StrRef(v) => v.to_vec(),
         }
     }
 
@@ -0,0 +1,716 @@
+//! Interactive step debugger for the `btcexec` CLI.
+//!
+//! Reverse stepping is implemented by restoring the last checkpoint before
+//! the requested step and re-executing from there, re-applying all stack
+//! edits the user made along the way.
+
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+use bitcoin::hex::{DisplayHex, FromHex};
+use bitcoin::opcodes::all::*;
+use bitcoin::{Opcode, Script};
+
+use bitcoin_scriptexec::*;
+
+/// The number of steps between two checkpoints used for reverse stepping.
+const CHECKPOINT_INTERVAL: usize = 1000;
+
+const HELP: &str = "\
+Commands:
+  s, step [n]              execute the next n instructions (default 1)
+  n, next                  execute the next instruction, stepping over OP_IF blocks
+  c, continue              execute until a breakpoint is hit or execution ends
+  rs, reverse-step [n]     go back n instructions (default 1)
+  b, break <pos>           break at script position <pos>
+  b, break <OPCODE>        break before every <OPCODE>, e.g. OP_CHECKSIG
+  b, break [alt]stack len <cmp> <n>
+                           break when the stack size matches, e.g. stack len >= 10
+  b, break [alt]stack <i> <==|!=> <hex>
+                           break when stack item <i> (0 is top) matches <hex>
+  d, delete <n>            delete breakpoint <n>
+  bl, breakpoints          list breakpoints
+  st, stack                print the stack
+  as, altstack             print the altstack
+  i, info                  print position, next instruction and stats
+  l, list [n]              print the next n instructions (default 10)
+  set [alt] <i> <hex>      replace stack item <i> (0 is top)
+  push [alt] <hex>         push an item on the stack
+  pop [alt]                pop an item off the stack
+  h, help                  print this help
+  q, quit                  quit the debugger
+An empty line repeats the last command.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CmpOp {
+    fn parse(s: &str) -> Option<CmpOp> {
+        Some(match s {
+            "==" => CmpOp::Eq,
+            "!=" => CmpOp::Ne,
+            "<" => CmpOp::Lt,
+            "<=" => CmpOp::Le,
+            ">" => CmpOp::Gt,
+            ">=" => CmpOp::Ge,
+            _ => return None,
+        })
+    }
+
+    fn eval<T: PartialOrd>(self, a: T, b: T) -> bool {
+        match self {
+            CmpOp::Eq => a == b,
+            CmpOp::Ne => a != b,
+            CmpOp::Lt => a < b,
+            CmpOp::Le => a <= b,
+            CmpOp::Gt => a > b,
+            CmpOp::Ge => a >= b,
+        }
+    }
+}
+
+impl fmt::Display for CmpOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            CmpOp::Eq => "==",
+            CmpOp::Ne => "!=",
+            CmpOp::Lt => "<",
+            CmpOp::Le => "<=",
+            CmpOp::Gt => ">",
+            CmpOp::Ge => ">=",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Breakpoint {
+    Position(usize),
+    Opcode(Opcode),
+    StackLen {
+        alt: bool,
+        cmp: CmpOp,
+        len: usize,
+    },
+    StackItem {
+        alt: bool,
+        idx: usize,
+        cmp: CmpOp,
+        value: Vec<u8>,
+    },
+}
+
+impl Breakpoint {
+    fn parse(args: &[&str]) -> Result<Breakpoint, String> {
+        match args {
+            [pos] if pos.starts_with("OP_") => parse_opcode(pos).map(Breakpoint::Opcode),
+            [pos] => pos
+                .parse()
+                .map(Breakpoint::Position)
+                .map_err(|_| format!("invalid position: {}", pos)),
+            [stack, "len", cmp, len] => Ok(Breakpoint::StackLen {
+                alt: parse_stack_name(stack)?,
+                cmp: CmpOp::parse(cmp).ok_or_else(|| format!("invalid comparison: {}", cmp))?,
+                len: len
+                    .parse()
+                    .map_err(|_| format!("invalid length: {}", len))?,
+            }),
+            [stack, idx, cmp, value] => {
+                let cmp =
+                    CmpOp::parse(cmp).ok_or_else(|| format!("invalid comparison: {}", cmp))?;
+                if cmp != CmpOp::Eq && cmp != CmpOp::Ne {
+                    return Err("stack items can only be compared with == and !=".into());
+                }
+                Ok(Breakpoint::StackItem {
+                    alt: parse_stack_name(stack)?,
+                    idx: idx.parse().map_err(|_| format!("invalid index: {}", idx))?,
+                    cmp,
+                    value: parse_hex(value)?,
+                })
+            }
+            _ => Err("invalid breakpoint, see help".into()),
+        }
+    }
+
+    /// Whether the breakpoint is hit before executing the next instruction.
+    fn is_hit(&self, exec: &Exec) -> bool {
+        match self {
+            Breakpoint::Position(pos) => exec.script_position() == *pos,
+            Breakpoint::Opcode(op) => next_opcode(exec) == Some(*op),
+            Breakpoint::StackLen { alt, cmp, len } => {
+                cmp.eval(select_stack(exec, *alt).len(), *len)
+            }
+            Breakpoint::StackItem {
+                alt,
+                idx,
+                cmp,
+                value,
+            } => {
+                let stack = select_stack(exec, *alt);
+                if *idx >= stack.len() {
+                    return false;
+                }
+                cmp.eval(&stack.get(stack.len() - 1 - idx), value)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = |alt: &bool| if *alt { "altstack" } else { "stack" };
+        match self {
+            Breakpoint::Position(pos) => write!(f, "position {}", pos),
+            Breakpoint::Opcode(op) => write!(f, "opcode {}", op),
+            Breakpoint::StackLen { alt, cmp, len } => {
+                write!(f, "{} len {} {}", name(alt), cmp, len)
+            }
+            Breakpoint::StackItem {
+                alt,
+                idx,
+                cmp,
+                value,
+            } => write!(f, "{}[{}] {} {}", name(alt), idx, cmp, value.as_hex()),
+        }
+    }
+}
+
+/// A modification of the stacks made by the user.
+#[derive(Debug, Clone)]
+enum Edit {
+    Set {
+        alt: bool,
+        idx: usize,
+        value: Vec<u8>,
+    },
+    Push {
+        alt: bool,
+        value: Vec<u8>,
+    },
+    Pop {
+        alt: bool,
+    },
+}
+
+impl Edit {
+    fn apply(&self, exec: &mut Exec) -> Result<(), String> {
+        match self {
+            Edit::Set { alt, idx, value } => {
+                let stack = select_stack_mut(exec, *alt);
+                if *idx >= stack.len() {
+                    return Err(format!("no item at index {}", idx));
+                }
+                let len = stack.len();
+                stack.set(len - 1 - idx, value);
+            }
+            Edit::Push { alt, value } => select_stack_mut(exec, *alt).pushstr(value),
+            Edit::Pop { alt } => {
+                if select_stack_mut(exec, *alt).pop().is_none() {
+                    return Err("stack is empty".into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    Vec::from_hex(s).map_err(|e| format!("invalid hex {}: {}", s, e))
+}
+
+fn parse_opcode(s: &str) -> Result<Opcode, String> {
+    (0..=u8::MAX)
+        .map(Opcode::from)
+        .find(|op| op.to_string() == s)
+        .ok_or_else(|| format!("unknown opcode: {}", s))
+}
+
+fn parse_stack_name(s: &str) -> Result<bool, String> {
+    match s {
+        "stack" => Ok(false),
+        "altstack" => Ok(true),
+        _ => Err(format!("expected stack or altstack, got {}", s)),
+    }
+}
+
+fn select_stack(exec: &Exec, alt: bool) -> &Stack {
+    if alt {
+        exec.altstack()
+    } else {
+        exec.stack()
+    }
+}
+
+fn select_stack_mut(exec: &mut Exec, alt: bool) -> &mut Stack {
+    if alt {
+        exec.altstack_mut()
+    } else {
+        exec.stack_mut()
+    }
+}
+
+/// Returns the size of the first instruction of the script in bytes.
+fn first_instruction_len(script: &Script) -> Option<usize> {
+    let mut instructions = script.instructions();
+    match instructions.next() {
+        Some(Ok(_)) => Some(script.len() - instructions.as_script().len()),
+        _ => None,
+    }
+}
+
+fn next_opcode(exec: &Exec) -> Option<Opcode> {
+    exec.remaining_script()
+        .as_bytes()
+        .first()
+        .map(|b| Opcode::from(*b))
+}
+
+/// Prints the stack with indices, top first.
+fn print_stack(name: &str, stack: &Stack) {
+    println!("{} ({} items):", name, stack.len());
+    for (i, item) in stack.iter_str().rev().enumerate() {
+        println!("  [{}] <{}>", i, item.as_hex());
+    }
+}
+
+pub struct Debugger {
+    new_exec: Box<dyn Fn() -> Exec>,
+    exec: Exec,
+    /// The number of instructions executed so far.
+    step: usize,
+    /// The edits made by the user, with the step at which they were made.
+    edits: Vec<(usize, Edit)>,
+    /// Snapshots taken every [CHECKPOINT_INTERVAL] steps, before any edits
+    /// made at that step.
+    checkpoints: Vec<(usize, ExecSnapshot)>,
+    breakpoints: Vec<Breakpoint>,
+}
+
+impl Debugger {
+    /// Creates a new debugger. The closure must create identical fresh
+    /// executions every time, they are used to go back in time.
+    pub fn new(new_exec: Box<dyn Fn() -> Exec>) -> Debugger {
+        Debugger {
+            exec: new_exec(),
+            new_exec,
+            step: 0,
+            edits: Vec::new(),
+            checkpoints: Vec::new(),
+            breakpoints: Vec::new(),
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.exec.result().is_some()
+    }
+
+    /// Executes a single instruction, returns false if execution is over.
+    fn step_once(&mut self) -> bool {
+        if self.exec.exec_next().is_err() {
+            return false;
+        }
+        self.step += 1;
+        if self.step.is_multiple_of(CHECKPOINT_INTERVAL)
+            && self
+                .checkpoints
+                .last()
+                .is_none_or(|(step, _)| *step < self.step)
+        {
+            self.checkpoints.push((self.step, self.exec.snapshot()));
+        }
+        true
+    }
+
+    fn step_over(&mut self) {
+        let mut depth = 0;
+        loop {
+            match next_opcode(&self.exec) {
+                Some(OP_IF) | Some(OP_NOTIF) => depth += 1,
+                Some(OP_ENDIF) if depth > 0 => depth -= 1,
+                _ => {}
+            }
+            if !self.step_once() || depth == 0 {
+                return;
+            }
+        }
+    }
+
+    /// Executes until a breakpoint is hit, returns the index of the breakpoint.
+    fn continue_exec(&mut self) -> Option<usize> {
+        while self.step_once() {
+            if let Some(idx) = self.breakpoints.iter().position(|b| b.is_hit(&self.exec)) {
+                return Some(idx);
+            }
+        }
+        None
+    }
+
+    /// Re-executes the script from the last checkpoint up to the given step.
+    fn goto(&mut self, target: usize) {
+        self.edits.retain(|(step, _)| *step <= target);
+        self.checkpoints.retain(|(step, _)| *step <= target);
+
+        self.exec = (self.new_exec)();
+        self.step = 0;
+        if let Some((step, snapshot)) = self.checkpoints.last() {
+            self.exec
+                .restore(snapshot)
+                .expect("snapshot of the same script");
+            self.step = *step;
+        }
+
+        let start = self.step;
+        let edits = self.edits.clone();
+        let mut edits = edits
+            .iter()
+            .skip_while(|(step, _)| *step < start)
+            .peekable();
+        loop {
+            while let Some((_, edit)) = edits.next_if(|(step, _)| *step == self.step) {
+                edit.apply(&mut self.exec).expect("edit was valid before");
+            }
+            if self.step == target || !self.step_once() {
+                break;
+            }
+        }
+    }
+
+    fn edit(&mut self, edit: Edit) -> Result<(), String> {
+        edit.apply(&mut self.exec)?;
+        self.edits.push((self.step, edit));
+        Ok(())
+    }
+
+    fn print_location(&self) {
+        if let Some(res) = self.exec.result() {
+            println!(
+                "Execution ended after {} steps. Success: {}",
+                self.step, res.success
+            );
+            if let Some(ref err) = res.error {
+                print!("Error: {} ({})", err, err.name());
+                if let (Some(opcode), Some(position)) = (res.opcode, res.position) {
+                    print!(" at position {} ({})", position, opcode);
+                }
+                println!();
+            }
+            return;
+        }
+
+        let remaining = self.exec.remaining_script();
+        let next = match first_instruction_len(remaining) {
+            Some(len) => remaining[..len].to_asm_string(),
+            None => "<end of script>".to_owned(),
+        };
+        println!(
+            "step {} | position {} | next: {}",
+            self.step,
+            self.exec.script_position(),
+            next
+        );
+    }
+
+    fn print_listing(&self, n: usize) {
+        let mut pos = self.exec.script_position();
+        let mut remaining = self.exec.remaining_script();
+        for _ in 0..n {
+            let len = match first_instruction_len(remaining) {
+                Some(len) => len,
+                None => break,
+            };
+            println!("  {:>8}  {}", pos, remaining[..len].to_asm_string());
+            pos += len;
+            remaining = &remaining[len..];
+        }
+    }
+
+    /// Parses an optional `alt` argument in front of the remaining arguments.
+    fn parse_alt<'a, 'b>(args: &'a [&'b str]) -> (bool, &'a [&'b str]) {
+        match args.first() {
+            Some(&"alt") => (true, &args[1..]),
+            _ => (false, args),
+        }
+    }
+
+    /// Executes a single command, returns false when the user wants to quit.
+    fn command(&mut self, line: &str) -> Result<bool, String> {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let (cmd, args) = match words.split_first() {
+            Some((cmd, args)) => (*cmd, args),
+            None => return Ok(true),
+        };
+        let count = |args: &[&str], default: usize| -> Result<usize, String> {
+            match args.first() {
+                Some(n) => n.parse().map_err(|_| format!("invalid number: {}", n)),
+                None => Ok(default),
+            }
+        };
+
+        match cmd {
+            "s" | "step" => {
+                let n = count(args, 1)?;
+                for _ in 0..n {
+                    if !self.step_once() {
+                        break;
+                    }
+                }
+                self.print_location();
+            }
+            "n" | "next" => {
+                self.step_over();
+                self.print_location();
+            }
+            "c" | "continue" => {
+                if let Some(idx) = self.continue_exec() {
+                    println!("Hit breakpoint {}: {}", idx, self.breakpoints[idx]);
+                }
+                self.print_location();
+            }
+            "rs" | "reverse-step" => {
+                let n = count(args, 1)?;
+                let target = self.step.saturating_sub(n);
+                self.goto(target);
+                self.print_location();
+            }
+            "b" | "break" => {
+                let bp = Breakpoint::parse(args)?;
+                println!("Breakpoint {}: {}", self.breakpoints.len(), bp);
+                self.breakpoints.push(bp);
+            }
+            "d" | "delete" => {
+                let idx = count(args, usize::MAX)?;
+                if idx >= self.breakpoints.len() {
+                    return Err(format!("no breakpoint {}", idx));
+                }
+                self.breakpoints.remove(idx);
+            }
+            "bl" | "breakpoints" => {
+                for (i, bp) in self.breakpoints.iter().enumerate() {
+                    println!("  {}: {}", i, bp);
+                }
+            }
+            "st" | "stack" => print_stack("Stack", self.exec.stack()),
+            "as" | "altstack" => print_stack("AltStack", self.exec.altstack()),
+            "i" | "info" => {
+                self.print_location();
+                println!("Stats: {:?}", self.exec.stats());
+            }
+            "l" | "list" => self.print_listing(count(args, 10)?),
+            "set" => {
+                let (alt, args) = Self::parse_alt(args);
+                match args {
+                    [idx, value] => self.edit(Edit::Set {
+                        alt,
+                        idx: idx.parse().map_err(|_| format!("invalid index: {}", idx))?,
+                        value: parse_hex(value)?,
+                    })?,
+                    _ => return Err("usage: set [alt] <i> <hex>".into()),
+                }
+            }
+            "push" => {
+                let (alt, args) = Self::parse_alt(args);
+                match args {
+                    [value] => self.edit(Edit::Push {
+                        alt,
+                        value: parse_hex(value)?,
+                    })?,
+                    _ => return Err("usage: push [alt] <hex>".into()),
+                }
+            }
+            "pop" => {
+                let (alt, _) = Self::parse_alt(args);
+                self.edit(Edit::Pop { alt })?;
+            }
+            "h" | "help" => println!("{}", HELP),
+            "q" | "quit" => return Ok(false),
+            _ => return Err(format!("unknown command: {}, try help", cmd)),
+        }
+
+        if self.is_done() && matches!(cmd, "set" | "push" | "pop") {
+            println!("Note: execution has ended, step back to continue with the modified stack");
+        }
+        Ok(true)
+    }
+
+    /// Runs the interactive loop on stdin until the user quits.
+    pub fn run(&mut self) -> io::Result<()> {
+        println!("Interactive debugger, type help for the list of commands.");
+        self.print_location();
+
+        let stdin = io::stdin();
+        let mut last_line = String::new();
+        loop {
+            print!("(btcexec) ");
+            io::stdout().flush()?;
+
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let line = if line.trim().is_empty() {
+                last_line.clone()
+            } else {
+                line.trim().to_owned()
+            };
+
+            match self.command(&line) {
+                Ok(true) => {}
+                Ok(false) => return Ok(()),
+                Err(e) => println!("Error: {}", e),
+            }
+            last_line = line;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::script::Builder;
+    use bitcoin::ScriptBuf;
+
+    use super::*;
+
+    fn debugger(script: ScriptBuf) -> Debugger {
+        Debugger::new(Box::new(move || {
+            Exec::new(
+                ExecCtx::Tapscript,
+                Options::default(),
+                TxTemplate::dummy(ExecCtx::Tapscript),
+                script.clone(),
+                vec![],
+            )
+            .unwrap()
+        }))
+    }
+
+    /// Pushes 0 and increments it, so the top of the stack is `n - 1` after
+    /// `n` steps.
+    fn counter_script(n: usize) -> ScriptBuf {
+        let mut builder = Builder::new().push_int(0);
+        for _ in 0..n {
+            builder = builder.push_opcode(OP_1ADD);
+        }
+        builder.into_script()
+    }
+
+    fn run(debugger: &mut Debugger, commands: &[&str]) {
+        for command in commands {
+            assert_eq!(debugger.command(command), Ok(true), "{}", command);
+        }
+    }
+
+    fn top(debugger: &Debugger) -> i64 {
+        debugger.exec.stack().topnum(-1, false).unwrap()
+    }
+
+    #[test]
+    fn test_step_and_reverse_step() {
+        let mut debugger = debugger(counter_script(10));
+        run(&mut debugger, &["s 5"]);
+        assert_eq!(debugger.step, 5);
+        assert_eq!(top(&debugger), 4);
+
+        run(&mut debugger, &["rs 2"]);
+        assert_eq!(debugger.step, 3);
+        assert_eq!(top(&debugger), 2);
+
+        // Stepping past the end stops at the end of the script.
+        run(&mut debugger, &["s 100"]);
+        assert_eq!(debugger.step, 11);
+        assert!(debugger.is_done());
+
+        run(&mut debugger, &["rs 100"]);
+        assert_eq!(debugger.step, 0);
+        assert_eq!(debugger.exec.stack().len(), 0);
+    }
+
+    #[test]
+    fn test_edits_are_replayed() {
+        let mut debugger = debugger(counter_script(10));
+        run(&mut debugger, &["s 3", "set 0 0a", "push alt 01", "s 2"]);
+        assert_eq!(top(&debugger), 12);
+        assert_eq!(debugger.exec.altstack().get(0), vec![1]);
+
+        // Going back to a step after the edits replays them.
+        run(&mut debugger, &["rs 1"]);
+        assert_eq!(debugger.step, 4);
+        assert_eq!(top(&debugger), 11);
+        assert_eq!(debugger.exec.altstack().len(), 1);
+
+        // Going back before the edits drops them.
+        run(&mut debugger, &["rs 2", "s 2"]);
+        assert_eq!(debugger.step, 4);
+        assert_eq!(top(&debugger), 3);
+        assert_eq!(debugger.exec.altstack().len(), 0);
+
+        assert!(debugger.command("set 5 00").is_err());
+        assert!(debugger.command("pop alt").is_err());
+        assert!(debugger.edits.is_empty());
+    }
+
+    #[test]
+    fn test_reverse_step_across_checkpoints() {
+        let n = CHECKPOINT_INTERVAL * 2 + 500;
+        let mut debugger = debugger(counter_script(n));
+        run(&mut debugger, &["s 2200"]);
+        assert_eq!(
+            debugger
+                .checkpoints
+                .iter()
+                .map(|(s, _)| *s)
+                .collect::<Vec<_>>(),
+            vec![1000, 2000],
+        );
+
+        run(&mut debugger, &["rs 1"]);
+        assert_eq!(top(&debugger), 2198);
+
+        // An edit made at the step of a checkpoint is replayed on top of it.
+        run(&mut debugger, &["rs 1199", "set 0 05", "s 1100"]);
+        assert_eq!(debugger.step, 2100);
+        assert_eq!(top(&debugger), 1105);
+        run(&mut debugger, &["rs 1090"]);
+        assert_eq!(debugger.step, 1010);
+        assert_eq!(top(&debugger), 15);
+
+        // Going back before a checkpoint drops it and the edits after it.
+        run(&mut debugger, &["rs 20"]);
+        assert_eq!(debugger.step, 990);
+        assert_eq!(debugger.checkpoints.len(), 0);
+        run(&mut debugger, &["s 20"]);
+        assert_eq!(top(&debugger), 1009);
+        assert_eq!(debugger.checkpoints.len(), 1);
+    }
+
+    #[test]
+    fn test_step_over() {
+        let script = Builder::new()
+            .push_int(1)
+            .push_opcode(OP_IF)
+            .push_int(2)
+            .push_int(3)
+            .push_opcode(OP_ENDIF)
+            .push_int(4)
+            .into_script();
+        let mut debugger = debugger(script);
+        run(&mut debugger, &["n"]);
+        assert_eq!(debugger.step, 1);
+
+        run(&mut debugger, &["n"]);
+        assert_eq!(debugger.step, 5);
+        assert_eq!(
+            debugger.exec.stack().iter_str().collect::<Vec<_>>(),
+            vec![vec![2], vec![3]],
+        );
+
+        run(&mut debugger, &["n", "rs 3"]);
+        assert_eq!(debugger.step, 3);
+        assert_eq!(debugger.exec.stack().len(), 1);
+    }
+}
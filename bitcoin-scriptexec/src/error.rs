@@ -1,3 +1,5 @@
+use std::fmt;
+
 use bitcoin::blockdata::script;
 
 /// Error of a script execution.
@@ -21,6 +23,7 @@ pub enum ExecError {
     EqualVerify,
     NumEqualVerify,
     CheckSigVerify,
+    CheckMultiSigVerify,
     TapscriptValidationWeight,
     PubkeyType,
     SchnorrSigSize,
@@ -28,17 +31,223 @@ pub enum ExecError {
     SchnorrSig,
     TapscriptCheckMultiSig,
     PubkeyCount,
+    SigCount,
+    SigNullDummy,
     StackSize,
     WitnessPubkeyType,
+    SigPushOnly,
+    EvalFalse,
+    CleanStack,
+    WitnessProgramWrongLength,
+    WitnessProgramWitnessEmpty,
+    WitnessProgramMismatch,
+    WitnessMalleated,
+    WitnessMalleatedP2SH,
+    WitnessUnexpected,
+    TaprootWrongControlSize,
+    SigHashtype,
+    SigDer,
+    SigHighS,
+    SigNullFail,
+    SigFindAndDelete,
+    MinimalIf,
+    DiscourageUpgradableNops,
+    DiscourageUpgradableWitnessProgram,
+    DiscourageUpgradableTaprootVersion,
+    DiscourageOpSuccess,
+    DiscourageUpgradablePubkeyType,
+    TemplateMismatch,
 
     // new ones for us
+    /// A number operand is longer than allowed. Core throws a
+    /// `scriptnum_error` for it, which `EvalScript` reports as
+    /// `SCRIPT_ERR_UNKNOWN_ERROR`.
     ScriptIntNumericOverflow,
+    /// A number operand is not minimally encoded while `require_minimal` is
+    /// set. Like [ExecError::ScriptIntNumericOverflow], Core reports this
+    /// `scriptnum_error` as `SCRIPT_ERR_UNKNOWN_ERROR`, not as
+    /// `SCRIPT_ERR_MINIMALDATA`, which is only used for pushes.
+    ScriptIntNonMinimal,
+    /// OP_RESERVED was executed. Core has no debug opcodes and fails on
+    /// OP_RESERVED with `SCRIPT_ERR_BAD_OPCODE`.
     Debug,
 }
 
+impl ExecError {
+    /// The name of the equivalent Bitcoin Core `ScriptError_t`, without the
+    /// `SCRIPT_ERR_` prefix.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExecError::DisabledOpcode => "DISABLED_OPCODE",
+            ExecError::OpCodeseparator => "OP_CODESEPARATOR",
+            ExecError::BadOpcode => "BAD_OPCODE",
+            ExecError::OpCount => "OP_COUNT",
+            ExecError::PushSize => "PUSH_SIZE",
+            ExecError::MinimalData => "MINIMALDATA",
+            ExecError::InvalidStackOperation => "INVALID_STACK_OPERATION",
+            ExecError::NegativeLocktime => "NEGATIVE_LOCKTIME",
+            ExecError::UnsatisfiedLocktime => "UNSATISFIED_LOCKTIME",
+            ExecError::UnbalancedConditional => "UNBALANCED_CONDITIONAL",
+            ExecError::TapscriptMinimalIf => "TAPSCRIPT_MINIMALIF",
+            ExecError::Verify => "VERIFY",
+            ExecError::OpReturn => "OP_RETURN",
+            ExecError::EqualVerify => "EQUALVERIFY",
+            ExecError::NumEqualVerify => "NUMEQUALVERIFY",
+            ExecError::CheckSigVerify => "CHECKSIGVERIFY",
+            ExecError::CheckMultiSigVerify => "CHECKMULTISIGVERIFY",
+            ExecError::TapscriptValidationWeight => "TAPSCRIPT_VALIDATION_WEIGHT",
+            ExecError::PubkeyType => "PUBKEYTYPE",
+            ExecError::SchnorrSigSize => "SCHNORR_SIG_SIZE",
+            ExecError::SchnorrSigHashtype => "SCHNORR_SIG_HASHTYPE",
+            ExecError::SchnorrSig => "SCHNORR_SIG",
+            ExecError::TapscriptCheckMultiSig => "TAPSCRIPT_CHECKMULTISIG",
+            ExecError::PubkeyCount => "PUBKEY_COUNT",
+            ExecError::SigCount => "SIG_COUNT",
+            ExecError::SigNullDummy => "SIG_NULLDUMMY",
+            ExecError::StackSize => "STACK_SIZE",
+            ExecError::WitnessPubkeyType => "WITNESS_PUBKEYTYPE",
+            ExecError::SigPushOnly => "SIG_PUSHONLY",
+            ExecError::EvalFalse => "EVAL_FALSE",
+            ExecError::CleanStack => "CLEANSTACK",
+            ExecError::WitnessProgramWrongLength => "WITNESS_PROGRAM_WRONG_LENGTH",
+            ExecError::WitnessProgramWitnessEmpty => "WITNESS_PROGRAM_WITNESS_EMPTY",
+            ExecError::WitnessProgramMismatch => "WITNESS_PROGRAM_MISMATCH",
+            ExecError::WitnessMalleated => "WITNESS_MALLEATED",
+            ExecError::WitnessMalleatedP2SH => "WITNESS_MALLEATED_P2SH",
+            ExecError::WitnessUnexpected => "WITNESS_UNEXPECTED",
+            ExecError::TaprootWrongControlSize => "TAPROOT_WRONG_CONTROL_SIZE",
+            ExecError::SigHashtype => "SIG_HASHTYPE",
+            ExecError::SigDer => "SIG_DER",
+            ExecError::SigHighS => "SIG_HIGH_S",
+            ExecError::SigNullFail => "NULLFAIL",
+            ExecError::SigFindAndDelete => "SIG_FINDANDDELETE",
+            ExecError::MinimalIf => "MINIMALIF",
+            ExecError::DiscourageUpgradableNops => "DISCOURAGE_UPGRADABLE_NOPS",
+            ExecError::DiscourageUpgradableWitnessProgram => {
+                "DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM"
+            }
+            ExecError::DiscourageUpgradableTaprootVersion => {
+                "DISCOURAGE_UPGRADABLE_TAPROOT_VERSION"
+            }
+            ExecError::DiscourageOpSuccess => "DISCOURAGE_OP_SUCCESS",
+            ExecError::DiscourageUpgradablePubkeyType => "DISCOURAGE_UPGRADABLE_PUBKEYTYPE",
+            ExecError::TemplateMismatch => "TEMPLATE_MISMATCH",
+            // These are the codes Core reports, see the variants.
+            ExecError::ScriptIntNumericOverflow => "UNKNOWN_ERROR",
+            ExecError::ScriptIntNonMinimal => "UNKNOWN_ERROR",
+            ExecError::Debug => "BAD_OPCODE",
+        }
+    }
+
+    /// The error message, like Bitcoin Core's `ScriptErrorString`.
+    pub fn message(&self) -> &'static str {
+        match self {
+            ExecError::DisabledOpcode => "Attempted to use a disabled opcode",
+            ExecError::OpCodeseparator => "Using OP_CODESEPARATOR in non-witness script",
+            ExecError::BadOpcode => "Opcode missing or not understood",
+            ExecError::OpCount => "Operation limit exceeded",
+            ExecError::PushSize => "Push value size limit exceeded",
+            ExecError::MinimalData => "Data push larger than necessary",
+            ExecError::InvalidStackOperation => "Operation not valid with the current stack size",
+            ExecError::NegativeLocktime => "Negative locktime",
+            ExecError::UnsatisfiedLocktime => "Locktime requirement not satisfied",
+            ExecError::UnbalancedConditional => "Invalid OP_IF construction",
+            ExecError::TapscriptMinimalIf => "OP_IF/NOTIF argument must be minimal in tapscript",
+            ExecError::Verify => "Script failed an OP_VERIFY operation",
+            ExecError::OpReturn => "OP_RETURN was encountered",
+            ExecError::EqualVerify => "Script failed an OP_EQUALVERIFY operation",
+            ExecError::NumEqualVerify => "Script failed an OP_NUMEQUALVERIFY operation",
+            ExecError::CheckSigVerify => "Script failed an OP_CHECKSIGVERIFY operation",
+            ExecError::CheckMultiSigVerify => "Script failed an OP_CHECKMULTISIGVERIFY operation",
+            ExecError::TapscriptValidationWeight => {
+                "Too much signature validation relative to witness weight"
+            }
+            ExecError::PubkeyType => "Public key is neither compressed or uncompressed",
+            ExecError::SchnorrSigSize => "Invalid Schnorr signature size",
+            ExecError::SchnorrSigHashtype => "Invalid Schnorr signature hash type",
+            ExecError::SchnorrSig => "Invalid Schnorr signature",
+            ExecError::TapscriptCheckMultiSig => {
+                "OP_CHECKMULTISIG(VERIFY) is not available in tapscript"
+            }
+            ExecError::PubkeyCount => "Pubkey count negative or limit exceeded",
+            ExecError::SigCount => "Signature count negative or greater than pubkey count",
+            ExecError::SigNullDummy => "Dummy CHECKMULTISIG argument must be zero",
+            ExecError::StackSize => "Stack size limit exceeded",
+            ExecError::WitnessPubkeyType => "Using non-compressed keys in segwit",
+            ExecError::SigPushOnly => "Only push operators allowed in signatures",
+            ExecError::EvalFalse => {
+                "Script evaluated without error but finished with a false/empty top stack element"
+            }
+            ExecError::CleanStack => "Stack size must be exactly one after execution",
+            ExecError::WitnessProgramWrongLength => "Witness program has incorrect length",
+            ExecError::WitnessProgramWitnessEmpty => "Witness program was passed an empty witness",
+            ExecError::WitnessProgramMismatch => "Witness program hash mismatch",
+            ExecError::WitnessMalleated => "Witness requires empty scriptSig",
+            ExecError::WitnessMalleatedP2SH => "Witness requires only-redeemscript scriptSig",
+            ExecError::WitnessUnexpected => "Witness provided for non-witness script",
+            ExecError::TaprootWrongControlSize => "Invalid Taproot control block size",
+            ExecError::SigHashtype => "Signature hash type missing or not understood",
+            ExecError::SigDer => "Non-canonical DER signature",
+            ExecError::SigHighS => "Non-canonical signature: S value is unnecessarily high",
+            ExecError::SigNullFail => "Signature must be zero for failed CHECK(MULTI)SIG operation",
+            ExecError::SigFindAndDelete => "Signature is found in scriptCode",
+            ExecError::MinimalIf => "OP_IF/NOTIF argument must be minimal",
+            ExecError::DiscourageUpgradableNops => "NOPx reserved for soft-fork upgrades",
+            ExecError::DiscourageUpgradableWitnessProgram => {
+                "Witness version reserved for soft-fork upgrades"
+            }
+            ExecError::DiscourageUpgradableTaprootVersion => {
+                "Taproot version reserved for soft-fork upgrades"
+            }
+            ExecError::DiscourageOpSuccess => "OP_SUCCESSx reserved for soft-fork upgrades",
+            ExecError::DiscourageUpgradablePubkeyType => {
+                "Public key version reserved for soft-fork upgrades"
+            }
+            ExecError::TemplateMismatch => "OP_CHECKTEMPLATEVERIFY template hash mismatch",
+            ExecError::ScriptIntNumericOverflow => "Script number overflow",
+            ExecError::ScriptIntNonMinimal => "Script number not minimally encoded",
+            ExecError::Debug => "Debug opcode encountered",
+        }
+    }
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl std::error::Error for ExecError {}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Error {
     Exec(ExecError),
     InvalidScript(script::Error),
     Other(&'static str),
 }
+
+impl From<ExecError> for Error {
+    fn from(e: ExecError) -> Error {
+        Error::Exec(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Exec(e) => fmt::Display::fmt(e, f),
+            Error::InvalidScript(e) => write!(f, "invalid script: {}", e),
+            Error::Other(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Exec(e) => Some(e),
+            Error::InvalidScript(e) => Some(e),
+            Error::Other(_) => None,
+        }
+    }
+}
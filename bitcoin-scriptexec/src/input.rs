@@ -0,0 +1,495 @@
+//! Loading the script and its execution context from the command line
+//! arguments.
+
+use std::io::Read;
+use std::path::Path;
+
+use bitcoin::consensus::encode::deserialize_hex;
+use bitcoin::hashes::Hash;
+use bitcoin::hex::FromHex;
+use bitcoin::opcodes::{Class, ClassifyContext};
+use bitcoin::script::Instruction;
+use bitcoin::taproot::{ControlBlock, LeafVersion, TAPROOT_ANNEX_PREFIX};
+use bitcoin::{Amount, Psbt, PubkeyHash, Script, ScriptBuf, Transaction, TxOut};
+use clap::ValueEnum;
+
+use bitcoin_scriptexec::{ExecCtx, Experimental};
+
+/// The execution context, as a command line argument.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Context {
+    /// Pre-segwit scripts.
+    Legacy,
+    /// Segwit v0 witness scripts.
+    SegwitV0,
+    /// Taproot script path spends.
+    Tapscript,
+}
+
+impl From<Context> for ExecCtx {
+    fn from(ctx: Context) -> ExecCtx {
+        match ctx {
+            Context::Legacy => ExecCtx::Legacy,
+            Context::SegwitV0 => ExecCtx::SegwitV0,
+            Context::Tapscript => ExecCtx::Tapscript,
+        }
+    }
+}
+
+/// An experimental script feature, as a command line argument.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Feature {
+    /// OP_CAT in tapscript.
+    Cat,
+    /// OP_CHECKTEMPLATEVERIFY (BIP 119).
+    Ctv,
+    /// OP_CHECKSIGFROMSTACK (BIP 348) in tapscript.
+    Csfs,
+}
+
+/// Enables the given experimental features.
+pub fn experimental(features: &[Feature]) -> Experimental {
+    Experimental {
+        op_cat: features.contains(&Feature::Cat),
+        op_ctv: features.contains(&Feature::Ctv),
+        op_csfs: features.contains(&Feature::Csfs),
+    }
+}
+
+/// The encoding of a script file.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ScriptFormat {
+    Asm,
+    Hex,
+    Binary,
+}
+
+/// Reads a script from the given file, or from stdin if the path is `-`.
+pub fn read_script(path: &Path, format: ScriptFormat) -> Result<ScriptBuf, String> {
+    let mut data = Vec::new();
+    if path == Path::new("-") {
+        std::io::stdin()
+            .read_to_end(&mut data)
+            .map_err(|e| format!("error reading script from stdin: {}", e))?;
+    } else {
+        data = std::fs::read(path)
+            .map_err(|e| format!("error reading script file {}: {}", path.display(), e))?;
+    }
+
+    match format {
+        ScriptFormat::Binary => Ok(ScriptBuf::from_bytes(data)),
+        ScriptFormat::Hex => {
+            let hex = String::from_utf8(data).map_err(|_| "script file is not hex")?;
+            let hex = hex.split_whitespace().collect::<String>();
+            ScriptBuf::from_hex(&hex).map_err(|e| format!("invalid script hex: {}", e))
+        }
+        ScriptFormat::Asm => {
+            let asm = String::from_utf8(data).map_err(|_| "script file is not ASM")?;
+            ScriptBuf::parse_asm(&asm).map_err(|e| format!("error parsing script: {:?}", e))
+        }
+    }
+}
+
+/// The script and initial stack of a transaction input.
+pub struct Spend {
+    pub ctx: ExecCtx,
+    pub script: ScriptBuf,
+    pub witness: Vec<Vec<u8>>,
+}
+
+/// Returns the items pushed by a push-only scriptSig.
+fn script_sig_pushes(script_sig: &Script) -> Result<Vec<Vec<u8>>, String> {
+    script_sig
+        .instructions()
+        .map(|ins| match ins {
+            Ok(Instruction::PushBytes(push)) => Ok(push.as_bytes().to_vec()),
+            Ok(Instruction::Op(op)) => match op.classify(ClassifyContext::Legacy) {
+                Class::PushNum(-1) => Ok(vec![0x81]),
+                Class::PushNum(n) => Ok(vec![n as u8]),
+                _ => Err(format!("scriptSig is not push-only: {}", op)),
+            },
+            Err(e) => Err(format!("invalid scriptSig: {}", e)),
+        })
+        .collect()
+}
+
+/// Extracts the script to execute and its initial stack from the input of
+/// the transaction.
+///
+/// Supported are taproot script path spends, P2WSH, P2WPKH, P2SH, including
+/// nested segwit, and bare scripts. For taproot, a trailing annex is
+/// skipped and the control block is checked to be for a tapscript leaf.
+pub fn extract_spend(
+    tx: &Transaction,
+    prevouts: &[TxOut],
+    input_idx: usize,
+) -> Result<Spend, String> {
+    let txin = tx.input.get(input_idx).ok_or_else(|| {
+        format!(
+            "input index {} out of bounds, the transaction has {} inputs",
+            input_idx,
+            tx.input.len(),
+        )
+    })?;
+    let spk = &prevouts
+        .get(input_idx)
+        .ok_or_else(|| format!("missing prevout for input {}", input_idx))?
+        .script_pubkey;
+    let mut witness = txin.witness.iter().map(|i| i.to_vec()).collect::<Vec<_>>();
+
+    if spk.is_p2tr() {
+        if witness.len() >= 2 && witness.last().unwrap().first() == Some(&TAPROOT_ANNEX_PREFIX) {
+            witness.pop();
+        }
+        if witness.len() < 2 {
+            return Err("taproot key path spends have no script to execute".to_owned());
+        }
+        let control_block = witness.pop().unwrap();
+        let control_block = ControlBlock::decode(&control_block)
+            .map_err(|e| format!("invalid taproot control block: {}", e))?;
+        if control_block.leaf_version != LeafVersion::TapScript {
+            return Err(format!(
+                "unsupported leaf version: {}",
+                control_block.leaf_version
+            ));
+        }
+        let script = ScriptBuf::from_bytes(witness.pop().unwrap());
+        return Ok(Spend {
+            ctx: ExecCtx::Tapscript,
+            script,
+            witness,
+        });
+    }
+
+    let (program_spk, script_sig_stack) = if spk.is_p2sh() {
+        let mut pushes = script_sig_pushes(&txin.script_sig)?;
+        let redeem_script = ScriptBuf::from_bytes(
+            pushes
+                .pop()
+                .ok_or("P2SH scriptSig doesn't contain the redeem script")?,
+        );
+        if !redeem_script.is_witness_program() {
+            return Ok(Spend {
+                ctx: ExecCtx::Legacy,
+                script: redeem_script,
+                witness: pushes,
+            });
+        }
+        (redeem_script, pushes)
+    } else {
+        (spk.clone(), script_sig_pushes(&txin.script_sig)?)
+    };
+
+    if program_spk.is_p2wsh() {
+        let script = ScriptBuf::from_bytes(
+            witness
+                .pop()
+                .ok_or("P2WSH witness doesn't contain the witness script")?,
+        );
+        Ok(Spend {
+            ctx: ExecCtx::SegwitV0,
+            script,
+            witness,
+        })
+    } else if program_spk.is_p2wpkh() {
+        let hash = PubkeyHash::from_slice(&program_spk.as_bytes()[2..])
+            .expect("P2WPKH program is 20 bytes");
+        Ok(Spend {
+            ctx: ExecCtx::SegwitV0,
+            script: ScriptBuf::new_p2pkh(&hash),
+            witness,
+        })
+    } else if program_spk.is_witness_program() {
+        Err("unsupported witness program".to_owned())
+    } else {
+        Ok(Spend {
+            ctx: ExecCtx::Legacy,
+            script: program_spk,
+            witness: script_sig_stack,
+        })
+    }
+}
+
+/// Parses hex-encoded witness stack items.
+pub fn parse_witness(items: &[String]) -> Result<Vec<Vec<u8>>, String> {
+    items
+        .iter()
+        .map(|item| {
+            Vec::<u8>::from_hex(item).map_err(|e| format!("invalid witness item {}: {}", item, e))
+        })
+        .collect()
+}
+
+/// Parses a prevout in the format `<amount in sats>:<scriptPubkey hex>`.
+pub fn parse_prevout(s: &str) -> Result<TxOut, String> {
+    let (amount, spk) = s
+        .split_once(':')
+        .ok_or_else(|| format!("invalid prevout {}: expected <sats>:<script hex>", s))?;
+    let value = amount
+        .parse()
+        .map(Amount::from_sat)
+        .map_err(|e| format!("invalid prevout amount {}: {}", amount, e))?;
+    let script_pubkey =
+        ScriptBuf::from_hex(spk).map_err(|e| format!("invalid prevout script {}: {}", spk, e))?;
+    Ok(TxOut {
+        value,
+        script_pubkey,
+    })
+}
+
+/// Loads the spending transaction and the outputs it spends from a PSBT
+/// file, either binary or hex.
+///
+/// Finalized witnesses and scriptSigs are put into the transaction.
+pub fn load_psbt(path: &Path) -> Result<(Transaction, Vec<TxOut>), String> {
+    let data = std::fs::read(path)
+        .map_err(|e| format!("error reading PSBT file {}: {}", path.display(), e))?;
+    let bytes = if data.starts_with(b"psbt\xff") {
+        data
+    } else {
+        let hex = String::from_utf8(data).map_err(|_| "PSBT file is not binary or hex")?;
+        Vec::<u8>::from_hex(hex.trim()).map_err(|e| format!("invalid PSBT hex: {}", e))?
+    };
+    let psbt = Psbt::deserialize(&bytes).map_err(|e| format!("invalid PSBT: {}", e))?;
+
+    let mut tx = psbt.unsigned_tx.clone();
+    let mut prevouts = Vec::with_capacity(tx.input.len());
+    for (idx, (txin, input)) in tx.input.iter_mut().zip(psbt.inputs.iter()).enumerate() {
+        let prevout = match (&input.witness_utxo, &input.non_witness_utxo) {
+            (Some(utxo), _) => utxo.clone(),
+            (None, Some(prev_tx)) => prev_tx
+                .output
+                .get(txin.previous_output.vout as usize)
+                .cloned()
+                .ok_or_else(|| format!("PSBT input {} spends a non-existing output", idx))?,
+            (None, None) => return Err(format!("PSBT input {} is missing its UTXO", idx)),
+        };
+        prevouts.push(prevout);
+
+        if let Some(ref script_sig) = input.final_script_sig {
+            txin.script_sig = script_sig.clone();
+        }
+        if let Some(ref witness) = input.final_script_witness {
+            txin.witness = witness.clone();
+        }
+    }
+    Ok((tx, prevouts))
+}
+
+/// Loads the spending transaction and its prevouts from the given
+/// transaction hex and prevouts or PSBT file.
+pub fn load_tx(
+    tx_hex: Option<&str>,
+    prevouts: &[String],
+    psbt: Option<&Path>,
+) -> Result<Option<(Transaction, Vec<TxOut>)>, String> {
+    if let Some(path) = psbt {
+        return load_psbt(path).map(Some);
+    }
+    let tx_hex = match tx_hex {
+        Some(hex) => hex,
+        None => return Ok(None),
+    };
+
+    let tx: Transaction =
+        deserialize_hex(tx_hex.trim()).map_err(|e| format!("invalid transaction: {}", e))?;
+    let prevouts = prevouts
+        .iter()
+        .map(|p| parse_prevout(p))
+        .collect::<Result<Vec<_>, _>>()?;
+    if prevouts.len() != tx.input.len() {
+        return Err(format!(
+            "expected {} prevouts, one for each transaction input, got {}",
+            tx.input.len(),
+            prevouts.len(),
+        ));
+    }
+    Ok(Some((tx, prevouts)))
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::key::{Keypair, Secp256k1};
+    use bitcoin::opcodes::all::*;
+    use bitcoin::script::{Builder, PushBytesBuf};
+    use bitcoin::secp256k1::SecretKey;
+    use bitcoin::taproot::TaprootBuilder;
+    use bitcoin::{
+        absolute, transaction, OutPoint, PublicKey, Sequence, TxIn, WPubkeyHash, Witness,
+    };
+
+    use super::*;
+
+    fn spending_tx(script_sig: ScriptBuf, witness: &[&[u8]]) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig,
+                sequence: Sequence::MAX,
+                witness: Witness::from_slice(witness),
+            }],
+            output: vec![],
+        }
+    }
+
+    fn prevout(script_pubkey: ScriptBuf) -> Vec<TxOut> {
+        vec![TxOut {
+            value: Amount::from_sat(10_000),
+            script_pubkey,
+        }]
+    }
+
+    fn push_script(script: &Script) -> ScriptBuf {
+        let push = PushBytesBuf::try_from(script.to_bytes()).unwrap();
+        Builder::new().push_slice(push).into_script()
+    }
+
+    fn public_key() -> PublicKey {
+        let secp = Secp256k1::new();
+        let key = SecretKey::from_slice(&[1; 32]).unwrap();
+        PublicKey::new(key.public_key(&secp))
+    }
+
+    #[test]
+    fn test_taproot_script_path() {
+        let secp = Secp256k1::new();
+        let keypair = Keypair::from_secret_key(&secp, &SecretKey::from_slice(&[1; 32]).unwrap());
+        let (internal_key, _) = keypair.x_only_public_key();
+        let leaf = Builder::new().push_opcode(OP_ADD).into_script();
+        let spend_info = TaprootBuilder::new()
+            .add_leaf(0, leaf.clone())
+            .unwrap()
+            .finalize(&secp, internal_key)
+            .unwrap();
+        let control_block = spend_info
+            .control_block(&(leaf.clone(), LeafVersion::TapScript))
+            .unwrap()
+            .serialize();
+        let prevouts = prevout(ScriptBuf::new_p2tr_tweaked(spend_info.output_key()));
+        let annex = [TAPROOT_ANNEX_PREFIX, 1];
+
+        for witness in [
+            vec![&[1][..], &[2], leaf.as_bytes(), &control_block],
+            vec![&[1][..], &[2], leaf.as_bytes(), &control_block, &annex],
+        ] {
+            let tx = spending_tx(ScriptBuf::new(), &witness);
+            let spend = extract_spend(&tx, &prevouts, 0).unwrap();
+            assert!(spend.ctx == ExecCtx::Tapscript);
+            assert_eq!(spend.script, leaf);
+            assert_eq!(spend.witness, vec![vec![1], vec![2]]);
+        }
+
+        // Key path spends, with and without annex.
+        let signature = [1; 64];
+        for witness in [vec![&signature[..]], vec![&signature[..], &annex]] {
+            let tx = spending_tx(ScriptBuf::new(), &witness);
+            let err = extract_spend(&tx, &prevouts, 0).err().unwrap();
+            assert!(err.contains("key path"), "{}", err);
+        }
+
+        // The same leaf with another, even, leaf version.
+        let mut other_version = control_block.clone();
+        other_version[0] = 0xc2 | (control_block[0] & 1);
+        let tx = spending_tx(ScriptBuf::new(), &[&[1], leaf.as_bytes(), &other_version]);
+        let err = extract_spend(&tx, &prevouts, 0).err().unwrap();
+        assert!(err.contains("unsupported leaf version"), "{}", err);
+
+        assert!(extract_spend(&tx, &prevouts, 1).is_err());
+    }
+
+    #[test]
+    fn test_segwit_v0() {
+        let witness_script = Builder::new()
+            .push_opcode(OP_DUP)
+            .push_opcode(OP_ADD)
+            .into_script();
+        let p2wsh = ScriptBuf::new_p2wsh(&witness_script.wscript_hash());
+        let pubkey = public_key();
+        let p2wpkh = ScriptBuf::new_p2wpkh(&WPubkeyHash::hash(&pubkey.to_bytes()));
+        let p2pkh = ScriptBuf::new_p2pkh(&pubkey.pubkey_hash());
+        let signature = [0x30; 71];
+
+        // P2WSH nested in P2SH.
+        let tx = spending_tx(push_script(&p2wsh), &[&[3], witness_script.as_bytes()]);
+        let spend =
+            extract_spend(&tx, &prevout(ScriptBuf::new_p2sh(&p2wsh.script_hash())), 0).unwrap();
+        assert!(spend.ctx == ExecCtx::SegwitV0);
+        assert_eq!(spend.script, witness_script);
+        assert_eq!(spend.witness, vec![vec![3]]);
+
+        // P2WPKH, bare and nested in P2SH.
+        let witness = [&signature[..], &pubkey.to_bytes()];
+        for (script_sig, spk) in [
+            (ScriptBuf::new(), p2wpkh.clone()),
+            (
+                push_script(&p2wpkh),
+                ScriptBuf::new_p2sh(&p2wpkh.script_hash()),
+            ),
+        ] {
+            let tx = spending_tx(script_sig, &witness);
+            let spend = extract_spend(&tx, &prevout(spk), 0).unwrap();
+            assert!(spend.ctx == ExecCtx::SegwitV0);
+            assert_eq!(spend.script, p2pkh);
+            assert_eq!(spend.witness, vec![signature.to_vec(), pubkey.to_bytes()]);
+        }
+
+        // Unknown witness versions can't be executed.
+        let v1_program = Builder::new()
+            .push_opcode(OP_PUSHNUM_2)
+            .push_slice([0; 32])
+            .into_script();
+        let tx = spending_tx(ScriptBuf::new(), &[&[1]]);
+        assert!(extract_spend(&tx, &prevout(v1_program), 0).is_err());
+    }
+
+    #[test]
+    fn test_legacy() {
+        let redeem_script = Builder::new()
+            .push_opcode(OP_ADD)
+            .push_int(3)
+            .push_opcode(OP_EQUAL)
+            .into_script();
+        let script_sig = Builder::new()
+            .push_int(1)
+            .push_int(2)
+            .into_script()
+            .into_bytes()
+            .into_iter()
+            .chain(push_script(&redeem_script).into_bytes())
+            .collect::<Vec<_>>();
+        let tx = spending_tx(ScriptBuf::from_bytes(script_sig), &[]);
+        let p2sh = ScriptBuf::new_p2sh(&redeem_script.script_hash());
+        let spend = extract_spend(&tx, &prevout(p2sh), 0).unwrap();
+        assert!(spend.ctx == ExecCtx::Legacy);
+        assert_eq!(spend.script, redeem_script);
+        assert_eq!(spend.witness, vec![vec![1], vec![2]]);
+
+        // A bare script gets the scriptSig pushes as its stack.
+        let tx = spending_tx(Builder::new().push_int(2).into_script(), &[]);
+        let spend = extract_spend(&tx, &prevout(redeem_script.clone()), 0).unwrap();
+        assert!(spend.ctx == ExecCtx::Legacy);
+        assert_eq!(spend.script, redeem_script);
+        assert_eq!(spend.witness, vec![vec![2]]);
+    }
+
+    #[test]
+    fn test_script_sig_pushes() {
+        let script_sig = Builder::new()
+            .push_int(0)
+            .push_int(-1)
+            .push_int(16)
+            .push_slice([0xab; 75])
+            .into_script();
+        assert_eq!(
+            script_sig_pushes(&script_sig),
+            Ok(vec![vec![], vec![0x81], vec![16], vec![0xab; 75]]),
+        );
+
+        let script_sig = Builder::new().push_int(1).push_opcode(OP_DUP).into_script();
+        assert!(script_sig_pushes(&script_sig).is_err());
+        // A push that runs past the end of the script.
+        assert!(script_sig_pushes(Script::from_bytes(&[0x02, 0x01])).is_err());
+    }
+}
@@ -4,7 +4,10 @@ use bitcoin::hex::DisplayHex;
 use bitcoin::{Opcode, Script};
 use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
 
-use crate::ExecStats;
+use crate::analysis::{Analysis, Issue, StepAnalysis, Underflow};
+use crate::budget::{BudgetReport, LimitUsage};
+use crate::profile::{OpcodeProfile, ProfileReport};
+use crate::{ExecError, ExecStats};
 
 /// Simple utility wrapper to serde-serialize using [fmt::Display].
 struct FmtSer<'a, T: fmt::Display>(&'a T);
@@ -63,8 +66,9 @@ impl<'a> Serialize for RunStep<'a> {
 
 pub struct RunResult<'a> {
     pub success: bool,
-    pub error: Option<String>,
+    pub error: Option<&'a ExecError>,
     pub opcode: Option<Opcode>,
+    pub position: Option<usize>,
     pub final_stack: &'a [Vec<u8>],
     pub stats: Option<&'a ExecStats>,
 }
@@ -73,12 +77,16 @@ impl<'a> Serialize for RunResult<'a> {
     fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
         let mut m = s.serialize_map(None)?;
         m.serialize_entry("success", &self.success)?;
-        if let Some(ref err) = self.error {
-            m.serialize_entry("error", err)?;
+        if let Some(err) = self.error {
+            m.serialize_entry("error", &FmtSer(err))?;
+            m.serialize_entry("error_code", err.name())?;
         }
         if let Some(opcode) = self.opcode {
             m.serialize_entry("opcode", &FmtSer(&opcode))?;
         }
+        if let Some(position) = self.position {
+            m.serialize_entry("position", &position)?;
+        }
         m.serialize_entry("final_stack", &StackSer(self.final_stack))?;
         if let Some(ref stats) = self.stats {
             m.serialize_entry("stats", stats)?;
@@ -86,3 +94,120 @@ impl<'a> Serialize for RunResult<'a> {
         m.end()
     }
 }
+
+impl Serialize for OpcodeProfile {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let mut m = s.serialize_map(Some(2))?;
+        m.serialize_entry("opcode", &FmtSer(&self.opcode))?;
+        m.serialize_entry("count", &self.count)?;
+        m.end()
+    }
+}
+
+impl Serialize for ProfileReport {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let mut m = s.serialize_map(None)?;
+        m.serialize_entry("executed_count", &self.executed_count)?;
+        m.serialize_entry("skipped_count", &self.skipped_count)?;
+        m.serialize_entry("max_nb_stack_items", &self.max_nb_stack_items)?;
+        if let Some(ref err) = self.error {
+            m.serialize_entry("error", &FmtSer(err))?;
+            m.serialize_entry("error_code", err.name())?;
+        }
+        m.serialize_entry("opcodes", &self.opcodes)?;
+        m.serialize_entry("regions", &self.regions)?;
+        m.end()
+    }
+}
+
+impl Serialize for StepAnalysis {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let mut m = s.serialize_map(None)?;
+        m.serialize_entry("position", &self.position)?;
+        if let Some(opcode) = self.opcode {
+            m.serialize_entry("opcode", &FmtSer(&opcode))?;
+        }
+        m.serialize_entry("stack", &self.stack)?;
+        m.serialize_entry("altstack", &self.altstack)?;
+        m.serialize_entry("stack_required", &self.stack_required)?;
+        m.serialize_entry("altstack_required", &self.altstack_required)?;
+        m.end()
+    }
+}
+
+impl Serialize for Issue {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let mut m = s.serialize_map(None)?;
+        match self {
+            Issue::UnbalancedConditional { position } => {
+                m.serialize_entry("issue", "unbalanced_conditional")?;
+                m.serialize_entry("position", position)?;
+            }
+            Issue::DataDependent { position, opcode } => {
+                m.serialize_entry("issue", "data_dependent")?;
+                m.serialize_entry("position", position)?;
+                m.serialize_entry("opcode", &FmtSer(opcode))?;
+            }
+        }
+        m.end()
+    }
+}
+
+impl Serialize for Underflow {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let mut m = s.serialize_map(None)?;
+        m.serialize_entry("position", &self.position)?;
+        if let Some(opcode) = self.opcode {
+            m.serialize_entry("opcode", &FmtSer(&opcode))?;
+        }
+        m.serialize_entry("missing", &self.missing)?;
+        m.serialize_entry("missing_altstack", &self.missing_altstack)?;
+        m.end()
+    }
+}
+
+pub struct AnalysisReport<'a> {
+    pub analysis: &'a Analysis,
+    pub underflows: &'a [Underflow],
+    /// Whether to include the analysis of every instruction.
+    pub steps: bool,
+}
+
+impl<'a> Serialize for AnalysisReport<'a> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let mut m = s.serialize_map(None)?;
+        m.serialize_entry("effect", &self.analysis.effect)?;
+        m.serialize_entry("issues", &self.analysis.issues)?;
+        m.serialize_entry("underflows", self.underflows)?;
+        if self.steps {
+            m.serialize_entry("steps", &self.analysis.steps)?;
+        }
+        m.end()
+    }
+}
+
+impl Serialize for LimitUsage {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let mut m = s.serialize_map(None)?;
+        m.serialize_entry("limit", self.limit.name())?;
+        m.serialize_entry("consensus", &self.limit.is_consensus())?;
+        m.serialize_entry("used", &self.used)?;
+        m.serialize_entry("max", &self.max)?;
+        m.serialize_entry("headroom", &self.headroom())?;
+        m.end()
+    }
+}
+
+impl Serialize for BudgetReport {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let mut m = s.serialize_map(None)?;
+        m.serialize_entry("consensus_valid", &self.is_consensus_valid())?;
+        m.serialize_entry("standard", &self.is_standard())?;
+        m.serialize_entry("limits", &self.usages)?;
+        if let Some(ref err) = self.result.error {
+            m.serialize_entry("error", &FmtSer(err))?;
+            m.serialize_entry("error_code", err.name())?;
+        }
+        m.end()
+    }
+}
@@ -1,25 +1,28 @@
 extern crate alloc;
 extern crate core;
 
-use alloc::borrow::Cow;
 use core::cmp;
+use core::ops::Range;
 
 use bitcoin::consensus::Encodable;
 use bitcoin::hashes::{hash160, ripemd160, sha1, sha256, sha256d, Hash};
 use bitcoin::opcodes::{all::*, Opcode};
-use bitcoin::script::{self, Instruction, Instructions, Script, ScriptBuf};
+use bitcoin::script::{self, Instruction, Script, ScriptBuf};
 use bitcoin::sighash::SighashCache;
-use bitcoin::taproot::{self, TapLeafHash};
+use bitcoin::taproot::{self, LeafVersion, TapLeafHash};
 use bitcoin::transaction::{self, Transaction, TxOut};
 
 #[macro_use]
 mod macros;
 
 mod utils;
-use utils::ConditionStack;
+use utils::{find_and_delete, ConditionStack};
 
 mod signatures;
 
+mod covenants;
+pub use covenants::{default_template_hash, OP_CHECKSIGFROMSTACK, OP_CHECKTEMPLATEVERIFY};
+
 mod error;
 pub use error::{Error, ExecError};
 
@@ -31,14 +34,38 @@ mod wasm;
 mod data_structures;
 pub use data_structures::Stack;
 
+mod trace;
+pub use trace::{ExecObserver, StackDiff, StepDiff, StepInfo};
+use trace::NoObserver;
+
+pub mod profile;
+
+pub mod analysis;
+
+pub mod budget;
+
+pub mod symbolic;
+mod solver;
+
+pub mod tracefile;
+
+mod snapshot;
+pub use snapshot::ExecSnapshot;
+
+mod verify;
+pub use verify::{verify_input, verify_input_with_options};
+
+#[cfg(test)]
+mod tests;
+
 /// Maximum number of non-push operations per script
 const MAX_OPS_PER_SCRIPT: usize = 201;
 
 /// Maximum number of bytes pushable to the stack
-const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+pub const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
 
 /// Maximum number of values on script interpreter stack
-const MAX_STACK_SIZE: usize = 1000;
+pub const MAX_STACK_SIZE: usize = 1000;
 
 /// If this flag is set, CTxIn::nSequence is NOT interpreted as a
 /// relative lock-time.
@@ -53,46 +80,144 @@ const VALIDATION_WEIGHT_OFFSET: i64 = 50;
 /// Validation weight per passing signature (Tapscript only, see BIP 342).
 const VALIDATION_WEIGHT_PER_SIGOP_PASSED: i64 = 50;
 
-// Maximum number of public keys per multisig
-const _MAX_PUBKEYS_PER_MULTISIG: i64 = 20;
+/// Maximum number of public keys per multisig
+const MAX_PUBKEYS_PER_MULTISIG: i64 = 20;
 
 /// Used to enable experimental script features.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub struct Experimental {
     /// Enable an experimental implementation of OP_CAT.
     pub op_cat: bool,
+    /// Enable OP_CHECKTEMPLATEVERIFY (BIP 119) in place of OP_NOP4.
+    pub op_ctv: bool,
+    /// Enable OP_CHECKSIGFROMSTACK (BIP 348) in place of OP_SUCCESS204 in
+    /// tapscript.
+    pub op_csfs: bool,
 }
 
 /// Used to fine-tune different variables during execution.
+///
+/// Most options correspond to one of Bitcoin Core's `SCRIPT_VERIFY_*` flags.
+/// Use [Options::consensus] to check consensus validity and
+/// [Options::standardness] to check whether a spend is relayed by default
+/// nodes. The default is [Options::standardness].
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Options {
-    /// Require data pushes be minimally encoded.
+    /// Evaluate P2SH subscripts (BIP 16, `P2SH`).
+    pub verify_p2sh: bool,
+    /// Require strict encoding of signatures and public keys (`STRICTENC`).
+    pub verify_strict_enc: bool,
+    /// Require strict DER encoding of signatures (BIP 66, `DERSIG`).
+    pub verify_der_sig: bool,
+    /// Require low S values in signatures (`LOW_S`).
+    pub verify_low_s: bool,
+    /// Require the extra element consumed by OP_CHECKMULTISIG to be empty (BIP 147, `NULLDUMMY`).
+    pub verify_null_dummy: bool,
+    /// Require the scriptSig to only contain pushes (`SIGPUSHONLY`).
+    pub verify_sig_push_only: bool,
+    /// Require data pushes be minimally encoded (`MINIMALDATA`).
     pub require_minimal: bool, //TODO(stevenroose) double check all fRequireMinimal usage in Core
-    /// Verify OP_CHECKLOCKTIMEVERIFY.
+    /// Fail on executing the reserved NOPs (`DISCOURAGE_UPGRADABLE_NOPS`).
+    pub discourage_upgradable_nops: bool,
+    /// Require exactly one element on the stack after legacy and P2SH
+    /// evaluation (`CLEANSTACK`). Only meaningful together with
+    /// [Options::verify_p2sh] and [Options::verify_witness].
+    pub verify_clean_stack: bool,
+    /// Verify OP_CHECKLOCKTIMEVERIFY (BIP 65, `CHECKLOCKTIMEVERIFY`).
     pub verify_cltv: bool,
-    /// Verify OP_CHECKSEQUENCEVERIFY.
+    /// Verify OP_CHECKSEQUENCEVERIFY (BIP 112, `CHECKSEQUENCEVERIFY`).
     pub verify_csv: bool,
-    /// Verify conditionals are minimally encoded.
+    /// Verify witness programs (BIP 141, `WITNESS`).
+    pub verify_witness: bool,
+    /// Fail on spending unknown witness versions
+    /// (`DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM`).
+    pub discourage_upgradable_witness_program: bool,
+    /// Verify segwit v0 conditionals are minimally encoded (`MINIMALIF`).
+    ///
+    /// In tapscript this is always enforced, as it is a consensus rule there.
     pub verify_minimal_if: bool,
+    /// Require failing signature checks to use empty signatures (BIP 146, `NULLFAIL`).
+    pub verify_null_fail: bool,
+    /// Require compressed public keys in segwit v0 (`WITNESS_PUBKEYTYPE`).
+    pub verify_witness_pubkey_type: bool,
+    /// Fail on OP_CODESEPARATOR and signatures found in the script code
+    /// in legacy scripts (`CONST_SCRIPTCODE`).
+    pub verify_const_scriptcode: bool,
+    /// Verify taproot spends (BIPs 341 and 342, `TAPROOT`).
+    pub verify_taproot: bool,
+    /// Fail on spending unknown tapleaf versions
+    /// (`DISCOURAGE_UPGRADABLE_TAPROOT_VERSION`).
+    pub discourage_upgradable_taproot_version: bool,
+    /// Fail on tapscripts with OP_SUCCESSx opcodes (`DISCOURAGE_OP_SUCCESS`).
+    pub discourage_op_success: bool,
+    /// Fail on signature checks with unknown public key types in tapscript
+    /// (`DISCOURAGE_UPGRADABLE_PUBKEYTYPE`).
+    pub discourage_upgradable_pubkey_type: bool,
     /// Enfore a strict limit of 1000 total stack items.
     pub enforce_stack_limit: bool,
 
     pub experimental: Experimental,
 }
 
-impl Default for Options {
-    fn default() -> Self {
+impl Options {
+    /// The rules enforced by consensus, like Bitcoin Core's mandatory
+    /// script verification flags.
+    pub fn consensus() -> Options {
         Options {
-            require_minimal: true,
+            verify_p2sh: true,
+            verify_strict_enc: false,
+            verify_der_sig: true,
+            verify_low_s: false,
+            verify_null_dummy: true,
+            verify_sig_push_only: false,
+            require_minimal: false,
+            discourage_upgradable_nops: false,
+            verify_clean_stack: false,
             verify_cltv: true,
             verify_csv: true,
-            verify_minimal_if: true,
+            verify_witness: true,
+            discourage_upgradable_witness_program: false,
+            verify_minimal_if: false,
+            verify_null_fail: false,
+            verify_witness_pubkey_type: false,
+            verify_const_scriptcode: false,
+            verify_taproot: true,
+            discourage_upgradable_taproot_version: false,
+            discourage_op_success: false,
+            discourage_upgradable_pubkey_type: false,
             enforce_stack_limit: true,
-            experimental: Experimental { op_cat: true },
+            experimental: Experimental::default(),
+        }
+    }
+
+    /// The rules enforced by default for relaying transactions, like
+    /// Bitcoin Core's standard script verification flags.
+    pub fn standardness() -> Options {
+        Options {
+            verify_strict_enc: true,
+            verify_low_s: true,
+            require_minimal: true,
+            discourage_upgradable_nops: true,
+            verify_clean_stack: true,
+            discourage_upgradable_witness_program: true,
+            verify_minimal_if: true,
+            verify_null_fail: true,
+            verify_witness_pubkey_type: true,
+            verify_const_scriptcode: true,
+            discourage_upgradable_taproot_version: true,
+            discourage_op_success: true,
+            discourage_upgradable_pubkey_type: true,
+            ..Options::consensus()
         }
     }
 }
 
+impl Default for Options {
+    fn default() -> Self {
+        Options::standardness()
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ExecCtx {
     Legacy,
@@ -100,6 +225,7 @@ pub enum ExecCtx {
     Tapscript,
 }
 
+#[derive(Debug, Clone)]
 pub struct TxTemplate {
     pub tx: Transaction,
     pub prevouts: Vec<TxOut>,
@@ -107,11 +233,82 @@ pub struct TxTemplate {
     pub taproot_annex_scriptleaf: Option<(TapLeafHash, Option<Vec<u8>>)>,
 }
 
+impl TxTemplate {
+    /// An empty dummy transaction, so that scripts that don't inspect the
+    /// transaction can still be executed.
+    ///
+    /// In tapscript, the leaf hash is all zeros and there is no annex.
+    pub fn dummy(ctx: ExecCtx) -> TxTemplate {
+        TxTemplate {
+            tx: Transaction {
+                version: transaction::Version::TWO,
+                lock_time: bitcoin::locktime::absolute::LockTime::ZERO,
+                input: vec![],
+                output: vec![],
+            },
+            prevouts: vec![],
+            input_idx: 0,
+            taproot_annex_scriptleaf: match ctx {
+                ExecCtx::Tapscript => Some((TapLeafHash::all_zeros(), None)),
+                _ => None,
+            },
+        }
+    }
+
+    /// Creates the template to execute the script of the given input in.
+    ///
+    /// In tapscript, the leaf hash is computed from the script and the annex
+    /// is taken from the witness of the input. Without a transaction, the
+    /// [TxTemplate::dummy] transaction is used.
+    pub fn for_input(
+        ctx: ExecCtx,
+        script: &Script,
+        tx: Option<(Transaction, Vec<TxOut>)>,
+        input_idx: usize,
+    ) -> Result<TxTemplate, Error> {
+        let (tx, prevouts) = match tx {
+            Some(tx) => tx,
+            None => return Ok(TxTemplate::dummy(ctx)),
+        };
+        if input_idx >= tx.input.len() {
+            return Err(Error::Other("input index out of bounds"));
+        }
+        if prevouts.len() != tx.input.len() {
+            return Err(Error::Other(
+                "number of prevouts doesn't match number of inputs",
+            ));
+        }
+
+        let taproot_annex_scriptleaf = match ctx {
+            ExecCtx::Tapscript => {
+                let leaf_hash = TapLeafHash::from_script(script, LeafVersion::TapScript);
+                let annex = tx.input[input_idx]
+                    .witness
+                    .taproot_annex()
+                    .map(|a| a.to_vec());
+                Some((leaf_hash, annex))
+            }
+            _ => None,
+        };
+        Ok(TxTemplate {
+            tx,
+            prevouts,
+            input_idx,
+            taproot_annex_scriptleaf,
+        })
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ExecutionResult {
     pub success: bool,
     pub error: Option<ExecError>,
+    /// The opcode that caused the error.
     pub opcode: Option<Opcode>,
+    /// The script position of the instruction that caused the error.
+    ///
+    /// This is the script length for errors at the end of the script.
+    pub position: Option<usize>,
     pub final_stack: Stack,
 }
 
@@ -137,6 +334,7 @@ impl ExecutionResult {
             final_stack,
             error: None,
             opcode: None,
+            position: None,
         }
     }
 }
@@ -149,7 +347,7 @@ pub struct ExecStats {
     pub max_nb_stack_items: usize,
 
     /// The number of opcodes executed, plus an additional one
-    /// per signature in CHECKMULTISIG.
+    /// per public key in CHECKMULTISIG.
     pub opcode_count: usize,
 
     /// The validation weight execution started with.
@@ -159,6 +357,8 @@ pub struct ExecStats {
 }
 
 /// Partial execution of a script.
+///
+/// The execution owns all its state, so it can be moved to other threads.
 pub struct Exec {
     ctx: ExecCtx,
     opt: Options,
@@ -166,16 +366,17 @@ pub struct Exec {
     result: Option<ExecutionResult>,
 
     sighashcache: SighashCache<Transaction>,
-    script: &'static Script,
-    instructions: Instructions<'static>,
+    script: ScriptBuf,
+    /// The position of the next instruction to execute.
+    position: usize,
+    /// The position of the instruction being executed.
     current_position: usize,
     cond_stack: ConditionStack,
     stack: Stack,
     altstack: Stack,
+    // The script code starts at the last OP_CODESEPARATOR
+    // or at the start of the script if there is none.
     last_codeseparator_pos: Option<u32>,
-    // Initially set to the whole script, but updated when
-    // OP_CODESEPARATOR is encountered.
-    script_code: &'static Script,
 
     opcode_count: usize,
     validation_weight: i64,
@@ -184,14 +385,13 @@ pub struct Exec {
     stats: ExecStats,
 }
 
-impl std::ops::Drop for Exec {
-    fn drop(&mut self) {
-        // we need to safely drop the script we allocated
-        unsafe {
-            let script = core::mem::replace(&mut self.script, Script::from_bytes(&[]));
-            let _ = Box::from_raw(script as *const Script as *mut Script);
-        }
-    }
+/// An instruction that doesn't borrow the script: the data of a push is
+/// referred to by its range in the script.
+enum ScriptInstruction {
+    PushBytes(Range<usize>),
+    Op(Opcode),
+    /// A push that can't be parsed because it runs past the end of the script.
+    Invalid,
 }
 
 impl Exec {
@@ -214,31 +414,6 @@ impl Exec {
             }
         }
 
-        // We want to make sure the script is valid so we don't have to throw parsing errors
-        // while executing.
-        let instructions = if opt.require_minimal {
-            script.instructions_minimal()
-        } else {
-            script.instructions()
-        };
-        if let Some(err) = instructions.clone().find_map(|res| res.err()) {
-            return Err(Error::InvalidScript(err));
-        }
-
-        // *****
-        // Make sure there is no more possible exit path after this point!
-        // Otherwise we are leaking memory.
-        // *****
-
-        // We box alocate the script to get a static Instructions iterator.
-        // We will manually drop this allocation in the ops::Drop impl.
-        let script = Box::leak(script.into_boxed_script()) as &'static Script;
-        let instructions = if opt.require_minimal {
-            script.instructions_minimal()
-        } else {
-            script.instructions()
-        };
-
         //TODO(stevenroose) make this more efficient
         let witness_size =
             Encodable::consensus_encode(&script_witness, &mut bitcoin::io::sink()).unwrap();
@@ -250,7 +425,7 @@ impl Exec {
 
             sighashcache: SighashCache::new(tx.tx.clone()),
             script,
-            instructions,
+            position: 0,
             current_position: 0,
             cond_stack: ConditionStack::new(),
             //TODO(stevenroose) does this need to be reversed?
@@ -259,7 +434,6 @@ impl Exec {
             opcode_count: 0,
             validation_weight: start_validation_weight,
             last_codeseparator_pos: None,
-            script_code: script,
 
             opt,
             tx,
@@ -298,13 +472,16 @@ impl Exec {
         self.result.as_ref()
     }
 
+    pub fn script(&self) -> &Script {
+        &self.script
+    }
+
     pub fn script_position(&self) -> usize {
-        self.script.len() - self.instructions.as_script().len()
+        self.position
     }
 
     pub fn remaining_script(&self) -> &Script {
-        let pos = self.script_position();
-        &self.script[pos..]
+        &self.script[self.position..]
     }
 
     pub fn stack(&self) -> &Stack {
@@ -315,19 +492,72 @@ impl Exec {
         &self.altstack
     }
 
+    /// Mutable access to the stack, to modify state mid-execution.
+    pub fn stack_mut(&mut self) -> &mut Stack {
+        &mut self.stack
+    }
+
+    /// Mutable access to the altstack, to modify state mid-execution.
+    pub fn altstack_mut(&mut self) -> &mut Stack {
+        &mut self.altstack
+    }
+
     pub fn stats(&self) -> &ExecStats {
         &self.stats
     }
 
+    /// Overrides the validation weight budget.
+    ///
+    /// By default the budget is derived from the script witness only, while
+    /// consensus uses the size of the full input witness, including the
+    /// tapscript, control block and annex.
+    pub(crate) fn set_start_validation_weight(&mut self, start_validation_weight: i64) {
+        self.validation_weight = start_validation_weight;
+        self.stats.start_validation_weight = start_validation_weight;
+        self.update_stats();
+    }
+
+    /// Restores the validation weight budget the execution started with, as
+    /// if the rest of the script was executed in a leaf of its own.
+    pub fn reset_validation_weight(&mut self) {
+        self.validation_weight = self.stats.start_validation_weight;
+        self.update_stats();
+    }
+
     ///////////////
     // UTILITIES //
     ///////////////
 
+    /// The part of the script used for signature hashes.
+    fn script_code(&self) -> &Script {
+        let start = self.last_codeseparator_pos.map(|p| p as usize).unwrap_or(0);
+        &self.script[start..]
+    }
+
+    /// Parses the next instruction and advances the cursor past it.
+    fn next_instruction(&mut self) -> Option<ScriptInstruction> {
+        let mut instructions = self.script[self.position..].instructions();
+        let instruction = match instructions.next()? {
+            Ok(Instruction::PushBytes(p)) => {
+                let end = self.script.len() - instructions.as_script().len();
+                ScriptInstruction::PushBytes(end - p.len()..end)
+            }
+            Ok(Instruction::Op(op)) => ScriptInstruction::Op(op),
+            Err(_) => {
+                self.position = self.script.len();
+                return Some(ScriptInstruction::Invalid);
+            }
+        };
+        self.position = self.script.len() - instructions.as_script().len();
+        Some(instruction)
+    }
+
     fn fail(&mut self, err: ExecError) -> Result<(), &ExecutionResult> {
         let res = ExecutionResult {
             success: false,
             error: Some(err),
             opcode: None,
+            position: Some(self.current_position),
             final_stack: self.stack.clone(),
         };
         self.result = Some(res);
@@ -339,6 +569,7 @@ impl Exec {
             success: false,
             error: Some(err),
             opcode: Some(op),
+            position: Some(self.current_position),
             final_stack: self.stack.clone(),
         };
         self.result = Some(res);
@@ -360,7 +591,12 @@ impl Exec {
             _ => {}
         }
 
-        if self.tx.tx.input[self.tx.input_idx].sequence.is_final() {
+        // There is no input to check against in a dummy transaction.
+        let txin = match self.tx.tx.input.get(self.tx.input_idx) {
+            Some(txin) => txin,
+            None => return false,
+        };
+        if txin.sequence.is_final() {
             return false;
         }
 
@@ -376,7 +612,10 @@ impl Exec {
             return false;
         }
 
-        let input_sequence = self.tx.tx.input[self.tx.input_idx].sequence;
+        let input_sequence = match self.tx.tx.input.get(self.tx.input_idx) {
+            Some(txin) => txin.sequence,
+            None => return false,
+        };
         let input_lock_time = match input_sequence.to_relative_lock_time() {
             Some(lt) => lt,
             None => return false,
@@ -402,26 +641,23 @@ impl Exec {
         //TODO(stevenroose) somehow sigops limit should be checked somewhere
 
         // Drop the signature in pre-segwit scripts but not segwit scripts
-        let mut scriptcode = Cow::Borrowed(self.script_code.as_bytes());
+        let mut scriptcode = self.script_code().as_bytes().to_vec();
         if self.ctx == ExecCtx::Legacy {
-            let mut i = 0;
-            while i < scriptcode.len() - sig.len() {
-                if &scriptcode[i..i + sig.len()] == sig {
-                    scriptcode.to_mut().drain(i..i + sig.len());
-                } else {
-                    i += 1;
-                }
+            let (code, nb_found) = find_and_delete(&scriptcode, sig);
+            if nb_found > 0 && self.opt.verify_const_scriptcode {
+                return Err(ExecError::SigFindAndDelete);
             }
+            scriptcode = code;
         }
 
-        //TODO(stevenroose) the signature and pk encoding checks we use here
-        // might not be exactly identical to Core's
+        self.check_signature_encoding(sig)?;
+        self.check_pubkey_encoding(pk)?;
 
-        if self.ctx == ExecCtx::SegwitV0 && pk.len() == 65 {
-            return Err(ExecError::WitnessPubkeyType);
+        let success = self.check_sig_ecdsa(sig, pk, &scriptcode);
+        if !success && self.opt.verify_null_fail && !sig.is_empty() {
+            return Err(ExecError::SigNullFail);
         }
-
-        Ok(self.check_sig_ecdsa(sig, pk, &scriptcode))
+        Ok(success)
     }
 
     fn check_sig_tap(&mut self, sig: &[u8], pk: &[u8]) -> Result<bool, ExecError> {
@@ -441,8 +677,11 @@ impl Exec {
             } else {
                 Ok(false)
             }
+        } else if self.opt.discourage_upgradable_pubkey_type {
+            Err(ExecError::DiscourageUpgradablePubkeyType)
         } else {
-            Ok(true)
+            // Unknown public key types succeed for any non-empty signature.
+            Ok(!sig.is_empty())
         }
     }
 
@@ -459,32 +698,98 @@ impl Exec {
 
     /// Returns true when execution is done.
     pub fn exec_next(&mut self) -> Result<(), &ExecutionResult> {
+        self.exec_next_observed(&mut NoObserver)
+    }
+
+    /// Like [Exec::exec_next], but reports the step to the given observer.
+    pub fn exec_next_observed<O: ExecObserver + ?Sized>(
+        &mut self,
+        observer: &mut O,
+    ) -> Result<(), &ExecutionResult> {
         if let Some(ref res) = self.result {
             return Err(res);
         }
 
-        self.current_position = self.script.len() - self.instructions.as_script().len();
-        let instruction = match self.instructions.next() {
-            Some(Ok(i)) => i,
+        self.current_position = self.position;
+        let instruction = match self.next_instruction() {
+            Some(i) => i,
             None => {
+                // All OP_IFs must be closed at the end of the script.
+                if !self.cond_stack.is_empty() {
+                    return self.fail(ExecError::UnbalancedConditional);
+                }
                 let res = ExecutionResult::from_final_stack(self.ctx, self.stack.clone());
                 self.result = Some(res);
                 return Err(self.result.as_ref().unwrap());
             }
-            Some(Err(_)) => unreachable!("we checked the script beforehand"),
         };
 
         let exec = self.cond_stack.all_true();
+        let opcode = Opcode::from(self.script.as_bytes()[self.current_position]);
+        let step = StepInfo {
+            position: self.current_position,
+            opcode,
+            executed: exec
+                || (opcode.to_u8() >= OP_IF.to_u8() && opcode.to_u8() <= OP_ENDIF.to_u8()),
+        };
+        observer.pre_step(self, &step);
+        let before = if observer.wants_stack_diffs() {
+            Some((self.stack.clone(), self.altstack.clone()))
+        } else {
+            None
+        };
+
+        if let Err((err, op)) = self.exec_instruction(instruction, exec) {
+            observer.on_error(self, &step, &err);
+            return match op {
+                Some(op) => self.failop(err, op),
+                None => self.failop(err, opcode),
+            };
+        }
+
+        if matches!(opcode, OP_IF | OP_NOTIF | OP_ELSE) {
+            observer.on_branch(self, &step, self.cond_stack.all_true());
+        }
+
+        self.update_stats();
+
+        let diff = before.map(|(stack, altstack)| StepDiff {
+            stack: StackDiff::compute(&stack, &self.stack),
+            altstack: StackDiff::compute(&altstack, &self.altstack),
+        });
+        observer.post_step(self, &step, diff.as_ref());
+        Ok(())
+    }
+
+    /// Executes a single instruction.
+    ///
+    /// On failure, returns the error and the opcode to report, if any.
+    fn exec_instruction(
+        &mut self,
+        instruction: ScriptInstruction,
+        exec: bool,
+    ) -> Result<(), (ExecError, Option<Opcode>)> {
         match instruction {
-            Instruction::PushBytes(p) => {
-                if p.len() > MAX_SCRIPT_ELEMENT_SIZE {
-                    return self.fail(ExecError::PushSize);
+            ScriptInstruction::PushBytes(range) => {
+                if range.len() > MAX_SCRIPT_ELEMENT_SIZE {
+                    return Err((ExecError::PushSize, None));
                 }
                 if exec {
-                    self.stack.pushstr(p.as_bytes());
+                    // Like in Core, only the pushes that are executed have to be minimal.
+                    if self.opt.require_minimal {
+                        let mut instructions =
+                            self.script[self.current_position..].instructions_minimal();
+                        if let Some(Err(script::Error::NonMinimalPush)) = instructions.next() {
+                            return Err((ExecError::MinimalData, None));
+                        }
+                    }
+                    self.stack.pushstr(&self.script.as_bytes()[range]);
                 }
             }
-            Instruction::Op(op) => {
+            // Core fails on the invalid push only when execution reaches it,
+            // even in a branch that is not executed.
+            ScriptInstruction::Invalid => return Err((ExecError::BadOpcode, None)),
+            ScriptInstruction::Op(op) => {
                 // Some things we do even when we're not executing.
 
                 // Note how OP_RESERVED does not count towards the opcode limit.
@@ -493,34 +798,36 @@ impl Exec {
                 {
                     self.opcode_count += 1;
                     if self.opcode_count > MAX_OPS_PER_SCRIPT {
-                        return self.fail(ExecError::OpCount);
+                        return Err((ExecError::OpCount, None));
                     }
                 }
 
                 match op {
                     OP_CAT if !self.opt.experimental.op_cat || self.ctx != ExecCtx::Tapscript => {
-                        return self.failop(ExecError::DisabledOpcode, op);
+                        return Err((ExecError::DisabledOpcode, Some(op)));
                     }
                     OP_SUBSTR | OP_LEFT | OP_RIGHT | OP_INVERT | OP_AND | OP_OR | OP_XOR
                     | OP_2MUL | OP_2DIV | OP_MUL | OP_DIV | OP_MOD | OP_LSHIFT | OP_RSHIFT => {
-                        return self.failop(ExecError::DisabledOpcode, op);
+                        return Err((ExecError::DisabledOpcode, Some(op)));
                     }
                     OP_RESERVED => {
-                        return self.failop(ExecError::Debug, op);
+                        return Err((ExecError::Debug, Some(op)));
+                    }
+                    OP_CODESEPARATOR
+                        if self.ctx == ExecCtx::Legacy && self.opt.verify_const_scriptcode =>
+                    {
+                        return Err((ExecError::OpCodeseparator, Some(op)));
                     }
 
                     _ => {}
                 }
 
                 if exec || (op.to_u8() >= OP_IF.to_u8() && op.to_u8() <= OP_ENDIF.to_u8()) {
-                    if let Err(err) = self.exec_opcode(op) {
-                        return self.failop(err, op);
-                    }
+                    self.exec_opcode(op).map_err(|err| (err, Some(op)))?;
                 }
             }
         }
 
-        self.update_stats();
         Ok(())
     }
 
@@ -592,8 +899,15 @@ impl Exec {
             }
             OP_CSV => {} // otherwise nop
 
+            OP_CHECKTEMPLATEVERIFY if self.opt.experimental.op_ctv => {
+                self.check_template_verify()?;
+            }
+
             OP_NOP1 | OP_NOP4 | OP_NOP5 | OP_NOP6 | OP_NOP7 | OP_NOP8 | OP_NOP9 | OP_NOP10 => {
-                // nops
+                // nops, reserved for soft-fork upgrades
+                if self.opt.discourage_upgradable_nops {
+                    return Err(ExecError::DiscourageUpgradableNops);
+                }
             }
 
             OP_IF | OP_NOTIF => {
@@ -613,7 +927,7 @@ impl Exec {
                         && self.ctx == ExecCtx::SegwitV0
                         && (top.len() > 1 || (top.len() == 1 && top[0] != 1))
                     {
-                        return Err(ExecError::TapscriptMinimalIf);
+                        return Err(ExecError::MinimalIf);
                     }
                     let b = if op == OP_NOTIF {
                         !script::read_scriptbool(&top)
@@ -773,7 +1087,8 @@ impl Exec {
                 // (xn ... x2 x1 x0 n - xn ... x2 x1 x0 xn)
                 // (xn ... x2 x1 x0 n - ... x2 x1 x0 xn)
                 let x = self.stack.topnum(-1, self.opt.require_minimal)?;
-                if x < 0 || x >= self.stack.len() as i64 {
+                // The index is checked against the stack without itself.
+                if x < 0 || x >= self.stack.len() as i64 - 1 {
                     return Err(ExecError::InvalidStackOperation);
                 }
                 self.stack.pop().unwrap();
@@ -960,7 +1275,6 @@ impl Exec {
             OP_CODESEPARATOR => {
                 // Store this CODESEPARATOR position and update the scriptcode.
                 self.last_codeseparator_pos = Some(self.current_position as u32);
-                self.script_code = &self.script[self.current_position..];
             }
 
             OP_CHECKSIG | OP_CHECKSIGVERIFY => {
@@ -993,7 +1307,101 @@ impl Exec {
             }
 
             OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
-                unimplemented!();
+                if self.ctx == ExecCtx::Tapscript {
+                    return Err(ExecError::TapscriptCheckMultiSig);
+                }
+
+                // ([dummy] [sig ...] nb_sigs [pubkey ...] nb_pubkeys -- bool)
+                let mut i: isize = 1;
+                let mut nb_keys = self.stack.topnum(-i, self.opt.require_minimal)?;
+                if !(0..=MAX_PUBKEYS_PER_MULTISIG).contains(&nb_keys) {
+                    return Err(ExecError::PubkeyCount);
+                }
+                // Every pubkey counts towards the opcode limit.
+                self.opcode_count += nb_keys as usize;
+                if self.opcode_count > MAX_OPS_PER_SCRIPT {
+                    return Err(ExecError::OpCount);
+                }
+                i += 1;
+                let mut ikey = i;
+                i += nb_keys as isize;
+                let mut nb_sigs = self.stack.topnum(-i, self.opt.require_minimal)?;
+                if nb_sigs < 0 || nb_sigs > nb_keys {
+                    return Err(ExecError::SigCount);
+                }
+                i += 1;
+                let first_sig = i;
+                let total_nb_sigs = nb_sigs;
+                let mut isig = i;
+                i += nb_sigs as isize;
+                // Due to a bug, one extra element, the dummy, is consumed.
+                let dummy = self.stack.topstr(-i)?;
+
+                // Drop the signatures in pre-segwit scripts but not segwit scripts
+                let mut scriptcode = self.script_code().as_bytes().to_vec();
+                if self.ctx == ExecCtx::Legacy {
+                    for k in 0..nb_sigs as isize {
+                        let sig = self.stack.topstr(-isig - k)?;
+                        let (code, nb_found) = find_and_delete(&scriptcode, &sig);
+                        if nb_found > 0 && self.opt.verify_const_scriptcode {
+                            return Err(ExecError::SigFindAndDelete);
+                        }
+                        scriptcode = code;
+                    }
+                }
+
+                let mut success = true;
+                while success && nb_sigs > 0 {
+                    let sig = self.stack.topstr(-isig)?;
+                    let pk = self.stack.topstr(-ikey)?;
+
+                    // Note how this makes the exact order of pubkey/signature evaluation
+                    // distinguishable by CHECKMULTISIG NOT if the STRICTENC flag is set.
+                    self.check_signature_encoding(&sig)?;
+                    self.check_pubkey_encoding(&pk)?;
+
+                    if self.check_sig_ecdsa(&sig, &pk, &scriptcode) {
+                        isig += 1;
+                        nb_sigs -= 1;
+                    }
+                    ikey += 1;
+                    nb_keys -= 1;
+
+                    // If there are more signatures left than keys left,
+                    // then too many signatures have failed. Exit early,
+                    // without checking any further signatures.
+                    if nb_sigs > nb_keys {
+                        success = false;
+                    }
+                }
+
+                // If the operation failed, all signatures must be empty.
+                if !success && self.opt.verify_null_fail {
+                    for k in 0..total_nb_sigs as isize {
+                        if !self.stack.topstr(-first_sig - k)?.is_empty() {
+                            return Err(ExecError::SigNullFail);
+                        }
+                    }
+                }
+
+                if self.opt.verify_null_dummy && !dummy.is_empty() {
+                    return Err(ExecError::SigNullDummy);
+                }
+
+                self.stack.popn(i as usize).unwrap();
+                if op == OP_CHECKMULTISIGVERIFY && !success {
+                    return Err(ExecError::CheckMultiSigVerify);
+                }
+                if op == OP_CHECKMULTISIG {
+                    let ret = if success { 1 } else { 0 };
+                    self.stack.pushnum(ret);
+                }
+            }
+
+            OP_CHECKSIGFROMSTACK
+                if self.opt.experimental.op_csfs && self.ctx == ExecCtx::Tapscript =>
+            {
+                self.check_sig_from_stack()?;
             }
 
             // remainder
@@ -1020,9 +1428,45 @@ impl Exec {
     }
 }
 
+/// Counts the signature operations in the script, like Bitcoin Core's `GetSigOpCount`.
+///
+/// When `accurate` is set, OP_CHECKMULTISIG preceded by a small integer push
+/// counts as that many sigops, otherwise it always counts as the maximum of 20.
+pub fn count_sigops(script: &Script, accurate: bool) -> usize {
+    let mut count = 0;
+    let mut last_op: Option<Opcode> = None;
+    for instruction in script.instructions() {
+        let op = match instruction {
+            Ok(Instruction::Op(op)) => op,
+            Ok(Instruction::PushBytes(_)) => {
+                last_op = None;
+                continue;
+            }
+            Err(_) => break,
+        };
+
+        match op {
+            OP_CHECKSIG | OP_CHECKSIGVERIFY => count += 1,
+            OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => match last_op {
+                Some(last)
+                    if accurate
+                        && last.to_u8() >= OP_PUSHNUM_1.to_u8()
+                        && last.to_u8() <= OP_PUSHNUM_16.to_u8() =>
+                {
+                    count += (last.to_u8() - OP_PUSHNUM_1.to_u8() + 1) as usize;
+                }
+                _ => count += MAX_PUBKEYS_PER_MULTISIG as usize,
+            },
+            _ => {}
+        }
+        last_op = Some(op);
+    }
+    count
+}
+
 fn read_scriptint(item: &[u8], size: usize, minimal: bool) -> Result<i64, ExecError> {
     script::read_scriptint_size(item, size, minimal).map_err(|e| match e {
-        script::ScriptIntError::NonMinimalPush => ExecError::MinimalData,
+        script::ScriptIntError::NonMinimalPush => ExecError::ScriptIntNonMinimal,
         // only possible if size is 4 or lower
         script::ScriptIntError::NumericOverflow => ExecError::ScriptIntNumericOverflow,
     })
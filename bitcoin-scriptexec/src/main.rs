@@ -1,27 +1,131 @@
 use std::fmt;
+use std::fs::File;
 use std::io::{self, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use bitcoin::hashes::Hash;
 use bitcoin::hex::DisplayHex;
-use bitcoin::taproot::TapLeafHash;
-use bitcoin::{ScriptBuf, Transaction};
-use clap::Parser;
+use bitcoin::Script;
+use clap::{Parser, ValueEnum};
 
+use bitcoin_scriptexec::profile::Profiler;
+use bitcoin_scriptexec::tracefile::{self, TraceReader, TraceRecord, TraceWriter};
 use bitcoin_scriptexec::*;
 
+mod debugger;
+mod input;
+mod serve;
+
+use input::{Context, Feature, ScriptFormat};
+
 #[derive(Parser)]
 #[command(author = "Steven Roose <steven@roose.io>", version, about)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    /// filepath to script ASM file
-    #[arg(required = true)]
-    script_path: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// filepath to script ASM file, or `-` to read from stdin
+    #[arg(required_unless_present = "extract")]
+    script_path: Option<PathBuf>,
+    /// The script file contains the script in hex instead of ASM.
+    #[arg(long, conflicts_with = "binary")]
+    hex: bool,
+    /// The script file contains the raw script bytes instead of ASM.
+    #[arg(long)]
+    binary: bool,
+    /// Execute the script spent by input --input-idx of the transaction,
+    /// with the stack from its witness or scriptSig.
+    #[arg(long, conflicts_with_all = ["script_path", "hex", "binary", "witness", "ctx"])]
+    extract: bool,
     /// Whether to print debug info
     #[arg(long)]
     debug: bool,
     /// Whether to output result in JSON.
     #[arg(long)]
     json: bool,
+    /// Profile the execution and print a report in the given format.
+    #[arg(long, value_enum)]
+    profile: Option<ProfileFormat>,
+    /// Write the profile report to this file instead of stdout.
+    #[arg(long, requires = "profile")]
+    profile_out: Option<PathBuf>,
+    /// Start an interactive debugger session.
+    #[arg(long, short, conflicts_with_all = ["debug", "json", "profile"])]
+    interactive: bool,
+    /// The context to execute the script in.
+    #[arg(long, value_enum, default_value_t = Context::Tapscript)]
+    ctx: Context,
+    /// Hex-encoded witness stack item to start the execution with, can be
+    /// repeated. Items are given bottom first.
+    #[arg(long = "witness", value_name = "HEX")]
+    witness: Vec<String>,
+    /// Hex-encoded spending transaction.
+    #[arg(long, value_name = "HEX", conflicts_with = "psbt")]
+    tx: Option<String>,
+    /// Output spent by the transaction as `<amount in sats>:<scriptPubkey hex>`,
+    /// one for each transaction input, in order.
+    #[arg(long = "prevout", value_name = "PREVOUT", requires = "tx")]
+    prevouts: Vec<String>,
+    /// PSBT file, binary or hex, with the spending transaction and the
+    /// outputs it spends.
+    #[arg(long, value_name = "PATH")]
+    psbt: Option<PathBuf>,
+    /// The index of the transaction input that is executed.
+    #[arg(long, default_value_t = 0)]
+    input_idx: usize,
+    /// Analyze the stack usage of the script without executing it.
+    /// With --debug, the stack depths before each instruction are printed.
+    #[arg(long, conflicts_with_all = ["interactive", "profile", "trace"])]
+    analyze: bool,
+    /// Report the consensus and policy limits used by a spend of the script
+    /// as a tapscript leaf with the witness.
+    #[arg(long, conflicts_with_all = ["interactive", "analyze", "profile", "trace"])]
+    budget: bool,
+    /// The size of the control block of the leaf, which depends on its
+    /// depth in the taproot tree.
+    #[arg(long, value_name = "BYTES", requires = "budget", default_value_t = budget::MIN_CONTROL_BLOCK_SIZE)]
+    control_block_size: usize,
+    /// Enable an experimental script feature, can be repeated.
+    #[arg(long = "experimental", value_enum, value_name = "FEATURE")]
+    experimental: Vec<Feature>,
+    /// Write a compact trace of the execution to this file.
+    #[arg(long, value_name = "PATH", conflicts_with = "interactive")]
+    trace: Option<PathBuf>,
+    /// The number of steps between two full stack snapshots in the trace.
+    #[arg(long, requires = "trace", default_value_t = tracefile::DEFAULT_SNAPSHOT_INTERVAL)]
+    trace_interval: usize,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Reconstruct the state at a step of a trace written with --trace.
+    Replay {
+        /// The trace file.
+        trace: PathBuf,
+        /// The step to show the state at, the number of steps executed.
+        /// Without it, a summary of the trace is printed.
+        #[arg(long)]
+        step: Option<usize>,
+        /// Whether to output the state in JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Serve JSON-RPC requests, one per line, on stdio or a TCP socket.
+    Serve {
+        /// Listen on this TCP address, like 127.0.0.1:8332, instead of
+        /// using stdio.
+        #[arg(long, value_name = "ADDR")]
+        listen: Option<String>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ProfileFormat {
+    /// Human-readable tables.
+    Text,
+    /// JSON object.
+    Json,
+    /// Folded stacks, as used by flamegraph tools.
+    Folded,
 }
 
 /// A wrapper for the stack types to print them better.
@@ -39,33 +143,243 @@ impl<'a> fmt::Display for FmtStack<'a> {
     }
 }
 
+fn replay(path: &Path, step: Option<usize>, json: bool) -> Result<(), String> {
+    let file = File::open(path)
+        .map_err(|e| format!("error opening trace file {}: {}", path.display(), e))?;
+    let mut reader = TraceReader::new(io::BufReader::new(file))
+        .map_err(|e| format!("error reading trace: {}", e))?;
+
+    let step = match step {
+        Some(step) => step,
+        None => {
+            let mut end = None;
+            while let Some(record) = reader
+                .next_record()
+                .map_err(|e| format!("error reading trace: {}", e))?
+            {
+                if let TraceRecord::End(e) = record {
+                    end = Some(e);
+                }
+            }
+            let end = end.expect("the reader stops after the end record");
+            println!("Script size: {} bytes", reader.script().len());
+            println!("Steps: {}", reader.nb_steps());
+            println!("Success: {}", end.success);
+            if let Some(error) = end.error {
+                println!("Error: {} at script position {}", error, end.position);
+            }
+            println!(
+                "Final stack: {}",
+                FmtStack(&Stack::from_u8_vec(reader.stack().to_vec()))
+            );
+            return Ok(());
+        }
+    };
+
+    let state = reader
+        .state_at(step)
+        .map_err(|e| format!("error reading trace: {}", e))?
+        .ok_or_else(|| format!("the trace has less than {} steps", step))?;
+    let remaining_script = &reader.script()[state.position..];
+    if json {
+        let step = json::RunStep {
+            remaining_script,
+            stack: &state.stack,
+            altstack: &state.altstack,
+            stats: None,
+        };
+        println!(
+            "{}",
+            serde_json::to_string(&step).expect("serialization error")
+        );
+    } else {
+        println!("Step: {}", state.step);
+        println!("Script position: {}", state.position);
+        println!("Remaining script: {}", remaining_script.to_asm_string());
+        println!("Stack: {}", FmtStack(&Stack::from_u8_vec(state.stack)));
+        println!(
+            "AltStack: {}",
+            FmtStack(&Stack::from_u8_vec(state.altstack))
+        );
+    }
+    Ok(())
+}
+
+fn analyze(script: &Script, nb_stack: usize, json: bool, debug: bool) -> Result<(), String> {
+    let analysis = analysis::analyze(script).map_err(|e| format!("error parsing script: {}", e))?;
+    let underflows = analysis.underflows(nb_stack, 0);
+
+    if json {
+        let report = json::AnalysisReport {
+            analysis: &analysis,
+            underflows: &underflows,
+            steps: debug,
+        };
+        println!(
+            "{}",
+            serde_json::to_string(&report).expect("serialization error")
+        );
+        return Ok(());
+    }
+
+    if debug {
+        println!(
+            "{:>10} {:<24} {:>12} {:>12}",
+            "POSITION", "OPCODE", "STACK", "ALTSTACK"
+        );
+        for step in &analysis.steps {
+            let opcode = match step.opcode {
+                Some(op) => op.to_string(),
+                None => "<push>".to_owned(),
+            };
+            println!(
+                "{:>10} {:<24} {:>12} {:>12}",
+                step.position,
+                opcode,
+                step.stack.to_string(),
+                step.altstack.to_string(),
+            );
+        }
+        println!();
+    }
+    print!("{}", analysis);
+    for underflow in &underflows {
+        let opcode = match underflow.opcode {
+            Some(op) => op.to_string(),
+            None => "push".to_owned(),
+        };
+        println!(
+            "Error: {} at position {} underflows the stack by {} and the altstack by {} items",
+            opcode, underflow.position, underflow.missing, underflow.missing_altstack,
+        );
+    }
+    Ok(())
+}
+
 fn inner_main() -> Result<(), String> {
     let args = Args::parse();
 
-    let script_asm = std::fs::read_to_string(args.script_path).expect("error reading script file");
-    let script = ScriptBuf::parse_asm(&script_asm).expect("error parsing script");
+    match args.command {
+        Some(Command::Replay {
+            ref trace,
+            step,
+            json,
+        }) => return replay(trace, step, json),
+        Some(Command::Serve { ref listen }) => {
+            let res = match listen {
+                Some(addr) => serve::serve_tcp(addr),
+                None => serve::serve_stdio(),
+            };
+            return res.map_err(|e| format!("I/O error: {}", e));
+        }
+        None => {}
+    }
+
+    let tx = input::load_tx(args.tx.as_deref(), &args.prevouts, args.psbt.as_deref())?;
+    let (ctx, script, witness) = if args.extract {
+        let (tx, prevouts) = tx
+            .as_ref()
+            .ok_or("--extract requires a transaction from --tx or --psbt")?;
+        let spend = input::extract_spend(tx, prevouts, args.input_idx)?;
+        (spend.ctx, spend.script, spend.witness)
+    } else {
+        let format = if args.hex {
+            ScriptFormat::Hex
+        } else if args.binary {
+            ScriptFormat::Binary
+        } else {
+            ScriptFormat::Asm
+        };
+        let path = args.script_path.as_ref().expect("required by clap");
+        let script = input::read_script(path, format)?;
+        (
+            args.ctx.into(),
+            script,
+            input::parse_witness(&args.witness)?,
+        )
+    };
     println!("Script in hex: {}", script.as_bytes().to_lower_hex_string());
     println!("Script size: {} bytes", script.as_bytes().len());
+    if ctx != ExecCtx::Tapscript {
+        // Tapscript has no sigop limit, and witness scripts are counted
+        // accurately like Core does.
+        println!(
+            "Sigops: {}",
+            count_sigops(&script, ctx == ExecCtx::SegwitV0)
+        );
+    }
 
-    let start = std::time::Instant::now();
-    let mut exec = Exec::new(
-        ExecCtx::Tapscript,
-        Options::default(),
-        TxTemplate {
-            tx: Transaction {
-                version: bitcoin::transaction::Version::TWO,
-                lock_time: bitcoin::locktime::absolute::LockTime::ZERO,
-                input: vec![],
-                output: vec![],
-            },
-            prevouts: vec![],
-            input_idx: 0,
-            taproot_annex_scriptleaf: Some((TapLeafHash::all_zeros(), None)),
-        },
-        script,
-        vec![],
+    if args.analyze {
+        return analyze(&script, witness.len(), args.json, args.debug);
+    }
+
+    let profiler = args.profile.map(|_| Profiler::new(&script));
+
+    let tx = TxTemplate::for_input(ctx, &script, tx, args.input_idx).map_err(|e| e.to_string())?;
+    let opts = Options {
+        experimental: input::experimental(&args.experimental),
+        ..Options::default()
+    };
+    if args.budget {
+        if ctx != ExecCtx::Tapscript {
+            return Err("--budget only applies to tapscript leaves".to_owned());
+        }
+        let report = budget::check_leaf(opts, tx, script, witness, args.control_block_size)
+            .map_err(|e| format!("error creating exec: {}", e))?;
+        if args.json {
+            println!(
+                "{}",
+                serde_json::to_string(&report).expect("serialization error")
+            );
+        } else {
+            print!("{}", report);
+            if let Some(ref err) = report.result.error {
+                println!("Execution failed: {} ({})", err, err.name());
+            }
+        }
+        return Ok(());
+    }
+
+    // Check that we can create the execution before we start.
+    Exec::new(
+        ctx,
+        opts.clone(),
+        tx.clone(),
+        script.clone(),
+        witness.clone(),
     )
-    .expect("error creating exec");
+    .map_err(|e| format!("error creating exec: {}", e))?;
+
+    let new_exec = move || {
+        Exec::new(
+            ctx,
+            opts.clone(),
+            tx.clone(),
+            script.clone(),
+            witness.clone(),
+        )
+        .expect("error creating exec")
+    };
+
+    if args.interactive {
+        let mut debugger = debugger::Debugger::new(Box::new(new_exec));
+        return debugger.run().map_err(|e| format!("I/O error: {}", e));
+    }
+
+    let start = std::time::Instant::now();
+    let mut exec = new_exec();
+
+    let tracer = match args.trace {
+        Some(ref path) => {
+            let file = File::create(path)
+                .map_err(|e| format!("error creating trace file {}: {}", path.display(), e))?;
+            let writer = TraceWriter::new(io::BufWriter::new(file), &exec, args.trace_interval)
+                .map_err(|e| format!("error writing trace: {}", e))?;
+            Some(writer)
+        }
+        None => None,
+    };
+    let mut observer = (profiler, tracer);
 
     const SEP: &str = "--------------------------------------------------";
 
@@ -94,17 +408,24 @@ fn inner_main() -> Result<(), String> {
             }
         }
 
-        if exec.exec_next().is_err() {
+        if exec.exec_next_observed(&mut observer).is_err() {
             break;
         }
     }
 
     let res = exec.result().unwrap().clone();
+    let (profiler, tracer) = observer;
+    if let Some(tracer) = tracer {
+        tracer
+            .finish(&exec, &res)
+            .map_err(|e| format!("error writing trace: {}", e))?;
+    }
     if args.json {
         let ret = json::RunResult {
             success: res.success,
-            error: res.error.map(|e| format!("{:?}", e)), //TODO(stevenroose) fmt::Display
+            error: res.error.as_ref(),
             opcode: res.opcode,
+            position: res.position,
             final_stack: &res.final_stack.iter_str().collect::<Vec<Vec<u8>>>(),
             stats: Some(exec.stats()),
         };
@@ -113,14 +434,38 @@ fn inner_main() -> Result<(), String> {
         println!("Execution ended. Success: {}", res.success);
         print!("Final stack: {}", FmtStack(&res.final_stack));
         println!();
-        if !res.success {
-            println!("Failed on opcode: {:?}", res.opcode);
-            println!("Error: {:?}", res.error);
+        if let Some(ref err) = res.error {
+            println!("Error: {} ({})", err, err.name());
+            if let Some(opcode) = res.opcode {
+                println!("Failed on opcode: {}", opcode);
+            }
+            if let Some(position) = res.position {
+                println!("Failed at script position: {}", position);
+            }
         }
         println!("Stats:\n{:#?}", exec.stats());
         println!("Time elapsed: {}ms", start.elapsed().as_millis());
     }
 
+    if let (Some(profiler), Some(format)) = (profiler, args.profile) {
+        let report = profiler.finish();
+        let report = match format {
+            ProfileFormat::Text => report.to_string(),
+            ProfileFormat::Json => {
+                serde_json::to_string(&report).expect("serialization error") + "\n"
+            }
+            ProfileFormat::Folded => report.to_folded(),
+        };
+        match args.profile_out {
+            Some(path) => std::fs::write(&path, report)
+                .map_err(|e| format!("error writing profile to {}: {}", path.display(), e))?,
+            None => {
+                println!("{}", SEP);
+                print!("{}", report);
+            }
+        }
+    }
+
     Ok(())
 }
 
@@ -0,0 +1,336 @@
+//! Per-opcode and per-region profiling of script executions.
+//!
+//! Regions are delimited by marker instructions in the script itself:
+//! `<"PROFILE_BEGIN:" name> OP_DROP` and `<"PROFILE_END:" name> OP_DROP`.
+//! The markers don't change the outcome of the script, and they are excluded
+//! from all counts of the profile. Use [region_begin_marker] and
+//! [region_end_marker] to create them.
+//!
+//! The [Profiler] is an [ExecObserver], so it can be passed to
+//! [Exec::exec_next_observed].
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use bitcoin::opcodes::all::OP_DROP;
+use bitcoin::script::{self, Instruction, PushBytesBuf, Script, ScriptBuf};
+use bitcoin::Opcode;
+
+use crate::{Exec, ExecError, ExecObserver, StepDiff, StepInfo};
+
+/// Prefix of the data pushed by a region begin marker.
+pub const REGION_BEGIN_PREFIX: &[u8] = b"PROFILE_BEGIN:";
+
+/// Prefix of the data pushed by a region end marker.
+pub const REGION_END_PREFIX: &[u8] = b"PROFILE_END:";
+
+/// Name of the implicit region around the whole script.
+pub const ROOT_REGION: &str = "script";
+
+fn marker(prefix: &[u8], name: &str) -> ScriptBuf {
+    let data = prefix
+        .iter()
+        .chain(name.as_bytes())
+        .copied()
+        .collect::<Vec<u8>>();
+    let push = PushBytesBuf::try_from(data).expect("region name too long");
+    script::Builder::new()
+        .push_slice(push)
+        .push_opcode(OP_DROP)
+        .into_script()
+}
+
+/// Creates the marker that opens the region with the given name.
+pub fn region_begin_marker(name: &str) -> ScriptBuf {
+    marker(REGION_BEGIN_PREFIX, name)
+}
+
+/// Creates the marker that closes the region with the given name.
+pub fn region_end_marker(name: &str) -> ScriptBuf {
+    marker(REGION_END_PREFIX, name)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Marker {
+    Begin(String),
+    End(String),
+    /// The OP_DROP following a marker push.
+    Drop,
+}
+
+/// Finds all region markers in the script, indexed by position.
+fn find_markers(script: &Script) -> HashMap<usize, Marker> {
+    let mut markers = HashMap::new();
+    let mut pending: Option<(usize, Marker)> = None;
+    for res in script.instruction_indices() {
+        let (pos, instruction) = match res {
+            Ok(i) => i,
+            Err(_) => break,
+        };
+
+        if let Some((push_pos, marker)) = pending.take() {
+            if instruction == Instruction::Op(OP_DROP) {
+                markers.insert(push_pos, marker);
+                markers.insert(pos, Marker::Drop);
+                continue;
+            }
+        }
+
+        if let Instruction::PushBytes(push) = instruction {
+            let data = push.as_bytes();
+            let name = |prefix: &[u8]| String::from_utf8_lossy(&data[prefix.len()..]).into_owned();
+            if data.starts_with(REGION_BEGIN_PREFIX) {
+                pending = Some((pos, Marker::Begin(name(REGION_BEGIN_PREFIX))));
+            } else if data.starts_with(REGION_END_PREFIX) {
+                pending = Some((pos, Marker::End(name(REGION_END_PREFIX))));
+            }
+        }
+    }
+    markers
+}
+
+/// Execution profile of a single opcode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpcodeProfile {
+    pub opcode: Opcode,
+    /// The number of times the opcode was executed.
+    pub count: usize,
+}
+
+/// Execution profile of a region.
+///
+/// A region is identified by its path: the names of all enclosing regions,
+/// starting with [ROOT_REGION], joined by `;`. All executions of the same
+/// path are aggregated.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RegionProfile {
+    pub path: String,
+    /// The number of times the region was entered.
+    pub entries: usize,
+    /// The number of instructions executed in the region, including sub-regions.
+    pub total_count: usize,
+    /// The number of instructions executed in the region, excluding sub-regions.
+    pub self_count: usize,
+    /// The highest number of stack items (stack and altstack) inside the region.
+    pub max_nb_stack_items: usize,
+}
+
+impl RegionProfile {
+    /// The name of the region, i.e. the last element of its path.
+    pub fn name(&self) -> &str {
+        self.path.rsplit(';').next().unwrap_or(&self.path)
+    }
+
+    /// The nesting depth of the region, the root region has depth 0.
+    pub fn depth(&self) -> usize {
+        self.path.matches(';').count()
+    }
+}
+
+/// The result of profiling an execution.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ProfileReport {
+    /// The number of executed instructions, excluding markers.
+    pub executed_count: usize,
+    /// The number of instructions skipped in non-taken branches, excluding markers.
+    pub skipped_count: usize,
+    /// The highest number of stack items (stack and altstack) during execution.
+    pub max_nb_stack_items: usize,
+    /// The executed opcodes, most frequent first.
+    pub opcodes: Vec<OpcodeProfile>,
+    /// All regions, ordered by path.
+    pub regions: Vec<RegionProfile>,
+    /// The error that ended the execution, if any.
+    pub error: Option<ExecError>,
+}
+
+impl ProfileReport {
+    /// Writes the report in the folded stack format used by flamegraph tools,
+    /// with one line per region and its self count.
+    pub fn write_folded<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+        for region in &self.regions {
+            if region.self_count > 0 {
+                writeln!(w, "{} {}", region.path, region.self_count)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the report in the folded stack format.
+    pub fn to_folded(&self) -> String {
+        let mut ret = String::new();
+        self.write_folded(&mut ret).unwrap();
+        ret
+    }
+}
+
+impl fmt::Display for ProfileReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Executed instructions: {}", self.executed_count)?;
+        writeln!(f, "Skipped instructions: {}", self.skipped_count)?;
+        writeln!(f, "Max stack items: {}", self.max_nb_stack_items)?;
+        if let Some(ref err) = self.error {
+            writeln!(f, "Error: {} ({})", err, err.name())?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "{:<24} {:>12} {:>8}", "OPCODE", "COUNT", "%")?;
+        for op in &self.opcodes {
+            let pct = op.count as f64 * 100.0 / self.executed_count.max(1) as f64;
+            writeln!(
+                f,
+                "{:<24} {:>12} {:>7.2}%",
+                op.opcode.to_string(),
+                op.count,
+                pct
+            )?;
+        }
+
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:<40} {:>8} {:>12} {:>12} {:>10}",
+            "REGION", "ENTRIES", "TOTAL", "SELF", "MAX STACK"
+        )?;
+        for region in &self.regions {
+            let name = format!("{}{}", "  ".repeat(region.depth()), region.name());
+            writeln!(
+                f,
+                "{:<40} {:>8} {:>12} {:>12} {:>10}",
+                name,
+                region.entries,
+                region.total_count,
+                region.self_count,
+                region.max_nb_stack_items,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// An [ExecObserver] that profiles the execution.
+pub struct Profiler {
+    markers: HashMap<usize, Marker>,
+    /// The paths of the currently open regions, outermost first.
+    open_regions: Vec<String>,
+    /// Execution counts indexed by opcode byte.
+    opcodes: HashMap<u8, usize>,
+    regions: BTreeMap<String, RegionProfile>,
+    report: ProfileReport,
+}
+
+impl Profiler {
+    /// Creates a profiler for the execution of the given script.
+    pub fn new(script: &Script) -> Profiler {
+        let mut ret = Profiler {
+            markers: find_markers(script),
+            open_regions: vec![],
+            opcodes: HashMap::new(),
+            regions: BTreeMap::new(),
+            report: ProfileReport::default(),
+        };
+        ret.enter(ROOT_REGION);
+        ret
+    }
+
+    fn enter(&mut self, name: &str) {
+        let path = match self.open_regions.last() {
+            Some(parent) => format!("{};{}", parent, name),
+            None => name.to_owned(),
+        };
+        let region = self
+            .regions
+            .entry(path.clone())
+            .or_insert_with(|| RegionProfile {
+                path: path.clone(),
+                ..Default::default()
+            });
+        region.entries += 1;
+        self.open_regions.push(path);
+    }
+
+    fn exit(&mut self, name: &str) {
+        // Close all regions up to the matching one, but never the root.
+        if let Some(idx) = self
+            .open_regions
+            .iter()
+            .skip(1)
+            .rposition(|p| p.rsplit(';').next() == Some(name))
+        {
+            self.open_regions.truncate(idx + 1);
+        }
+    }
+
+    /// Finishes profiling and returns the report.
+    pub fn finish(mut self) -> ProfileReport {
+        let mut opcodes = self
+            .opcodes
+            .into_iter()
+            .map(|(opcode, count)| OpcodeProfile {
+                opcode: Opcode::from(opcode),
+                count,
+            })
+            .collect::<Vec<_>>();
+        opcodes.sort_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then(a.opcode.to_u8().cmp(&b.opcode.to_u8()))
+        });
+        self.report.opcodes = opcodes;
+        self.report.regions = self.regions.into_values().collect();
+        self.report
+    }
+}
+
+impl ExecObserver for Profiler {
+    fn wants_stack_diffs(&self) -> bool {
+        false
+    }
+
+    fn pre_step(&mut self, _exec: &Exec, step: &StepInfo) {
+        if let Some(marker) = self.markers.get(&step.position) {
+            if step.executed {
+                match marker.clone() {
+                    Marker::Begin(name) => self.enter(&name),
+                    Marker::End(name) => self.exit(&name),
+                    Marker::Drop => {}
+                }
+            }
+            return;
+        }
+
+        if !step.executed {
+            self.report.skipped_count += 1;
+            return;
+        }
+
+        self.report.executed_count += 1;
+        *self.opcodes.entry(step.opcode.to_u8()).or_insert(0) += 1;
+        let current = self.open_regions.len() - 1;
+        for (i, path) in self.open_regions.iter().enumerate() {
+            let region = self.regions.get_mut(path).expect("open regions exist");
+            region.total_count += 1;
+            if i == current {
+                region.self_count += 1;
+            }
+        }
+    }
+
+    fn post_step(&mut self, exec: &Exec, step: &StepInfo, _diff: Option<&StepDiff>) {
+        // The marker pushes would count one item too many.
+        if self.markers.contains_key(&step.position) {
+            return;
+        }
+        let nb_items = exec.stack().len() + exec.altstack().len();
+        self.report.max_nb_stack_items = self.report.max_nb_stack_items.max(nb_items);
+        for path in &self.open_regions {
+            let region = self.regions.get_mut(path).expect("open regions exist");
+            region.max_nb_stack_items = region.max_nb_stack_items.max(nb_items);
+        }
+    }
+
+    fn on_error(&mut self, _exec: &Exec, _step: &StepInfo, error: &ExecError) {
+        self.report.error = Some(error.clone());
+    }
+}
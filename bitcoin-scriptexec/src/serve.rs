@@ -0,0 +1,640 @@
+//! JSON-RPC 2.0 server mode of btcexec.
+//!
+//! Requests and responses are single lines of JSON, over stdio or over a
+//! TCP connection. Every connection has its own set of step sessions.
+//!
+//! Methods:
+//! - `run`: runs a script to the end and returns a `json::RunResult`
+//! - `step`: executes steps of a script in a session, see [Server::step]
+//! - `close`: closes a step session
+//! - `split`: splits a script into shards with the stacks in between
+//! - `verify_spend`: verifies the inputs of a transaction
+//!
+//! Scripts are given like on the command line, see [ScriptParams].
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpListener;
+
+use bitcoin::hex::DisplayHex;
+use bitcoin::opcodes::all::{OP_ENDIF, OP_IF, OP_NOTIF};
+use bitcoin::script::Instruction;
+use bitcoin::ScriptBuf;
+use clap::ValueEnum;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use bitcoin_scriptexec::*;
+
+use crate::input::{self, Context};
+
+/// The default shard size of the `split` method, in bytes, the same as the
+/// default script size of the splitter.
+const DEFAULT_SHARD_SIZE: usize = 7000;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn invalid_params(message: impl Into<String>) -> RpcError {
+        RpcError {
+            code: INVALID_PARAMS,
+            message: message.into(),
+        }
+    }
+
+    fn server(message: impl Into<String>) -> RpcError {
+        RpcError {
+            code: SERVER_ERROR,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<String> for RpcError {
+    fn from(message: String) -> RpcError {
+        RpcError::invalid_params(message)
+    }
+}
+
+fn parse_params<T: for<'de> Deserialize<'de>>(params: Value) -> Result<T, RpcError> {
+    // Omitted params are treated like an empty object.
+    let params = match params {
+        Value::Null => json!({}),
+        p => p,
+    };
+    serde_json::from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))
+}
+
+/// The script and its execution context.
+#[derive(Deserialize)]
+struct ScriptParams {
+    /// The script in hex.
+    script: Option<String>,
+    /// The script in ASM, if not given in hex.
+    script_asm: Option<String>,
+    /// The hex witness stack items to start with, bottom first.
+    #[serde(default)]
+    witness: Vec<String>,
+    /// "legacy", "segwit-v0" or "tapscript", the default.
+    ctx: Option<String>,
+    /// The spending transaction in hex.
+    tx: Option<String>,
+    /// The spent outputs as `<amount in sats>:<scriptPubkey hex>`.
+    #[serde(default)]
+    prevouts: Vec<String>,
+    #[serde(default)]
+    input_idx: usize,
+}
+
+impl ScriptParams {
+    fn script(&self) -> Result<ScriptBuf, RpcError> {
+        match (&self.script, &self.script_asm) {
+            (Some(hex), None) => ScriptBuf::from_hex(hex)
+                .map_err(|e| RpcError::invalid_params(format!("invalid script hex: {}", e))),
+            (None, Some(asm)) => ScriptBuf::parse_asm(asm)
+                .map_err(|e| RpcError::invalid_params(format!("error parsing script: {:?}", e))),
+            _ => Err(RpcError::invalid_params(
+                "exactly one of script and script_asm is required",
+            )),
+        }
+    }
+
+    fn new_exec(&self) -> Result<Exec, RpcError> {
+        let script = self.script()?;
+        let ctx = match self.ctx {
+            Some(ref ctx) => Context::from_str(ctx, true)?,
+            None => Context::Tapscript,
+        };
+        let ctx = ExecCtx::from(ctx);
+        let witness = input::parse_witness(&self.witness)?;
+        let tx = input::load_tx(self.tx.as_deref(), &self.prevouts, None)?;
+        let tx =
+            TxTemplate::for_input(ctx, &script, tx, self.input_idx).map_err(|e| e.to_string())?;
+        Exec::new(ctx, Options::default(), tx, script, witness)
+            .map_err(|e| RpcError::server(format!("error creating exec: {}", e)))
+    }
+}
+
+fn run_result(exec: &Exec) -> Value {
+    let res = exec.result().expect("execution finished");
+    let ret = json::RunResult {
+        success: res.success,
+        error: res.error.as_ref(),
+        opcode: res.opcode,
+        position: res.position,
+        final_stack: &res.final_stack.iter_str().collect::<Vec<_>>(),
+        stats: Some(exec.stats()),
+    };
+    serde_json::to_value(&ret).expect("serialization error")
+}
+
+fn run_step(exec: &Exec) -> Value {
+    let step = json::RunStep {
+        remaining_script: exec.remaining_script(),
+        stack: &exec.stack().iter_str().collect::<Vec<_>>(),
+        altstack: &exec.altstack().iter_str().collect::<Vec<_>>(),
+        stats: Some(exec.stats()),
+    };
+    serde_json::to_value(&step).expect("serialization error")
+}
+
+fn stack_json(stack: &Stack) -> Value {
+    stack
+        .iter_str()
+        .map(|i| Value::from(i.as_hex().to_string()))
+        .collect()
+}
+
+/// Returns the script positions at which the shards start, the first one
+/// is always zero.
+///
+/// This is deliberately only the naive `ByBytes` split of the splitter's
+/// `split_into_shards`: a shard is ended as soon as it reaches the shard
+/// size, but only when all its conditionals are closed. The splitter depends
+/// on this crate, so its fuzzy and optimal splits are not available here.
+/// Unlike the splitter, no empty shard is added at the end of the script.
+fn shard_boundaries(script: &ScriptBuf, shard_size: usize) -> Vec<usize> {
+    let mut ret = vec![0];
+    let mut depth = 0i64;
+    let mut instructions = script.instruction_indices().peekable();
+    while let Some(res) = instructions.next() {
+        // The rest of an invalid script stays in the last shard.
+        let (_, instruction) = match res {
+            Ok(i) => i,
+            Err(_) => break,
+        };
+        match instruction {
+            Instruction::Op(OP_IF) | Instruction::Op(OP_NOTIF) => depth += 1,
+            Instruction::Op(OP_ENDIF) => depth -= 1,
+            _ => {}
+        }
+        let end = match instructions.peek() {
+            Some(Ok((pos, _))) => *pos,
+            _ => script.len(),
+        };
+        if end - ret.last().unwrap() >= shard_size && depth == 0 && end < script.len() {
+            ret.push(end);
+        }
+    }
+    ret
+}
+
+/// The state of a connection.
+#[derive(Default)]
+struct Server {
+    sessions: HashMap<u64, Exec>,
+    next_session: u64,
+}
+
+impl Server {
+    fn run(&mut self, params: Value) -> Result<Value, RpcError> {
+        let params: ScriptParams = parse_params(params)?;
+        let mut exec = params.new_exec()?;
+        while exec.exec_next().is_ok() {}
+        Ok(run_result(&exec))
+    }
+
+    /// Executes `count` steps, 1 by default, of the script given in the
+    /// params, which starts a new session, or of the given session.
+    ///
+    /// Returns the session id, whether the execution finished, the state as
+    /// `json::RunStep` and the `json::RunResult` if it finished.
+    fn step(&mut self, params: Value) -> Result<Value, RpcError> {
+        #[derive(Deserialize)]
+        struct StepParams {
+            session: Option<u64>,
+            count: Option<usize>,
+            #[serde(flatten)]
+            script: ScriptParams,
+        }
+        let params: StepParams = parse_params(params)?;
+
+        let id = match params.session {
+            Some(id) => id,
+            None => {
+                let exec = params.script.new_exec()?;
+                let id = self.next_session;
+                self.next_session += 1;
+                self.sessions.insert(id, exec);
+                id
+            }
+        };
+        let exec = self
+            .sessions
+            .get_mut(&id)
+            .ok_or_else(|| RpcError::invalid_params(format!("unknown session: {}", id)))?;
+
+        for _ in 0..params.count.unwrap_or(1) {
+            if exec.exec_next().is_err() {
+                break;
+            }
+        }
+
+        let finished = exec.result().is_some();
+        let mut ret = json!({
+            "session": id,
+            "finished": finished,
+            "step": run_step(exec),
+        });
+        if finished {
+            ret["result"] = run_result(exec);
+        }
+        Ok(ret)
+    }
+
+    fn close(&mut self, params: Value) -> Result<Value, RpcError> {
+        #[derive(Deserialize)]
+        struct CloseParams {
+            session: u64,
+        }
+        let params: CloseParams = parse_params(params)?;
+        Ok(Value::Bool(self.sessions.remove(&params.session).is_some()))
+    }
+
+    /// Splits the script into shards of about `shard_size` bytes and executes
+    /// it, returning the shards with the stacks after each of them.
+    ///
+    /// Shards after a failure have no stacks.
+    fn split(&mut self, params: Value) -> Result<Value, RpcError> {
+        #[derive(Deserialize)]
+        struct SplitParams {
+            shard_size: Option<usize>,
+            #[serde(flatten)]
+            script: ScriptParams,
+        }
+        let params: SplitParams = parse_params(params)?;
+        let shard_size = params.shard_size.unwrap_or(DEFAULT_SHARD_SIZE);
+        if shard_size == 0 {
+            return Err(RpcError::invalid_params("shard size must be positive"));
+        }
+
+        let mut exec = params.script.new_exec()?;
+        let script = exec.script().to_owned();
+        let mut boundaries = shard_boundaries(&script, shard_size);
+        boundaries.push(script.len());
+
+        // The stacks are taken before executing the first instruction of the
+        // next shard, and at the end of the script for the last shard.
+        let mut states = Vec::new();
+        loop {
+            if states.len() + 1 < boundaries.len()
+                && exec.script_position() == boundaries[states.len() + 1]
+                && exec.result().is_none()
+            {
+                states.push((stack_json(exec.stack()), stack_json(exec.altstack())));
+            }
+            if exec.exec_next().is_err() {
+                break;
+            }
+        }
+
+        let shards = boundaries
+            .windows(2)
+            .enumerate()
+            .map(|(i, w)| {
+                let mut shard = json!({
+                    "start": w[0],
+                    "end": w[1],
+                    "script": script.as_bytes()[w[0]..w[1]].as_hex().to_string(),
+                });
+                if let Some((stack, altstack)) = states.get(i) {
+                    shard["stack"] = stack.clone();
+                    shard["altstack"] = altstack.clone();
+                }
+                shard
+            })
+            .collect::<Vec<_>>();
+        Ok(json!({
+            "shards": shards,
+            "result": run_result(&exec),
+        }))
+    }
+
+    /// Verifies the given input, or all inputs, of the transaction.
+    fn verify_spend(&mut self, params: Value) -> Result<Value, RpcError> {
+        #[derive(Deserialize)]
+        struct VerifyParams {
+            tx: String,
+            prevouts: Vec<String>,
+            input_idx: Option<usize>,
+            /// "consensus" or "standardness", the default.
+            flags: Option<String>,
+        }
+        let params: VerifyParams = parse_params(params)?;
+        let opt = match params.flags.as_deref() {
+            None | Some("standardness") => Options::standardness(),
+            Some("consensus") => Options::consensus(),
+            Some(f) => return Err(RpcError::invalid_params(format!("unknown flags: {}", f))),
+        };
+        let (tx, prevouts) =
+            input::load_tx(Some(&params.tx), &params.prevouts, None)?.expect("tx is given");
+
+        let indices = match params.input_idx {
+            Some(idx) if idx >= tx.input.len() => {
+                return Err(RpcError::invalid_params("input index out of bounds"));
+            }
+            Some(idx) => vec![idx],
+            None => (0..tx.input.len()).collect(),
+        };
+        let mut valid = true;
+        let inputs = indices
+            .into_iter()
+            .map(
+                |idx| match verify_input_with_options(opt.clone(), &tx, idx, &prevouts) {
+                    Ok(()) => json!({ "input_idx": idx, "valid": true }),
+                    Err(e) => {
+                        valid = false;
+                        let mut ret = json!({
+                            "input_idx": idx,
+                            "valid": false,
+                            "error": e.to_string(),
+                        });
+                        if let Error::Exec(ref e) = e {
+                            ret["error_code"] = e.name().into();
+                        }
+                        ret
+                    }
+                },
+            )
+            .collect::<Vec<_>>();
+        Ok(json!({ "valid": valid, "inputs": inputs }))
+    }
+
+    fn call(&mut self, method: &str, params: Value) -> Result<Value, RpcError> {
+        match method {
+            "run" => self.run(params),
+            "step" => self.step(params),
+            "close" => self.close(params),
+            "split" => self.split(params),
+            "verify_spend" => self.verify_spend(params),
+            _ => Err(RpcError {
+                code: METHOD_NOT_FOUND,
+                message: format!("unknown method: {}", method),
+            }),
+        }
+    }
+
+    /// Handles a single request, returns None for notifications.
+    fn handle_request(&mut self, request: Value) -> Option<Value> {
+        let id = request.get("id").cloned();
+        let method = request.get("method").and_then(Value::as_str);
+        let res = match (request.get("jsonrpc").and_then(Value::as_str), method) {
+            (Some("2.0"), Some(method)) => {
+                let params = request.get("params").cloned().unwrap_or(Value::Null);
+                self.call(method, params)
+            }
+            _ => Err(RpcError {
+                code: INVALID_REQUEST,
+                message: "invalid JSON-RPC 2.0 request".into(),
+            }),
+        };
+
+        // No response to notifications, unless the request was invalid.
+        if id.is_none() && method.is_some() {
+            return None;
+        }
+        Some(response(id.unwrap_or(Value::Null), res))
+    }
+
+    /// Handles a line of input, returns None if there is nothing to respond.
+    fn handle_line(&mut self, line: &str) -> Option<Value> {
+        let request = match serde_json::from_str::<Value>(line) {
+            Ok(r) => r,
+            Err(e) => {
+                let err = RpcError {
+                    code: PARSE_ERROR,
+                    message: e.to_string(),
+                };
+                return Some(response(Value::Null, Err(err)));
+            }
+        };
+        match request {
+            Value::Array(batch) if !batch.is_empty() => {
+                let responses = batch
+                    .into_iter()
+                    .filter_map(|r| self.handle_request(r))
+                    .collect::<Vec<_>>();
+                match responses.is_empty() {
+                    true => None,
+                    false => Some(Value::Array(responses)),
+                }
+            }
+            request => self.handle_request(request),
+        }
+    }
+}
+
+fn response(id: Value, res: Result<Value, RpcError>) -> Value {
+    match res {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(e) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": e.code, "message": e.message },
+        }),
+    }
+}
+
+/// Serves requests read line by line until the reader is exhausted.
+fn serve_connection<R: BufRead, W: Write>(reader: R, mut writer: W) -> io::Result<()> {
+    let mut server = Server::default();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = server.handle_line(&line) {
+            serde_json::to_writer(&mut writer, &response)?;
+            writer.write_all(b"\n")?;
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Serves requests from stdin until it is closed.
+pub fn serve_stdio() -> io::Result<()> {
+    serve_connection(io::stdin().lock(), io::stdout().lock())
+}
+
+/// Serves requests from TCP connections on the given address, each
+/// connection in its own thread.
+///
+/// There is no authentication, so the address should be a local one.
+pub fn serve_tcp(addr: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    eprintln!("Listening on {}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Error accepting connection: {}", e);
+                continue;
+            }
+        };
+        std::thread::spawn(move || {
+            let peer = stream.peer_addr();
+            let res = stream
+                .try_clone()
+                .and_then(|reader| serve_connection(BufReader::new(reader), stream));
+            if let Err(e) = res {
+                eprintln!("Connection error with {:?}: {}", peer, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds the lines through a connection, returns the responses.
+    fn serve(lines: &[String]) -> Vec<Value> {
+        let input = lines.iter().map(|l| format!("{}\n", l)).collect::<String>();
+        let mut output = Vec::new();
+        serve_connection(input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect()
+    }
+
+    fn request(id: u64, method: &str, params: Value) -> String {
+        json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }).to_string()
+    }
+
+    fn error_code(response: &Value) -> i64 {
+        response["error"]["code"].as_i64().unwrap()
+    }
+
+    /// OP_1 OP_2 OP_ADD OP_3 OP_EQUAL
+    const SCRIPT: &str = "5152935387";
+
+    #[test]
+    fn test_run() {
+        let responses = serve(&[
+            request(1, "run", json!({ "script": SCRIPT })),
+            // OP_2 OP_EQUAL with 3 on the witness stack
+            request(2, "run", json!({ "script": "5287", "witness": ["03"] })),
+        ]);
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0]["id"], 1);
+        assert_eq!(responses[0]["result"]["success"], true);
+        assert_eq!(responses[0]["result"]["final_stack"], json!(["01"]));
+        assert_eq!(responses[1]["id"], 2);
+        assert_eq!(responses[1]["result"]["success"], false);
+        assert_eq!(responses[1]["result"]["final_stack"], json!([""]));
+    }
+
+    #[test]
+    fn test_step_sessions() {
+        let responses = serve(&[
+            request(1, "step", json!({ "script": SCRIPT, "count": 2 })),
+            request(2, "step", json!({ "script": "51" })),
+            request(3, "step", json!({ "session": 0 })),
+            request(4, "step", json!({ "session": 0, "count": 10 })),
+            request(5, "close", json!({ "session": 0 })),
+            request(6, "close", json!({ "session": 0 })),
+            request(7, "step", json!({ "session": 0 })),
+        ]);
+        let results = responses.iter().map(|r| &r["result"]).collect::<Vec<_>>();
+
+        assert_eq!(results[0]["session"], 0);
+        assert_eq!(results[0]["finished"], false);
+        assert_eq!(results[0]["step"]["stack"], json!(["01", "02"]));
+        assert_eq!(results[0]["step"]["remaining_script_hex"], "935387");
+
+        // A new script starts a new session.
+        assert_eq!(results[1]["session"], 1);
+
+        // The first session continues where it stopped.
+        assert_eq!(results[2]["session"], 0);
+        assert_eq!(results[2]["step"]["stack"], json!(["03"]));
+        assert_eq!(results[3]["finished"], true);
+        assert_eq!(results[3]["result"]["success"], true);
+
+        assert_eq!(results[4], &json!(true));
+        assert_eq!(results[5], &json!(false));
+        assert_eq!(error_code(&responses[6]), INVALID_PARAMS);
+    }
+
+    #[test]
+    fn test_split() {
+        let responses = serve(&[
+            request(1, "split", json!({ "script": SCRIPT, "shard_size": 2 })),
+            // OP_1 OP_IF OP_2 OP_3 OP_ENDIF OP_4, not cut inside the OP_IF
+            request(
+                2,
+                "split",
+                json!({ "script": "516352536854", "shard_size": 1 }),
+            ),
+            request(3, "split", json!({ "script": SCRIPT, "shard_size": 0 })),
+        ]);
+
+        let result = &responses[0]["result"];
+        assert_eq!(result["result"]["success"], true);
+        let shards = result["shards"].as_array().unwrap();
+        assert_eq!(shards.len(), 3);
+        assert_eq!(shards[0]["script"], "5152");
+        assert_eq!(shards[0]["stack"], json!(["01", "02"]));
+        assert_eq!(shards[1]["start"], 2);
+        assert_eq!(shards[1]["end"], 4);
+        assert_eq!(shards[1]["stack"], json!(["03", "03"]));
+        assert_eq!(shards[2]["script"], "87");
+        assert_eq!(shards[2]["stack"], json!(["01"]));
+
+        let shards = responses[1]["result"]["shards"].as_array().unwrap();
+        let scripts = shards
+            .iter()
+            .map(|s| s["script"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(scripts, vec!["51", "63525368", "54"]);
+
+        assert_eq!(error_code(&responses[2]), INVALID_PARAMS);
+    }
+
+    #[test]
+    fn test_errors() {
+        let responses = serve(&[
+            "{".to_owned(),
+            json!({ "id": 1, "method": "run" }).to_string(),
+            request(2, "unknown", json!({})),
+            request(3, "run", json!({})),
+            request(4, "run", json!({ "script": "zz" })),
+            // A truncated OP_PUSHDATA1 fails when it is executed.
+            request(5, "run", json!({ "script": "4c" })),
+            // Notifications don't get a response.
+            json!({ "jsonrpc": "2.0", "method": "run", "params": { "script": SCRIPT } })
+                .to_string(),
+            format!(
+                "[{}, {}]",
+                request(6, "run", json!({ "script": SCRIPT })),
+                request(7, "close", json!({})),
+            ),
+        ]);
+        assert_eq!(responses.len(), 7);
+        assert_eq!(error_code(&responses[0]), PARSE_ERROR);
+        assert_eq!(responses[0]["id"], Value::Null);
+        assert_eq!(error_code(&responses[1]), INVALID_REQUEST);
+        assert_eq!(responses[1]["id"], 1);
+        assert_eq!(error_code(&responses[2]), METHOD_NOT_FOUND);
+        assert_eq!(error_code(&responses[3]), INVALID_PARAMS);
+        assert_eq!(error_code(&responses[4]), INVALID_PARAMS);
+        assert_eq!(responses[5]["result"]["error_code"], "BAD_OPCODE");
+
+        let batch = responses[6].as_array().unwrap();
+        assert_eq!(batch[0]["result"]["success"], true);
+        assert_eq!(error_code(&batch[1]), INVALID_PARAMS);
+    }
+}
@@ -4,10 +4,123 @@ use bitcoin::sighash::{Annex, EcdsaSighashType, Prevouts, TapSighashType};
 use crate::*;
 
 lazy_static::lazy_static! {
-    static ref SECP: secp256k1::Secp256k1<secp256k1::All> = secp256k1::Secp256k1::new();
+    pub(crate) static ref SECP: secp256k1::Secp256k1<secp256k1::All> = secp256k1::Secp256k1::new();
+}
+
+/// Bitcoin Core's `IsValidSignatureEncoding`: strict DER encoding (BIP 66)
+/// of a signature followed by a sighash byte.
+fn is_valid_signature_encoding(sig: &[u8]) -> bool {
+    // Format: 0x30 [total-length] 0x02 [R-length] [R] 0x02 [S-length] [S] [sighash]
+    if sig.len() < 9 || sig.len() > 73 {
+        return false;
+    }
+    if sig[0] != 0x30 || sig[1] as usize != sig.len() - 3 {
+        return false;
+    }
+
+    let len_r = sig[3] as usize;
+    if 5 + len_r >= sig.len() {
+        return false;
+    }
+    let len_s = sig[5 + len_r] as usize;
+    if len_r + len_s + 7 != sig.len() {
+        return false;
+    }
+
+    // R must be a positive integer without unnecessary padding.
+    if sig[2] != 0x02 || len_r == 0 || sig[4] & 0x80 != 0 {
+        return false;
+    }
+    if len_r > 1 && sig[4] == 0x00 && sig[5] & 0x80 == 0 {
+        return false;
+    }
+
+    // Same for S.
+    if sig[len_r + 4] != 0x02 || len_s == 0 || sig[len_r + 6] & 0x80 != 0 {
+        return false;
+    }
+    if len_s > 1 && sig[len_r + 6] == 0x00 && sig[len_r + 7] & 0x80 == 0 {
+        return false;
+    }
+
+    true
+}
+
+/// Whether the S value of the signature (without sighash byte) is at most
+/// half the curve order.
+fn is_low_s(sig: &[u8]) -> bool {
+    match secp256k1::ecdsa::Signature::from_der_lax(sig) {
+        Ok(sig) => {
+            let mut normalized = sig;
+            normalized.normalize_s();
+            normalized == sig
+        }
+        Err(_) => false,
+    }
+}
+
+/// Whether the sighash byte of the signature is one of the defined types.
+fn is_defined_hashtype_signature(sig: &[u8]) -> bool {
+    const SIGHASH_ANYONECANPAY: u8 = 0x80;
+    match sig.last() {
+        Some(b) => {
+            let hashtype = b & !SIGHASH_ANYONECANPAY;
+            (EcdsaSighashType::All as u8..=EcdsaSighashType::Single as u8).contains(&hashtype)
+        }
+        None => false,
+    }
+}
+
+fn is_compressed_or_uncompressed_pubkey(pk: &[u8]) -> bool {
+    match pk.first() {
+        Some(0x04) => pk.len() == 65,
+        Some(0x02) | Some(0x03) => pk.len() == 33,
+        _ => false,
+    }
+}
+
+fn is_compressed_pubkey(pk: &[u8]) -> bool {
+    pk.len() == 33 && (pk[0] == 0x02 || pk[0] == 0x03)
 }
 
 impl Exec {
+    /// Checks the encoding of an ECDSA signature according to the enabled
+    /// options, like Bitcoin Core's `CheckSignatureEncoding`.
+    pub(crate) fn check_signature_encoding(&self, sig: &[u8]) -> Result<(), ExecError> {
+        // Empty signatures are always allowed, they just fail the check.
+        if sig.is_empty() {
+            return Ok(());
+        }
+        let opt = &self.opt;
+        if (opt.verify_der_sig || opt.verify_low_s || opt.verify_strict_enc)
+            && !is_valid_signature_encoding(sig)
+        {
+            return Err(ExecError::SigDer);
+        }
+        if opt.verify_low_s && !is_low_s(&sig[..sig.len() - 1]) {
+            return Err(ExecError::SigHighS);
+        }
+        if opt.verify_strict_enc && !is_defined_hashtype_signature(sig) {
+            return Err(ExecError::SigHashtype);
+        }
+        Ok(())
+    }
+
+    /// Checks the encoding of an ECDSA public key according to the enabled
+    /// options, like Bitcoin Core's `CheckPubKeyEncoding`.
+    pub(crate) fn check_pubkey_encoding(&self, pk: &[u8]) -> Result<(), ExecError> {
+        if self.opt.verify_strict_enc && !is_compressed_or_uncompressed_pubkey(pk) {
+            return Err(ExecError::PubkeyType);
+        }
+        if self.opt.verify_witness_pubkey_type
+            && self.ctx == ExecCtx::SegwitV0
+            && !is_compressed_pubkey(pk)
+        {
+            return Err(ExecError::WitnessPubkeyType);
+        }
+        Ok(())
+    }
+
     pub fn check_sig_ecdsa(&mut self, sig: &[u8], pk: &[u8], script_code: &[u8]) -> bool {
         let pk = match PublicKey::from_slice(pk) {
             Ok(pk) => pk,
@@ -18,32 +131,41 @@ impl Exec {
             return false;
         }
 
+        // Like Core, we parse signatures laxly and only verify normalized ones,
+        // the encoding rules are checked before.
         let hashtype = *sig.last().unwrap();
-        let sig = match secp256k1::ecdsa::Signature::from_der(&sig[0..sig.len() - 1]) {
+        let mut sig = match secp256k1::ecdsa::Signature::from_der_lax(&sig[0..sig.len() - 1]) {
             Ok(s) => s,
             Err(_) => return false,
         };
+        sig.normalize_s();
 
+        // The sighash can't be calculated if the transaction has no input
+        // or prevout to sign, like the dummy transaction of the CLI.
         let sighash = if self.ctx == ExecCtx::SegwitV0 {
-            self.sighashcache
-                .p2wsh_signature_hash(
-                    self.tx.input_idx,
-                    Script::from_bytes(script_code),
-                    self.tx.prevouts[self.tx.input_idx].value,
-                    //TODO(stevenroose) this might not actually emulate consensus behavior
-                    EcdsaSighashType::from_consensus(hashtype as u32),
-                )
-                .expect("only happens on prevout index out of bounds")
-                .into()
+            let value = match self.tx.prevouts.get(self.tx.input_idx) {
+                Some(prevout) => prevout.value,
+                None => return false,
+            };
+            match self.sighashcache.p2wsh_signature_hash(
+                self.tx.input_idx,
+                Script::from_bytes(script_code),
+                value,
+                //TODO(stevenroose) this might not actually emulate consensus behavior
+                EcdsaSighashType::from_consensus(hashtype as u32),
+            ) {
+                Ok(sighash) => sighash.into(),
+                Err(_) => return false,
+            }
         } else if self.ctx == ExecCtx::Legacy {
-            self.sighashcache
-                .legacy_signature_hash(
-                    self.tx.input_idx,
-                    Script::from_bytes(script_code),
-                    hashtype as u32,
-                )
-                .expect("TODO(stevenroose) seems to only happen if prevout index out of bound")
-                .into()
+            match self.sighashcache.legacy_signature_hash(
+                self.tx.input_idx,
+                Script::from_bytes(script_code),
+                hashtype as u32,
+            ) {
+                Ok(sighash) => sighash.into(),
+                Err(_) => return false,
+            }
         } else {
             unreachable!();
         };
@@ -59,7 +181,7 @@ impl Exec {
             return Err(ExecError::SchnorrSigSize);
         }
 
-        let pk = XOnlyPublicKey::from_slice(pk).expect("TODO(stevenroose) what to do here?");
+        let pk = XOnlyPublicKey::from_slice(pk).map_err(|_| ExecError::SchnorrSig)?;
         let (sig, hashtype) = if sig.len() == 65 {
             let b = *sig.last().unwrap();
             let sig = secp256k1::schnorr::Signature::from_slice(&sig[0..sig.len() - 1])
@@ -90,7 +212,8 @@ impl Exec {
                 Some((*leaf_hash, self.last_codeseparator_pos.unwrap_or(u32::MAX))),
                 hashtype,
             )
-            .expect("TODO(stevenroose) seems to only happen if prevout index out of bound");
+            // Also fails for SIGHASH_SINGLE without a corresponding output.
+            .map_err(|_| ExecError::SchnorrSig)?;
 
         if SECP.verify_schnorr(&sig, &sighash.into(), &pk) != Ok(()) {
             return Err(ExecError::SchnorrSig);
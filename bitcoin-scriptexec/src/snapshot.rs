@@ -0,0 +1,116 @@
+//! Snapshots of the execution state, to checkpoint and resume executions.
+
+use bitcoin::ScriptBuf;
+
+use crate::utils::ConditionStack;
+use crate::{Error, Exec, ExecCtx, ExecStats, Options, Stack, TxTemplate};
+
+/// The state of an [Exec] in between two instructions.
+///
+/// A snapshot doesn't contain the script, the transaction or the options,
+/// it can only be restored into an execution of the same script. Restoring
+/// a snapshot and continuing the execution gives exactly the same result as
+/// continuing the original execution.
+///
+/// The result of finished executions is not part of the snapshot, so
+/// snapshots should be taken while the execution is still running.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExecSnapshot {
+    position: usize,
+    cond_stack: ConditionStack,
+    // The stacks are kept as is, so numbers stay numbers when restored.
+    stack: Stack,
+    altstack: Stack,
+    last_codeseparator_pos: Option<u32>,
+    opcode_count: usize,
+    validation_weight: i64,
+    stats: ExecStats,
+}
+
+impl ExecSnapshot {
+    /// The script position of the next instruction to execute.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// The stack.
+    pub fn stack(&self) -> &Stack {
+        &self.stack
+    }
+
+    /// The altstack.
+    pub fn altstack(&self) -> &Stack {
+        &self.altstack
+    }
+
+    pub fn stats(&self) -> &ExecStats {
+        &self.stats
+    }
+}
+
+impl Exec {
+    /// Takes a snapshot of the current execution state.
+    pub fn snapshot(&self) -> ExecSnapshot {
+        ExecSnapshot {
+            position: self.script_position(),
+            cond_stack: self.cond_stack.clone(),
+            stack: self.stack.clone(),
+            altstack: self.altstack.clone(),
+            last_codeseparator_pos: self.last_codeseparator_pos,
+            opcode_count: self.opcode_count,
+            validation_weight: self.validation_weight,
+            stats: self.stats.clone(),
+        }
+    }
+
+    /// Restores the execution state from a snapshot taken from an execution
+    /// of the same script.
+    ///
+    /// Any result of the current execution is discarded.
+    pub fn restore(&mut self, snapshot: &ExecSnapshot) -> Result<(), Error> {
+        let position = snapshot.position;
+        let is_boundary = position == self.script.len()
+            || self
+                .script
+                .instruction_indices()
+                .any(|res| matches!(res, Ok((pos, _)) if pos == position));
+        if !is_boundary {
+            return Err(Error::Other(
+                "snapshot position is not an instruction boundary",
+            ));
+        }
+        if let Some(pos) = snapshot.last_codeseparator_pos {
+            if pos as usize >= position {
+                return Err(Error::Other(
+                    "snapshot OP_CODESEPARATOR position is invalid",
+                ));
+            }
+        }
+
+        self.position = position;
+        self.current_position = position;
+        self.cond_stack = snapshot.cond_stack.clone();
+        self.stack = snapshot.stack.clone();
+        self.altstack = snapshot.altstack.clone();
+        self.last_codeseparator_pos = snapshot.last_codeseparator_pos;
+        self.opcode_count = snapshot.opcode_count;
+        self.validation_weight = snapshot.validation_weight;
+        self.stats = snapshot.stats.clone();
+        self.result = None;
+        Ok(())
+    }
+
+    /// Creates an execution of the script that resumes from the snapshot.
+    pub fn from_snapshot(
+        ctx: ExecCtx,
+        opt: Options,
+        tx: TxTemplate,
+        script: ScriptBuf,
+        snapshot: &ExecSnapshot,
+    ) -> Result<Exec, Error> {
+        let mut ret = Exec::new(ctx, opt, tx, script, vec![])?;
+        ret.restore(snapshot)?;
+        Ok(ret)
+    }
+}
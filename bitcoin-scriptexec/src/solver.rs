@@ -0,0 +1,677 @@
+//! A small CDCL SAT solver with a bit-blaster for [Term]s, used by the
+//! symbolic executor.
+//!
+//! Script numbers are blasted to 64-bit two's complement bitvectors, which
+//! can't overflow: operands are limited to 4 bytes and the executor doesn't
+//! let terms grow deeper than [crate::symbolic::MAX_TERM_DEPTH].
+
+use std::collections::{BinaryHeap, HashMap};
+use std::ops::Not;
+
+use crate::symbolic::Term;
+
+/// The width of the bitvectors numbers are blasted to.
+const WIDTH: usize = 64;
+
+/// Base number of conflicts between restarts, scaled by the Luby sequence.
+const RESTART_BASE: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct Lit(u32);
+
+impl Lit {
+    fn new(var: usize, negated: bool) -> Lit {
+        Lit((var as u32) << 1 | negated as u32)
+    }
+
+    fn var(self) -> usize {
+        (self.0 >> 1) as usize
+    }
+
+    fn is_negated(self) -> bool {
+        self.0 & 1 == 1
+    }
+
+    fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl Not for Lit {
+    type Output = Lit;
+
+    fn not(self) -> Lit {
+        Lit(self.0 ^ 1)
+    }
+}
+
+/// The outcome of a satisfiability check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SatResult<T> {
+    Sat(T),
+    Unsat,
+    /// The conflict budget was exhausted.
+    Unknown,
+}
+
+/// A CDCL solver with two watched literals, 1UIP clause learning, VSIDS
+/// decisions, phase saving and Luby restarts.
+///
+/// Clauses are added at decision level 0 before calling [Sat::solve].
+struct Sat {
+    clauses: Vec<Vec<Lit>>,
+    /// The clauses watching each literal, indexed by [Lit::index].
+    watches: Vec<Vec<usize>>,
+    /// The value of each variable, if assigned.
+    assigns: Vec<Option<bool>>,
+    level: Vec<usize>,
+    reason: Vec<Option<usize>>,
+    trail: Vec<Lit>,
+    trail_lim: Vec<usize>,
+    qhead: usize,
+    activity: Vec<f64>,
+    var_inc: f64,
+    /// Variables ordered by activity. Entries are not removed when a
+    /// variable is bumped or assigned, so stale ones are skipped on pop.
+    order: BinaryHeap<(u64, usize)>,
+    phase: Vec<bool>,
+    seen: Vec<bool>,
+    /// False once a conflict was found at level 0.
+    ok: bool,
+}
+
+impl Sat {
+    fn new() -> Sat {
+        Sat {
+            clauses: Vec::new(),
+            watches: Vec::new(),
+            assigns: Vec::new(),
+            level: Vec::new(),
+            reason: Vec::new(),
+            trail: Vec::new(),
+            trail_lim: Vec::new(),
+            qhead: 0,
+            activity: Vec::new(),
+            var_inc: 1.0,
+            order: BinaryHeap::new(),
+            phase: Vec::new(),
+            seen: Vec::new(),
+            ok: true,
+        }
+    }
+
+    fn new_var(&mut self) -> usize {
+        let var = self.assigns.len();
+        self.watches.push(Vec::new());
+        self.watches.push(Vec::new());
+        self.assigns.push(None);
+        self.level.push(0);
+        self.reason.push(None);
+        self.activity.push(0.0);
+        self.order.push((0f64.to_bits(), var));
+        self.phase.push(false);
+        self.seen.push(false);
+        var
+    }
+
+    fn value(&self, lit: Lit) -> Option<bool> {
+        self.assigns[lit.var()].map(|v| v != lit.is_negated())
+    }
+
+    fn decision_level(&self) -> usize {
+        self.trail_lim.len()
+    }
+
+    fn add_clause(&mut self, lits: &[Lit]) {
+        if !self.ok {
+            return;
+        }
+        debug_assert_eq!(self.decision_level(), 0);
+
+        let mut clause = lits.to_vec();
+        clause.sort_unstable();
+        clause.dedup();
+        if clause.windows(2).any(|w| w[0] == !w[1]) {
+            return;
+        }
+        if clause.iter().any(|&l| self.value(l) == Some(true)) {
+            return;
+        }
+        clause.retain(|&l| self.value(l).is_none());
+
+        match clause.len() {
+            0 => self.ok = false,
+            1 => {
+                self.enqueue(clause[0], None);
+                if self.propagate().is_some() {
+                    self.ok = false;
+                }
+            }
+            _ => {
+                self.attach(clause);
+            }
+        }
+    }
+
+    /// Adds a clause whose first two literals are watched.
+    fn attach(&mut self, clause: Vec<Lit>) -> usize {
+        let idx = self.clauses.len();
+        self.watches[clause[0].index()].push(idx);
+        self.watches[clause[1].index()].push(idx);
+        self.clauses.push(clause);
+        idx
+    }
+
+    fn enqueue(&mut self, lit: Lit, reason: Option<usize>) {
+        let var = lit.var();
+        self.assigns[var] = Some(!lit.is_negated());
+        self.level[var] = self.decision_level();
+        self.reason[var] = reason;
+        self.trail.push(lit);
+    }
+
+    /// Propagates all enqueued assignments, returning a conflicting clause.
+    fn propagate(&mut self) -> Option<usize> {
+        while self.qhead < self.trail.len() {
+            let false_lit = !self.trail[self.qhead];
+            self.qhead += 1;
+
+            let mut watchers = std::mem::take(&mut self.watches[false_lit.index()]);
+            let mut i = 0;
+            let mut j = 0;
+            let mut conflict = None;
+            while i < watchers.len() {
+                let ci = watchers[i];
+                i += 1;
+
+                let clause = &mut self.clauses[ci];
+                if clause[0] == false_lit {
+                    clause.swap(0, 1);
+                }
+                let first = clause[0];
+                if self.assigns[first.var()].map(|v| v != first.is_negated()) == Some(true) {
+                    watchers[j] = ci;
+                    j += 1;
+                    continue;
+                }
+
+                // Look for a new literal to watch.
+                let assigns = &self.assigns;
+                let new_watch = (2..clause.len()).find(|&k| {
+                    let l = clause[k];
+                    assigns[l.var()].map(|v| v != l.is_negated()) != Some(false)
+                });
+                if let Some(k) = new_watch {
+                    clause.swap(1, k);
+                    let watch = clause[1];
+                    self.watches[watch.index()].push(ci);
+                    continue;
+                }
+
+                watchers[j] = ci;
+                j += 1;
+                if self.value(first) == Some(false) {
+                    conflict = Some(ci);
+                    while i < watchers.len() {
+                        watchers[j] = watchers[i];
+                        i += 1;
+                        j += 1;
+                    }
+                } else {
+                    self.enqueue(first, Some(ci));
+                }
+            }
+            watchers.truncate(j);
+            self.watches[false_lit.index()] = watchers;
+
+            if conflict.is_some() {
+                return conflict;
+            }
+        }
+        None
+    }
+
+    fn bump(&mut self, var: usize) {
+        self.activity[var] += self.var_inc;
+        if self.activity[var] > 1e100 {
+            for a in self.activity.iter_mut() {
+                *a *= 1e-100;
+            }
+            self.var_inc *= 1e-100;
+            self.order = (0..self.activity.len())
+                .map(|v| (self.activity[v].to_bits(), v))
+                .collect();
+        } else {
+            self.order.push((self.activity[var].to_bits(), var));
+        }
+    }
+
+    /// Derives the 1UIP clause of a conflict, with the asserting literal
+    /// first, and returns it with the level to backjump to.
+    fn analyze(&mut self, mut conflict: usize) -> (Vec<Lit>, usize) {
+        let mut learnt = vec![Lit(0)];
+        let mut pending = 0;
+        let mut implied: Option<Lit> = None;
+        let mut idx = self.trail.len();
+
+        loop {
+            // The first literal of a reason clause is the one it implied.
+            let skip = if implied.is_some() { 1 } else { 0 };
+            for k in skip..self.clauses[conflict].len() {
+                let q = self.clauses[conflict][k];
+                let var = q.var();
+                if !self.seen[var] && self.level[var] > 0 {
+                    self.seen[var] = true;
+                    self.bump(var);
+                    if self.level[var] == self.decision_level() {
+                        pending += 1;
+                    } else {
+                        learnt.push(q);
+                    }
+                }
+            }
+
+            loop {
+                idx -= 1;
+                if self.seen[self.trail[idx].var()] {
+                    break;
+                }
+            }
+            let p = self.trail[idx];
+            self.seen[p.var()] = false;
+            pending -= 1;
+            implied = Some(p);
+            if pending == 0 {
+                break;
+            }
+            conflict = self.reason[p.var()].expect("implied literal without reason");
+        }
+        learnt[0] = !implied.unwrap();
+
+        for l in &learnt[1..] {
+            self.seen[l.var()] = false;
+        }
+
+        // Watch the literal of the highest level after the asserting one,
+        // so that the clause is correctly watched after backjumping.
+        let mut backjump = 0;
+        if learnt.len() > 1 {
+            let max = (1..learnt.len())
+                .max_by_key(|&k| self.level[learnt[k].var()])
+                .unwrap();
+            learnt.swap(1, max);
+            backjump = self.level[learnt[1].var()];
+        }
+        (learnt, backjump)
+    }
+
+    fn cancel_until(&mut self, level: usize) {
+        if self.decision_level() <= level {
+            return;
+        }
+        let start = self.trail_lim[level];
+        for k in (start..self.trail.len()).rev() {
+            let var = self.trail[k].var();
+            self.phase[var] = self.assigns[var].unwrap();
+            self.assigns[var] = None;
+            self.reason[var] = None;
+            self.order.push((self.activity[var].to_bits(), var));
+        }
+        self.trail.truncate(start);
+        self.trail_lim.truncate(level);
+        self.qhead = start;
+    }
+
+    fn pick_branch_var(&mut self) -> Option<usize> {
+        while let Some((activity, var)) = self.order.pop() {
+            if self.assigns[var].is_none() && activity == self.activity[var].to_bits() {
+                return Some(var);
+            }
+        }
+        // Every unassigned variable has an up-to-date entry, so the heap
+        // only runs out once all variables are assigned.
+        None
+    }
+
+    /// Solves the clauses, giving up after the given number of conflicts.
+    fn solve(&mut self, max_conflicts: u64) -> SatResult<()> {
+        if !self.ok {
+            return SatResult::Unsat;
+        }
+
+        let mut conflicts = 0u64;
+        let mut restart = 1u64;
+        loop {
+            let budget = luby(restart) * RESTART_BASE;
+            restart += 1;
+            let mut restart_conflicts = 0;
+
+            loop {
+                if let Some(conflict) = self.propagate() {
+                    conflicts += 1;
+                    restart_conflicts += 1;
+                    if self.decision_level() == 0 {
+                        self.ok = false;
+                        return SatResult::Unsat;
+                    }
+                    let (learnt, backjump) = self.analyze(conflict);
+                    self.cancel_until(backjump);
+                    if learnt.len() == 1 {
+                        self.enqueue(learnt[0], None);
+                    } else {
+                        let asserting = learnt[0];
+                        let ci = self.attach(learnt);
+                        self.enqueue(asserting, Some(ci));
+                    }
+                    self.var_inc /= 0.95;
+                    continue;
+                }
+
+                if conflicts >= max_conflicts {
+                    self.cancel_until(0);
+                    return SatResult::Unknown;
+                }
+                if restart_conflicts >= budget {
+                    self.cancel_until(0);
+                    break;
+                }
+
+                match self.pick_branch_var() {
+                    Some(var) => {
+                        self.trail_lim.push(self.trail.len());
+                        let lit = Lit::new(var, !self.phase[var]);
+                        self.enqueue(lit, None);
+                    }
+                    None => return SatResult::Sat(()),
+                }
+            }
+        }
+    }
+}
+
+/// The Luby sequence: 1 1 2 1 1 2 4 1 1 2 1 1 2 4 8 ...
+fn luby(mut i: u64) -> u64 {
+    loop {
+        let mut k = 1;
+        while (1 << k) - 1 < i {
+            k += 1;
+        }
+        if i == (1 << k) - 1 {
+            return 1 << (k - 1);
+        }
+        i -= (1 << (k - 1)) - 1;
+    }
+}
+
+/// Translates terms to clauses.
+pub(crate) struct Blaster {
+    sat: Sat,
+    tru: Lit,
+    /// Structural hashing of gates.
+    gates: HashMap<(u8, Lit, Lit, Lit), Lit>,
+    terms: HashMap<*const Term, Vec<Lit>>,
+    inputs: HashMap<usize, Vec<Lit>>,
+}
+
+impl Blaster {
+    pub(crate) fn new() -> Blaster {
+        let mut sat = Sat::new();
+        let tru = Lit::new(sat.new_var(), false);
+        sat.add_clause(&[tru]);
+        Blaster {
+            sat,
+            tru,
+            gates: HashMap::new(),
+            terms: HashMap::new(),
+            inputs: HashMap::new(),
+        }
+    }
+
+    fn fls(&self) -> Lit {
+        !self.tru
+    }
+
+    fn fresh(&mut self) -> Lit {
+        Lit::new(self.sat.new_var(), false)
+    }
+
+    fn and(&mut self, a: Lit, b: Lit) -> Lit {
+        if a == self.fls() || b == self.fls() || a == !b {
+            return self.fls();
+        }
+        if a == self.tru || a == b {
+            return b;
+        }
+        if b == self.tru {
+            return a;
+        }
+        let key = (0, a.min(b), a.max(b), Lit(0));
+        if let Some(&g) = self.gates.get(&key) {
+            return g;
+        }
+        let g = self.fresh();
+        self.sat.add_clause(&[!g, a]);
+        self.sat.add_clause(&[!g, b]);
+        self.sat.add_clause(&[g, !a, !b]);
+        self.gates.insert(key, g);
+        g
+    }
+
+    fn or(&mut self, a: Lit, b: Lit) -> Lit {
+        !self.and(!a, !b)
+    }
+
+    fn xor(&mut self, a: Lit, b: Lit) -> Lit {
+        if a == self.fls() {
+            return b;
+        }
+        if b == self.fls() {
+            return a;
+        }
+        if a == self.tru {
+            return !b;
+        }
+        if b == self.tru {
+            return !a;
+        }
+        if a == b {
+            return self.fls();
+        }
+        if a == !b {
+            return self.tru;
+        }
+        let key = (1, a.min(b), a.max(b), Lit(0));
+        if let Some(&g) = self.gates.get(&key) {
+            return g;
+        }
+        let g = self.fresh();
+        self.sat.add_clause(&[!g, a, b]);
+        self.sat.add_clause(&[!g, !a, !b]);
+        self.sat.add_clause(&[g, !a, b]);
+        self.sat.add_clause(&[g, a, !b]);
+        self.gates.insert(key, g);
+        g
+    }
+
+    /// `c ? a : b`
+    fn mux(&mut self, c: Lit, a: Lit, b: Lit) -> Lit {
+        if c == self.tru || a == b {
+            return a;
+        }
+        if c == self.fls() {
+            return b;
+        }
+        let key = (2, c, a, b);
+        if let Some(&g) = self.gates.get(&key) {
+            return g;
+        }
+        let g = self.fresh();
+        self.sat.add_clause(&[!c, !a, g]);
+        self.sat.add_clause(&[!c, a, !g]);
+        self.sat.add_clause(&[c, !b, g]);
+        self.sat.add_clause(&[c, b, !g]);
+        self.gates.insert(key, g);
+        g
+    }
+
+    fn constant(&self, n: i64) -> Vec<Lit> {
+        (0..WIDTH)
+            .map(|i| {
+                if (n >> i) & 1 == 1 {
+                    self.tru
+                } else {
+                    self.fls()
+                }
+            })
+            .collect()
+    }
+
+    fn add(&mut self, a: &[Lit], b: &[Lit], mut carry: Lit) -> Vec<Lit> {
+        let mut sum = Vec::with_capacity(WIDTH);
+        for i in 0..WIDTH {
+            let ab = self.xor(a[i], b[i]);
+            sum.push(self.xor(ab, carry));
+            let both = self.and(a[i], b[i]);
+            let propagated = self.and(carry, ab);
+            carry = self.or(both, propagated);
+        }
+        sum
+    }
+
+    fn sub(&mut self, a: &[Lit], b: &[Lit]) -> Vec<Lit> {
+        let not_b = b.iter().map(|&l| !l).collect::<Vec<_>>();
+        self.add(a, &not_b, self.tru)
+    }
+
+    /// Signed `a < b`.
+    fn less_than(&mut self, a: &[Lit], b: &[Lit]) -> Lit {
+        let mut lt = self.fls();
+        for i in 0..WIDTH {
+            let (a, b) = if i == WIDTH - 1 {
+                // The sign bit compares the other way around.
+                (!a[i], !b[i])
+            } else {
+                (a[i], b[i])
+            };
+            let strictly = self.and(!a, b);
+            let equal = !self.xor(a, b);
+            let keep = self.and(equal, lt);
+            lt = self.or(strictly, keep);
+        }
+        lt
+    }
+
+    fn equal(&mut self, a: &[Lit], b: &[Lit]) -> Lit {
+        let mut eq = self.tru;
+        for i in 0..WIDTH {
+            let bit = !self.xor(a[i], b[i]);
+            eq = self.and(eq, bit);
+        }
+        eq
+    }
+
+    fn nonzero(&mut self, a: &[Lit]) -> Lit {
+        let mut any = self.fls();
+        for &bit in a {
+            any = self.or(any, bit);
+        }
+        any
+    }
+
+    fn bool_bits(&self, b: Lit) -> Vec<Lit> {
+        let mut bits = vec![self.fls(); WIDTH];
+        bits[0] = b;
+        bits
+    }
+
+    /// The bits of an input, constrained to the range of 4-byte numbers.
+    fn input(&mut self, idx: usize) -> Vec<Lit> {
+        if let Some(bits) = self.inputs.get(&idx) {
+            return bits.clone();
+        }
+        let bits = (0..WIDTH).map(|_| self.fresh()).collect::<Vec<_>>();
+        let min = self.constant(-(i32::MAX as i64));
+        let max = self.constant(i32::MAX as i64);
+        let below = self.less_than(&bits, &min);
+        let above = self.less_than(&max, &bits);
+        self.sat.add_clause(&[!below]);
+        self.sat.add_clause(&[!above]);
+        self.inputs.insert(idx, bits.clone());
+        bits
+    }
+
+    fn blast(&mut self, term: &Term) -> Vec<Lit> {
+        let key = term as *const Term;
+        if let Some(bits) = self.terms.get(&key) {
+            return bits.clone();
+        }
+        let bits = match term {
+            Term::Input(idx) => self.input(*idx),
+            Term::Const(n) => self.constant(*n),
+            Term::Add(a, b) => {
+                let (a, b) = (self.blast(a), self.blast(b));
+                self.add(&a, &b, self.fls())
+            }
+            Term::Sub(a, b) => {
+                let (a, b) = (self.blast(a), self.blast(b));
+                self.sub(&a, &b)
+            }
+            Term::Neg(a) => {
+                let a = self.blast(a);
+                self.sub(&self.constant(0), &a)
+            }
+            Term::Lt(a, b) => {
+                let (a, b) = (self.blast(a), self.blast(b));
+                let lt = self.less_than(&a, &b);
+                self.bool_bits(lt)
+            }
+            Term::Eq(a, b) => {
+                let (a, b) = (self.blast(a), self.blast(b));
+                let eq = self.equal(&a, &b);
+                self.bool_bits(eq)
+            }
+            Term::Ite(c, a, b) => {
+                let c = self.blast(c);
+                let c = self.nonzero(&c);
+                let (a, b) = (self.blast(a), self.blast(b));
+                (0..WIDTH).map(|i| self.mux(c, a[i], b[i])).collect()
+            }
+        };
+        self.terms.insert(key, bits.clone());
+        bits
+    }
+
+    /// Asserts that the term is non-zero if `holds`, or zero otherwise.
+    pub(crate) fn assert(&mut self, term: &Term, holds: bool) {
+        let bits = self.blast(term);
+        let nonzero = self.nonzero(&bits);
+        self.sat
+            .add_clause(&[if holds { nonzero } else { !nonzero }]);
+    }
+
+    /// Solves the assertions, returning the values of all inputs that
+    /// occur in them.
+    pub(crate) fn solve(mut self, max_conflicts: u64) -> SatResult<Vec<(usize, i64)>> {
+        match self.sat.solve(max_conflicts) {
+            SatResult::Sat(()) => {}
+            SatResult::Unsat => return SatResult::Unsat,
+            SatResult::Unknown => return SatResult::Unknown,
+        }
+        let mut model = self
+            .inputs
+            .iter()
+            .map(|(&idx, bits)| {
+                let value = bits.iter().enumerate().fold(0u64, |acc, (i, &l)| {
+                    if self.sat.value(l) == Some(true) {
+                        acc | 1 << i
+                    } else {
+                        acc
+                    }
+                });
+                (idx, value as i64)
+            })
+            .collect::<Vec<_>>();
+        model.sort_unstable();
+        SatResult::Sat(model)
+    }
+}
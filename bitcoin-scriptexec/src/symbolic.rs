@@ -0,0 +1,843 @@
+//! Concolic execution of scripts.
+//!
+//! The input stack items that are numbers of at most 4 bytes are treated as
+//! symbols. The script is executed concretely by [Exec], while a shadow of
+//! the stacks tracks the items that depend on the inputs as [Term]s. Every
+//! decision taken on such an item, like the condition of an OP_IF or the
+//! argument of an OP_VERIFY, is recorded as a [Constraint].
+//!
+//! [explore] then negates the constraints of every path one by one and asks
+//! the embedded solver for inputs that satisfy the constraints before it
+//! together with the negated one, to execute the script along new paths,
+//! including the ones on which the script fails.
+//!
+//! Only the numeric opcodes and OP_EQUAL are executed symbolically. Items
+//! that are used by other opcodes, like hashes or signature checks, are
+//! fixed to their value in the current execution, so the solver doesn't
+//! explore paths depending on them.
+
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+
+use bitcoin::opcodes::all::*;
+use bitcoin::script::{self, ScriptBuf};
+use bitcoin::Opcode;
+
+use crate::solver::{Blaster, SatResult};
+use crate::*;
+
+/// Terms deeper than this are fixed to their value, to keep the solver
+/// queries small.
+pub const MAX_TERM_DEPTH: usize = 256;
+
+/// A symbolic script number.
+///
+/// Like their opcodes, comparisons are 1 if they hold and 0 otherwise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Term {
+    /// The input stack item with the given index, counted from the bottom.
+    Input(usize),
+    Const(i64),
+    Add(Arc<Term>, Arc<Term>),
+    Sub(Arc<Term>, Arc<Term>),
+    Neg(Arc<Term>),
+    /// Whether the first term is smaller than the second.
+    Lt(Arc<Term>, Arc<Term>),
+    /// Whether both terms are equal.
+    Eq(Arc<Term>, Arc<Term>),
+    /// The second term if the first one is non-zero, the third otherwise.
+    Ite(Arc<Term>, Arc<Term>, Arc<Term>),
+}
+
+impl Term {
+    /// Evaluates the term with the given values of the inputs.
+    pub fn eval(&self, inputs: &[i64]) -> i64 {
+        self.eval_memo(inputs, &mut HashMap::new())
+    }
+
+    fn eval_memo(&self, inputs: &[i64], memo: &mut HashMap<*const Term, i64>) -> i64 {
+        if let Some(&v) = memo.get(&(self as *const Term)) {
+            return v;
+        }
+        let v = match self {
+            Term::Input(idx) => inputs[*idx],
+            Term::Const(n) => *n,
+            Term::Add(a, b) => a.eval_memo(inputs, memo) + b.eval_memo(inputs, memo),
+            Term::Sub(a, b) => a.eval_memo(inputs, memo) - b.eval_memo(inputs, memo),
+            Term::Neg(a) => -a.eval_memo(inputs, memo),
+            Term::Lt(a, b) => (a.eval_memo(inputs, memo) < b.eval_memo(inputs, memo)) as i64,
+            Term::Eq(a, b) => (a.eval_memo(inputs, memo) == b.eval_memo(inputs, memo)) as i64,
+            Term::Ite(c, a, b) => {
+                if c.eval_memo(inputs, memo) != 0 {
+                    a.eval_memo(inputs, memo)
+                } else {
+                    b.eval_memo(inputs, memo)
+                }
+            }
+        };
+        memo.insert(self, v);
+        v
+    }
+
+    /// Returns the indices of the inputs the term depends on.
+    pub fn inputs(&self) -> BTreeSet<usize> {
+        let mut inputs = BTreeSet::new();
+        let mut visited = HashSet::new();
+        let mut todo = vec![self];
+        while let Some(term) = todo.pop() {
+            if !visited.insert(term as *const Term) {
+                continue;
+            }
+            match term {
+                Term::Input(idx) => {
+                    inputs.insert(*idx);
+                }
+                Term::Const(_) => {}
+                Term::Neg(a) => todo.push(a),
+                Term::Add(a, b) | Term::Sub(a, b) | Term::Lt(a, b) | Term::Eq(a, b) => {
+                    todo.push(a);
+                    todo.push(b);
+                }
+                Term::Ite(c, a, b) => {
+                    todo.push(c);
+                    todo.push(a);
+                    todo.push(b);
+                }
+            }
+        }
+        inputs
+    }
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Term::Input(idx) => write!(f, "x{}", idx),
+            Term::Const(n) => write!(f, "{}", n),
+            Term::Add(a, b) => write!(f, "({} + {})", a, b),
+            Term::Sub(a, b) => write!(f, "({} - {})", a, b),
+            Term::Neg(a) => write!(f, "-{}", a),
+            Term::Lt(a, b) => write!(f, "({} < {})", a, b),
+            Term::Eq(a, b) => write!(f, "({} == {})", a, b),
+            Term::Ite(c, a, b) => write!(f, "({} ? {} : {})", c, a, b),
+        }
+    }
+}
+
+/// What a [Constraint] was recorded for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConstraintKind {
+    /// The condition of OP_IF, OP_NOTIF or OP_IFDUP.
+    Branch,
+    /// The argument of OP_VERIFY or of one of the verifying opcodes.
+    Verify,
+    /// A numeric operand fits in 4 bytes.
+    Range,
+    /// The condition of OP_IF or OP_NOTIF is minimally encoded.
+    MinimalIf,
+    /// The item was used by an opcode that isn't executed symbolically and
+    /// is fixed to its value. These are never negated.
+    Concrete,
+}
+
+/// A decision taken on a symbolic value during an execution.
+#[derive(Debug, Clone)]
+pub struct Constraint {
+    /// The script position of the instruction that took the decision.
+    pub position: usize,
+    pub opcode: Opcode,
+    pub kind: ConstraintKind,
+    /// The decision holds if this term is non-zero.
+    pub term: Arc<Term>,
+    /// Whether the decision held in the execution.
+    pub holds: bool,
+}
+
+/// A symbolic stack item.
+#[derive(Debug, Clone)]
+struct Sym {
+    term: Arc<Term>,
+    depth: usize,
+}
+
+impl Sym {
+    fn input(idx: usize) -> Sym {
+        Sym {
+            term: Arc::new(Term::Input(idx)),
+            depth: 0,
+        }
+    }
+
+    fn constant(n: i64) -> Sym {
+        Sym {
+            term: Arc::new(Term::Const(n)),
+            depth: 0,
+        }
+    }
+
+    fn is_const(&self) -> bool {
+        matches!(*self.term, Term::Const(_))
+    }
+
+    /// Creates a node, folding it if all its children are constants.
+    fn node(term: Term, children: &[&Sym]) -> Sym {
+        if children.iter().all(|c| c.is_const()) {
+            return Sym::constant(term.eval(&[]));
+        }
+        Sym {
+            term: Arc::new(term),
+            depth: 1 + children.iter().map(|c| c.depth).max().unwrap(),
+        }
+    }
+
+    fn add(&self, other: &Sym) -> Sym {
+        Sym::node(
+            Term::Add(self.term.clone(), other.term.clone()),
+            &[self, other],
+        )
+    }
+
+    fn sub(&self, other: &Sym) -> Sym {
+        Sym::node(
+            Term::Sub(self.term.clone(), other.term.clone()),
+            &[self, other],
+        )
+    }
+
+    fn neg(&self) -> Sym {
+        Sym::node(Term::Neg(self.term.clone()), &[self])
+    }
+
+    fn lt(&self, other: &Sym) -> Sym {
+        Sym::node(
+            Term::Lt(self.term.clone(), other.term.clone()),
+            &[self, other],
+        )
+    }
+
+    fn eq(&self, other: &Sym) -> Sym {
+        Sym::node(
+            Term::Eq(self.term.clone(), other.term.clone()),
+            &[self, other],
+        )
+    }
+
+    fn ite(&self, then: &Sym, otherwise: &Sym) -> Sym {
+        Sym::node(
+            Term::Ite(self.term.clone(), then.term.clone(), otherwise.term.clone()),
+            &[self, then, otherwise],
+        )
+    }
+
+    fn not(&self) -> Sym {
+        self.eq(&Sym::constant(0))
+    }
+
+    fn bool(&self) -> Sym {
+        self.ite(&Sym::constant(1), &Sym::constant(0))
+    }
+
+    fn abs(&self) -> Sym {
+        self.lt(&Sym::constant(0)).ite(&self.neg(), self)
+    }
+
+    /// The size of the minimal encoding of the number.
+    fn size(&self) -> Sym {
+        let abs = self.abs();
+        let mut size = Sym::constant(8);
+        for len in (1..8).rev() {
+            let fits = abs.lt(&Sym::constant(1 << (8 * len - 1)));
+            size = fits.ite(&Sym::constant(len), &size);
+        }
+        self.not().ite(&Sym::constant(0), &size)
+    }
+}
+
+/// Observer keeping the symbolic shadow of the stacks of an execution.
+struct Shadow {
+    /// The values of the inputs in this execution.
+    inputs: Vec<i64>,
+    stack: Vec<Option<Sym>>,
+    altstack: Vec<Option<Sym>>,
+    constraints: Vec<Constraint>,
+    /// The stacks before an instruction whose effect is taken from the
+    /// stack diffs.
+    before: Option<(Stack, Stack)>,
+}
+
+impl Shadow {
+    fn new(input: &[Vec<u8>]) -> Shadow {
+        let mut inputs = Vec::with_capacity(input.len());
+        let mut stack = Vec::with_capacity(input.len());
+        for (idx, item) in input.iter().enumerate() {
+            match read_scriptint(item, 4, true) {
+                Ok(n) => {
+                    inputs.push(n);
+                    stack.push(Some(Sym::input(idx)));
+                }
+                Err(_) => {
+                    inputs.push(0);
+                    stack.push(None);
+                }
+            }
+        }
+        Shadow {
+            inputs,
+            stack,
+            altstack: Vec::new(),
+            constraints: Vec::new(),
+            before: None,
+        }
+    }
+
+    fn record(&mut self, step: &StepInfo, kind: ConstraintKind, sym: &Sym, holds: bool) {
+        if sym.is_const() {
+            return;
+        }
+        debug_assert_eq!(sym.term.eval(&self.inputs) != 0, holds, "{}", sym.term);
+        self.constraints.push(Constraint {
+            position: step.position,
+            opcode: step.opcode,
+            kind,
+            term: sym.term.clone(),
+            holds,
+        });
+    }
+
+    /// Fixes the item to its value in this execution.
+    fn concretize(&mut self, step: &StepInfo, sym: &Sym) {
+        let value = Sym::constant(sym.term.eval(&self.inputs));
+        self.record(step, ConstraintKind::Concrete, &sym.eq(&value), true);
+    }
+
+    /// Pushes the result of a numeric opcode.
+    fn push_result(&mut self, step: &StepInfo, result: Sym) {
+        if result.is_const() {
+            self.stack.push(None);
+        } else if result.depth > MAX_TERM_DEPTH {
+            self.concretize(step, &result);
+            self.stack.push(None);
+        } else {
+            self.stack.push(Some(result));
+        }
+    }
+
+    /// Returns the numeric operand at the given depth, 1 being the top.
+    ///
+    /// Returns None if the execution fails on the operand.
+    fn operand(&mut self, exec: &Exec, step: &StepInfo, depth: usize) -> Option<Sym> {
+        let idx = exec.stack.len().checked_sub(depth)?;
+        let value = exec
+            .stack
+            .topnum(-(depth as isize), exec.opt.require_minimal);
+        match self.stack[idx].clone() {
+            None => value.ok().map(Sym::constant),
+            Some(sym) => {
+                // Inputs are always in range. Other symbolic items are results
+                // of numeric opcodes, which may exceed 4 bytes either way.
+                if !matches!(*sym.term, Term::Input(_)) {
+                    let in_range = sym.abs().lt(&Sym::constant(i32::MAX as i64 + 1));
+                    self.record(step, ConstraintKind::Range, &in_range, value.is_ok());
+                }
+                value.ok().map(|_| sym)
+            }
+        }
+    }
+
+    fn top(&self) -> Option<Sym> {
+        self.stack.last().cloned().flatten()
+    }
+
+    fn step(&mut self, exec: &Exec, step: &StepInfo) {
+        let op = step.opcode;
+        let len = exec.stack.len();
+        debug_assert_eq!(self.stack.len(), len);
+        debug_assert_eq!(self.altstack.len(), exec.altstack.len());
+
+        if op.to_u8() <= OP_PUSHNUM_16.to_u8() && op != OP_RESERVED {
+            self.stack.push(None);
+            return;
+        }
+
+        match op {
+            OP_IF | OP_NOTIF => {
+                if let Some(cond) = self.top() {
+                    let top = exec.stack.topstr(-1).unwrap();
+                    if exec.ctx == ExecCtx::Tapscript
+                        || (exec.ctx == ExecCtx::SegwitV0 && exec.opt.verify_minimal_if)
+                    {
+                        let minimal = top.is_empty() || top == [1];
+                        let zero_or_one = cond
+                            .not()
+                            .ite(&Sym::constant(1), &cond.eq(&Sym::constant(1)));
+                        self.record(step, ConstraintKind::MinimalIf, &zero_or_one, minimal);
+                        if !minimal {
+                            return;
+                        }
+                    }
+                    self.record(
+                        step,
+                        ConstraintKind::Branch,
+                        &cond,
+                        script::read_scriptbool(&top),
+                    );
+                }
+                self.stack.pop();
+            }
+            OP_ELSE | OP_ENDIF | OP_NOP => {}
+
+            OP_VERIFY => {
+                if let Some(cond) = self.top() {
+                    let holds = script::read_scriptbool(&exec.stack.topstr(-1).unwrap());
+                    self.record(step, ConstraintKind::Verify, &cond, holds);
+                }
+                self.stack.pop();
+            }
+
+            OP_TOALTSTACK => {
+                if let Some(item) = self.stack.pop() {
+                    self.altstack.push(item);
+                }
+            }
+            OP_FROMALTSTACK => {
+                if let Some(item) = self.altstack.pop() {
+                    self.stack.push(item);
+                }
+            }
+            OP_2DROP if len >= 2 => self.stack.truncate(len - 2),
+            OP_2DUP if len >= 2 => self.stack.extend_from_within(len - 2..),
+            OP_3DUP if len >= 3 => self.stack.extend_from_within(len - 3..),
+            OP_2OVER if len >= 4 => self.stack.extend_from_within(len - 4..len - 2),
+            OP_2ROT if len >= 6 => self.stack[len - 6..].rotate_left(2),
+            OP_2SWAP if len >= 4 => self.stack[len - 4..].rotate_left(2),
+            OP_IFDUP => {
+                if let Some(cond) = self.top() {
+                    let holds = script::read_scriptbool(&exec.stack.topstr(-1).unwrap());
+                    self.record(step, ConstraintKind::Branch, &cond, holds);
+                    if holds {
+                        self.stack.push(Some(cond));
+                    }
+                } else if len >= 1 && script::read_scriptbool(&exec.stack.topstr(-1).unwrap()) {
+                    self.stack.push(None);
+                }
+            }
+            OP_DEPTH => self.stack.push(None),
+            OP_DROP => {
+                self.stack.pop();
+            }
+            OP_DUP if len >= 1 => self.stack.extend_from_within(len - 1..),
+            OP_NIP if len >= 2 => {
+                self.stack.remove(len - 2);
+            }
+            OP_OVER if len >= 2 => self.stack.extend_from_within(len - 2..len - 1),
+            OP_PICK | OP_ROLL => {
+                let Some(index) = self.operand(exec, step, 1) else {
+                    return;
+                };
+                if !index.is_const() {
+                    self.concretize(step, &index);
+                }
+                let n = index.term.eval(&self.inputs);
+                if n < 0 || n as usize + 1 >= len {
+                    return;
+                }
+                self.stack.pop();
+                let idx = len - 2 - n as usize;
+                let item = if op == OP_ROLL {
+                    self.stack.remove(idx)
+                } else {
+                    self.stack[idx].clone()
+                };
+                self.stack.push(item);
+            }
+            OP_ROT if len >= 3 => self.stack[len - 3..].rotate_left(1),
+            OP_SWAP if len >= 2 => self.stack.swap(len - 2, len - 1),
+            OP_TUCK if len >= 2 => {
+                let top = self.stack[len - 1].clone();
+                self.stack.insert(len - 2, top);
+            }
+
+            OP_SIZE => match self.top() {
+                Some(item) => {
+                    let size = item.size();
+                    self.push_result(step, size);
+                }
+                None => self.stack.push(None),
+            },
+
+            OP_EQUAL | OP_EQUALVERIFY if len >= 2 => {
+                let equal = match (self.stack[len - 2].clone(), self.stack[len - 1].clone()) {
+                    (None, None) => None,
+                    (Some(sym), None) => Some(equal_item(&sym, &exec.stack.get(len - 1))),
+                    (None, Some(sym)) => Some(equal_item(&sym, &exec.stack.get(len - 2))),
+                    (Some(a), Some(b)) => Some(a.eq(&b)),
+                };
+                if op == OP_EQUALVERIFY {
+                    let holds = exec.stack.get(len - 2) == exec.stack.get(len - 1);
+                    if let Some(ref equal) = equal {
+                        self.record(step, ConstraintKind::Verify, equal, holds);
+                    }
+                    if holds {
+                        self.stack.truncate(len - 2);
+                    }
+                } else {
+                    self.stack.truncate(len - 2);
+                    match equal {
+                        Some(equal) => self.push_result(step, equal),
+                        None => self.stack.push(None),
+                    }
+                }
+            }
+
+            OP_1ADD | OP_1SUB | OP_NEGATE | OP_ABS | OP_NOT | OP_0NOTEQUAL => {
+                let Some(x) = self.operand(exec, step, 1) else {
+                    return;
+                };
+                let one = Sym::constant(1);
+                let res = match op {
+                    OP_1ADD => x.add(&one),
+                    OP_1SUB => x.sub(&one),
+                    OP_NEGATE => x.neg(),
+                    OP_ABS => x.abs(),
+                    OP_NOT => x.not(),
+                    OP_0NOTEQUAL => x.bool(),
+                    _ => unreachable!(),
+                };
+                self.stack.pop();
+                self.push_result(step, res);
+            }
+
+            OP_ADD
+            | OP_SUB
+            | OP_BOOLAND
+            | OP_BOOLOR
+            | OP_NUMEQUAL
+            | OP_NUMEQUALVERIFY
+            | OP_NUMNOTEQUAL
+            | OP_LESSTHAN
+            | OP_GREATERTHAN
+            | OP_LESSTHANOREQUAL
+            | OP_GREATERTHANOREQUAL
+            | OP_MIN
+            | OP_MAX => {
+                let Some(x1) = self.operand(exec, step, 2) else {
+                    return;
+                };
+                let Some(x2) = self.operand(exec, step, 1) else {
+                    return;
+                };
+                let res = match op {
+                    OP_ADD => x1.add(&x2),
+                    OP_SUB => x1.sub(&x2),
+                    OP_BOOLAND => x1.ite(&x2.bool(), &Sym::constant(0)),
+                    OP_BOOLOR => x1.ite(&Sym::constant(1), &x2.bool()),
+                    OP_NUMEQUAL | OP_NUMEQUALVERIFY => x1.eq(&x2),
+                    OP_NUMNOTEQUAL => x1.eq(&x2).not(),
+                    OP_LESSTHAN => x1.lt(&x2),
+                    OP_GREATERTHAN => x2.lt(&x1),
+                    OP_LESSTHANOREQUAL => x2.lt(&x1).not(),
+                    OP_GREATERTHANOREQUAL => x1.lt(&x2).not(),
+                    OP_MIN => x1.lt(&x2).ite(&x1, &x2),
+                    OP_MAX => x1.lt(&x2).ite(&x2, &x1),
+                    _ => unreachable!(),
+                };
+                self.stack.truncate(len - 2);
+                if op == OP_NUMEQUALVERIFY {
+                    let holds = res.term.eval(&self.inputs) != 0;
+                    self.record(step, ConstraintKind::Verify, &res, holds);
+                } else {
+                    self.push_result(step, res);
+                }
+            }
+
+            OP_WITHIN => {
+                let Some(x) = self.operand(exec, step, 3) else {
+                    return;
+                };
+                let Some(min) = self.operand(exec, step, 2) else {
+                    return;
+                };
+                let Some(max) = self.operand(exec, step, 1) else {
+                    return;
+                };
+                let res = x.lt(&min).ite(&Sym::constant(0), &x.lt(&max));
+                self.stack.truncate(len - 3);
+                self.push_result(step, res);
+            }
+
+            // The locktime opcodes only inspect the top item.
+            OP_CLTV | OP_CSV | OP_NOP4 => {
+                if let Some(item) = self.top() {
+                    self.concretize(step, &item);
+                }
+            }
+
+            // Opcodes that fail on a too small stack in the arms above, and
+            // all others, are applied through the stack diffs.
+            _ => self.before = Some((exec.stack.clone(), exec.altstack.clone())),
+        }
+    }
+
+    /// Applies a stack diff to a shadow stack, fixing the popped items.
+    fn apply(&mut self, step: &StepInfo, diff: &StackDiff, altstack: bool) {
+        let shadow = if altstack {
+            &mut self.altstack
+        } else {
+            &mut self.stack
+        };
+        let popped = shadow.split_off(diff.base);
+        shadow.extend(diff.pushed.iter().map(|_| None));
+        for sym in popped.into_iter().flatten() {
+            self.concretize(step, &sym);
+        }
+    }
+}
+
+/// Compares a symbolic item to a concrete one byte for byte.
+fn equal_item(sym: &Sym, item: &[u8]) -> Sym {
+    // Symbolic items are always minimally encoded.
+    match read_scriptint(item, 8, true) {
+        Ok(n) => sym.eq(&Sym::constant(n)),
+        Err(_) => Sym::constant(0),
+    }
+}
+
+impl ExecObserver for Shadow {
+    fn wants_stack_diffs(&self) -> bool {
+        false
+    }
+
+    fn pre_step(&mut self, exec: &Exec, step: &StepInfo) {
+        // Conditionals don't touch the stack in non-executed branches.
+        if exec.cond_stack.all_true() {
+            self.step(exec, step);
+        }
+    }
+
+    fn post_step(&mut self, exec: &Exec, step: &StepInfo, _diff: Option<&StepDiff>) {
+        if let Some((stack, altstack)) = self.before.take() {
+            self.apply(step, &StackDiff::compute(&stack, &exec.stack), false);
+            self.apply(step, &StackDiff::compute(&altstack, &exec.altstack), true);
+        }
+    }
+}
+
+/// Limits of an exploration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// The maximum number of executions of the script.
+    pub max_runs: usize,
+    /// The maximum number of solver queries.
+    pub max_queries: usize,
+    /// The maximum number of conflicts of a single solver query, after
+    /// which it is given up on.
+    pub max_conflicts: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_runs: 64,
+            max_queries: 1024,
+            max_conflicts: 10_000,
+        }
+    }
+}
+
+/// An execution of the script explored by [explore].
+#[derive(Debug, Clone)]
+pub struct Path {
+    /// The input stack of the execution, bottom to top.
+    pub input: Vec<Vec<u8>>,
+    /// The decisions taken on symbolic values, in execution order.
+    pub constraints: Vec<Constraint>,
+    pub result: ExecutionResult,
+    pub final_altstack: Stack,
+    /// Whether the execution didn't take the decisions the solver aimed
+    /// for. This happens when the solver changed items that were fixed to
+    /// their values in the execution the input was derived from.
+    pub diverged: bool,
+}
+
+impl Path {
+    /// Whether the execution failed with an error.
+    pub fn failed(&self) -> bool {
+        self.result.error.is_some()
+    }
+}
+
+/// The result of [explore].
+#[derive(Debug, Clone)]
+pub struct Exploration {
+    /// The distinct paths that were executed, starting with the one of the
+    /// seed input.
+    pub paths: Vec<Path>,
+    /// The number of solver queries made.
+    pub queries: usize,
+    /// Whether all paths reachable by negating constraints were explored
+    /// within the limits.
+    pub complete: bool,
+}
+
+impl Exploration {
+    /// The paths on which the script failed with an error.
+    pub fn failures(&self) -> impl Iterator<Item = &Path> {
+        self.paths.iter().filter(|p| p.failed())
+    }
+}
+
+/// Input to execute, with the decisions the solver aimed for.
+struct Job {
+    input: Vec<Vec<u8>>,
+    /// Constraints before this index were already negated by the parents.
+    bound: usize,
+    expected: Vec<(usize, ConstraintKind, bool)>,
+}
+
+fn run(
+    ctx: ExecCtx,
+    opt: &Options,
+    tx: &TxTemplate,
+    script: &ScriptBuf,
+    input: &[Vec<u8>],
+) -> Result<(Vec<Constraint>, ExecutionResult, Stack), Error> {
+    let mut exec = Exec::new(ctx, opt.clone(), tx.clone(), script.clone(), input.to_vec())?;
+    let mut shadow = Shadow::new(input);
+    while exec.exec_next_observed(&mut shadow).is_ok() {}
+    let result = exec.result().unwrap().clone();
+    Ok((shadow.constraints, result, exec.altstack.clone()))
+}
+
+/// Looks for inputs that satisfy the constraints of the prefix and negate
+/// the flipped one.
+///
+/// Only the constraints that share inputs with the flipped one, directly or
+/// transitively, are passed to the solver. The other inputs keep their
+/// values, so the other constraints still hold.
+fn solve(
+    prefix: &[Constraint],
+    prefix_inputs: &[BTreeSet<usize>],
+    flipped: &Constraint,
+    max_conflicts: u64,
+) -> SatResult<Vec<(usize, i64)>> {
+    let mut inputs = flipped.term.inputs();
+    let mut included = vec![false; prefix.len()];
+    loop {
+        let mut changed = false;
+        for (i, set) in prefix_inputs.iter().enumerate() {
+            if !included[i] && !set.is_disjoint(&inputs) {
+                included[i] = true;
+                inputs.extend(set);
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    let mut blaster = Blaster::new();
+    for (constraint, _) in prefix.iter().zip(included).filter(|(_, inc)| *inc) {
+        blaster.assert(&constraint.term, constraint.holds);
+    }
+    blaster.assert(&flipped.term, !flipped.holds);
+    blaster.solve(max_conflicts)
+}
+
+/// Explores the paths of the script, starting from the given input stack.
+///
+/// The paths are explored breadth-first: the constraints of every path are
+/// negated one by one, and every satisfiable negation yields a new input.
+pub fn explore(
+    ctx: ExecCtx,
+    opt: Options,
+    tx: TxTemplate,
+    script: ScriptBuf,
+    seed: Vec<Vec<u8>>,
+    limits: &Limits,
+) -> Result<Exploration, Error> {
+    let mut paths = Vec::new();
+    let mut seen = HashSet::new();
+    let mut queries = 0;
+    let mut complete = true;
+    let mut runs = 0;
+
+    let mut queue = VecDeque::new();
+    queue.push_back(Job {
+        input: seed,
+        bound: 0,
+        expected: Vec::new(),
+    });
+    while let Some(job) = queue.pop_front() {
+        if runs == limits.max_runs {
+            complete = false;
+            break;
+        }
+        runs += 1;
+
+        let (constraints, result, final_altstack) = run(ctx, &opt, &tx, &script, &job.input)?;
+        let signature = constraints
+            .iter()
+            .map(|c| (c.position, c.kind, c.holds))
+            .collect::<Vec<_>>();
+        let diverged = !signature.starts_with(&job.expected);
+        if !seen.insert(signature.clone()) {
+            continue;
+        }
+
+        let prefix_inputs = constraints
+            .iter()
+            .map(|c| c.term.inputs())
+            .collect::<Vec<_>>();
+        for k in job.bound..constraints.len() {
+            if constraints[k].kind == ConstraintKind::Concrete {
+                continue;
+            }
+            if queries == limits.max_queries {
+                complete = false;
+                break;
+            }
+            queries += 1;
+
+            let model = match solve(
+                &constraints[..k],
+                &prefix_inputs[..k],
+                &constraints[k],
+                limits.max_conflicts,
+            ) {
+                SatResult::Sat(model) => model,
+                SatResult::Unsat => continue,
+                SatResult::Unknown => {
+                    complete = false;
+                    continue;
+                }
+            };
+            let mut input = job.input.clone();
+            for (idx, value) in model {
+                input[idx] = script::scriptint_vec(value);
+            }
+            let mut expected = signature[..=k].to_vec();
+            expected[k].2 = !expected[k].2;
+            queue.push_back(Job {
+                input,
+                bound: k + 1,
+                expected,
+            });
+        }
+
+        paths.push(Path {
+            input: job.input,
+            constraints,
+            result,
+            final_altstack,
+            diverged,
+        });
+    }
+
+    Ok(Exploration {
+        paths,
+        queries,
+        complete,
+    })
+}
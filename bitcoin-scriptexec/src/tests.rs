@@ -0,0 +1,1265 @@
+use bitcoin::hashes::Hash;
+use bitcoin::key::{Keypair, Secp256k1};
+use bitcoin::opcodes::all::*;
+use bitcoin::script::Builder;
+use bitcoin::secp256k1::{Message, SecretKey};
+use bitcoin::sighash::{EcdsaSighashType, SighashCache};
+use bitcoin::taproot::{LeafVersion, TaprootBuilder};
+use bitcoin::{
+    absolute, transaction, Amount, OutPoint, PublicKey, ScriptBuf, Sequence, TapLeafHash,
+    Transaction, TxIn, TxOut, Witness,
+};
+
+use crate::*;
+
+/// Executes the script until the end and returns the result and the stats.
+fn execute(ctx: ExecCtx, script: ScriptBuf) -> (ExecutionResult, ExecStats) {
+    let mut exec = Exec::new(
+        ctx,
+        Options::default(),
+        TxTemplate::dummy(ExecCtx::Tapscript),
+        script,
+        vec![],
+    )
+    .expect("error creating exec");
+    loop {
+        if exec.exec_next().is_err() {
+            break;
+        }
+    }
+    (exec.result().unwrap().clone(), exec.stats().clone())
+}
+
+fn secret_keys() -> Vec<SecretKey> {
+    (1..=3)
+        .map(|i| SecretKey::from_slice(&[i; 32]).unwrap())
+        .collect()
+}
+
+fn public_keys() -> Vec<PublicKey> {
+    let secp = Secp256k1::new();
+    secret_keys()
+        .iter()
+        .map(|sk| PublicKey::new(sk.public_key(&secp)))
+        .collect()
+}
+
+/// Appends `<nb_sigs> <pk1> <pk2> <pk3> 3 OP_CHECKMULTISIG` to the builder.
+fn push_multisig(builder: Builder, nb_sigs: i64) -> Builder {
+    let mut builder = builder.push_int(nb_sigs);
+    for pk in public_keys() {
+        builder = builder.push_key(&pk);
+    }
+    builder.push_int(3).push_opcode(OP_CHECKMULTISIG)
+}
+
+#[test]
+fn test_checkmultisig_zero_of_zero() {
+    let script = Builder::new()
+        .push_int(0)
+        .push_int(0)
+        .push_int(0)
+        .push_opcode(OP_CHECKMULTISIG)
+        .into_script();
+    let (res, stats) = execute(ExecCtx::SegwitV0, script);
+    assert!(res.success);
+    assert_eq!(stats.opcode_count, 1);
+}
+
+#[test]
+fn test_checkmultisig_counts_pubkeys_as_opcodes() {
+    let script = push_multisig(Builder::new().push_int(0), 0).into_script();
+    let (res, stats) = execute(ExecCtx::SegwitV0, script);
+    assert!(res.success);
+    // One for OP_CHECKMULTISIG and one per public key.
+    assert_eq!(stats.opcode_count, 4);
+}
+
+#[test]
+fn test_checkmultisig_null_dummy() {
+    let script = Builder::new()
+        .push_int(1)
+        .push_int(0)
+        .push_int(0)
+        .push_opcode(OP_CHECKMULTISIG)
+        .into_script();
+    let (res, _) = execute(ExecCtx::SegwitV0, script);
+    assert_eq!(res.error, Some(ExecError::SigNullDummy));
+}
+
+#[test]
+fn test_checkmultisig_invalid_counts() {
+    let script = Builder::new()
+        .push_int(0)
+        .push_int(0)
+        .push_int(21)
+        .push_opcode(OP_CHECKMULTISIG)
+        .into_script();
+    let (res, _) = execute(ExecCtx::SegwitV0, script);
+    assert_eq!(res.error, Some(ExecError::PubkeyCount));
+
+    let script = push_multisig(Builder::new().push_int(0), 4).into_script();
+    let (res, _) = execute(ExecCtx::SegwitV0, script);
+    assert_eq!(res.error, Some(ExecError::SigCount));
+
+    // Missing the dummy element.
+    let script = push_multisig(Builder::new(), 0).into_script();
+    let (res, _) = execute(ExecCtx::SegwitV0, script);
+    assert_eq!(res.error, Some(ExecError::InvalidStackOperation));
+}
+
+#[test]
+fn test_checkmultisig_disabled_in_tapscript() {
+    let script = Builder::new()
+        .push_int(0)
+        .push_int(0)
+        .push_int(0)
+        .push_opcode(OP_CHECKMULTISIG)
+        .into_script();
+    let (res, _) = execute(ExecCtx::Tapscript, script);
+    assert_eq!(res.error, Some(ExecError::TapscriptCheckMultiSig));
+}
+
+#[test]
+fn test_checkmultisig_p2wsh_spend() {
+    let secp = Secp256k1::new();
+    let witness_script = push_multisig(Builder::new(), 2).into_script();
+    let prevouts = vec![TxOut {
+        value: Amount::from_sat(100_000),
+        script_pubkey: ScriptBuf::new_p2wsh(&witness_script.wscript_hash()),
+    }];
+    let mut tx = Transaction {
+        version: transaction::Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: vec![],
+    };
+
+    let sighash = SighashCache::new(&tx)
+        .p2wsh_signature_hash(0, &witness_script, prevouts[0].value, EcdsaSighashType::All)
+        .unwrap();
+    let msg = Message::from(sighash);
+    let sigs = secret_keys()
+        .iter()
+        .map(|sk| {
+            let mut sig = secp.sign_ecdsa(&msg, sk).serialize_der().to_vec();
+            sig.push(EcdsaSighashType::All as u8);
+            sig
+        })
+        .collect::<Vec<_>>();
+
+    let mut spend = |opt: Options, dummy: &[u8], sigs: &[&Vec<u8>]| {
+        let mut witness = Witness::new();
+        witness.push(dummy);
+        for sig in sigs {
+            witness.push(sig);
+        }
+        witness.push(witness_script.as_bytes());
+        tx.input[0].witness = witness;
+        verify_input_with_options(opt, &tx, 0, &prevouts)
+    };
+
+    let std = Options::standardness;
+    assert_eq!(spend(std(), &[], &[&sigs[0], &sigs[1]]), Ok(()));
+    assert_eq!(spend(std(), &[], &[&sigs[0], &sigs[2]]), Ok(()));
+    // Signatures must be in the same order as the public keys.
+    assert_eq!(
+        spend(Options::consensus(), &[], &[&sigs[2], &sigs[0]]),
+        Err(Error::Exec(ExecError::EvalFalse)),
+    );
+    // Failing signatures must be empty under NULLFAIL.
+    assert_eq!(
+        spend(std(), &[], &[&sigs[2], &sigs[0]]),
+        Err(Error::Exec(ExecError::SigNullFail)),
+    );
+    assert_eq!(
+        spend(std(), &[1], &[&sigs[0], &sigs[1]]),
+        Err(Error::Exec(ExecError::SigNullDummy)),
+    );
+}
+
+#[test]
+fn test_count_sigops() {
+    let script = push_multisig(Builder::new(), 2)
+        .push_opcode(OP_CHECKSIGVERIFY)
+        .into_script();
+    assert_eq!(count_sigops(&script, true), 4);
+    assert_eq!(count_sigops(&script, false), 21);
+}
+
+#[derive(Default)]
+struct RecordingObserver {
+    steps: Vec<(StepInfo, StepDiff)>,
+    branches: Vec<(usize, bool)>,
+    errors: Vec<ExecError>,
+}
+
+impl ExecObserver for RecordingObserver {
+    fn post_step(&mut self, _exec: &Exec, step: &StepInfo, diff: Option<&StepDiff>) {
+        self.steps.push((*step, diff.unwrap().clone()));
+    }
+
+    fn on_branch(&mut self, _exec: &Exec, step: &StepInfo, taken: bool) {
+        self.branches.push((step.position, taken));
+    }
+
+    fn on_error(&mut self, _exec: &Exec, _step: &StepInfo, error: &ExecError) {
+        self.errors.push(error.clone());
+    }
+}
+
+#[test]
+fn test_observer() {
+    // 2 3 OP_SWAP OP_0 OP_IF OP_ADD OP_ELSE OP_DROP OP_ENDIF OP_DROP OP_DROP
+    let script = Builder::new()
+        .push_int(2)
+        .push_int(3)
+        .push_opcode(OP_SWAP)
+        .push_int(0)
+        .push_opcode(OP_IF)
+        .push_opcode(OP_ADD)
+        .push_opcode(OP_ELSE)
+        .push_opcode(OP_DROP)
+        .push_opcode(OP_ENDIF)
+        .push_opcode(OP_DROP)
+        .push_opcode(OP_DROP)
+        .into_script();
+    let mut exec = Exec::new(
+        ExecCtx::Tapscript,
+        Options::default(),
+        TxTemplate::dummy(ExecCtx::Tapscript),
+        script,
+        vec![],
+    )
+    .unwrap();
+    let mut observer = RecordingObserver::default();
+    while exec.exec_next_observed(&mut observer).is_ok() {}
+
+    assert_eq!(observer.branches, vec![(4, false), (6, true)]);
+    assert_eq!(observer.errors, vec![ExecError::InvalidStackOperation]);
+
+    // OP_SWAP
+    let (step, diff) = &observer.steps[2];
+    assert_eq!(step.opcode, OP_SWAP);
+    assert_eq!(diff.stack.base, 0);
+    assert_eq!(diff.stack.popped, vec![vec![2], vec![3]]);
+    assert_eq!(diff.stack.pushed, vec![vec![3], vec![2]]);
+
+    // OP_ADD is skipped
+    let (step, diff) = &observer.steps[5];
+    assert_eq!(step.opcode, OP_ADD);
+    assert!(!step.executed);
+    assert!(diff.stack.is_empty());
+}
+
+#[test]
+fn test_snapshot_resume() {
+    // 1 2 1 OP_IF OP_DUP OP_TOALTSTACK OP_ADD OP_ENDIF OP_FROMALTSTACK OP_2DROP OP_1
+    let script = Builder::new()
+        .push_int(1)
+        .push_int(2)
+        .push_int(1)
+        .push_opcode(OP_IF)
+        .push_opcode(OP_DUP)
+        .push_opcode(OP_TOALTSTACK)
+        .push_opcode(OP_ADD)
+        .push_opcode(OP_ENDIF)
+        .push_opcode(OP_FROMALTSTACK)
+        .push_opcode(OP_2DROP)
+        .push_int(1)
+        .into_script();
+    let new_exec = || {
+        Exec::new(
+            ExecCtx::Tapscript,
+            Options::default(),
+            TxTemplate::dummy(ExecCtx::Tapscript),
+            script.clone(),
+            vec![],
+        )
+        .unwrap()
+    };
+
+    let mut exec = new_exec();
+    let mut snapshots = vec![exec.snapshot()];
+    while exec.exec_next().is_ok() {
+        snapshots.push(exec.snapshot());
+    }
+    let res = exec.result().unwrap().clone();
+    assert!(res.success);
+
+    // Resuming from every snapshot gives the same result and stats.
+    for snapshot in &snapshots {
+        let mut resumed = Exec::from_snapshot(
+            ExecCtx::Tapscript,
+            Options::default(),
+            TxTemplate::dummy(ExecCtx::Tapscript),
+            script.clone(),
+            snapshot,
+        )
+        .unwrap();
+        while resumed.exec_next().is_ok() {}
+        // Numbers on the stack stay numbers, so the result is identical.
+        assert_eq!(resumed.result(), exec.result());
+        assert_eq!(resumed.stats(), exec.stats());
+    }
+
+    // Restoring in the middle of the OP_IF block.
+    let mut exec = new_exec();
+    exec.restore(&snapshots[6]).unwrap();
+    assert_eq!(exec.snapshot(), snapshots[6]);
+    assert_eq!(exec.altstack().len(), 1);
+
+    // Positions inside a push are rejected.
+    let snapshot = &snapshots[1];
+    assert_eq!(snapshot.position(), 1);
+    let script = Builder::new().push_slice([0xaa; 4]).into_script();
+    let mut exec = Exec::new(
+        ExecCtx::Tapscript,
+        Options::default(),
+        TxTemplate::dummy(ExecCtx::Tapscript),
+        script,
+        vec![],
+    )
+    .unwrap();
+    assert!(exec.restore(snapshot).is_err());
+}
+
+#[test]
+fn test_exec_on_other_thread() {
+    let script = Builder::new()
+        .push_int(2)
+        .push_int(3)
+        .push_opcode(OP_ADD)
+        .push_int(5)
+        .push_opcode(OP_EQUAL)
+        .into_script();
+    let mut exec = Exec::new(
+        ExecCtx::Tapscript,
+        Options::default(),
+        TxTemplate::dummy(ExecCtx::Tapscript),
+        script,
+        vec![],
+    )
+    .unwrap();
+    // Execute the first half here and the rest on a worker thread.
+    exec.exec_next().unwrap();
+    exec.exec_next().unwrap();
+    let res = std::thread::spawn(move || {
+        while exec.exec_next().is_ok() {}
+        exec.result().unwrap().clone()
+    })
+    .join()
+    .unwrap();
+    assert!(res.success);
+}
+
+#[test]
+fn test_signature_encoding_flags() {
+    let secp = Secp256k1::new();
+    let sk = secret_keys()[0];
+    let msg = Message::from_digest([7; 32]);
+    let mut sig = secp.sign_ecdsa(&msg, &sk);
+    sig.normalize_s();
+    let mut low_s = sig.serialize_der().to_vec();
+    low_s.push(EcdsaSighashType::All as u8);
+
+    let exec = |opt: Options, ctx: ExecCtx| {
+        Exec::new(
+            ctx,
+            opt,
+            TxTemplate::dummy(ExecCtx::Tapscript),
+            ScriptBuf::new(),
+            vec![],
+        )
+        .unwrap()
+    };
+    let std = exec(Options::standardness(), ExecCtx::Legacy);
+    let consensus = exec(Options::consensus(), ExecCtx::Legacy);
+
+    assert_eq!(std.check_signature_encoding(&low_s), Ok(()));
+    assert_eq!(std.check_signature_encoding(&[]), Ok(()));
+
+    // Undefined sighash type.
+    let mut bad_hashtype = low_s.clone();
+    *bad_hashtype.last_mut().unwrap() = 0x04;
+    assert_eq!(
+        std.check_signature_encoding(&bad_hashtype),
+        Err(ExecError::SigHashtype),
+    );
+    assert_eq!(consensus.check_signature_encoding(&bad_hashtype), Ok(()));
+
+    // Non-DER encoding.
+    let mut not_der = low_s.clone();
+    not_der[0] = 0x31;
+    assert_eq!(
+        consensus.check_signature_encoding(&not_der),
+        Err(ExecError::SigDer)
+    );
+
+    // Uncompressed keys are only non-standard in segwit v0.
+    let pk = public_keys()[0].inner.serialize_uncompressed();
+    assert_eq!(std.check_pubkey_encoding(&pk), Ok(()));
+    assert_eq!(
+        exec(Options::standardness(), ExecCtx::SegwitV0).check_pubkey_encoding(&pk),
+        Err(ExecError::WitnessPubkeyType),
+    );
+    assert_eq!(
+        std.check_pubkey_encoding(&pk[1..]),
+        Err(ExecError::PubkeyType)
+    );
+    assert_eq!(consensus.check_pubkey_encoding(&pk[1..]), Ok(()));
+}
+
+#[test]
+fn test_discourage_upgradable_nops() {
+    let script = Builder::new()
+        .push_opcode(OP_NOP4)
+        .push_int(1)
+        .into_script();
+    let run = |opt: Options| {
+        let mut exec = Exec::new(
+            ExecCtx::SegwitV0,
+            opt,
+            TxTemplate::dummy(ExecCtx::Tapscript),
+            script.clone(),
+            vec![],
+        )
+        .unwrap();
+        while exec.exec_next().is_ok() {}
+        exec.result().unwrap().clone()
+    };
+    assert!(run(Options::consensus()).success);
+    assert_eq!(
+        run(Options::standardness()).error,
+        Some(ExecError::DiscourageUpgradableNops),
+    );
+}
+
+#[test]
+fn test_error_position() {
+    let script = Builder::new()
+        .push_int(1)
+        .push_int(2)
+        .push_opcode(OP_EQUALVERIFY)
+        .push_int(1)
+        .into_script();
+    let (res, _) = execute(ExecCtx::Tapscript, script);
+    assert!(!res.success);
+    assert_eq!(res.error, Some(ExecError::EqualVerify));
+    assert_eq!(res.opcode, Some(OP_EQUALVERIFY));
+    assert_eq!(res.position, Some(2));
+
+    let err = res.error.unwrap();
+    assert_eq!(err.name(), "EQUALVERIFY");
+    assert_eq!(err.to_string(), "Script failed an OP_EQUALVERIFY operation");
+    assert_eq!(
+        Error::Exec(err).to_string(),
+        "Script failed an OP_EQUALVERIFY operation"
+    );
+
+    // Unbalanced conditionals are detected at the end of the script.
+    let script = Builder::new().push_int(1).push_opcode(OP_IF).into_script();
+    let (res, _) = execute(ExecCtx::Tapscript, script);
+    assert_eq!(res.error, Some(ExecError::UnbalancedConditional));
+    assert_eq!(res.opcode, None);
+    assert_eq!(res.position, Some(2));
+}
+
+#[test]
+fn test_invalid_pushes() {
+    let script = |bytes: &[u8]| ScriptBuf::from_bytes(bytes.to_vec());
+
+    // Non-minimal pushes only fail when they are executed.
+    // OP_0 OP_IF OP_PUSHDATA1 0x01 0x05 OP_ENDIF OP_1
+    let (res, _) = execute(
+        ExecCtx::Tapscript,
+        script(&[0x00, 0x63, 0x4c, 0x01, 0x05, 0x68, 0x51]),
+    );
+    assert_eq!(res.error, None);
+    // OP_1 OP_IF OP_PUSHDATA1 0x01 0x05 OP_ENDIF OP_1
+    let (res, _) = execute(
+        ExecCtx::Tapscript,
+        script(&[0x51, 0x63, 0x4c, 0x01, 0x05, 0x68, 0x51]),
+    );
+    assert_eq!(res.error, Some(ExecError::MinimalData));
+    assert_eq!(res.position, Some(2));
+
+    // Truncated pushes fail when execution reaches them, even in a
+    // non-executed branch.
+    // OP_0 OP_IF 0x02 0xaa
+    let (res, _) = execute(ExecCtx::Tapscript, script(&[0x00, 0x63, 0x02, 0xaa]));
+    assert_eq!(res.error, Some(ExecError::BadOpcode));
+    assert_eq!(res.position, Some(2));
+    // OP_RETURN 0x02 0xaa
+    let (res, _) = execute(ExecCtx::Tapscript, script(&[0x6a, 0x02, 0xaa]));
+    assert_eq!(res.error, Some(ExecError::OpReturn));
+}
+
+#[test]
+fn test_trace_roundtrip() {
+    use crate::tracefile::{TraceReader, TraceRecord, TraceWriter};
+
+    let script = Builder::new()
+        .push_int(1)
+        .push_int(2)
+        .push_opcode(OP_DUP)
+        .push_opcode(OP_TOALTSTACK)
+        .push_opcode(OP_ADD)
+        .push_int(0)
+        .push_opcode(OP_IF)
+        .push_int(5)
+        .push_opcode(OP_ENDIF)
+        .push_opcode(OP_FROMALTSTACK)
+        .push_opcode(OP_SUB)
+        .into_script();
+    let new_exec = || {
+        Exec::new(
+            ExecCtx::Tapscript,
+            Options::default(),
+            TxTemplate::dummy(ExecCtx::Tapscript),
+            script.clone(),
+            vec![],
+        )
+        .unwrap()
+    };
+
+    // Record the expected states while writing the trace.
+    let mut exec = new_exec();
+    let mut tracer = TraceWriter::new(Vec::new(), &exec, 3).unwrap();
+    let mut states = vec![];
+    loop {
+        states.push((
+            exec.script_position(),
+            exec.stack().iter_str().collect::<Vec<_>>(),
+            exec.altstack().iter_str().collect::<Vec<_>>(),
+        ));
+        if exec.exec_next_observed(&mut tracer).is_err() {
+            break;
+        }
+    }
+    let res = exec.result().unwrap().clone();
+    let trace = tracer.finish(&exec, &res).unwrap();
+
+    for (step, (position, stack, altstack)) in states.iter().enumerate() {
+        let mut reader = TraceReader::new(&trace[..]).unwrap();
+        let state = reader.state_at(step).unwrap().unwrap();
+        assert_eq!(state.position, *position, "step {}", step);
+        assert_eq!(&state.stack, stack, "step {}", step);
+        assert_eq!(&state.altstack, altstack, "step {}", step);
+    }
+
+    let mut reader = TraceReader::new(&trace[..]).unwrap();
+    assert_eq!(reader.script(), &script);
+    assert!(reader.state_at(states.len()).unwrap().is_none());
+
+    let mut reader = TraceReader::new(&trace[..]).unwrap();
+    let mut nb_snapshots = 0;
+    let mut end = None;
+    while let Some(record) = reader.next_record().unwrap() {
+        match record {
+            TraceRecord::Snapshot(_) => nb_snapshots += 1,
+            TraceRecord::Step(_) => {}
+            TraceRecord::End(e) => end = Some(e),
+        }
+    }
+    assert_eq!(reader.nb_steps(), states.len() - 1);
+    assert_eq!(nb_snapshots, 1 + reader.nb_steps() / 3);
+    let end = end.unwrap();
+    assert!(end.success);
+    assert_eq!(end.position, script.len());
+}
+
+/// Executes the script with the given options and transaction until the end.
+fn execute_with(ctx: ExecCtx, opt: Options, tx: TxTemplate, script: ScriptBuf) -> ExecutionResult {
+    let mut exec = Exec::new(ctx, opt, tx, script, vec![]).expect("error creating exec");
+    while exec.exec_next().is_ok() {}
+    exec.result().unwrap().clone()
+}
+
+fn ctv_tx_template(script_sig: ScriptBuf, input_idx: usize) -> TxTemplate {
+    let txin = |sequence| TxIn {
+        previous_output: OutPoint::null(),
+        script_sig: ScriptBuf::new(),
+        sequence: Sequence(sequence),
+        witness: Witness::new(),
+    };
+    let mut tx = Transaction {
+        version: transaction::Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![txin(0xfffffffd), txin(0xffffffff)],
+        output: vec![
+            TxOut {
+                value: Amount::from_sat(100_000),
+                script_pubkey: ScriptBuf::from_bytes([&[0x00, 0x14][..], &[0x11; 20]].concat()),
+            },
+            TxOut {
+                value: Amount::from_sat(50_000),
+                script_pubkey: ScriptBuf::from_bytes([&[0x51, 0x20][..], &[0x22; 32]].concat()),
+            },
+        ],
+    };
+    tx.input[1].script_sig = script_sig;
+    TxTemplate {
+        prevouts: tx.output.clone(),
+        tx,
+        input_idx,
+        taproot_annex_scriptleaf: Some((TapLeafHash::all_zeros(), None)),
+    }
+}
+
+#[test]
+fn test_ctv_template_hash() {
+    // Calculated with an independent implementation of BIP 119.
+    let tx = ctv_tx_template(ScriptBuf::new(), 0).tx;
+    assert_eq!(
+        default_template_hash(&tx, 0).to_string(),
+        "4e2efd6aa4ebc515116d6bc1f500b67e4c7bc8229cce47c560cf4b8f758b8943",
+    );
+    assert_eq!(
+        default_template_hash(&tx, 1).to_string(),
+        "c453f68a9ea5d10bd9bab8b7c364191b13590c25ee2f9175e6856a9ed3ff9b5b",
+    );
+    // The scriptSigs are committed to when one is non-empty.
+    let tx = ctv_tx_template(ScriptBuf::from_bytes(vec![0x01, 0x02]), 0).tx;
+    assert_eq!(
+        default_template_hash(&tx, 0).to_string(),
+        "b23bd6a6822f5fb64db816827a1b143f02af9b38744314dd366de9616b5f5cd7",
+    );
+}
+
+#[test]
+fn test_ctv() {
+    let ctv = Options {
+        experimental: Experimental {
+            op_ctv: true,
+            ..Default::default()
+        },
+        ..Options::default()
+    };
+    let tx = ctv_tx_template(ScriptBuf::new(), 0);
+    let hash = default_template_hash(&tx.tx, 0);
+    let script = |template: &[u8]| {
+        Builder::new()
+            .push_slice(<&bitcoin::script::PushBytes>::try_from(template).unwrap())
+            .push_opcode(OP_CHECKTEMPLATEVERIFY)
+            .into_script()
+    };
+
+    for ctx in [ExecCtx::Legacy, ExecCtx::SegwitV0, ExecCtx::Tapscript] {
+        let res = execute_with(ctx, ctv.clone(), tx.clone(), script(hash.as_byte_array()));
+        assert!(res.success, "{:?}: {:?}", ctx, res.error);
+    }
+
+    // The template of another input doesn't match.
+    let other = default_template_hash(&tx.tx, 1);
+    let res = execute_with(
+        ExecCtx::Tapscript,
+        ctv.clone(),
+        tx.clone(),
+        script(other.as_byte_array()),
+    );
+    assert_eq!(res.error, Some(ExecError::TemplateMismatch));
+    assert_eq!(res.opcode, Some(OP_CHECKTEMPLATEVERIFY));
+
+    // Other template sizes are upgradable NOPs.
+    let res = execute_with(
+        ExecCtx::Tapscript,
+        ctv.clone(),
+        tx.clone(),
+        script(&[1; 20]),
+    );
+    assert_eq!(res.error, Some(ExecError::DiscourageUpgradableNops));
+    let consensus = Options {
+        experimental: ctv.experimental.clone(),
+        ..Options::consensus()
+    };
+    let res = execute_with(ExecCtx::Tapscript, consensus, tx.clone(), script(&[1; 20]));
+    assert!(res.success);
+
+    let empty = Builder::new()
+        .push_opcode(OP_CHECKTEMPLATEVERIFY)
+        .into_script();
+    let res = execute_with(ExecCtx::Tapscript, ctv, tx.clone(), empty);
+    assert_eq!(res.error, Some(ExecError::InvalidStackOperation));
+
+    // Without the flag, it's OP_NOP4.
+    let res = execute_with(
+        ExecCtx::Tapscript,
+        Options::consensus(),
+        tx.clone(),
+        script(other.as_byte_array()),
+    );
+    assert!(res.success);
+}
+
+#[test]
+fn test_csfs() {
+    use bitcoin::hex::FromHex;
+    use bitcoin::script::PushBytesBuf;
+
+    let csfs = Options {
+        experimental: Experimental {
+            op_csfs: true,
+            ..Default::default()
+        },
+        ..Options::default()
+    };
+    let push = |hex: &str| PushBytesBuf::try_from(Vec::<u8>::from_hex(hex).unwrap()).unwrap();
+    let script = |sig: PushBytesBuf, msg: PushBytesBuf, pk: PushBytesBuf| {
+        Builder::new()
+            .push_slice(sig)
+            .push_slice(msg)
+            .push_slice(pk)
+            .push_opcode(OP_CHECKSIGFROMSTACK)
+            .into_script()
+    };
+
+    // BIP 340 test vectors 1 and 15 to 18, with messages of different sizes.
+    let pk1 = "dff1d77f2a671c5f36183726db2341be58feae1da2deced843240f7b502ba659";
+    let pk2 = "778caa53b4393ac467774d09497a87224bf9fab6f6e68b23086497324d6fd117";
+    let vectors = [
+        (
+            pk1,
+            "243f6a8885a308d313198a2e03707344a4093822299f31d0082efa98ec4e6c89".to_owned(),
+            "6896bd60eeae296db48a229ff71dfe071bde413e6d43f917dc8dcf8c78de33418906d11ac976abccb20b091292bff4ea897efcb639ea871cfa95f6de339e4b0a",
+        ),
+        (
+            pk2,
+            "".to_owned(),
+            "71535db165ecd9fbbc046e5ffaea61186bb6ad436732fccc25291a55895464cf6069ce26bf03466228f19a3a62db8a649f2d560fac652827d1af0574e427ab63",
+        ),
+        (
+            pk2,
+            "11".to_owned(),
+            "08a20a0afef64124649232e0693c583ab1b9934ae63b4c3511f3ae1134c6a303ea3173bfea6683bd101fa5aa5dbc1996fe7cacfc5a577d33ec14564cec2bacbf",
+        ),
+        (
+            pk2,
+            "0102030405060708090a0b0c0d0e0f1011".to_owned(),
+            "5130f39a4059b43bc7cac09a19ece52b5d8699d1a71e3c52da9afdb6b50ac370c4a482b77bf960f8681540e25b6771ece1e5a37fd80e5a51897c5566a97ea5a5",
+        ),
+        (
+            pk2,
+            "99".repeat(100),
+            "403b12b0d8555a344175ea7ec746566303321e5dbfa8be6f091635163eca79a8585ed3e3170807e7c03b720fc54c7b23897fcba0e9d0b4a06894cfd249f22367",
+        ),
+    ];
+    for (pk, msg, sig) in &vectors {
+        let res = execute_with(
+            ExecCtx::Tapscript,
+            csfs.clone(),
+            TxTemplate::dummy(ExecCtx::Tapscript),
+            script(push(sig), push(msg), push(pk)),
+        );
+        assert!(res.success, "msg {}: {:?}", msg, res.error);
+    }
+
+    let (pk, msg, sig) = &vectors[0];
+    let run = |opt: Options, ctx: ExecCtx, sig: PushBytesBuf, pk: PushBytesBuf| {
+        execute_with(
+            ctx,
+            opt,
+            TxTemplate::dummy(ExecCtx::Tapscript),
+            script(sig, push(msg), pk),
+        )
+    };
+
+    // A signature over another message fails the script.
+    let res = run(
+        csfs.clone(),
+        ExecCtx::Tapscript,
+        push(vectors[1].2),
+        push(pk),
+    );
+    assert_eq!(res.error, Some(ExecError::SchnorrSig));
+    assert_eq!(res.opcode, Some(OP_CHECKSIGFROMSTACK));
+
+    // Signatures have no sighash byte.
+    let res = run(
+        csfs.clone(),
+        ExecCtx::Tapscript,
+        push(&format!("{}01", sig)),
+        push(pk),
+    );
+    assert_eq!(res.error, Some(ExecError::SchnorrSigSize));
+
+    // An empty signature pushes false.
+    let res = run(csfs.clone(), ExecCtx::Tapscript, push(""), push(pk));
+    assert!(!res.success);
+    assert_eq!(res.error, None);
+
+    let res = run(csfs.clone(), ExecCtx::Tapscript, push(sig), push(""));
+    assert_eq!(res.error, Some(ExecError::PubkeyType));
+    let res = run(
+        csfs.clone(),
+        ExecCtx::Tapscript,
+        push(sig),
+        push(&format!("02{}", pk)),
+    );
+    assert_eq!(res.error, Some(ExecError::DiscourageUpgradablePubkeyType));
+
+    // It's only defined in tapscript and only with the flag.
+    let res = run(csfs, ExecCtx::SegwitV0, push(sig), push(pk));
+    assert_eq!(res.error, Some(ExecError::BadOpcode));
+    let res = run(Options::default(), ExecCtx::Tapscript, push(sig), push(pk));
+    assert_eq!(res.error, Some(ExecError::BadOpcode));
+}
+
+#[test]
+fn test_analysis() {
+    use crate::analysis::{analyze, DepthBounds, Issue};
+
+    // (a b c -- a b c+a), with a detour through the altstack.
+    let script = Builder::new()
+        .push_int(2)
+        .push_opcode(OP_PICK)
+        .push_opcode(OP_ADD)
+        .push_opcode(OP_TOALTSTACK)
+        .push_opcode(OP_DUP)
+        .push_opcode(OP_DROP)
+        .push_opcode(OP_FROMALTSTACK)
+        .into_script();
+    let analysis = analyze(&script).unwrap();
+    assert!(analysis.issues.is_empty());
+    assert_eq!(analysis.effect.inputs, 3);
+    assert_eq!(analysis.effect.outputs(), Some(3));
+    assert_eq!(analysis.effect.max_depth, 1);
+    assert_eq!(analysis.effect.altstack_max_depth, 1);
+    assert_eq!(analysis.effect.to_string(), "( 3 -- 3 )");
+    assert_eq!(analysis.steps[2].opcode, Some(OP_ADD));
+    assert_eq!(analysis.steps[2].stack, DepthBounds { min: 1, max: 1 });
+
+    // Branches with different effects.
+    let script = Builder::new()
+        .push_opcode(OP_IF)
+        .push_opcode(OP_DROP)
+        .push_opcode(OP_ELSE)
+        .push_opcode(OP_DUP)
+        .push_opcode(OP_ENDIF)
+        .into_script();
+    let analysis = analyze(&script).unwrap();
+    assert_eq!(analysis.effect.inputs, 2);
+    assert_eq!(analysis.effect.delta, DepthBounds { min: -2, max: 0 });
+    assert_eq!(analysis.effect.outputs(), None);
+    assert_eq!(analysis.effect.to_string(), "( 2 -- 0..2 )");
+
+    // Pushed indices are followed through arithmetic and branches.
+    let script = Builder::new()
+        .push_int(1)
+        .push_int(1)
+        .push_opcode(OP_IF)
+        .push_opcode(OP_1ADD)
+        .push_opcode(OP_ELSE)
+        .push_int(1)
+        .push_opcode(OP_ADD)
+        .push_opcode(OP_ENDIF)
+        .push_opcode(OP_ROLL)
+        .into_script();
+    let analysis = analyze(&script).unwrap();
+    assert!(analysis.issues.is_empty());
+    assert_eq!(analysis.effect.inputs, 3);
+    assert_eq!(analysis.effect.outputs(), Some(3));
+
+    let script = Builder::new()
+        .push_opcode(OP_DEPTH)
+        .push_opcode(OP_PICK)
+        .push_opcode(OP_ENDIF)
+        .push_int(1)
+        .push_opcode(OP_IF)
+        .into_script();
+    let analysis = analyze(&script).unwrap();
+    assert_eq!(
+        analysis.issues,
+        vec![
+            Issue::DataDependent {
+                position: 1,
+                opcode: OP_PICK,
+            },
+            Issue::UnbalancedConditional { position: 2 },
+            Issue::UnbalancedConditional { position: 5 },
+        ],
+    );
+}
+
+#[test]
+fn test_analysis_underflow() {
+    use crate::analysis::analyze;
+
+    let script = Builder::new()
+        .push_int(1)
+        .push_opcode(OP_ADD)
+        .push_opcode(OP_FROMALTSTACK)
+        .into_script();
+    let analysis = analyze(&script).unwrap();
+    assert_eq!(analysis.effect.inputs, 1);
+    assert_eq!(analysis.effect.altstack_inputs, 1);
+
+    let underflows = analysis.underflows(0, 0);
+    assert_eq!(underflows.len(), 2);
+    assert_eq!(underflows[0].position, 1);
+    assert_eq!(underflows[0].opcode, Some(OP_ADD));
+    assert_eq!(underflows[0].missing, 1);
+    assert_eq!(underflows[1].opcode, Some(OP_FROMALTSTACK));
+    assert_eq!(underflows[1].missing_altstack, 1);
+    assert!(analysis.underflows(1, 1).is_empty());
+
+    // The analysis agrees with the execution.
+    let (res, _) = execute(ExecCtx::Tapscript, script);
+    assert_eq!(res.error, Some(ExecError::InvalidStackOperation));
+    assert_eq!(res.position, Some(1));
+}
+
+#[test]
+fn test_pick_out_of_range() {
+    // The index can't refer to itself.
+    for op in [OP_PICK, OP_ROLL] {
+        let script = Builder::new()
+            .push_int(1)
+            .push_int(2)
+            .push_int(2)
+            .push_opcode(op)
+            .into_script();
+        let (res, _) = execute(ExecCtx::Tapscript, script);
+        assert_eq!(res.error, Some(ExecError::InvalidStackOperation));
+    }
+}
+
+#[test]
+fn test_numeric_overflow() {
+    // Results of numeric opcodes can't be used as operands once they exceed
+    // 4 bytes, whatever their sign.
+    for n in [1 << 30, -(1 << 30)] {
+        let mut builder = Builder::new().push_int(n);
+        for _ in 0..33 {
+            builder = builder.push_opcode(OP_DUP).push_opcode(OP_ADD);
+        }
+        let script = builder.push_opcode(OP_ABS).into_script();
+        let (res, _) = execute(ExecCtx::Tapscript, script);
+        assert_eq!(res.error, Some(ExecError::ScriptIntNumericOverflow));
+        assert_eq!(res.opcode, Some(OP_ADD));
+    }
+}
+
+#[test]
+fn test_dummy_tx() {
+    // Checks against the spending transaction fail instead of panicking when
+    // there is no input to check, like with the dummy transaction of the CLI.
+    for op in [OP_CLTV, OP_CSV] {
+        let script = Builder::new().push_int(1).push_opcode(op).into_script();
+        let (res, _) = execute(ExecCtx::Tapscript, script);
+        assert_eq!(res.error, Some(ExecError::UnsatisfiedLocktime));
+    }
+
+    let secp = Secp256k1::new();
+    let sk = secret_keys()[0];
+    let msg = Message::from_digest([1; 32]);
+
+    let (pk, _) = sk.x_only_public_key(&secp);
+    let sig = secp.sign_schnorr_no_aux_rand(&msg, &sk.keypair(&secp));
+    let script = Builder::new()
+        .push_slice(sig.serialize())
+        .push_slice(pk.serialize())
+        .push_opcode(OP_CHECKSIG)
+        .into_script();
+    let (res, _) = execute(ExecCtx::Tapscript, script);
+    assert_eq!(res.error, Some(ExecError::SchnorrSig));
+
+    // A 32-byte public key that is not on the curve.
+    let script = Builder::new()
+        .push_slice(sig.serialize())
+        .push_slice([0xff; 32])
+        .push_opcode(OP_CHECKSIG)
+        .into_script();
+    let (res, _) = execute(ExecCtx::Tapscript, script);
+    assert_eq!(res.error, Some(ExecError::SchnorrSig));
+
+    let mut sig = secp.sign_ecdsa(&msg, &sk).serialize_der().to_vec();
+    sig.push(EcdsaSighashType::All as u8);
+    let script = Builder::new()
+        .push_key(&public_keys()[0])
+        .push_opcode(OP_CHECKSIG)
+        .into_script();
+    for ctx in [ExecCtx::Legacy, ExecCtx::SegwitV0] {
+        let mut exec = Exec::new(
+            ctx,
+            Options::default(),
+            TxTemplate::dummy(ExecCtx::Tapscript),
+            script.clone(),
+            vec![sig.clone()],
+        )
+        .unwrap();
+        while exec.exec_next().is_ok() {}
+        assert_eq!(exec.result().unwrap().error, Some(ExecError::SigNullFail));
+    }
+}
+
+#[test]
+fn test_symbolic() {
+    use crate::symbolic::{explore, ConstraintKind, Limits};
+
+    let script = Builder::new()
+        .push_opcode(OP_DUP)
+        .push_int(100)
+        .push_opcode(OP_GREATERTHAN)
+        .push_opcode(OP_IF)
+        .push_int(13)
+        .push_opcode(OP_SUB)
+        .push_int(90)
+        .push_opcode(OP_NUMEQUALVERIFY)
+        .push_opcode(OP_ELSE)
+        .push_opcode(OP_DROP)
+        .push_opcode(OP_ENDIF)
+        .push_int(1)
+        .into_script();
+    let exploration = explore(
+        ExecCtx::Tapscript,
+        Options::default(),
+        TxTemplate::dummy(ExecCtx::Tapscript),
+        script,
+        vec![vec![5]],
+        &Limits::default(),
+    )
+    .unwrap();
+    assert!(exploration.complete);
+    assert_eq!(exploration.paths.len(), 3);
+    assert!(exploration.paths.iter().all(|p| !p.diverged));
+
+    let seed = &exploration.paths[0];
+    assert!(seed.result.success);
+    assert_eq!(seed.constraints.len(), 2);
+    assert_eq!(seed.constraints[0].kind, ConstraintKind::MinimalIf);
+    assert_eq!(seed.constraints[1].kind, ConstraintKind::Branch);
+    assert!(!seed.constraints[1].holds);
+    assert_eq!(seed.constraints[1].term.to_string(), "(100 < x0)");
+
+    // Only 103 passes the OP_NUMEQUALVERIFY in the OP_IF branch.
+    let failures = exploration.failures().collect::<Vec<_>>();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].result.error, Some(ExecError::NumEqualVerify));
+    let x = read_scriptint(&failures[0].input[0], 4, true).unwrap();
+    assert!(x > 100 && x != 103);
+    assert!(exploration
+        .paths
+        .iter()
+        .any(|p| p.result.success && p.input == vec![vec![103]]));
+
+    // Results of additions can overflow the operand range.
+    let script = Builder::new()
+        .push_opcode(OP_DUP)
+        .push_opcode(OP_ADD)
+        .push_opcode(OP_1ADD)
+        .push_opcode(OP_DROP)
+        .push_int(1)
+        .into_script();
+    let exploration = explore(
+        ExecCtx::Tapscript,
+        Options::default(),
+        TxTemplate::dummy(ExecCtx::Tapscript),
+        script,
+        vec![vec![1]],
+        &Limits::default(),
+    )
+    .unwrap();
+    assert!(exploration.complete);
+    assert_eq!(exploration.paths.len(), 2);
+    assert_eq!(
+        exploration.paths[0].constraints[0].kind,
+        ConstraintKind::Range
+    );
+    let failure = &exploration.paths[1];
+    assert_eq!(
+        failure.result.error,
+        Some(ExecError::ScriptIntNumericOverflow)
+    );
+    let x = read_scriptint(&failure.input[0], 4, true).unwrap();
+    assert!(x.abs() >= 1 << 30);
+
+    // Items used by opcodes that aren't executed symbolically are fixed.
+    let script = Builder::new()
+        .push_opcode(OP_SHA256)
+        .push_opcode(OP_SIZE)
+        .push_opcode(OP_NIP)
+        .push_int(32)
+        .push_opcode(OP_EQUAL)
+        .into_script();
+    let exploration = explore(
+        ExecCtx::Tapscript,
+        Options::default(),
+        TxTemplate::dummy(ExecCtx::Tapscript),
+        script,
+        vec![vec![7]],
+        &Limits::default(),
+    )
+    .unwrap();
+    assert!(exploration.complete);
+    assert_eq!(exploration.paths.len(), 1);
+    assert_eq!(
+        exploration.paths[0].constraints[0].kind,
+        ConstraintKind::Concrete
+    );
+}
+
+#[test]
+fn test_budget() {
+    use crate::budget::{check_leaf, Limit, MIN_CONTROL_BLOCK_SIZE};
+
+    // A witness item that is too large to be relayed.
+    let script = Builder::new()
+        .push_opcode(OP_DROP)
+        .push_opcode(OP_PUSHNUM_1)
+        .into_script();
+    let report = check_leaf(
+        Options::default(),
+        TxTemplate::dummy(ExecCtx::Tapscript),
+        script,
+        vec![vec![1; 81]],
+        MIN_CONTROL_BLOCK_SIZE,
+    )
+    .unwrap();
+    assert!(report.result.success);
+    assert!(report.is_consensus_valid());
+    assert!(!report.is_standard());
+    let violations = report.violations().map(|u| u.limit).collect::<Vec<_>>();
+    assert_eq!(violations, vec![Limit::StandardWitnessItemSize]);
+    assert_eq!(report.usage(Limit::ElementSize).headroom(), 520 - 81);
+    assert_eq!(report.usage(Limit::StackSize).used, 1);
+    // The witness is 1 + (1 + 81) + (1 + 2) + (1 + 33) bytes.
+    assert_eq!(report.usage(Limit::ValidationWeight).used, 0);
+    assert_eq!(report.usage(Limit::ValidationWeight).max, 50 + 120);
+    assert_eq!(report.usage(Limit::StandardTxWeight).used, 94 * 4 + 2 + 120);
+
+    // The stack limit is exceeded even though the stack is cleaned up.
+    let mut builder = Builder::new();
+    for _ in 0..1001 {
+        builder = builder.push_opcode(OP_PUSHNUM_1);
+    }
+    for _ in 0..1000 {
+        builder = builder.push_opcode(OP_DROP);
+    }
+    let report = check_leaf(
+        Options::default(),
+        TxTemplate::dummy(ExecCtx::Tapscript),
+        builder.into_script(),
+        vec![],
+        MIN_CONTROL_BLOCK_SIZE,
+    )
+    .unwrap();
+    assert!(report.result.success);
+    assert!(!report.is_consensus_valid());
+    assert_eq!(report.usage(Limit::StackSize).used, 1001);
+    assert_eq!(report.usage(Limit::StackSize).headroom(), -1);
+}
+
+fn spending_tx(witness: Witness) -> Transaction {
+    Transaction {
+        version: transaction::Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness,
+        }],
+        output: vec![],
+    }
+}
+
+#[test]
+fn test_taproot_script_path() {
+    let secp = Secp256k1::new();
+    let keypair = Keypair::from_secret_key(&secp, &SecretKey::from_slice(&[1; 32]).unwrap());
+    let (internal_key, _) = keypair.x_only_public_key();
+
+    let leaf = Builder::new()
+        .push_int(2)
+        .push_opcode(OP_ADD)
+        .push_int(5)
+        .push_opcode(OP_EQUAL)
+        .into_script();
+    let other_leaf = Builder::new().push_opcode(OP_RETURN).into_script();
+    let spend_info = TaprootBuilder::new()
+        .add_leaf(1, leaf.clone())
+        .unwrap()
+        .add_leaf(1, other_leaf)
+        .unwrap()
+        .finalize(&secp, internal_key)
+        .unwrap();
+    let control_block = spend_info
+        .control_block(&(leaf.clone(), LeafVersion::TapScript))
+        .unwrap();
+    let prevouts = vec![TxOut {
+        value: Amount::from_sat(10_000),
+        script_pubkey: ScriptBuf::new_p2tr_tweaked(spend_info.output_key()),
+    }];
+
+    let mut witness = Witness::new();
+    witness.push([3u8]);
+    witness.push(leaf.as_bytes());
+    witness.push(control_block.serialize());
+    assert_eq!(verify_input(&spending_tx(witness), 0, &prevouts), Ok(()));
+
+    let mut witness = Witness::new();
+    witness.push([4u8]);
+    witness.push(leaf.as_bytes());
+    witness.push(control_block.serialize());
+    assert_eq!(
+        verify_input(&spending_tx(witness), 0, &prevouts),
+        Err(Error::Exec(ExecError::EvalFalse)),
+    );
+
+    // A script that is not committed to by the output key.
+    let mut witness = Witness::new();
+    witness.push(Vec::<u8>::new());
+    witness.push(Builder::new().push_int(1).as_bytes());
+    witness.push(control_block.serialize());
+    assert_eq!(
+        verify_input(&spending_tx(witness), 0, &prevouts),
+        Err(Error::Exec(ExecError::WitnessProgramMismatch)),
+    );
+
+    // The initial stack size is checked before the element sizes.
+    let mut witness = Witness::new();
+    witness.push([0u8; MAX_SCRIPT_ELEMENT_SIZE + 1]);
+    for _ in 0..MAX_STACK_SIZE {
+        witness.push([3u8]);
+    }
+    witness.push(leaf.as_bytes());
+    witness.push(control_block.serialize());
+    assert_eq!(
+        verify_input(&spending_tx(witness), 0, &prevouts),
+        Err(Error::Exec(ExecError::StackSize)),
+    );
+}
+
+#[test]
+fn test_p2wsh() {
+    let witness_script = Builder::new()
+        .push_opcode(OP_DUP)
+        .push_opcode(OP_ADD)
+        .push_int(6)
+        .push_opcode(OP_EQUAL)
+        .into_script();
+    let prevouts = vec![TxOut {
+        value: Amount::from_sat(10_000),
+        script_pubkey: ScriptBuf::new_p2wsh(&witness_script.wscript_hash()),
+    }];
+
+    let mut witness = Witness::new();
+    witness.push([3u8]);
+    witness.push(witness_script.as_bytes());
+    assert_eq!(verify_input(&spending_tx(witness), 0, &prevouts), Ok(()));
+
+    let mut witness = Witness::new();
+    witness.push([3u8]);
+    witness.push([3u8]);
+    witness.push(witness_script.as_bytes());
+    assert_eq!(
+        verify_input(&spending_tx(witness), 0, &prevouts),
+        Err(Error::Exec(ExecError::CleanStack)),
+    );
+}
@@ -0,0 +1,190 @@
+//! Hooks to observe the execution of a script step by step.
+//!
+//! An [ExecObserver] can be passed to [Exec::exec_next_observed] to get
+//! notified before and after every instruction, on every branch decision
+//! and on errors. This is the building block for profilers, coverage tools
+//! and debuggers.
+
+use bitcoin::Opcode;
+
+use crate::{Exec, ExecError, Stack};
+
+/// Information about the instruction being executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepInfo {
+    /// The position of the instruction in the script, in bytes.
+    pub position: usize,
+    /// The opcode of the instruction.
+    ///
+    /// Data pushes are represented by their push opcode.
+    pub opcode: Opcode,
+    /// Whether the instruction is actually executed.
+    ///
+    /// This is false for instructions inside non-taken branches,
+    /// except for the conditional opcodes themselves.
+    pub executed: bool,
+}
+
+/// The change a single step made to a stack.
+///
+/// The bottom `base` items are untouched, the `popped` items were
+/// replaced by the `pushed` items.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StackDiff {
+    /// The number of items at the bottom of the stack that were untouched.
+    pub base: usize,
+    /// The items that were removed from the stack, bottom to top.
+    pub popped: Vec<Vec<u8>>,
+    /// The items that were added to the stack, bottom to top.
+    pub pushed: Vec<Vec<u8>>,
+}
+
+impl StackDiff {
+    /// Computes the diff between two stacks.
+    pub fn compute(before: &Stack, after: &Stack) -> StackDiff {
+        let base = before.common_prefix_len(after);
+        StackDiff {
+            base,
+            popped: (base..before.len()).map(|i| before.get(i)).collect(),
+            pushed: (base..after.len()).map(|i| after.get(i)).collect(),
+        }
+    }
+
+    /// Whether the step left the stack unchanged.
+    pub fn is_empty(&self) -> bool {
+        self.popped.is_empty() && self.pushed.is_empty()
+    }
+
+    /// Applies the diff to a stack of raw items.
+    pub fn apply(&self, stack: &mut Vec<Vec<u8>>) {
+        stack.truncate(self.base);
+        stack.extend(self.pushed.iter().cloned());
+    }
+}
+
+/// The changes a single step made to the stack and the altstack.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StepDiff {
+    pub stack: StackDiff,
+    pub altstack: StackDiff,
+}
+
+/// Observer of a script execution.
+///
+/// All methods have empty default implementations, so implementors only
+/// have to implement the hooks they are interested in.
+pub trait ExecObserver {
+    /// Whether [ExecObserver::post_step] should be given the stack diffs.
+    ///
+    /// Computing diffs requires copying the stacks on every step, so
+    /// observers that don't need them should return false.
+    fn wants_stack_diffs(&self) -> bool {
+        true
+    }
+
+    /// Called before an instruction is executed.
+    fn pre_step(&mut self, _exec: &Exec, _step: &StepInfo) {}
+
+    /// Called after an instruction was executed successfully.
+    fn post_step(&mut self, _exec: &Exec, _step: &StepInfo, _diff: Option<&StepDiff>) {}
+
+    /// Called after OP_IF, OP_NOTIF and OP_ELSE, with whether the
+    /// following instructions will be executed.
+    fn on_branch(&mut self, _exec: &Exec, _step: &StepInfo, _taken: bool) {}
+
+    /// Called when an instruction fails. The execution is over after this.
+    fn on_error(&mut self, _exec: &Exec, _step: &StepInfo, _error: &ExecError) {}
+}
+
+/// Observer that doesn't do anything, used for plain execution.
+pub(crate) struct NoObserver;
+
+impl ExecObserver for NoObserver {
+    fn wants_stack_diffs(&self) -> bool {
+        false
+    }
+}
+
+impl<T: ExecObserver + ?Sized> ExecObserver for &mut T {
+    fn wants_stack_diffs(&self) -> bool {
+        (**self).wants_stack_diffs()
+    }
+
+    fn pre_step(&mut self, exec: &Exec, step: &StepInfo) {
+        (**self).pre_step(exec, step)
+    }
+
+    fn post_step(&mut self, exec: &Exec, step: &StepInfo, diff: Option<&StepDiff>) {
+        (**self).post_step(exec, step, diff)
+    }
+
+    fn on_branch(&mut self, exec: &Exec, step: &StepInfo, taken: bool) {
+        (**self).on_branch(exec, step, taken)
+    }
+
+    fn on_error(&mut self, exec: &Exec, step: &StepInfo, error: &ExecError) {
+        (**self).on_error(exec, step, error)
+    }
+}
+
+/// An absent observer doesn't observe anything.
+impl<T: ExecObserver> ExecObserver for Option<T> {
+    fn wants_stack_diffs(&self) -> bool {
+        self.as_ref()
+            .map(|o| o.wants_stack_diffs())
+            .unwrap_or(false)
+    }
+
+    fn pre_step(&mut self, exec: &Exec, step: &StepInfo) {
+        if let Some(o) = self {
+            o.pre_step(exec, step)
+        }
+    }
+
+    fn post_step(&mut self, exec: &Exec, step: &StepInfo, diff: Option<&StepDiff>) {
+        if let Some(o) = self {
+            o.post_step(exec, step, diff)
+        }
+    }
+
+    fn on_branch(&mut self, exec: &Exec, step: &StepInfo, taken: bool) {
+        if let Some(o) = self {
+            o.on_branch(exec, step, taken)
+        }
+    }
+
+    fn on_error(&mut self, exec: &Exec, step: &StepInfo, error: &ExecError) {
+        if let Some(o) = self {
+            o.on_error(exec, step, error)
+        }
+    }
+}
+
+/// Two observers observing the same execution.
+impl<A: ExecObserver, B: ExecObserver> ExecObserver for (A, B) {
+    fn wants_stack_diffs(&self) -> bool {
+        self.0.wants_stack_diffs() || self.1.wants_stack_diffs()
+    }
+
+    fn pre_step(&mut self, exec: &Exec, step: &StepInfo) {
+        self.0.pre_step(exec, step);
+        self.1.pre_step(exec, step);
+    }
+
+    fn post_step(&mut self, exec: &Exec, step: &StepInfo, diff: Option<&StepDiff>) {
+        self.0.post_step(exec, step, diff);
+        self.1.post_step(exec, step, diff);
+    }
+
+    fn on_branch(&mut self, exec: &Exec, step: &StepInfo, taken: bool) {
+        self.0.on_branch(exec, step, taken);
+        self.1.on_branch(exec, step, taken);
+    }
+
+    fn on_error(&mut self, exec: &Exec, step: &StepInfo, error: &ExecError) {
+        self.0.on_error(exec, step, error);
+        self.1.on_error(exec, step, error);
+    }
+}
@@ -0,0 +1,526 @@
+//! A compact binary format for execution traces.
+//!
+//! Instead of the full stacks, a trace contains the stack changes of every
+//! step, with full snapshots of the stacks at regular intervals. The script
+//! itself is stored once in the header, so that the remaining script at any
+//! step can be derived from the position of the step.
+//!
+//! A trace consists of the magic bytes [MAGIC], a version byte, a header and
+//! a sequence of records. All integers are encoded as LEB128 varints and all
+//! byte strings are prefixed with their length.
+//!
+//! - header: `<snapshot interval> <script>`
+//! - snapshot: `0x01 <step> <nb items> <stack items..> <nb items> <altstack items..>`
+//! - step: `0x02 <position> <opcode byte> <flags> [<stack diff>] [<altstack diff>]`
+//!   where the flags indicate whether the step was executed and which diffs
+//!   follow, and a diff is `<base> <nb popped> <nb pushed> <pushed items..>`
+//! - end: `0x03 <success byte> <position> <error name>`, with an empty error
+//!   name on success
+//!
+//! Steps are numbered from zero, the state at step `n` is the state after
+//! executing `n` steps. Use [TraceWriter] to write a trace while executing
+//! and [TraceReader] to read it back. The functions encoding the integers and
+//! the items are public, so that other formats can use the same encoding.
+
+use std::io::{self, Read, Write};
+
+use bitcoin::{Opcode, ScriptBuf};
+
+use crate::{Exec, ExecObserver, ExecutionResult, StackDiff, StepDiff, StepInfo};
+
+/// The magic bytes every trace starts with.
+pub const MAGIC: &[u8; 8] = b"BTCTRACE";
+
+/// The version of the trace format.
+pub const VERSION: u8 = 1;
+
+/// The default number of steps between two snapshots.
+pub const DEFAULT_SNAPSHOT_INTERVAL: usize = 1000;
+
+const TAG_SNAPSHOT: u8 = 0x01;
+const TAG_STEP: u8 = 0x02;
+const TAG_END: u8 = 0x03;
+
+const FLAG_EXECUTED: u8 = 0x01;
+const FLAG_STACK_DIFF: u8 = 0x02;
+const FLAG_ALTSTACK_DIFF: u8 = 0x04;
+
+/// Writes the number as a LEB128 varint.
+pub fn write_varint<W: Write>(w: &mut W, mut n: u64) -> io::Result<()> {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            return w.write_all(&[byte]);
+        }
+        w.write_all(&[byte | 0x80])?;
+    }
+}
+
+/// Writes the bytes prefixed with their length.
+pub fn write_bytes<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+    write_varint(w, bytes.len() as u64)?;
+    w.write_all(bytes)
+}
+
+/// Writes the number of items followed by the items, each prefixed with
+/// its length.
+pub fn write_items<W: Write>(w: &mut W, items: &[Vec<u8>]) -> io::Result<()> {
+    write_varint(w, items.len() as u64)?;
+    for item in items {
+        write_bytes(w, item)?;
+    }
+    Ok(())
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_u8<R: Read>(r: &mut R) -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+/// Reads a number written by [write_varint].
+pub fn read_varint<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut ret = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = read_u8(r)?;
+        ret |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(ret);
+        }
+    }
+    Err(invalid_data("varint too long"))
+}
+
+fn read_usize<R: Read>(r: &mut R) -> io::Result<usize> {
+    usize::try_from(read_varint(r)?).map_err(|_| invalid_data("number too large"))
+}
+
+/// Reads bytes written by [write_bytes].
+pub fn read_bytes<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let len = read_usize(r)?;
+    let mut ret = Vec::new();
+    r.take(len as u64).read_to_end(&mut ret)?;
+    if ret.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(ret)
+}
+
+/// Reads items written by [write_items].
+pub fn read_items<R: Read>(r: &mut R) -> io::Result<Vec<Vec<u8>>> {
+    let len = read_usize(r)?;
+    (0..len).map(|_| read_bytes(r)).collect()
+}
+
+/// The change a step made to a stack, like [StackDiff] but without the
+/// popped items.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TraceDiff {
+    /// The number of items at the bottom of the stack that were untouched.
+    pub base: usize,
+    /// The number of items that were removed from the stack.
+    pub nb_popped: usize,
+    /// The items that were added to the stack, bottom to top.
+    pub pushed: Vec<Vec<u8>>,
+}
+
+impl TraceDiff {
+    fn is_empty(&self) -> bool {
+        self.nb_popped == 0 && self.pushed.is_empty()
+    }
+
+    /// Applies the diff to a stack of raw items.
+    ///
+    /// Unchanged stacks are not written in the trace, so an empty diff
+    /// applies to any stack.
+    pub fn apply(&self, stack: &mut Vec<Vec<u8>>) -> io::Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        if stack.len() != self.base + self.nb_popped {
+            return Err(invalid_data("stack diff doesn't match the stack"));
+        }
+        stack.truncate(self.base);
+        stack.extend(self.pushed.iter().cloned());
+        Ok(())
+    }
+
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_varint(w, self.base as u64)?;
+        write_varint(w, self.nb_popped as u64)?;
+        write_items(w, &self.pushed)
+    }
+
+    fn read<R: Read>(r: &mut R) -> io::Result<TraceDiff> {
+        Ok(TraceDiff {
+            base: read_usize(r)?,
+            nb_popped: read_usize(r)?,
+            pushed: read_items(r)?,
+        })
+    }
+}
+
+impl From<&StackDiff> for TraceDiff {
+    fn from(diff: &StackDiff) -> TraceDiff {
+        TraceDiff {
+            base: diff.base,
+            nb_popped: diff.popped.len(),
+            pushed: diff.pushed.clone(),
+        }
+    }
+}
+
+/// A single step of the trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceStep {
+    /// The position of the instruction in the script, in bytes.
+    pub position: usize,
+    /// The opcode of the instruction.
+    pub opcode: Opcode,
+    /// Whether the instruction was actually executed.
+    pub executed: bool,
+    pub stack: TraceDiff,
+    pub altstack: TraceDiff,
+}
+
+/// The full stacks at a step of the trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceSnapshot {
+    pub step: usize,
+    pub stack: Vec<Vec<u8>>,
+    pub altstack: Vec<Vec<u8>>,
+}
+
+/// The end of the execution.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEnd {
+    pub success: bool,
+    /// The script position of the failure, or the script length on success.
+    pub position: usize,
+    /// The name of the error, see [crate::ExecError::name].
+    pub error: Option<String>,
+}
+
+/// A record of the trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceRecord {
+    Snapshot(TraceSnapshot),
+    Step(TraceStep),
+    End(TraceEnd),
+}
+
+/// An [ExecObserver] that writes a trace of the execution.
+///
+/// I/O errors don't interrupt the execution, the first one is returned by
+/// [TraceWriter::finish].
+pub struct TraceWriter<W: Write> {
+    writer: W,
+    snapshot_interval: usize,
+    step: usize,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> TraceWriter<W> {
+    /// Starts a trace of the given execution, which should not have
+    /// executed any steps yet.
+    ///
+    /// A snapshot is written every `snapshot_interval` steps.
+    pub fn new(mut writer: W, exec: &Exec, snapshot_interval: usize) -> io::Result<TraceWriter<W>> {
+        let snapshot_interval = snapshot_interval.max(1);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        write_varint(&mut writer, snapshot_interval as u64)?;
+        write_bytes(&mut writer, exec.script().as_bytes())?;
+
+        let mut ret = TraceWriter {
+            writer,
+            snapshot_interval,
+            step: 0,
+            error: None,
+        };
+        ret.write_snapshot(exec)?;
+        Ok(ret)
+    }
+
+    fn write_snapshot(&mut self, exec: &Exec) -> io::Result<()> {
+        self.writer.write_all(&[TAG_SNAPSHOT])?;
+        write_varint(&mut self.writer, self.step as u64)?;
+        write_items(
+            &mut self.writer,
+            &exec.stack().iter_str().collect::<Vec<_>>(),
+        )?;
+        write_items(
+            &mut self.writer,
+            &exec.altstack().iter_str().collect::<Vec<_>>(),
+        )
+    }
+
+    fn write_step(&mut self, step: &StepInfo, diff: Option<&StepDiff>) -> io::Result<()> {
+        let (stack, altstack) = match diff {
+            Some(d) => (TraceDiff::from(&d.stack), TraceDiff::from(&d.altstack)),
+            None => Default::default(),
+        };
+        let mut flags = 0;
+        if step.executed {
+            flags |= FLAG_EXECUTED;
+        }
+        if !stack.is_empty() {
+            flags |= FLAG_STACK_DIFF;
+        }
+        if !altstack.is_empty() {
+            flags |= FLAG_ALTSTACK_DIFF;
+        }
+
+        self.writer.write_all(&[TAG_STEP])?;
+        write_varint(&mut self.writer, step.position as u64)?;
+        self.writer.write_all(&[step.opcode.to_u8(), flags])?;
+        if !stack.is_empty() {
+            stack.write(&mut self.writer)?;
+        }
+        if !altstack.is_empty() {
+            altstack.write(&mut self.writer)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the end of the execution and returns the writer.
+    pub fn finish(mut self, exec: &Exec, result: &ExecutionResult) -> io::Result<W> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.writer.write_all(&[TAG_END, result.success as u8])?;
+        let position = result.position.unwrap_or(exec.script().len());
+        write_varint(&mut self.writer, position as u64)?;
+        let error = result.error.as_ref().map(|e| e.name()).unwrap_or("");
+        write_bytes(&mut self.writer, error.as_bytes())?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> ExecObserver for TraceWriter<W> {
+    fn post_step(&mut self, exec: &Exec, step: &StepInfo, diff: Option<&StepDiff>) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let mut res = self.write_step(step, diff);
+        self.step += 1;
+        if res.is_ok() && self.step.is_multiple_of(self.snapshot_interval) {
+            res = self.write_snapshot(exec);
+        }
+        if let Err(e) = res {
+            self.error = Some(e);
+        }
+    }
+}
+
+/// The full state at a step of the trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceState {
+    pub step: usize,
+    /// The position of the next instruction to execute.
+    pub position: usize,
+    pub stack: Vec<Vec<u8>>,
+    pub altstack: Vec<Vec<u8>>,
+}
+
+/// Reader of a trace written by [TraceWriter].
+///
+/// The reader keeps track of the stacks while reading, so the full state is
+/// available at every step.
+pub struct TraceReader<R: Read> {
+    reader: R,
+    snapshot_interval: usize,
+    script: ScriptBuf,
+    /// A record that was read but not yet applied.
+    pending: Option<TraceRecord>,
+    finished: bool,
+    /// The number of steps applied to the stacks.
+    nb_steps: usize,
+    stack: Vec<Vec<u8>>,
+    altstack: Vec<Vec<u8>>,
+}
+
+impl<R: Read> TraceReader<R> {
+    /// Reads the header of the trace.
+    pub fn new(mut reader: R) -> io::Result<TraceReader<R>> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a trace file"));
+        }
+        if read_u8(&mut reader)? != VERSION {
+            return Err(invalid_data("unsupported trace version"));
+        }
+        let snapshot_interval = read_usize(&mut reader)?;
+        let script = ScriptBuf::from_bytes(read_bytes(&mut reader)?);
+        Ok(TraceReader {
+            reader,
+            snapshot_interval,
+            script,
+            pending: None,
+            finished: false,
+            nb_steps: 0,
+            stack: vec![],
+            altstack: vec![],
+        })
+    }
+
+    /// The traced script.
+    pub fn script(&self) -> &ScriptBuf {
+        &self.script
+    }
+
+    /// The number of steps between two snapshots.
+    pub fn snapshot_interval(&self) -> usize {
+        self.snapshot_interval
+    }
+
+    /// The number of steps read so far.
+    pub fn nb_steps(&self) -> usize {
+        self.nb_steps
+    }
+
+    /// The stack after the steps read so far.
+    pub fn stack(&self) -> &[Vec<u8>] {
+        &self.stack
+    }
+
+    /// The altstack after the steps read so far.
+    pub fn altstack(&self) -> &[Vec<u8>] {
+        &self.altstack
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<TraceRecord>> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        let r = &mut self.reader;
+        let record = match read_u8(r)? {
+            TAG_SNAPSHOT => TraceRecord::Snapshot(TraceSnapshot {
+                step: read_usize(r)?,
+                stack: read_items(r)?,
+                altstack: read_items(r)?,
+            }),
+            TAG_STEP => {
+                let position = read_usize(r)?;
+                let opcode = Opcode::from(read_u8(r)?);
+                let flags = read_u8(r)?;
+                let stack = match flags & FLAG_STACK_DIFF {
+                    0 => TraceDiff::default(),
+                    _ => TraceDiff::read(r)?,
+                };
+                let altstack = match flags & FLAG_ALTSTACK_DIFF {
+                    0 => TraceDiff::default(),
+                    _ => TraceDiff::read(r)?,
+                };
+                TraceRecord::Step(TraceStep {
+                    position,
+                    opcode,
+                    executed: flags & FLAG_EXECUTED != 0,
+                    stack,
+                    altstack,
+                })
+            }
+            TAG_END => {
+                let success = read_u8(r)? != 0;
+                let position = read_usize(r)?;
+                let error = read_bytes(r)?;
+                let error = if error.is_empty() {
+                    None
+                } else {
+                    let name =
+                        String::from_utf8(error).map_err(|_| invalid_data("invalid error name"))?;
+                    Some(name)
+                };
+                self.finished = true;
+                TraceRecord::End(TraceEnd {
+                    success,
+                    position,
+                    error,
+                })
+            }
+            _ => return Err(invalid_data("unknown record type")),
+        };
+        Ok(Some(record))
+    }
+
+    fn peek_record(&mut self) -> io::Result<Option<&TraceRecord>> {
+        if self.pending.is_none() {
+            self.pending = self.read_record()?;
+        }
+        Ok(self.pending.as_ref())
+    }
+
+    /// Reads the next record and applies it to the stacks.
+    ///
+    /// Returns None after the end record.
+    pub fn next_record(&mut self) -> io::Result<Option<TraceRecord>> {
+        let record = match self.pending.take() {
+            Some(r) => r,
+            None => match self.read_record()? {
+                Some(r) => r,
+                None => return Ok(None),
+            },
+        };
+
+        match record {
+            TraceRecord::Snapshot(ref s) => {
+                if s.step != self.nb_steps {
+                    return Err(invalid_data("snapshot at unexpected step"));
+                }
+                self.stack = s.stack.clone();
+                self.altstack = s.altstack.clone();
+            }
+            TraceRecord::Step(ref s) => {
+                s.stack.apply(&mut self.stack)?;
+                s.altstack.apply(&mut self.altstack)?;
+                self.nb_steps += 1;
+            }
+            TraceRecord::End(_) => {}
+        }
+        Ok(Some(record))
+    }
+
+    /// Reconstructs the full state at the given step.
+    ///
+    /// Returns None if the trace has fewer steps. The reader can't go back,
+    /// so steps must be requested in increasing order.
+    pub fn state_at(&mut self, step: usize) -> io::Result<Option<TraceState>> {
+        if step < self.nb_steps {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the trace was already read past this step",
+            ));
+        }
+
+        loop {
+            let (position, is_end) = match self.peek_record()? {
+                None => return Ok(None),
+                Some(TraceRecord::Snapshot(_)) => (None, false),
+                Some(TraceRecord::Step(s)) => (Some(s.position), false),
+                Some(TraceRecord::End(e)) => (Some(e.position), true),
+            };
+            if self.nb_steps == step {
+                if let Some(position) = position {
+                    return Ok(Some(TraceState {
+                        step,
+                        position,
+                        stack: self.stack.clone(),
+                        altstack: self.altstack.clone(),
+                    }));
+                }
+            } else if is_end {
+                return Ok(None);
+            }
+            self.next_record()?;
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use bitcoin::script::{self, PushBytesBuf, Script};
+
 ///  A data type to abstract out the condition stack during script execution.
 ///
 /// Conceptually it acts like a vector of booleans, one for each level of nested
@@ -12,6 +14,8 @@
 /// This uses an optimized implementation that does not materialize the
 /// actual stack. Instead, it just stores the size of the would-be stack,
 /// and the position of the first false value in it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ConditionStack {
     /// The size of the implied stack.
     size: usize,
@@ -31,6 +35,10 @@ impl ConditionStack {
         }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
     pub fn all_true(&self) -> bool {
         self.first_false_pos == Self::NO_FALSE
     }
@@ -78,3 +86,42 @@ impl ConditionStack {
         }
     }
 }
+
+/// Removes all pushes of `sig` from the script code, like Bitcoin Core's
+/// `FindAndDelete`.
+///
+/// Matches are only considered at opcode boundaries. Returns the resulting
+/// script code and the number of occurrences that were removed.
+pub fn find_and_delete(script_code: &[u8], sig: &[u8]) -> (Vec<u8>, usize) {
+    let pattern = match PushBytesBuf::try_from(sig.to_vec()) {
+        Ok(push) => script::Builder::new().push_slice(push).into_bytes(),
+        // Stack elements this large can't occur in a valid script.
+        Err(_) => return (script_code.to_vec(), 0),
+    };
+
+    let mut result = Vec::with_capacity(script_code.len());
+    let mut nb_found = 0;
+    let mut pos = 0;
+    loop {
+        while script_code[pos..].starts_with(&pattern) {
+            pos += pattern.len();
+            nb_found += 1;
+        }
+
+        let mut instructions = Script::from_bytes(&script_code[pos..]).instructions();
+        match instructions.next() {
+            Some(Ok(_)) => {
+                let next_pos = script_code.len() - instructions.as_script().len();
+                result.extend_from_slice(&script_code[pos..next_pos]);
+                pos = next_pos;
+            }
+            // Either the end of the script or a parsing error, copy the remainder.
+            _ => {
+                result.extend_from_slice(&script_code[pos..]);
+                break;
+            }
+        }
+    }
+
+    (result, nb_found)
+}
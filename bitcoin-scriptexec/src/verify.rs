@@ -0,0 +1,409 @@
+//! Full validation of transaction inputs.
+//!
+//! [Exec] only executes a single script on a given stack. The functions in
+//! this module perform everything else that is needed to check that an input
+//! of a transaction correctly spends its prevout: scriptSig and P2SH handling,
+//! witness program matching, annex handling, taproot key path signatures and
+//! control block merkle proofs.
+
+use bitcoin::consensus::Encodable;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::opcodes::{all::*, Class, ClassifyContext};
+use bitcoin::script::{self, Instruction, PushBytesBuf, Script, ScriptBuf};
+use bitcoin::secp256k1::{schnorr, XOnlyPublicKey};
+use bitcoin::sighash::{Annex, Prevouts, SighashCache, TapSighashType};
+use bitcoin::taproot::{self, ControlBlock, LeafVersion, TapLeafHash};
+use bitcoin::transaction::{Transaction, TxOut};
+use bitcoin::PubkeyHash;
+
+use crate::signatures::SECP;
+use crate::*;
+
+/// Size of a P2WPKH witness program.
+const WITNESS_V0_KEYHASH_SIZE: usize = 20;
+
+/// Size of a P2WSH witness program.
+const WITNESS_V0_SCRIPTHASH_SIZE: usize = 32;
+
+/// Size of a taproot (segwit v1) witness program.
+const WITNESS_V1_TAPROOT_SIZE: usize = 32;
+
+/// Maximum number of nodes in a control block merkle path.
+const TAPROOT_CONTROL_MAX_NODE_COUNT: usize = 128;
+
+/// Verifies that input `input_idx` of `tx` correctly spends its prevout,
+/// using the default [Options].
+///
+/// `prevouts` must contain the outputs spent by all inputs of `tx`, in order.
+///
+/// Script failures are reported as [Error::Exec].
+pub fn verify_input(tx: &Transaction, input_idx: usize, prevouts: &[TxOut]) -> Result<(), Error> {
+    verify_input_with_options(Options::default(), tx, input_idx, prevouts)
+}
+
+/// Verifies that input `input_idx` of `tx` correctly spends its prevout.
+///
+/// See [verify_input] for more details.
+pub fn verify_input_with_options(
+    opt: Options,
+    tx: &Transaction,
+    input_idx: usize,
+    prevouts: &[TxOut],
+) -> Result<(), Error> {
+    if input_idx >= tx.input.len() {
+        return Err(Error::Other("input index out of bounds"));
+    }
+    if prevouts.len() != tx.input.len() {
+        return Err(Error::Other(
+            "number of prevouts doesn't match number of inputs",
+        ));
+    }
+
+    let verifier = InputVerifier {
+        opt,
+        tx,
+        input_idx,
+        prevouts,
+    };
+    verifier.verify()
+}
+
+/// Returns the witness version and program if the script is a witness program.
+fn witness_program(script: &Script) -> Option<(u8, &[u8])> {
+    if !script.is_witness_program() {
+        return None;
+    }
+
+    let bytes = script.as_bytes();
+    let version = match bytes[0] {
+        0 => 0,
+        op => op - OP_PUSHNUM_1.to_u8() + 1,
+    };
+    Some((version, &bytes[2..]))
+}
+
+struct InputVerifier<'a> {
+    opt: Options,
+    tx: &'a Transaction,
+    input_idx: usize,
+    prevouts: &'a [TxOut],
+}
+
+impl<'a> InputVerifier<'a> {
+    fn verify(&self) -> Result<(), Error> {
+        let txin = &self.tx.input[self.input_idx];
+        let script_sig = &txin.script_sig;
+        let script_pubkey = &self.prevouts[self.input_idx].script_pubkey;
+        let witness = txin.witness.to_vec();
+
+        if self.opt.verify_sig_push_only && !script_sig.is_push_only() {
+            return Err(ExecError::SigPushOnly.into());
+        }
+
+        // Native witness programs require an empty scriptSig.
+        if let Some((version, program)) = witness_program(script_pubkey) {
+            if self.opt.verify_witness {
+                if !script_sig.is_empty() {
+                    return Err(ExecError::WitnessMalleated.into());
+                }
+                return self.verify_witness_program(version, program, witness, false);
+            }
+        }
+
+        let stack = self.execute(ExecCtx::Legacy, script_sig.clone(), Stack::new())?;
+        let stack_copy = stack.clone();
+        let res = self.execute_to_result(ExecCtx::Legacy, script_pubkey.clone(), stack)?;
+        if !res.success {
+            return Err(ExecError::EvalFalse.into());
+        }
+        let mut final_stack = res.final_stack;
+
+        if self.opt.verify_p2sh && script_pubkey.is_p2sh() {
+            // The scriptSig of a P2SH spend can only push the redeem script and its inputs.
+            if !script_sig.is_push_only() {
+                return Err(ExecError::SigPushOnly.into());
+            }
+
+            // The P2SH script has succeeded, so the stack can't be empty.
+            let mut stack = stack_copy;
+            let redeem_script = ScriptBuf::from_bytes(stack.popstr()?);
+
+            if self.opt.verify_witness {
+                if let Some((version, program)) = witness_program(&redeem_script) {
+                    // The scriptSig must be exactly a single push of the redeem script,
+                    // otherwise we'd introduce malleability.
+                    let push = PushBytesBuf::try_from(redeem_script.to_bytes())
+                        .map_err(|_| ExecError::PushSize)?;
+                    if script_sig.as_bytes() != script::Builder::new().push_slice(push).as_bytes() {
+                        return Err(ExecError::WitnessMalleatedP2SH.into());
+                    }
+                    return self.verify_witness_program(version, program, witness, true);
+                }
+            }
+
+            let res = self.execute_to_result(ExecCtx::Legacy, redeem_script, stack)?;
+            if !res.success {
+                return Err(ExecError::EvalFalse.into());
+            }
+            final_stack = res.final_stack;
+        }
+
+        // The clean stack check is only performed after potential P2SH evaluation.
+        if self.opt.verify_clean_stack && final_stack.len() != 1 {
+            return Err(ExecError::CleanStack.into());
+        }
+
+        // Non-witness spends can't carry witness data.
+        if self.opt.verify_witness && !witness.is_empty() {
+            return Err(ExecError::WitnessUnexpected.into());
+        }
+
+        Ok(())
+    }
+
+    fn verify_witness_program(
+        &self,
+        version: u8,
+        program: &[u8],
+        mut witness: Vec<Vec<u8>>,
+        is_p2sh: bool,
+    ) -> Result<(), Error> {
+        if version == 0 {
+            let script = if program.len() == WITNESS_V0_SCRIPTHASH_SIZE {
+                let script = witness.pop().ok_or(ExecError::WitnessProgramWitnessEmpty)?;
+                if sha256::Hash::hash(&script).as_byte_array()[..] != program[..] {
+                    return Err(ExecError::WitnessProgramMismatch.into());
+                }
+                ScriptBuf::from_bytes(script)
+            } else if program.len() == WITNESS_V0_KEYHASH_SIZE {
+                if witness.len() != 2 {
+                    return Err(ExecError::WitnessProgramMismatch.into());
+                }
+                let pkh = PubkeyHash::from_slice(program).expect("checked length");
+                ScriptBuf::new_p2pkh(&pkh)
+            } else {
+                return Err(ExecError::WitnessProgramWrongLength.into());
+            };
+
+            self.check_witness_element_sizes(&witness)?;
+            let res =
+                self.execute_to_result(ExecCtx::SegwitV0, script, Stack::from_u8_vec(witness))?;
+            return Self::check_witness_result(&res);
+        }
+
+        if version == 1 && program.len() == WITNESS_V1_TAPROOT_SIZE && !is_p2sh {
+            if !self.opt.verify_taproot {
+                return Ok(());
+            }
+            return self.verify_taproot(program, witness);
+        }
+
+        // Future witness versions are anyone-can-spend.
+        if self.opt.discourage_upgradable_witness_program {
+            return Err(ExecError::DiscourageUpgradableWitnessProgram.into());
+        }
+        Ok(())
+    }
+
+    fn verify_taproot(&self, program: &[u8], mut witness: Vec<Vec<u8>>) -> Result<(), Error> {
+        if witness.is_empty() {
+            return Err(ExecError::WitnessProgramWitnessEmpty.into());
+        }
+
+        // The validation weight budget is based on the full witness.
+        let witness_size = Encodable::consensus_encode(&witness, &mut bitcoin::io::sink()).unwrap();
+        let start_validation_weight = VALIDATION_WEIGHT_OFFSET + witness_size as i64;
+
+        let annex = if witness.len() >= 2
+            && witness.last().unwrap().first() == Some(&taproot::TAPROOT_ANNEX_PREFIX)
+        {
+            witness.pop()
+        } else {
+            None
+        };
+
+        if witness.len() == 1 {
+            // Key path spending.
+            let sig = witness.pop().unwrap();
+            return self.verify_taproot_key_spend(program, &sig, annex);
+        }
+
+        // Script path spending.
+        let control_block = witness.pop().unwrap();
+        let script = ScriptBuf::from_bytes(witness.pop().unwrap());
+        if control_block.len() < taproot::TAPROOT_CONTROL_BASE_SIZE
+            || control_block.len()
+                > taproot::TAPROOT_CONTROL_BASE_SIZE
+                    + TAPROOT_CONTROL_MAX_NODE_COUNT * taproot::TAPROOT_CONTROL_NODE_SIZE
+            || !(control_block.len() - taproot::TAPROOT_CONTROL_BASE_SIZE)
+                .is_multiple_of(taproot::TAPROOT_CONTROL_NODE_SIZE)
+        {
+            return Err(ExecError::TaprootWrongControlSize.into());
+        }
+        let control_block =
+            ControlBlock::decode(&control_block).map_err(|_| ExecError::TaprootWrongControlSize)?;
+        let output_key =
+            XOnlyPublicKey::from_slice(program).map_err(|_| ExecError::WitnessProgramMismatch)?;
+        if !control_block.verify_taproot_commitment(&SECP, output_key, &script) {
+            return Err(ExecError::WitnessProgramMismatch.into());
+        }
+
+        if control_block.leaf_version != LeafVersion::TapScript {
+            // Future leaf versions are anyone-can-spend.
+            if self.opt.discourage_upgradable_taproot_version {
+                return Err(ExecError::DiscourageUpgradableTaprootVersion.into());
+            }
+            return Ok(());
+        }
+
+        // Any OP_SUCCESSx in the script makes the spend valid without execution.
+        for instruction in script.instructions() {
+            match instruction {
+                Ok(Instruction::Op(op)) => {
+                    if op == OP_CAT && self.opt.experimental.op_cat {
+                        continue;
+                    }
+                    if op == OP_CHECKSIGFROMSTACK && self.opt.experimental.op_csfs {
+                        continue;
+                    }
+                    if op.classify(ClassifyContext::TapScript) == Class::SuccessOp {
+                        if self.opt.discourage_op_success {
+                            return Err(ExecError::DiscourageOpSuccess.into());
+                        }
+                        return Ok(());
+                    }
+                }
+                Ok(Instruction::PushBytes(_)) => {}
+                Err(_) => return Err(ExecError::BadOpcode.into()),
+            }
+        }
+
+        // Tapscript limits the initial stack size, the altstack is empty here.
+        if witness.len() > MAX_STACK_SIZE {
+            return Err(ExecError::StackSize.into());
+        }
+        self.check_witness_element_sizes(&witness)?;
+        let leaf_hash = TapLeafHash::from_script(&script, LeafVersion::TapScript);
+        let mut exec = Exec::new(
+            ExecCtx::Tapscript,
+            self.opt.clone(),
+            self.tx_template(Some((leaf_hash, annex))),
+            script,
+            witness,
+        )?;
+        exec.set_start_validation_weight(start_validation_weight);
+        let res = run_to_result(&mut exec)?;
+        Self::check_witness_result(&res)
+    }
+
+    fn verify_taproot_key_spend(
+        &self,
+        program: &[u8],
+        sig: &[u8],
+        annex: Option<Vec<u8>>,
+    ) -> Result<(), Error> {
+        let (sig, hashtype) = match sig.len() {
+            64 => (sig, TapSighashType::Default),
+            65 => {
+                let b = sig[64];
+                if b == TapSighashType::Default as u8 {
+                    return Err(ExecError::SchnorrSigHashtype.into());
+                }
+                let hashtype = TapSighashType::from_consensus_u8(b)
+                    .map_err(|_| ExecError::SchnorrSigHashtype)?;
+                (&sig[..64], hashtype)
+            }
+            _ => return Err(ExecError::SchnorrSigSize.into()),
+        };
+        let sig = schnorr::Signature::from_slice(sig).map_err(|_| ExecError::SchnorrSig)?;
+        let pk = XOnlyPublicKey::from_slice(program).map_err(|_| ExecError::SchnorrSig)?;
+
+        let annex = match annex {
+            Some(ref a) => {
+                Some(Annex::new(a).map_err(|_| Error::Other("invalid annex: missing prefix"))?)
+            }
+            None => None,
+        };
+        let sighash = SighashCache::new(self.tx)
+            .taproot_signature_hash(
+                self.input_idx,
+                &Prevouts::All(self.prevouts),
+                annex,
+                None,
+                hashtype,
+            )
+            .map_err(|_| ExecError::SchnorrSigHashtype)?;
+
+        if SECP.verify_schnorr(&sig, &sighash.into(), &pk).is_err() {
+            return Err(ExecError::SchnorrSig.into());
+        }
+        Ok(())
+    }
+
+    /// Witness stack elements are subject to the same limit as pushes.
+    fn check_witness_element_sizes(&self, witness: &[Vec<u8>]) -> Result<(), Error> {
+        if witness.iter().any(|i| i.len() > MAX_SCRIPT_ELEMENT_SIZE) {
+            return Err(ExecError::PushSize.into());
+        }
+        Ok(())
+    }
+
+    /// Witness scripts must leave exactly one true element on the stack.
+    fn check_witness_result(res: &ExecutionResult) -> Result<(), Error> {
+        if res.success {
+            Ok(())
+        } else if res.final_stack.len() != 1 {
+            Err(ExecError::CleanStack.into())
+        } else {
+            Err(ExecError::EvalFalse.into())
+        }
+    }
+
+    fn tx_template(
+        &self,
+        taproot_annex_scriptleaf: Option<(TapLeafHash, Option<Vec<u8>>)>,
+    ) -> TxTemplate {
+        TxTemplate {
+            tx: self.tx.clone(),
+            prevouts: self.prevouts.to_vec(),
+            input_idx: self.input_idx,
+            taproot_annex_scriptleaf,
+        }
+    }
+
+    /// Executes the script on the given stack and returns the execution result.
+    fn execute_to_result(
+        &self,
+        ctx: ExecCtx,
+        script: ScriptBuf,
+        stack: Stack,
+    ) -> Result<ExecutionResult, Error> {
+        let mut exec = Exec::with_stack(
+            ctx,
+            self.opt.clone(),
+            self.tx_template(None),
+            script,
+            vec![],
+            stack,
+            Stack::new(),
+        )?;
+        run_to_result(&mut exec)
+    }
+
+    /// Executes the script on the given stack and returns the final stack.
+    fn execute(&self, ctx: ExecCtx, script: ScriptBuf, stack: Stack) -> Result<Stack, Error> {
+        Ok(self.execute_to_result(ctx, script, stack)?.final_stack)
+    }
+}
+
+/// Runs the execution to the end, turning execution errors into [Error::Exec].
+fn run_to_result(exec: &mut Exec) -> Result<ExecutionResult, Error> {
+    loop {
+        if let Err(res) = exec.exec_next() {
+            return match res.error {
+                Some(ref err) => Err(Error::Exec(err.clone())),
+                None => Ok(res.clone()),
+            };
+        }
+    }
+}
@@ -1,7 +1,8 @@
-use bitcoin::hashes::Hash;
+use std::collections::BTreeSet;
+
+use bitcoin::consensus::encode::deserialize_hex;
 use bitcoin::hex::{DisplayHex, FromHex};
-use bitcoin::taproot::TapLeafHash;
-use bitcoin::{ScriptBuf, Transaction};
+use bitcoin::{Amount, ScriptBuf, Transaction, TxOut};
 use serde_json::json;
 use wasm_bindgen::prelude::*;
 
@@ -22,13 +23,55 @@ pub fn script_hex_to_asm(script_hex: &str) -> Result<String, JsValue> {
     Ok(script.to_asm_string())
 }
 
+fn parse_witness(script_witness: &[JsValue]) -> Result<Vec<Vec<u8>>, JsValue> {
+    let mut ret = Vec::with_capacity(script_witness.len());
+    for item in script_witness.iter() {
+        let hex = item
+            .as_string()
+            .ok_or("script witness must be list of hex strings")?;
+        let bytes = Vec::from_hex(&hex).map_err(|_| "invalid hex in script witness")?;
+        ret.push(bytes);
+    }
+    Ok(ret)
+}
+
+fn stack_to_json(stack: &Stack) -> Vec<String> {
+    stack.iter_str().map(|i| i.as_hex().to_string()).collect()
+}
+
+/// The result object of [run_script] and [Executor::result].
+fn result_to_json(res: &ExecutionResult, stats: &ExecStats) -> serde_json::Value {
+    let mut ret = json!({
+        "success": res.success,
+        "final_stack": stack_to_json(&res.final_stack),
+        "stats": serde_json::to_value(stats).unwrap(),
+    });
+    if !res.success {
+        let obj = ret.as_object_mut().unwrap();
+        obj.insert(
+            "last_opcode".into(),
+            res.opcode.map(|o| o.to_string()).unwrap_or_default().into(),
+        );
+        if let Some(ref err) = res.error {
+            obj.insert("error".into(), err.to_string().into());
+            obj.insert("error_code".into(), err.name().into());
+        }
+        if let Some(position) = res.position {
+            obj.insert("position".into(), position.into());
+        }
+    }
+    ret
+}
+
 /// Run the given script.
 ///
 /// Fields on the return value are:
 /// - success: bool
 /// - final_stack: list of hex stack items after execution
-/// - error: (optional) error that caused execution halt
+/// - error: (optional) error message of the error that caused execution halt
+/// - error_code: (optional) Bitcoin Core name of the error, like `EVAL_FALSE`
 /// - last_opcode: (optional) last opcode run before error produced
+/// - position: (optional) script position of the opcode that produced the error
 /// - stats: execution runtime statistics with following fields:
 ///   - max_nb_stack_items
 ///   - max_stack_size
@@ -41,64 +84,226 @@ pub fn run_script(script_hex: &str, script_witness: Box<[JsValue]>) -> Result<Js
     console_error_panic_hook::set_once();
 
     let script =
-        ScriptBuf::from_hex(script_hex).map_err(|e| format!("invalid hex script: {:?}", e))?;
-    let witness = {
-        let mut ret = Vec::with_capacity(script_witness.len());
-        for item in script_witness.iter() {
-            let hex = item
-                .as_string()
-                .ok_or("script witness must be list of hex strings")?;
-            let bytes = Vec::from_hex(&hex).map_err(|_| "invalid hex in script witness")?;
-            ret.push(bytes);
-        }
-        ret
-    };
+        ScriptBuf::from_hex(script_hex).map_err(|e| format!("invalid hex script: {}", e))?;
+    let witness = parse_witness(&script_witness)?;
 
     let mut exec = Exec::new(
         ExecCtx::Tapscript,
         Options::default(),
-        TxTemplate {
-            tx: Transaction {
-                version: bitcoin::transaction::Version::TWO,
-                lock_time: bitcoin::locktime::absolute::LockTime::ZERO,
-                input: vec![],
-                output: vec![],
-            },
-            prevouts: vec![],
-            input_idx: 0,
-            taproot_annex_scriptleaf: Some((TapLeafHash::all_zeros(), None)),
-        },
+        TxTemplate::dummy(ExecCtx::Tapscript),
         script,
         witness,
     )
-    .map_err(|e| format!("error creating exec: {:?}", e))?;
+    .map_err(|e| format!("error creating exec: {}", e))?;
 
     loop {
         if let Err(res) = exec.exec_next() {
             let res = res.clone();
-            let mut ret = json!({
-                "success": res.success,
-                "final_stack": res.final_stack.iter_str()
-                    .map(|i| i.as_hex().to_string())
-                    .collect::<Vec<_>>(),
-                "stats": serde_json::to_value(exec.stats()).unwrap(),
+            let ret = result_to_json(&res, exec.stats());
+            return Ok(serde_wasm_bindgen::to_value(&ret).unwrap());
+        }
+    }
+}
+
+/// An output spent by the transaction, as passed from JS.
+#[derive(serde::Deserialize)]
+struct JsPrevout {
+    /// The amount in sats.
+    value: u64,
+    /// The scriptPubkey in hex.
+    script_pubkey: String,
+}
+
+/// The execution options of an [Executor], as passed from JS.
+#[derive(serde::Deserialize, Default)]
+#[serde(default)]
+struct JsExecOptions {
+    /// "legacy", "segwitv0" or "tapscript" (default).
+    ctx: Option<String>,
+    /// The spending transaction in hex.
+    tx: Option<String>,
+    prevouts: Vec<JsPrevout>,
+    input_idx: usize,
+}
+
+impl JsExecOptions {
+    fn ctx(&self) -> Result<ExecCtx, String> {
+        match self.ctx.as_deref() {
+            None | Some("tapscript") => Ok(ExecCtx::Tapscript),
+            Some("segwitv0") => Ok(ExecCtx::SegwitV0),
+            Some("legacy") => Ok(ExecCtx::Legacy),
+            Some(ctx) => Err(format!("unknown execution context: {}", ctx)),
+        }
+    }
+
+    fn tx_template(&self, ctx: ExecCtx, script: &ScriptBuf) -> Result<TxTemplate, String> {
+        let tx_hex = match self.tx {
+            Some(ref tx) => tx,
+            None => return Ok(TxTemplate::dummy(ctx)),
+        };
+
+        let tx: Transaction =
+            deserialize_hex(tx_hex).map_err(|e| format!("invalid transaction: {}", e))?;
+        let mut prevouts = Vec::with_capacity(self.prevouts.len());
+        for prevout in &self.prevouts {
+            prevouts.push(TxOut {
+                value: Amount::from_sat(prevout.value),
+                script_pubkey: ScriptBuf::from_hex(&prevout.script_pubkey)
+                    .map_err(|e| format!("invalid prevout scriptPubkey: {}", e))?,
             });
-            if !res.success {
-                let obj = ret.as_object_mut().unwrap();
-                obj.insert(
-                    "last_opcode".into(),
-                    res.opcode.map(|o| o.to_string()).unwrap_or_default().into(),
-                );
-                obj.insert(
-                    "error".into(),
-                    res.error
-                        .map(|o| format!("{:?}", o))
-                        .unwrap_or_default()
-                        .into(),
-                );
+        }
+        TxTemplate::for_input(ctx, script, Some((tx, prevouts)), self.input_idx)
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// A stateful script execution that can be stepped through.
+///
+/// Breakpoints are script positions: [Executor::run] stops before executing
+/// the instruction at a breakpoint.
+#[wasm_bindgen]
+pub struct Executor {
+    exec: Exec,
+    breakpoints: BTreeSet<usize>,
+    nb_steps: usize,
+}
+
+#[wasm_bindgen]
+impl Executor {
+    /// Creates an execution of the given script.
+    ///
+    /// The optional options object can have the following fields:
+    /// - ctx: "legacy", "segwitv0" or "tapscript" (default)
+    /// - tx: the spending transaction in hex
+    /// - prevouts: the outputs spent by the transaction, as objects with
+    ///   the amount in sats as `value` and the hex `script_pubkey`
+    /// - input_idx: the index of the executed input
+    ///
+    /// Without a transaction, an empty dummy transaction is used.
+    #[wasm_bindgen(constructor)]
+    #[allow(clippy::boxed_local)]
+    pub fn new(
+        script_hex: &str,
+        script_witness: Box<[JsValue]>,
+        options: JsValue,
+    ) -> Result<Executor, JsValue> {
+        console_error_panic_hook::set_once();
+
+        let script =
+            ScriptBuf::from_hex(script_hex).map_err(|e| format!("invalid hex script: {}", e))?;
+        let witness = parse_witness(&script_witness)?;
+        let options: JsExecOptions = if options.is_undefined() || options.is_null() {
+            JsExecOptions::default()
+        } else {
+            serde_wasm_bindgen::from_value(options)
+                .map_err(|e| format!("invalid options: {}", e))?
+        };
+        let ctx = options.ctx()?;
+        let tx = options.tx_template(ctx, &script)?;
+
+        let exec = Exec::new(ctx, Options::default(), tx, script, witness)
+            .map_err(|e| format!("error creating exec: {}", e))?;
+        Ok(Executor {
+            exec,
+            breakpoints: BTreeSet::new(),
+            nb_steps: 0,
+        })
+    }
+
+    /// Executes the next instruction. Returns true if the execution finished.
+    pub fn step(&mut self) -> bool {
+        if self.exec.exec_next().is_err() {
+            return true;
+        }
+        self.nb_steps += 1;
+        false
+    }
+
+    /// Executes instructions until the next instruction is at the given
+    /// script position, a breakpoint is hit or the execution finishes.
+    /// At least one instruction is executed.
+    ///
+    /// Returns true if the execution finished.
+    pub fn run_until(&mut self, position: usize) -> bool {
+        loop {
+            if self.step() {
+                return true;
+            }
+            let next = self.exec.script_position();
+            if next == position || self.breakpoints.contains(&next) {
+                return false;
             }
+        }
+    }
 
-            return Ok(serde_wasm_bindgen::to_value(&ret).unwrap());
+    /// Executes instructions until a breakpoint is hit or the execution
+    /// finishes. At least one instruction is executed.
+    ///
+    /// Returns true if the execution finished.
+    pub fn run(&mut self) -> bool {
+        self.run_until(usize::MAX)
+    }
+
+    pub fn add_breakpoint(&mut self, position: usize) {
+        self.breakpoints.insert(position);
+    }
+
+    pub fn remove_breakpoint(&mut self, position: usize) {
+        self.breakpoints.remove(&position);
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// The positions of all breakpoints, in increasing order.
+    pub fn breakpoints(&self) -> Vec<usize> {
+        self.breakpoints.iter().copied().collect()
+    }
+
+    /// The script position of the next instruction to execute.
+    pub fn position(&self) -> usize {
+        self.exec.script_position()
+    }
+
+    /// The number of instructions executed so far.
+    pub fn nb_steps(&self) -> usize {
+        self.nb_steps
+    }
+
+    /// The remaining script in ASM.
+    pub fn remaining_script_asm(&self) -> String {
+        self.exec.remaining_script().to_asm_string()
+    }
+
+    /// The stack items in hex, bottom first.
+    pub fn stack(&self) -> Vec<String> {
+        stack_to_json(self.exec.stack())
+    }
+
+    /// The altstack items in hex, bottom first.
+    pub fn altstack(&self) -> Vec<String> {
+        stack_to_json(self.exec.altstack())
+    }
+
+    /// The execution statistics, with the fields described in [run_script].
+    pub fn stats(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(self.exec.stats()).unwrap()
+    }
+
+    pub fn finished(&self) -> bool {
+        self.exec.result().is_some()
+    }
+
+    /// The result of the execution, in the format of [run_script], or null
+    /// if the execution didn't finish yet.
+    pub fn result(&self) -> JsValue {
+        match self.exec.result() {
+            Some(res) => {
+                let ret = result_to_json(res, self.exec.stats());
+                serde_wasm_bindgen::to_value(&ret).unwrap()
+            }
+            None => JsValue::NULL,
         }
     }
 }
@@ -0,0 +1,485 @@
+//! Differential tests with script and transaction test vectors in the format
+//! of Bitcoin Core's `script_tests.json`, `tx_valid.json` and `tx_invalid.json`.
+//!
+//! The vectors are read from the `*_local.json` files in the `tests/data`
+//! directory, see `tests/data/README.md`. Bitcoin Core's own vector files are
+//! not part of the repository.
+//!
+//! Every vector whose outcome differs from the expected one is reported, the
+//! test fails if there is at least one mismatch.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use bitcoin::consensus::encode::deserialize_hex;
+use bitcoin::hex::FromHex;
+use bitcoin::opcodes::all::*;
+use bitcoin::opcodes::Opcode;
+use bitcoin::script::{self, ScriptBuf};
+use bitcoin::{
+    absolute, transaction, Amount, OutPoint, Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+};
+use serde_json::Value;
+
+use bitcoin_scriptexec::{verify_input_with_options, Error, Experimental, Options};
+
+/// The names of all script verification flags as used in the test vectors.
+const FLAG_NAMES: &[&str] = &[
+    "P2SH",
+    "STRICTENC",
+    "DERSIG",
+    "LOW_S",
+    "NULLDUMMY",
+    "SIGPUSHONLY",
+    "MINIMALDATA",
+    "DISCOURAGE_UPGRADABLE_NOPS",
+    "CLEANSTACK",
+    "CHECKLOCKTIMEVERIFY",
+    "CHECKSEQUENCEVERIFY",
+    "WITNESS",
+    "DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM",
+    "MINIMALIF",
+    "NULLFAIL",
+    "WITNESS_PUBKEYTYPE",
+    "CONST_SCRIPTCODE",
+    "TAPROOT",
+    "DISCOURAGE_UPGRADABLE_TAPROOT_VERSION",
+    "DISCOURAGE_OP_SUCCESS",
+    "DISCOURAGE_UPGRADABLE_PUBKEYTYPE",
+];
+
+/// Options without any of the script verification flags.
+fn no_flags() -> Options {
+    Options {
+        verify_p2sh: false,
+        verify_strict_enc: false,
+        verify_der_sig: false,
+        verify_low_s: false,
+        verify_null_dummy: false,
+        verify_sig_push_only: false,
+        require_minimal: false,
+        discourage_upgradable_nops: false,
+        verify_clean_stack: false,
+        verify_cltv: false,
+        verify_csv: false,
+        verify_witness: false,
+        discourage_upgradable_witness_program: false,
+        verify_minimal_if: false,
+        verify_null_fail: false,
+        verify_witness_pubkey_type: false,
+        verify_const_scriptcode: false,
+        verify_taproot: false,
+        discourage_upgradable_taproot_version: false,
+        discourage_op_success: false,
+        discourage_upgradable_pubkey_type: false,
+        enforce_stack_limit: true,
+        experimental: Experimental::default(),
+    }
+}
+
+fn set_flag(opt: &mut Options, name: &str, value: bool) -> Result<(), String> {
+    let flag = match name {
+        "P2SH" => &mut opt.verify_p2sh,
+        "STRICTENC" => &mut opt.verify_strict_enc,
+        "DERSIG" => &mut opt.verify_der_sig,
+        "LOW_S" => &mut opt.verify_low_s,
+        "NULLDUMMY" => &mut opt.verify_null_dummy,
+        "SIGPUSHONLY" => &mut opt.verify_sig_push_only,
+        "MINIMALDATA" => &mut opt.require_minimal,
+        "DISCOURAGE_UPGRADABLE_NOPS" => &mut opt.discourage_upgradable_nops,
+        "CLEANSTACK" => &mut opt.verify_clean_stack,
+        "CHECKLOCKTIMEVERIFY" => &mut opt.verify_cltv,
+        "CHECKSEQUENCEVERIFY" => &mut opt.verify_csv,
+        "WITNESS" => &mut opt.verify_witness,
+        "DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM" => &mut opt.discourage_upgradable_witness_program,
+        "MINIMALIF" => &mut opt.verify_minimal_if,
+        "NULLFAIL" => &mut opt.verify_null_fail,
+        "WITNESS_PUBKEYTYPE" => &mut opt.verify_witness_pubkey_type,
+        "CONST_SCRIPTCODE" => &mut opt.verify_const_scriptcode,
+        "TAPROOT" => &mut opt.verify_taproot,
+        "DISCOURAGE_UPGRADABLE_TAPROOT_VERSION" => &mut opt.discourage_upgradable_taproot_version,
+        "DISCOURAGE_OP_SUCCESS" => &mut opt.discourage_op_success,
+        "DISCOURAGE_UPGRADABLE_PUBKEYTYPE" => &mut opt.discourage_upgradable_pubkey_type,
+        _ => return Err(format!("unknown flag: {}", name)),
+    };
+    *flag = value;
+    Ok(())
+}
+
+fn flag_list(flags: &str) -> impl Iterator<Item = &str> {
+    flags.split(',').filter(|f| !f.is_empty() && *f != "NONE")
+}
+
+/// Options with exactly the given comma-separated flags.
+fn parse_flags(flags: &str) -> Result<Options, String> {
+    let mut opt = no_flags();
+    for flag in flag_list(flags) {
+        set_flag(&mut opt, flag, true)?;
+    }
+    Ok(opt)
+}
+
+/// Options with all flags except the given comma-separated ones.
+fn parse_excluded_flags(flags: &str) -> Result<Options, String> {
+    let mut opt = no_flags();
+    for flag in FLAG_NAMES {
+        set_flag(&mut opt, flag, true)?;
+    }
+    for flag in flag_list(flags) {
+        set_flag(&mut opt, flag, false)?;
+    }
+    Ok(opt)
+}
+
+/// The names of the opcodes that can be used in the test vectors, with and
+/// without `OP_` prefix.
+fn opcode_names() -> HashMap<String, Opcode> {
+    let mut names = HashMap::new();
+    for byte in OP_NOP.to_u8()..=u8::MAX {
+        let op = Opcode::from(byte);
+        let name = op.to_string();
+        names.insert(name[3..].to_owned(), op);
+        names.insert(name, op);
+    }
+    names.insert("OP_RESERVED".to_owned(), OP_RESERVED);
+    names.insert("RESERVED".to_owned(), OP_RESERVED);
+    for (name, op) in [
+        ("NOP2", OP_CLTV),
+        ("CHECKLOCKTIMEVERIFY", OP_CLTV),
+        ("NOP3", OP_CSV),
+        ("CHECKSEQUENCEVERIFY", OP_CSV),
+    ] {
+        names.insert(format!("OP_{}", name), op);
+        names.insert(name.to_owned(), op);
+    }
+    names
+}
+
+/// Appends a push of the data with the smallest push opcode.
+fn push_data(script: &mut Vec<u8>, data: &[u8]) {
+    let len = data.len();
+    if len < OP_PUSHDATA1.to_u8() as usize {
+        script.push(len as u8);
+    } else if len <= 0xff {
+        script.push(OP_PUSHDATA1.to_u8());
+        script.push(len as u8);
+    } else if len <= 0xffff {
+        script.push(OP_PUSHDATA2.to_u8());
+        script.extend_from_slice(&(len as u16).to_le_bytes());
+    } else {
+        script.push(OP_PUSHDATA4.to_u8());
+        script.extend_from_slice(&(len as u32).to_le_bytes());
+    }
+    script.extend_from_slice(data);
+}
+
+/// Parses a script in the notation of the test vectors, like Bitcoin Core's
+/// `ParseScript`: decimal numbers are pushed as numbers, `0x` hex is inserted
+/// raw, strings in single quotes are pushed and anything else is an opcode.
+fn parse_script(s: &str, names: &HashMap<String, Opcode>) -> Result<ScriptBuf, String> {
+    let mut script = Vec::new();
+    for word in s.split_whitespace() {
+        let digits = word.strip_prefix('-').unwrap_or(word);
+        if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) {
+            let n = i64::from_str(word).map_err(|e| format!("invalid number {}: {}", word, e))?;
+            script.extend_from_slice(script::Builder::new().push_int(n).as_bytes());
+        } else if let Some(hex) = word.strip_prefix("0x").filter(|h| !h.is_empty()) {
+            let bytes =
+                Vec::<u8>::from_hex(hex).map_err(|e| format!("invalid hex {}: {}", word, e))?;
+            script.extend_from_slice(&bytes);
+        } else if let Some(text) = word.strip_prefix('\'').and_then(|w| w.strip_suffix('\'')) {
+            push_data(&mut script, text.as_bytes());
+        } else if let Some(op) = names.get(word) {
+            script.push(op.to_u8());
+        } else {
+            return Err(format!("unknown script token: {}", word));
+        }
+    }
+    Ok(ScriptBuf::from_bytes(script))
+}
+
+/// The name used in the test vectors for the outcome of a verification.
+fn outcome_name(res: &Result<(), Error>) -> String {
+    match res {
+        Ok(()) => "OK".to_owned(),
+        Err(Error::Exec(e)) => e.name().to_owned(),
+        Err(e) => e.to_string(),
+    }
+}
+
+/// Collects the outcome of all vectors of a file.
+struct Report {
+    name: String,
+    nb_passed: usize,
+    skipped: Vec<String>,
+    mismatches: Vec<String>,
+}
+
+impl Report {
+    fn new(name: &str) -> Report {
+        Report {
+            name: name.to_owned(),
+            nb_passed: 0,
+            skipped: Vec::new(),
+            mismatches: Vec::new(),
+        }
+    }
+
+    /// Prints the report and panics if there were any mismatches.
+    fn finish(self) {
+        println!(
+            "{}: {} passed, {} skipped, {} mismatches",
+            self.name,
+            self.nb_passed,
+            self.skipped.len(),
+            self.mismatches.len(),
+        );
+        for skipped in &self.skipped {
+            println!("  skipped: {}", skipped);
+        }
+        for mismatch in &self.mismatches {
+            println!("  MISMATCH: {}", mismatch);
+        }
+        assert!(
+            self.mismatches.is_empty(),
+            "{} mismatches in {}",
+            self.mismatches.len(),
+            self.name
+        );
+    }
+}
+
+fn data_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/data")
+        .join(name)
+}
+
+/// Loads the vectors of the given file.
+fn load_vectors(name: &str) -> Vec<Value> {
+    let path = data_path(name);
+    let json = std::fs::read_to_string(&path).unwrap_or_else(|e| {
+        panic!(
+            "error reading test vectors {}: {}, see tests/data/README.md",
+            path.display(),
+            e
+        )
+    });
+    serde_json::from_str(&json).expect("invalid test vector json")
+}
+
+/// Like Bitcoin Core's `BuildCreditingTransaction`.
+fn crediting_tx(script_pubkey: ScriptBuf, value: Amount) -> Transaction {
+    Transaction {
+        version: transaction::Version::ONE,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: script::Builder::new().push_int(0).push_int(0).into_script(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value,
+            script_pubkey,
+        }],
+    }
+}
+
+/// Like Bitcoin Core's `BuildSpendingTransaction`.
+fn spending_tx(script_sig: ScriptBuf, witness: Witness, credit: &Transaction) -> Transaction {
+    Transaction {
+        version: transaction::Version::ONE,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(credit.compute_txid(), 0),
+            script_sig,
+            sequence: Sequence::MAX,
+            witness,
+        }],
+        output: vec![TxOut {
+            value: credit.output[0].value,
+            script_pubkey: ScriptBuf::new(),
+        }],
+    }
+}
+
+fn as_str(v: &Value) -> Result<&str, String> {
+    v.as_str()
+        .ok_or_else(|| format!("expected string, got {}", v))
+}
+
+/// Runs a single script test, returns the actual and expected outcome.
+fn run_script_test(
+    test: &[Value],
+    names: &HashMap<String, Opcode>,
+) -> Result<(String, String), String> {
+    let (witness, value, test) = match test.first() {
+        Some(Value::Array(wit)) => {
+            let (amount, items) = wit.split_last().ok_or("empty witness array")?;
+            let amount = amount.as_f64().ok_or("invalid amount")?;
+            let mut witness = Witness::new();
+            for item in items {
+                let item = Vec::<u8>::from_hex(as_str(item)?).map_err(|e| e.to_string())?;
+                witness.push(item);
+            }
+            let value = Amount::from_sat((amount * 100_000_000.0).round() as u64);
+            (witness, value, &test[1..])
+        }
+        _ => (Witness::new(), Amount::ZERO, test),
+    };
+    if test.len() < 4 {
+        return Err("too few elements".to_owned());
+    }
+
+    let script_sig = parse_script(as_str(&test[0])?, names)?;
+    let script_pubkey = parse_script(as_str(&test[1])?, names)?;
+    let opt = parse_flags(as_str(&test[2])?)?;
+    let expected = as_str(&test[3])?.to_owned();
+
+    let credit = crediting_tx(script_pubkey, value);
+    let spend = spending_tx(script_sig, witness, &credit);
+    let res = verify_input_with_options(opt, &spend, 0, &credit.output);
+    Ok((outcome_name(&res), expected))
+}
+
+fn run_script_tests(file: &str) {
+    let vectors = load_vectors(file);
+    let names = opcode_names();
+    let mut report = Report::new(file);
+    for vector in &vectors {
+        let test = vector.as_array().expect("vectors are arrays");
+        // Comments
+        if test.len() == 1 {
+            continue;
+        }
+
+        match run_script_test(test, &names) {
+            Ok((actual, expected)) if actual == expected => report.nb_passed += 1,
+            Ok((actual, expected)) => report
+                .mismatches
+                .push(format!("{}: expected {}, got {}", vector, expected, actual)),
+            Err(e) => report.skipped.push(format!("{}: {}", vector, e)),
+        }
+    }
+    report.finish();
+}
+
+/// Verifies all inputs of a transaction test, returns whether they are all
+/// valid, with the outcome of the first failing input.
+fn run_tx_test(
+    test: &[Value],
+    names: &HashMap<String, Opcode>,
+    valid: bool,
+) -> Result<Result<(), String>, String> {
+    if test.len() < 3 {
+        return Err("too few elements".to_owned());
+    }
+
+    let mut prevouts = HashMap::new();
+    for input in test[0].as_array().ok_or("invalid prevouts")? {
+        let input = input.as_array().ok_or("invalid prevout")?;
+        if input.len() < 3 {
+            return Err("invalid prevout".to_owned());
+        }
+        let txid = Txid::from_str(as_str(&input[0])?).map_err(|e| e.to_string())?;
+        let vout = input[1].as_i64().ok_or("invalid prevout index")? as u32;
+        let script_pubkey = parse_script(as_str(&input[2])?, names)?;
+        let value = match input.get(3) {
+            Some(v) => Amount::from_sat(v.as_u64().ok_or("invalid amount")?),
+            None => Amount::ZERO,
+        };
+        prevouts.insert(
+            OutPoint::new(txid, vout),
+            TxOut {
+                value,
+                script_pubkey,
+            },
+        );
+    }
+
+    let tx: Transaction = deserialize_hex(as_str(&test[1])?).map_err(|e| e.to_string())?;
+    let flags = as_str(&test[2])?;
+    if flag_list(flags).any(|f| f == "BADTX") {
+        return Err("transaction level checks are not supported".to_owned());
+    }
+    let opt = if valid {
+        parse_excluded_flags(flags)?
+    } else {
+        parse_flags(flags)?
+    };
+
+    let spent = match tx
+        .input
+        .iter()
+        .map(|i| prevouts.get(&i.previous_output).cloned())
+        .collect::<Option<Vec<_>>>()
+    {
+        Some(spent) => spent,
+        None => return Ok(Err("missing prevout".to_owned())),
+    };
+    for idx in 0..tx.input.len() {
+        let res = verify_input_with_options(opt.clone(), &tx, idx, &spent);
+        if res.is_err() {
+            return Ok(Err(format!("input {}: {}", idx, outcome_name(&res))));
+        }
+    }
+    Ok(Ok(()))
+}
+
+fn run_tx_tests(file: &str, valid: bool) {
+    let vectors = load_vectors(file);
+    let names = opcode_names();
+    let mut report = Report::new(file);
+    for vector in &vectors {
+        let test = vector.as_array().expect("vectors are arrays");
+        // Comments
+        if !test.first().map(Value::is_array).unwrap_or(false) {
+            continue;
+        }
+
+        match run_tx_test(test, &names, valid) {
+            Ok(Ok(())) if valid => report.nb_passed += 1,
+            Ok(Err(_)) if !valid => report.nb_passed += 1,
+            Ok(Ok(())) => report
+                .mismatches
+                .push(format!("{}: expected invalid", vector)),
+            Ok(Err(e)) => report
+                .mismatches
+                .push(format!("{}: expected valid, got {}", vector, e)),
+            Err(e) => report.skipped.push(format!("{}: {}", vector, e)),
+        }
+    }
+    report.finish();
+}
+
+#[test]
+fn test_local_script_vectors() {
+    run_script_tests("script_tests_local.json");
+}
+
+#[test]
+fn test_local_tx_vectors() {
+    run_tx_tests("tx_valid_local.json", true);
+    run_tx_tests("tx_invalid_local.json", false);
+}
+
+#[test]
+fn test_parse_script() {
+    let names = opcode_names();
+    let script = parse_script("0 -1 17 0x4c01 0x02 'ab' OP_DUP HASH160 NOP2", &names).unwrap();
+    let expected = script::Builder::new()
+        .push_int(0)
+        .push_int(-1)
+        .push_int(17)
+        .into_bytes()
+        .into_iter()
+        .chain([OP_PUSHDATA1.to_u8(), 0x01, 0x02, 0x02, b'a', b'b'])
+        .chain([OP_DUP.to_u8(), OP_HASH160.to_u8(), OP_CLTV.to_u8()])
+        .collect::<Vec<u8>>();
+    assert_eq!(script.as_bytes(), &expected[..]);
+    assert!(parse_script("FOO", &names).is_err());
+    // Pushes of small numbers are not opcodes.
+    assert!(parse_script("PUSHNUM_1", &names).is_err());
+}
@@ -0,0 +1,245 @@
+//! Module containing the splitting of scripts across the branches of
+//! conditionals.
+//!
+//! [`split_into_shards`](super::core::split_into_shards) never cuts inside an
+//! `OP_IF ... OP_ENDIF` block, so a large branch ends up in a single shard.
+//! Here, both branches of a large block are cut into pieces that are
+//! executed side by side: the `i`-th shard of the block contains the `i`-th
+//! piece of each branch. The branch taken is materialized in the
+//! intermediate state as a marker on top of the altstack, `OP_TRUE` if the
+//! first branch is executed and `OP_FALSE` otherwise, so that every shard
+//! re-enters the correct branch:
+//!
+//! ```text
+//! OP_IF <a_1> OP_TRUE OP_TOALTSTACK OP_ELSE <b_1> OP_FALSE OP_TOALTSTACK OP_ENDIF
+//! OP_FROMALTSTACK OP_IF <a_2> OP_TRUE OP_TOALTSTACK OP_ELSE <b_2> OP_FALSE OP_TOALTSTACK OP_ENDIF
+//! ...
+//! OP_FROMALTSTACK OP_IF <a_n> OP_ELSE <b_n> OP_ENDIF
+//! ```
+//!
+//! Nested blocks are cut the same way, the markers of the enclosing blocks
+//! being below theirs on the altstack.
+
+use bitcoin::{
+    opcodes::{
+        all::{OP_ELSE, OP_ENDIF, OP_FROMALTSTACK, OP_IF, OP_NOTIF, OP_TOALTSTACK},
+        Opcode, OP_FALSE, OP_TRUE,
+    },
+    script::Instruction,
+};
+use bitcoin_utils::treepp::*;
+
+use super::core::{SplitType, MAX_SCRIPT_SIZE};
+
+/// Number of instructions added to each shard of a cut block to re-enter the
+/// branch: `OP_FROMALTSTACK OP_IF`, `OP_ELSE`, `OP_ENDIF` and the two markers
+/// with their `OP_TOALTSTACK`.
+const BRANCH_OVERHEAD: usize = 8;
+
+/// Part of a script, executed from the state left by the previous part
+#[derive(Clone, Default)]
+struct Fragment {
+    bytes: Vec<u8>,
+    nb_instructions: usize,
+}
+
+impl Fragment {
+    fn from_instruction(instruction: Instruction) -> Self {
+        let mut script = Script::new();
+        script.push_instruction(instruction);
+        Self {
+            bytes: script.into_bytes(),
+            nb_instructions: 1,
+        }
+    }
+
+    fn size(&self, split_type: SplitType) -> usize {
+        match split_type {
+            SplitType::ByInstructions => self.nb_instructions,
+            SplitType::ByBytes => self.bytes.len(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.nb_instructions == 0
+    }
+
+    fn push_opcode(&mut self, opcode: Opcode) {
+        self.bytes.push(opcode.to_u8());
+        self.nb_instructions += 1;
+    }
+
+    fn append(&mut self, other: &Fragment) {
+        self.bytes.extend_from_slice(&other.bytes);
+        self.nb_instructions += other.nb_instructions;
+    }
+}
+
+/// Node of the control flow tree of a script
+enum Node {
+    /// Any instruction but the conditional ones
+    Instruction(Fragment),
+    /// `OP_IF` or `OP_NOTIF` block with its branches. Blocks with more than
+    /// one `OP_ELSE` execute several branches and are never cut.
+    Conditional {
+        opcode: Opcode,
+        branches: Vec<Vec<Node>>,
+    },
+}
+
+/// Parses the nodes up to the end of the script or the next `OP_ELSE` or
+/// `OP_ENDIF`, which is returned.
+fn parse_nodes<'a>(
+    instructions: &mut impl Iterator<Item = Instruction<'a>>,
+) -> (Vec<Node>, Option<Opcode>) {
+    let mut nodes = vec![];
+    while let Some(instruction) = instructions.next() {
+        match instruction {
+            Instruction::Op(op @ (OP_ELSE | OP_ENDIF)) => return (nodes, Some(op)),
+            Instruction::Op(opcode @ (OP_IF | OP_NOTIF)) => {
+                let mut branches = vec![];
+                loop {
+                    let (branch, end) = parse_nodes(instructions);
+                    branches.push(branch);
+                    match end {
+                        Some(OP_ELSE) => continue,
+                        Some(_) => break,
+                        None => panic!("OP_IF without OP_ENDIF"),
+                    }
+                }
+                nodes.push(Node::Conditional { opcode, branches });
+            }
+            _ => nodes.push(Node::Instruction(Fragment::from_instruction(instruction))),
+        }
+    }
+    (nodes, None)
+}
+
+/// Returns the script of the nodes as a single fragment
+fn render(nodes: &[Node]) -> Fragment {
+    let mut fragment = Fragment::default();
+    for node in nodes {
+        match node {
+            Node::Instruction(instruction) => fragment.append(instruction),
+            Node::Conditional { opcode, branches } => {
+                fragment.push_opcode(*opcode);
+                for (i, branch) in branches.iter().enumerate() {
+                    if i > 0 {
+                        fragment.push_opcode(OP_ELSE);
+                    }
+                    fragment.append(&render(branch));
+                }
+                fragment.push_opcode(OP_ENDIF);
+            }
+        }
+    }
+    fragment
+}
+
+/// Cuts the nodes into fragments of about `chunk_size`, merging small
+/// consecutive fragments.
+fn cut(nodes: &[Node], chunk_size: usize, split_type: SplitType) -> Vec<Fragment> {
+    let mut fragments = vec![Fragment::default()];
+    for node in nodes {
+        for fragment in cut_node(node, chunk_size, split_type) {
+            let current = fragments.last_mut().expect("fragments should not be empty");
+            if !current.is_empty()
+                && current.size(split_type) + fragment.size(split_type) > chunk_size
+            {
+                fragments.push(fragment);
+            } else {
+                current.append(&fragment);
+            }
+        }
+    }
+    fragments
+}
+
+/// Cuts a single node, see the module documentation for conditionals.
+fn cut_node(node: &Node, chunk_size: usize, split_type: SplitType) -> Vec<Fragment> {
+    let whole = render(std::slice::from_ref(node));
+    let (opcode, then_branch, else_branch) = match node {
+        Node::Conditional { opcode, branches } if branches.len() <= 2 => {
+            (*opcode, &branches[0], branches.get(1))
+        }
+        _ => return vec![whole],
+    };
+    if whole.size(split_type) <= chunk_size {
+        return vec![whole];
+    }
+
+    // The budget is shared between the branches in proportion to their sizes,
+    // so that both are cut into about the same number of pieces.
+    let else_branch = else_branch.map(Vec::as_slice).unwrap_or_default();
+    let then_size = render(then_branch).size(split_type);
+    let else_size = render(else_branch).size(split_type);
+    let budget = chunk_size.saturating_sub(BRANCH_OVERHEAD).max(2);
+    let then_budget = (budget * then_size / (then_size + else_size).max(1)).max(1);
+    let else_budget = budget.saturating_sub(then_budget).max(1);
+
+    let mut then_pieces = cut(then_branch, then_budget, split_type);
+    let mut else_pieces = cut(else_branch, else_budget, split_type);
+    let nb_pieces = then_pieces.len().max(else_pieces.len());
+    if nb_pieces == 1 {
+        return vec![whole];
+    }
+    then_pieces.resize(nb_pieces, Fragment::default());
+    else_pieces.resize(nb_pieces, Fragment::default());
+
+    let mut fragments = vec![];
+    for (i, (then_piece, else_piece)) in then_pieces.iter().zip(&else_pieces).enumerate() {
+        let is_first = i == 0;
+        let is_last = i == nb_pieces - 1;
+
+        let mut fragment = Fragment::default();
+        if is_first {
+            fragment.push_opcode(opcode);
+        } else {
+            fragment.push_opcode(OP_FROMALTSTACK);
+            fragment.push_opcode(OP_IF);
+        }
+        fragment.append(then_piece);
+        if !is_last {
+            fragment.push_opcode(OP_TRUE);
+            fragment.push_opcode(OP_TOALTSTACK);
+        }
+        fragment.push_opcode(OP_ELSE);
+        fragment.append(else_piece);
+        if !is_last {
+            fragment.push_opcode(OP_FALSE);
+            fragment.push_opcode(OP_TOALTSTACK);
+        }
+        fragment.push_opcode(OP_ENDIF);
+        fragments.push(fragment);
+    }
+    fragments
+}
+
+/// Splits the given script into smaller parts of about `chunk_size`, cutting
+/// inside the branches of conditionals if needed. Executed one after another,
+/// the shards are equivalent to the script.
+///
+/// NOTE: Panics if the conditionals of the script are unbalanced or if a
+/// shard exceeds [`MAX_SCRIPT_SIZE`].
+pub fn split_into_branch_shards(
+    script: &Script,
+    chunk_size: usize,
+    split_type: SplitType,
+) -> Vec<Script> {
+    let mut instructions = script
+        .instructions()
+        .map(|instruction| instruction.expect("script is most likely corrupted"));
+    let (nodes, end) = parse_nodes(&mut instructions);
+    assert!(end.is_none(), "{:?} without OP_IF", end);
+
+    cut(&nodes, chunk_size, split_type)
+        .into_iter()
+        .map(|fragment| {
+            assert!(
+                fragment.bytes.len() <= MAX_SCRIPT_SIZE,
+                "Script size has exceeded the maximum size"
+            );
+            Script::from_bytes(fragment.bytes)
+        })
+        .collect()
+}
@@ -0,0 +1,227 @@
+//! Module containing the on-disk cache of the intermediate states.
+//!
+//! Executing a shard is deterministic, so the state a shard ends in only
+//! depends on the shard and on the state it starts from. The cache maps
+//! such pairs to the resulting states, together with the usage of the limits
+//! by the steps of the shard, so that splitting the same script again with a
+//! repeated input, or with another chunk size producing some of the same
+//! shards, does not execute the shards again.
+//!
+//! Entries are addressed by content: the state `f(z)` of the shard `f`
+//! started from the state `z` is stored in the file
+//! `v<version>-<sha256(options)>/<sha256(f)>/<sha256(z)>` of the cache
+//! directory as `<state> <max nb items> <max element size>`. The version is
+//! [`FORMAT_VERSION`] and the options are the ones the shards are executed
+//! with, encoded as the version followed by a byte for each flag, so that
+//! entries written by another version or with other options are not read.
+//! The states are encoded as
+//! `<nb items> <stack items..> <nb items> <altstack items..>` with the
+//! encoding of [`bitcoin_scriptexec::tracefile`].
+//!
+//! NOTE: The items of the states read from the cache are bytes rather than
+//! numbers, like the items pushed back by
+//! [`IntermediateState::inject_script`].
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin_scriptexec::{
+    tracefile::{read_items, read_varint, write_items, write_varint},
+    Experimental, Options, Stack,
+};
+use bitcoin_utils::{debug::default_options, treepp::*};
+
+use super::{core::ShardUsage, intermediate_state::IntermediateState};
+
+/// The version of the cache, to be increased whenever the encoding of the
+/// entries or the results of executing the shards change
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Number of temporary files created by the process, for their names
+static NB_TMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Encodes the options the shards are executed with. The fields are listed
+/// without a rest pattern, so that a new option does not compile until it
+/// is encoded as well.
+fn encode_options(options: &Options) -> Vec<u8> {
+    let Options {
+        verify_p2sh,
+        verify_strict_enc,
+        verify_der_sig,
+        verify_low_s,
+        verify_null_dummy,
+        verify_sig_push_only,
+        require_minimal,
+        discourage_upgradable_nops,
+        verify_clean_stack,
+        verify_cltv,
+        verify_csv,
+        verify_witness,
+        discourage_upgradable_witness_program,
+        verify_minimal_if,
+        verify_null_fail,
+        verify_witness_pubkey_type,
+        verify_const_scriptcode,
+        verify_taproot,
+        discourage_upgradable_taproot_version,
+        discourage_op_success,
+        discourage_upgradable_pubkey_type,
+        enforce_stack_limit,
+        experimental:
+            Experimental {
+                op_cat,
+                op_ctv,
+                op_csfs,
+            },
+    } = options;
+
+    let mut bytes = FORMAT_VERSION.to_le_bytes().to_vec();
+    bytes.extend(
+        [
+            verify_p2sh,
+            verify_strict_enc,
+            verify_der_sig,
+            verify_low_s,
+            verify_null_dummy,
+            verify_sig_push_only,
+            require_minimal,
+            discourage_upgradable_nops,
+            verify_clean_stack,
+            verify_cltv,
+            verify_csv,
+            verify_witness,
+            discourage_upgradable_witness_program,
+            verify_minimal_if,
+            verify_null_fail,
+            verify_witness_pubkey_type,
+            verify_const_scriptcode,
+            verify_taproot,
+            discourage_upgradable_taproot_version,
+            discourage_op_success,
+            discourage_upgradable_pubkey_type,
+            enforce_stack_limit,
+            op_cat,
+            op_ctv,
+            op_csfs,
+        ]
+        .map(|flag| u8::from(*flag)),
+    );
+    bytes
+}
+
+fn encode_state(state: &IntermediateState) -> Vec<u8> {
+    let mut bytes = vec![];
+    for stack in [&state.stack, &state.altstack] {
+        let items: Vec<Vec<u8>> = stack.iter_str().collect();
+        write_items(&mut bytes, &items).expect("writing to a vector does not fail");
+    }
+    bytes
+}
+
+fn decode_state(bytes: &mut &[u8]) -> io::Result<IntermediateState> {
+    let stack = Stack::from_u8_vec(read_items(bytes)?);
+    let altstack = Stack::from_u8_vec(read_items(bytes)?);
+    Ok(IntermediateState { stack, altstack })
+}
+
+fn encode_entry(state: &IntermediateState, usage: &ShardUsage) -> Vec<u8> {
+    let mut bytes = encode_state(state);
+    for n in [usage.max_nb_items, usage.max_element_size] {
+        write_varint(&mut bytes, n as u64).expect("writing to a vector does not fail");
+    }
+    bytes
+}
+
+fn decode_entry(mut bytes: &[u8]) -> io::Result<(IntermediateState, ShardUsage)> {
+    let state = decode_state(&mut bytes)?;
+    let mut read_usize =
+        || usize::try_from(read_varint(&mut bytes)?).map_err(|_| invalid_data("number too large"));
+    let usage = ShardUsage {
+        max_nb_items: read_usize()?,
+        max_element_size: read_usize()?,
+    };
+    if !bytes.is_empty() {
+        return Err(invalid_data("trailing bytes after the entry"));
+    }
+    Ok((state, usage))
+}
+
+/// Cache of the states the shards end in, persisted to a local directory
+#[derive(Debug, Clone)]
+pub struct ShardCache {
+    dir: PathBuf,
+    /// Directory of the entries for the current version and options
+    entries_dir: PathBuf,
+}
+
+impl ShardCache {
+    /// Opens the cache stored in the given directory, creating it if needed
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let options_hash = sha256::Hash::hash(&encode_options(&default_options()));
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+            entries_dir: dir
+                .as_ref()
+                .join(format!("v{}-{}", FORMAT_VERSION, options_hash)),
+        })
+    }
+
+    /// Returns the directory the cache is stored in
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn entry_path(&self, shard: &Script, from: &IntermediateState) -> PathBuf {
+        let shard_hash = sha256::Hash::hash(shard.as_bytes());
+        let state_hash = sha256::Hash::hash(&encode_state(from));
+        self.entries_dir
+            .join(shard_hash.to_string())
+            .join(state_hash.to_string())
+    }
+
+    /// Returns the state the shard ends in when started from `from` and the
+    /// usage of the limits by the steps of the shard, if they are in the
+    /// cache. Unreadable entries are treated as missing.
+    pub fn get(
+        &self,
+        shard: &Script,
+        from: &IntermediateState,
+    ) -> Option<(IntermediateState, ShardUsage)> {
+        let bytes = fs::read(self.entry_path(shard, from)).ok()?;
+        decode_entry(&bytes).ok()
+    }
+
+    /// Stores the state `to` the shard ends in when started from `from`,
+    /// with the usage of the limits by the steps of the shard
+    pub fn insert(
+        &self,
+        shard: &Script,
+        from: &IntermediateState,
+        to: &IntermediateState,
+        usage: &ShardUsage,
+    ) -> io::Result<()> {
+        let path = self.entry_path(shard, from);
+        let shard_dir = path.parent().expect("entries are inside the cache");
+        fs::create_dir_all(shard_dir)?;
+
+        // Writing to a temporary file first, so that concurrent readers never
+        // see a partially written entry. Its name is unique to the call, as
+        // other threads or processes may be writing the same entry.
+        let tmp_path = path.with_extension(format!(
+            "tmp-{}-{}",
+            std::process::id(),
+            NB_TMP_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&tmp_path, encode_entry(to, usage))?;
+        fs::rename(tmp_path, path)
+    }
+}
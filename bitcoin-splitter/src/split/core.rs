@@ -4,12 +4,22 @@ use std::panic;
 
 use bitcoin::{
     opcodes::all::{OP_ENDIF, OP_IF, OP_NOTIF},
-    script::Instruction,
+    script::{self, Instruction},
 };
-use bitcoin_utils::treepp::*;
+use bitcoin_scriptexec::{
+    Exec, ExecCtx, ExecObserver, Options, Stack, StepDiff, StepInfo, TxTemplate,
+    MAX_SCRIPT_ELEMENT_SIZE, MAX_STACK_SIZE,
+};
+use bitcoin_utils::{debug::default_options, treepp::*};
 use indicatif::ProgressBar;
 
-use super::script::SplitResult;
+use super::{
+    branches::split_into_branch_shards,
+    cache::ShardCache,
+    cost::{CostModel, StackSizeIndex},
+    optimal::split_into_optimal_shards_with_cost,
+    script::SplitResult,
+};
 use crate::split::intermediate_state::IntermediateState;
 
 /// Optimal size of the script in bytes
@@ -33,7 +43,8 @@ pub(super) const MAX_SCRIPT_SIZE: usize = 50000;
 /// `shard_size_i + (z_i_size + z_(i-1)_size) * STACK_SIZE_INDEX`
 ///
 /// The `STACK_SIZE_INDEX` is the factor of how much intermediate states
-/// are contributing to the total size of the script.
+/// are contributing to the total size of the script. The `*_with_cost`
+/// splits can be given a more precise [`CostModel`] instead.
 pub(super) const STACK_SIZE_INDEX: usize = 1000;
 
 /// Type of the split that we are going to use
@@ -108,6 +119,19 @@ pub fn split_into_shards(script: &Script, chunk_size: usize, split_type: SplitTy
 /// Fuzzy split of the script into smaller parts by searching for the optimal size
 /// by checking various script sizes
 pub fn fuzzy_split(input: Script, script: Script, split_type: SplitType) -> SplitResult {
+    fuzzy_split_with_cost(input, script, split_type, &StackSizeIndex, None)
+}
+
+/// Fuzzy split of the script, see [`fuzzy_split`], keeping the chunk size
+/// minimizing the largest cost of a shard under the given [`CostModel`], with
+/// the intermediate states of the shards going through the cache if any
+pub fn fuzzy_split_with_cost<C: CostModel>(
+    input: Script,
+    script: Script,
+    split_type: SplitType,
+    cost_model: &C,
+    cache: Option<&ShardCache>,
+) -> SplitResult {
     // Define the limits
     const MIN_CHUNK_SIZE: usize = 100;
     const MAX_CHUNK_SIZE: usize = MAX_SCRIPT_SIZE;
@@ -130,10 +154,11 @@ pub fn fuzzy_split(input: Script, script: Script, split_type: SplitType) -> Spli
         // during the splitting process. If a panic occurs, we just skip the current
         // chunk size and continue with the next one.
         let current_split_result = panic::catch_unwind(|| {
-            naive_split(input.clone(), script.clone(), split_type, chunk_size)
+            let shards = split_into_shards(&script, chunk_size, split_type);
+            split_result_from_shards(input.clone(), shards, cache)
         });
         if let Ok(split_result) = current_split_result {
-            let current_complexity = split_result.complexity_index();
+            let current_complexity = split_result.max_cost(&input, cost_model);
 
             if current_complexity < resultant_complexity {
                 resultant_complexity = current_complexity;
@@ -155,7 +180,8 @@ pub fn default_split(input: Script, script: Script, split_type: SplitType) -> Sp
 /// 1. We split the script into smaller parts
 /// 2. We execute each shard with the input
 /// 3. Save intermediate results
-/// 4. Return all the shards and intermediate results in the form of [`SplitResult`]
+/// 4. Check that the shards stay within the tapscript limits
+/// 5. Return all the shards and intermediate results in the form of [`SplitResult`]
 pub fn naive_split(
     input: Script,
     script: Script,
@@ -163,8 +189,58 @@ pub fn naive_split(
     chunk_size: usize,
 ) -> SplitResult {
     let shards = split_into_shards(&script, chunk_size, split_type);
-    let intermediate_states: Vec<IntermediateState> =
-        form_states_from_shards(shards.clone(), input);
+    split_result_from_shards(input, shards, None)
+}
+
+/// Split of the script into smaller parts that can cut inside the branches
+/// of conditionals, see [`split_into_branch_shards`]. Otherwise works as
+/// [`naive_split`].
+pub fn branch_split(
+    input: Script,
+    script: Script,
+    split_type: SplitType,
+    chunk_size: usize,
+) -> SplitResult {
+    let shards = split_into_branch_shards(&script, chunk_size, split_type);
+    split_result_from_shards(input, shards, None)
+}
+
+/// Split of the script into the smaller parts minimizing
+/// [`SplitResult::complexity_index`] for the given input, see
+/// [`split_into_optimal_shards`](super::optimal::split_into_optimal_shards).
+/// Unlike [`fuzzy_split`], the script is executed only once to choose the
+/// shards.
+pub fn optimal_split(input: Script, script: Script) -> SplitResult {
+    optimal_split_with_cost(input, script, &StackSizeIndex)
+}
+
+/// Split of the script into the smaller parts minimizing the largest cost of
+/// a shard under the given [`CostModel`], see [`optimal_split`]
+pub fn optimal_split_with_cost<C: CostModel>(
+    input: Script,
+    script: Script,
+    cost_model: &C,
+) -> SplitResult {
+    let shards = split_into_optimal_shards_with_cost(&input, &script, cost_model);
+    split_result_from_shards(input, shards, None)
+}
+
+/// Executes the shards with the input, through the cache if any, and checks
+/// them against the limits
+fn split_result_from_shards(
+    input: Script,
+    shards: Vec<Script>,
+    cache: Option<&ShardCache>,
+) -> SplitResult {
+    let (intermediate_states, usages) = match cache {
+        Some(cache) => execute_shards_with_cache(&shards, input, cache),
+        None => {
+            let (states, input_usage, shard_usages) = execute_shards(&shards, input);
+            let usages = leaf_usages(input_usage, &states, &shard_usages);
+            (states, usages)
+        }
+    };
+    assert_shards_within_limits(&usages);
 
     SplitResult {
         shards,
@@ -172,8 +248,316 @@ pub fn naive_split(
     }
 }
 
-/// Given an array of shards and input, creates the vector of intermediate states
-pub fn form_states_from_shards(shards: Vec<Script>, input: Script) -> Vec<IntermediateState> {
+/// Usage of the tapscript limits by a script, either a shard executed as a
+/// leaf, or a part of such a leaf
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShardUsage {
+    /// Largest number of items on the stack and the altstack together
+    pub max_nb_items: usize,
+    /// Size of the largest item pushed or on top of the stack or the altstack
+    pub max_element_size: usize,
+}
+
+impl ShardUsage {
+    /// Usage of pushing the state with [`IntermediateState::inject_script`]
+    fn of_injection(state: &IntermediateState) -> Self {
+        let max_element_size = state
+            .stack
+            .iter_str()
+            .chain(state.altstack.iter_str())
+            .map(|item| item.len())
+            .max()
+            .unwrap_or(0);
+        // Moving the items to the altstack pushes their index on the stack
+        let max_index_size = match state.altstack.len() {
+            0 => 0,
+            len => script::scriptint_vec(len as i64 - 1).len(),
+        };
+        Self {
+            max_nb_items: state.size() + usize::from(!state.altstack.is_empty()),
+            max_element_size: max_element_size.max(max_index_size),
+        }
+    }
+
+    /// Usage of executing both parts, one after the other
+    fn combine(self, other: Self) -> Self {
+        Self {
+            max_nb_items: self.max_nb_items.max(other.max_nb_items),
+            max_element_size: self.max_element_size.max(other.max_element_size),
+        }
+    }
+
+    /// Adds the stacks after a step of the script to the usage
+    fn observe(&mut self, stack: &Stack, altstack: &Stack) {
+        self.max_nb_items = self.max_nb_items.max(stack.len() + altstack.len());
+        for stack in [stack, altstack] {
+            if let Ok(top) = stack.last() {
+                self.max_element_size = self.max_element_size.max(top.len());
+            }
+        }
+    }
+
+    /// Adds the pushes of the script to the usage, as pushes are limited in
+    /// branches that are not executed as well
+    fn observe_pushes(&mut self, script: &Script) {
+        let max_push = script
+            .instructions()
+            .filter_map(|instruction| match instruction {
+                Ok(Instruction::PushBytes(bytes)) => Some(bytes.len()),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        self.max_element_size = self.max_element_size.max(max_push);
+    }
+
+    /// Whether the usage stays within the stack and the element size limits
+    pub fn is_within_limits(&self) -> bool {
+        self.max_nb_items <= MAX_STACK_SIZE && self.max_element_size <= MAX_SCRIPT_ELEMENT_SIZE
+    }
+}
+
+/// Records the state at the end of each shard and the usage of the limits
+/// by the steps of the input and of each shard while executing the input
+/// followed by the shards
+struct ShardEndStates {
+    script_len: usize,
+    input_len: usize,
+    /// Offsets of the ends of the shards in the executed script, in bytes
+    shard_ends: Vec<usize>,
+    states: Vec<IntermediateState>,
+    input_usage: ShardUsage,
+    shard_usages: Vec<ShardUsage>,
+}
+
+impl ShardEndStates {
+    /// Records the given state for the shards ending at or before `offset`
+    /// whose state is not recorded yet
+    fn record(&mut self, offset: usize, stack: &Stack, altstack: &Stack) {
+        while self.states.len() < self.shard_ends.len()
+            && self.shard_ends[self.states.len()] <= offset
+        {
+            self.states.push(IntermediateState {
+                stack: stack.clone(),
+                altstack: altstack.clone(),
+            });
+        }
+    }
+}
+
+impl ExecObserver for ShardEndStates {
+    fn wants_stack_diffs(&self) -> bool {
+        false
+    }
+
+    fn post_step(&mut self, exec: &Exec, _step: &StepInfo, _diff: Option<&StepDiff>) {
+        let offset = self.script_len - exec.remaining_script().len();
+        // Steps after the input belong to the first shard whose state is not
+        // recorded
+        let usage = if offset <= self.input_len {
+            Some(&mut self.input_usage)
+        } else {
+            self.shard_usages.get_mut(self.states.len())
+        };
+        if let Some(usage) = usage {
+            usage.observe(exec.stack(), exec.altstack());
+        }
+        self.record(offset, exec.stack(), exec.altstack());
+    }
+}
+
+/// Given an array of shards and input, creates the vector of intermediate states.
+///
+/// The input and the shards are executed one after another in a single
+/// execution, recording the stack and the altstack at the end of each shard,
+/// so the entries of the states keep their types. The validation weight budget
+/// is reset at the start of each shard, as each one is a leaf of its own. If a
+/// shard fails, its state is the one at the failure, and the next shards are
+/// executed from it as in [`form_states_by_reinjection`].
+///
+/// With a cache, the states of the shards are read from it, and the shards
+/// following the first one missing from the cache are executed, their states
+/// being stored in the cache.
+///
+/// NOTE: The shards are expected to start and end outside of conditionals,
+/// as the shards of the splitters do.
+pub fn form_states_from_shards(
+    shards: Vec<Script>,
+    input: Script,
+    cache: Option<&ShardCache>,
+) -> Vec<IntermediateState> {
+    match cache {
+        Some(cache) => execute_shards_with_cache(&shards, input, cache).0,
+        None => execute_shards(&shards, input).0,
+    }
+}
+
+/// Executes the shards like [`form_states_from_shards`], returning the
+/// intermediate states together with the usage of the limits by the input
+/// and by each shard alone, see [`leaf_usages`] for the usage of the leaves
+fn execute_shards(
+    shards: &[Script],
+    input: Script,
+) -> (Vec<IntermediateState>, ShardUsage, Vec<ShardUsage>) {
+    execute_shards_with_options(shards, input, default_options())
+}
+
+/// Executes the shards like [`execute_shards`], with the given options
+/// instead of the default ones of `execute_script`
+pub(super) fn execute_shards_with_options(
+    shards: &[Script],
+    input: Script,
+    options: Options,
+) -> (Vec<IntermediateState>, ShardUsage, Vec<ShardUsage>) {
+    let mut script = input.to_bytes();
+    let mut shard_ends = vec![];
+    for shard in shards.iter() {
+        script.extend_from_slice(shard.as_bytes());
+        shard_ends.push(script.len());
+    }
+
+    let mut recorder = ShardEndStates {
+        script_len: script.len(),
+        input_len: input.len(),
+        shard_ends,
+        states: vec![],
+        input_usage: ShardUsage::default(),
+        shard_usages: vec![ShardUsage::default(); shards.len()],
+    };
+    recorder.record(0, &Stack::new(), &Stack::new());
+    let mut exec = Exec::new(
+        ExecCtx::Tapscript,
+        options.clone(),
+        TxTemplate::dummy(ExecCtx::Tapscript),
+        Script::from_bytes(script),
+        vec![],
+    )
+    .expect("error when creating the execution body");
+    loop {
+        let nb_states = recorder.states.len();
+        if exec.exec_next_observed(&mut recorder).is_err() {
+            break;
+        }
+        // Each shard is executed as a leaf of its own, with its own budget
+        if recorder.states.len() > nb_states {
+            exec.reset_validation_weight();
+        }
+    }
+    let failed = exec.result().unwrap().error.is_some();
+    let (main_stack, alt_stack) = (exec.stack().clone(), exec.altstack().clone());
+
+    if !failed {
+        // Shards left are empty ones at the end of the script
+        recorder.record(recorder.script_len, &main_stack, &alt_stack);
+    }
+
+    let mut input_usage = recorder.input_usage;
+    input_usage.observe_pushes(&input);
+    let mut shard_usages = recorder.shard_usages;
+    for (usage, shard) in shard_usages.iter_mut().zip(shards.iter()) {
+        usage.observe_pushes(shard);
+    }
+
+    let mut intermediate_states = recorder.states;
+    if intermediate_states.len() < shards.len() {
+        intermediate_states.push(IntermediateState {
+            stack: main_stack,
+            altstack: alt_stack,
+        });
+        for i in intermediate_states.len()..shards.len() {
+            let from_state = intermediate_states.last().unwrap().inject_script();
+            let (mut states, _, usages) =
+                execute_shards_with_options(&shards[i..i + 1], from_state, options.clone());
+            intermediate_states.append(&mut states);
+            shard_usages[i] = usages[0];
+        }
+    }
+
+    assert_eq!(
+        intermediate_states.len(),
+        shards.len(),
+        "Intermediate results should be the same as the number of scripts"
+    );
+
+    (intermediate_states, input_usage, shard_usages)
+}
+
+/// Returns the usage of the limits by each shard executed as a leaf, from the
+/// input for the first shard, or from the state before it pushed by
+/// [`IntermediateState::inject_script`], given the usage of the input and of
+/// each shard alone
+fn leaf_usages(
+    input_usage: ShardUsage,
+    intermediate_states: &[IntermediateState],
+    shard_usages: &[ShardUsage],
+) -> Vec<ShardUsage> {
+    shard_usages
+        .iter()
+        .enumerate()
+        .map(|(i, usage)| match i {
+            0 => usage.combine(input_usage),
+            i => usage.combine(ShardUsage::of_injection(&intermediate_states[i - 1])),
+        })
+        .collect()
+}
+
+/// Forms the states like [`form_states_from_shards`] with a cache, returning
+/// the states together with the usage of the limits by each shard executed
+/// as a leaf, read from the cache as well for the cached shards
+fn execute_shards_with_cache(
+    shards: &[Script],
+    input: Script,
+    cache: &ShardCache,
+) -> (Vec<IntermediateState>, Vec<ShardUsage>) {
+    // Executing the input alone, followed by an empty shard, for the state
+    // the first shard starts from
+    let (input_states, input_usage, _) = execute_shards(&[Script::new()], input.clone());
+    let mut from_state = input_states.into_iter().next().unwrap();
+
+    let mut intermediate_states = vec![];
+    let mut shard_usages = vec![];
+    for shard in shards.iter() {
+        match cache.get(shard, &from_state) {
+            Some((to_state, usage)) => {
+                intermediate_states.push(to_state.clone());
+                shard_usages.push(usage);
+                from_state = to_state;
+            }
+            None => break,
+        }
+    }
+
+    let nb_cached = intermediate_states.len();
+    if nb_cached < shards.len() {
+        let remaining_input = if nb_cached == 0 {
+            input
+        } else {
+            from_state.inject_script()
+        };
+        let (computed_states, _, computed_usages) =
+            execute_shards(&shards[nb_cached..], remaining_input);
+        for ((shard, to_state), usage) in shards[nb_cached..]
+            .iter()
+            .zip(computed_states.iter())
+            .zip(computed_usages.iter())
+        {
+            // The cache only saves executions, failing to write it is not an error
+            let _ = cache.insert(shard, &from_state, to_state, usage);
+            from_state = to_state.clone();
+        }
+
+        intermediate_states.extend(computed_states);
+        shard_usages.extend(computed_usages);
+    }
+
+    let usages = leaf_usages(input_usage, &intermediate_states, &shard_usages);
+    (intermediate_states, usages)
+}
+
+/// Given an array of shards and input, creates the vector of intermediate
+/// states by executing each shard separately, from the state left by the
+/// previous one pushed back by [`IntermediateState::inject_script`]
+pub fn form_states_by_reinjection(shards: Vec<Script>, input: Script) -> Vec<IntermediateState> {
     let mut intermediate_states: Vec<IntermediateState> = vec![];
 
     // We do the following steps:
@@ -204,3 +588,28 @@ pub fn form_states_from_shards(shards: Vec<Script>, input: Script) -> Vec<Interm
 
     intermediate_states
 }
+
+/// Checks that every shard, executed from the state before it, stays within
+/// the stack and element size limits of tapscript leaves, as measured while
+/// forming the states. Since `execute_script` does not enforce the stack
+/// limit, states alone do not guarantee that.
+///
+/// The validation weight is not checked here. Every shard gets the budget of
+/// a leaf with an empty witness while forming the states, so a shard
+/// exceeding it fails with
+/// [`ExecError::TapscriptValidationWeight`](bitcoin_scriptexec::ExecError::TapscriptValidationWeight)
+/// and its state is the one at the failure.
+///
+/// NOTE: Panics if a limit is exceeded, so that [`fuzzy_split`] skips
+/// the chunk size.
+pub fn assert_shards_within_limits(usages: &[ShardUsage]) {
+    for (i, usage) in usages.iter().enumerate() {
+        assert!(
+            usage.is_within_limits(),
+            "Shard {} exceeds the tapscript limits: {} stack items, {} byte element",
+            i,
+            usage.max_nb_items,
+            usage.max_element_size
+        );
+    }
+}
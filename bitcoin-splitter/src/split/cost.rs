@@ -0,0 +1,80 @@
+//! Module containing the cost models the splitter optimizes against.
+//!
+//! Every shard `f[i]` ends up in a disprove leaf checking `z[i] = f[i](z[i-1])`,
+//! so the cost of a shard depends on the shard itself and on the shapes of
+//! the states around it. The cost models here are restricted to costs of the
+//! form `from_state_cost(z[i-1]) + |f[i]| + to_state_cost(z[i])`, which lets
+//! [`split_into_optimal_shards_with_cost`] find the best split in a single
+//! execution of the script.
+//!
+//! [`split_into_optimal_shards_with_cost`]: super::optimal::split_into_optimal_shards_with_cost
+
+use super::{core::STACK_SIZE_INDEX, intermediate_state::IntermediateState};
+
+/// Number of elements of the stack and the altstack of a state
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct StateShape {
+    pub stack: usize,
+    pub altstack: usize,
+}
+
+impl StateShape {
+    /// Returns the total number of elements (stack + altstack)
+    pub fn size(&self) -> usize {
+        self.stack + self.altstack
+    }
+}
+
+impl From<&IntermediateState> for StateShape {
+    fn from(state: &IntermediateState) -> Self {
+        Self {
+            stack: state.stack.len(),
+            altstack: state.altstack.len(),
+        }
+    }
+}
+
+/// Cost of the disprove leaf of a shard, which is
+/// `from_state_cost(from) + shard_size + to_state_cost(to)` for the shard
+/// going from the state `from` to the state `to`.
+pub trait CostModel {
+    /// Part of the cost coming from the state the shard starts from.
+    /// `is_input` is set for the first shard, which starts from the input.
+    fn from_state_cost(&self, from: StateShape, is_input: bool) -> usize;
+
+    /// Part of the cost coming from the state the shard ends in
+    fn to_state_cost(&self, to: StateShape) -> usize;
+
+    /// Cost of the shard of `shard_size` bytes going from `from` to `to`
+    fn shard_cost(
+        &self,
+        from: StateShape,
+        is_input: bool,
+        shard_size: usize,
+        to: StateShape,
+    ) -> usize {
+        self.from_state_cost(from, is_input) + shard_size + self.to_state_cost(to)
+    }
+}
+
+/// Approximation used by [`SplitResult::complexity_index`]: every element of
+/// the states around the shard costs [`STACK_SIZE_INDEX`], and the input is
+/// free.
+///
+/// [`SplitResult::complexity_index`]: super::script::SplitResult::complexity_index
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StackSizeIndex;
+
+impl CostModel for StackSizeIndex {
+    fn from_state_cost(&self, from: StateShape, is_input: bool) -> usize {
+        if is_input {
+            0
+        } else {
+            from.size() * STACK_SIZE_INDEX
+        }
+    }
+
+    fn to_state_cost(&self, to: StateShape) -> usize {
+        to.size() * STACK_SIZE_INDEX
+    }
+}
@@ -1,8 +1,12 @@
 //! Module that contains the implementation of the splitter
 //! together with all auxiliary functions and data structures.
 
+pub mod branches;
+pub mod cache;
 pub mod core;
+pub mod cost;
 pub mod intermediate_state;
+pub mod optimal;
 pub mod script;
 
 #[cfg(test)]
@@ -0,0 +1,311 @@
+//! Module containing the search for the optimal shard boundaries.
+//!
+//! Instead of trying uniform chunk sizes like
+//! [`fuzzy_split`](super::core::fuzzy_split), the script is executed once,
+//! recording the shape of the state at every instruction boundary, and the
+//! cut points minimizing the largest cost of a shard, by default
+//! [`SplitResult::complexity_index`], are chosen by dynamic programming.
+//!
+//! The cost of a shard from boundary `i` to boundary `j` is
+//! `from_state_cost(z_i) + |f| + to_state_cost(z_j)` (see [`CostModel`]),
+//! which is the sum of a term depending on `i` only and a term depending on
+//! `j` only. For a given
+//! limit on the cost, the boundaries reachable with shards within the limit
+//! are thus found in a single pass, keeping the best starting boundaries of
+//! the shards in a sliding window. The smallest feasible limit is found by
+//! binary search, and the split with the fewest shards within it is chosen.
+//!
+//! [`SplitResult::complexity_index`]: super::script::SplitResult::complexity_index
+
+use std::collections::{HashMap, VecDeque};
+
+use bitcoin::{
+    opcodes::all::{OP_ENDIF, OP_IF, OP_NOTIF},
+    script::Instruction,
+};
+use bitcoin_scriptexec::{Exec, ExecObserver, StepDiff, StepInfo};
+use bitcoin_utils::{debug::execute_script_observed, treepp::*};
+
+use super::{
+    core::MAX_SCRIPT_SIZE,
+    cost::{CostModel, StackSizeIndex, StateShape},
+    intermediate_state::IntermediateState,
+};
+
+/// Records the shape of the state after every instruction of the script
+/// following the input
+struct StateShapes {
+    script_start: usize,
+    shapes: Vec<StateShape>,
+}
+
+impl ExecObserver for StateShapes {
+    fn wants_stack_diffs(&self) -> bool {
+        false
+    }
+
+    fn post_step(&mut self, exec: &Exec, step: &StepInfo, _diff: Option<&StepDiff>) {
+        if step.position >= self.script_start {
+            self.shapes.push(StateShape {
+                stack: exec.stack().len(),
+                altstack: exec.altstack().len(),
+            });
+        }
+    }
+}
+
+/// Instruction boundaries of the script, the `k`-th boundary being after the
+/// `k`-th instruction
+struct Boundaries {
+    /// Offset of the boundary in the script, in bytes
+    offsets: Vec<usize>,
+    /// Cost of the shards starting at the boundary coming from the state
+    from_costs: Vec<usize>,
+    /// Cost of the shards ending at the boundary coming from the state
+    to_costs: Vec<usize>,
+    /// Whether the script can be cut at the boundary, that is, whether it is
+    /// outside of any conditional
+    can_cut: Vec<bool>,
+}
+
+impl Boundaries {
+    fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// Part of the cost of the shards starting at the boundary
+    fn start_cost(&self, i: usize) -> i64 {
+        self.from_costs[i] as i64 - self.offsets[i] as i64
+    }
+
+    /// Part of the cost of the shards ending at the boundary
+    fn end_cost(&self, j: usize) -> i64 {
+        (self.offsets[j] + self.to_costs[j]) as i64
+    }
+
+    fn fits(&self, i: usize, j: usize) -> bool {
+        self.offsets[j] - self.offsets[i] <= MAX_SCRIPT_SIZE
+    }
+}
+
+/// Boundaries shards can start from, by increasing start cost. Boundaries
+/// that are both before and more expensive than another one are dropped.
+struct Window<'a> {
+    boundaries: &'a Boundaries,
+    starts: VecDeque<usize>,
+}
+
+impl<'a> Window<'a> {
+    fn new(boundaries: &'a Boundaries) -> Self {
+        Self {
+            boundaries,
+            starts: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, i: usize) {
+        let cost = self.boundaries.start_cost(i);
+        while let Some(&last) = self.starts.back() {
+            if self.boundaries.start_cost(last) < cost {
+                break;
+            }
+            self.starts.pop_back();
+        }
+        self.starts.push_back(i);
+    }
+
+    /// Returns the cheapest boundary a shard ending at `j` can start from
+    fn best_start(&mut self, j: usize) -> Option<usize> {
+        while let Some(&first) = self.starts.front() {
+            if self.boundaries.fits(first, j) {
+                break;
+            }
+            self.starts.pop_front();
+        }
+        self.starts.front().copied()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.starts.is_empty()
+    }
+}
+
+/// Whether the end of the script can be reached with shards of cost at most
+/// `limit`
+fn is_feasible(boundaries: &Boundaries, limit: i64) -> bool {
+    let mut window = Window::new(boundaries);
+    window.push(0);
+    for j in 1..boundaries.len() {
+        if !boundaries.can_cut[j] {
+            continue;
+        }
+        let Some(i) = window.best_start(j) else {
+            return false;
+        };
+        if boundaries.start_cost(i) + boundaries.end_cost(j) <= limit {
+            if j == boundaries.len() - 1 {
+                return true;
+            }
+            window.push(j);
+        }
+    }
+    boundaries.len() == 1
+}
+
+/// Returns the boundaries of the split with the fewest shards of cost at
+/// most `limit`, if any. The boundaries reachable with `n + 1` shards are
+/// found from the ones reachable with `n` shards.
+fn fewest_cuts(boundaries: &Boundaries, limit: i64) -> Option<Vec<usize>> {
+    let end = boundaries.len() - 1;
+    let mut previous: Vec<Option<usize>> = vec![None; boundaries.len()];
+    let mut reached = vec![false; boundaries.len()];
+    reached[0] = true;
+
+    let mut layer = vec![0];
+    while !reached[end] {
+        let mut next_layer = vec![];
+        let mut window = Window::new(boundaries);
+        let mut starts = layer.iter().copied().peekable();
+        for j in layer[0] + 1..=end {
+            while let Some(i) = starts.next_if(|&i| i < j) {
+                window.push(i);
+            }
+            let best_start = window.best_start(j);
+            if window.is_empty() && starts.peek().is_none() {
+                break;
+            }
+            if reached[j] || !boundaries.can_cut[j] {
+                continue;
+            }
+            if let Some(i) = best_start {
+                if boundaries.start_cost(i) + boundaries.end_cost(j) <= limit {
+                    reached[j] = true;
+                    previous[j] = Some(i);
+                    next_layer.push(j);
+                }
+            }
+        }
+        if next_layer.is_empty() {
+            return None;
+        }
+        layer = next_layer;
+    }
+
+    let mut cuts = vec![end];
+    while let Some(i) = previous[*cuts.last().unwrap()] {
+        cuts.push(i);
+    }
+    cuts.reverse();
+    Some(cuts)
+}
+
+/// Splits the given script into the shards minimizing
+/// [`SplitResult::complexity_index`](super::script::SplitResult::complexity_index)
+/// for the given input, executing the script only once. The script is only
+/// cut outside of conditionals.
+///
+/// NOTE: Panics if the script fails on the input or if it can not be split
+/// into shards of at most [`MAX_SCRIPT_SIZE`].
+pub fn split_into_optimal_shards(input: &Script, script: &Script) -> Vec<Script> {
+    split_into_optimal_shards_with_cost(input, script, &StackSizeIndex)
+}
+
+/// Splits the given script into the shards minimizing the largest cost of a
+/// shard under the given [`CostModel`], see [`split_into_optimal_shards`].
+///
+/// NOTE: Panics if the script fails on the input or if it can not be split
+/// into shards of at most [`MAX_SCRIPT_SIZE`].
+pub fn split_into_optimal_shards_with_cost<C: CostModel>(
+    input: &Script,
+    script: &Script,
+    cost_model: &C,
+) -> Vec<Script> {
+    let instructions: Vec<Instruction> = script
+        .instructions()
+        .map(|instruction| instruction.expect("script is most likely corrupted"))
+        .collect();
+    if instructions.is_empty() {
+        return vec![Script::new()];
+    }
+
+    let mut state_shapes = StateShapes {
+        script_start: input.len(),
+        shapes: vec![],
+    };
+    let mut full_script = input.to_bytes();
+    full_script.extend_from_slice(script.as_bytes());
+    let result = execute_script_observed(Script::from_bytes(full_script), &mut state_shapes);
+    assert!(
+        result.error.is_none(),
+        "Script fails on the input: {:?}",
+        result.error
+    );
+
+    // States of large scripts take few distinct shapes, whose costs may be
+    // expensive to compute
+    let mut costs: HashMap<StateShape, (usize, usize)> = HashMap::new();
+    let mut costs_of = |shape: StateShape| {
+        *costs.entry(shape).or_insert_with(|| {
+            (
+                cost_model.from_state_cost(shape, false),
+                cost_model.to_state_cost(shape),
+            )
+        })
+    };
+
+    let input_shape = StateShape::from(&IntermediateState::from_inject_script(input));
+    let mut boundaries = Boundaries {
+        offsets: vec![0],
+        from_costs: vec![cost_model.from_state_cost(input_shape, true)],
+        to_costs: vec![costs_of(input_shape).1],
+        can_cut: vec![true],
+    };
+    let mut depth = 0;
+    for (instruction, shape) in instructions.iter().zip(state_shapes.shapes) {
+        let mut instruction_script = Script::new();
+        instruction_script.push_instruction(*instruction);
+        boundaries
+            .offsets
+            .push(boundaries.offsets.last().unwrap() + instruction_script.len());
+        let (from_cost, to_cost) = costs_of(shape);
+        boundaries.from_costs.push(from_cost);
+        boundaries.to_costs.push(to_cost);
+
+        match instruction {
+            Instruction::Op(OP_IF | OP_NOTIF) => depth += 1,
+            Instruction::Op(OP_ENDIF) => depth -= 1,
+            _ => {}
+        }
+        boundaries.can_cut.push(depth == 0);
+    }
+
+    // Binary search of the smallest limit on the cost of the shards
+    let max_from_cost = boundaries.from_costs.iter().max().copied().unwrap_or(0);
+    let max_to_cost = boundaries.to_costs.iter().max().copied().unwrap_or(0);
+    let mut high = (MAX_SCRIPT_SIZE + max_from_cost + max_to_cost) as i64;
+    assert!(
+        is_feasible(&boundaries, high),
+        "Script can not be split into shards of at most {} bytes",
+        MAX_SCRIPT_SIZE
+    );
+    let mut low = 0;
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if is_feasible(&boundaries, middle) {
+            high = middle;
+        } else {
+            low = middle + 1;
+        }
+    }
+
+    let cuts = fewest_cuts(&boundaries, high).expect("the limit is feasible");
+    cuts.windows(2)
+        .map(|cut| {
+            let mut shard = Script::new();
+            for instruction in &instructions[cut[0]..cut[1]] {
+                shard.push_instruction(*instruction);
+            }
+            shard
+        })
+        .collect()
+}
@@ -3,10 +3,16 @@
 use core::fmt;
 
 use super::{
-    core::{default_split, fuzzy_split, naive_split, SplitType, STACK_SIZE_INDEX},
+    cache::ShardCache,
+    core::{
+        branch_split, default_split, fuzzy_split, fuzzy_split_with_cost, naive_split,
+        optimal_split, optimal_split_with_cost, SplitType, STACK_SIZE_INDEX,
+    },
+    cost::{CostModel, StateShape},
     intermediate_state::IntermediateState,
 };
-use bitcoin_utils::treepp::*;
+use bitcoin_scriptexec::{symbolic::Limits, ExecError, Stack};
+use bitcoin_utils::{debug::explore_script, stack_to_script, treepp::*};
 
 /// Structure that represents a pair of input and output scripts. Typically, the prover
 /// wants to prove `script(input) == output`
@@ -122,6 +128,47 @@ impl SplitResult {
 
         resultant_complexity
     }
+
+    /// Returns the largest cost of a shard under the given [`CostModel`],
+    /// the first shard starting from the state of the input
+    pub fn max_cost<C: CostModel>(&self, input: &Script, cost_model: &C) -> usize {
+        let input_shape = StateShape::from(&IntermediateState::from_inject_script(input));
+
+        (0..self.len())
+            .map(|i| {
+                let from = if i > 0 {
+                    StateShape::from(&self.intermediate_states[i - 1])
+                } else {
+                    input_shape
+                };
+                let to = StateShape::from(&self.intermediate_states[i]);
+
+                cost_model.shard_cost(from, i == 0, self.shards[i].len(), to)
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Reason why an input breaks the script
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BreakReason {
+    /// The script fails with the given error
+    Failure(ExecError),
+    /// The script leaves the given number of elements on the stack instead of `OUTPUT_SIZE`
+    OutputSize(usize),
+    /// The script leaves the given number of elements on the altstack
+    Altstack(usize),
+}
+
+/// Structure that represents an input on which the script does not behave
+/// like on valid inputs
+#[derive(Debug, Clone)]
+pub struct BreakingInput {
+    /// Input script containing the elements which break the main script
+    pub input: Script,
+    /// Reason why the input breaks the main script
+    pub reason: BreakReason,
 }
 
 /// Trait that any script that can be split should implement
@@ -170,6 +217,37 @@ pub trait SplitableScript<const INPUT_SIZE: usize, const OUTPUT_SIZE: usize> {
         Self::verify(input, output)
     }
 
+    /// Looks for inputs breaking the script by exploring its paths
+    /// symbolically, starting from a valid input.
+    ///
+    /// NOTE: Only the elements that are numbers of at most 4 bytes are
+    /// treated as symbols, see [bitcoin_scriptexec::symbolic].
+    fn find_breaking_inputs(limits: &Limits) -> Vec<BreakingInput> {
+        let IOPair { input, .. } = Self::generate_valid_io_pair();
+        let seed = execute_script(input).main_stack.iter_str().collect();
+
+        explore_script(Self::script(), seed, limits)
+            .paths
+            .into_iter()
+            .filter_map(|path| {
+                let reason = if let Some(error) = path.result.error {
+                    BreakReason::Failure(error)
+                } else if path.result.final_stack.len() != OUTPUT_SIZE {
+                    BreakReason::OutputSize(path.result.final_stack.len())
+                } else if !path.final_altstack.is_empty() {
+                    BreakReason::Altstack(path.final_altstack.len())
+                } else {
+                    return None;
+                };
+
+                Some(BreakingInput {
+                    input: stack_to_script(&Stack::from_u8_vec(path.input)),
+                    reason,
+                })
+            })
+            .collect()
+    }
+
     /// Splits the script into smaller parts
     fn default_split(input: Script, split_type: SplitType) -> SplitResult {
         default_split(input, Self::script(), split_type)
@@ -180,8 +258,38 @@ pub trait SplitableScript<const INPUT_SIZE: usize, const OUTPUT_SIZE: usize> {
         naive_split(input, Self::script(), split_type, chunk_size)
     }
 
+    /// Splits the script into smaller parts with the specified chunk size,
+    /// cutting inside the branches of conditionals if needed
+    fn branch_split(input: Script, split_type: SplitType, chunk_size: usize) -> SplitResult {
+        branch_split(input, Self::script(), split_type, chunk_size)
+    }
+
     /// Splits the script into smaller parts with the fuzzy split
     fn fuzzy_split(input: Script, split_type: SplitType) -> SplitResult {
         fuzzy_split(input, Self::script(), split_type)
     }
+
+    /// Splits the script into the smaller parts minimizing the complexity
+    /// index for the given input
+    fn optimal_split(input: Script) -> SplitResult {
+        optimal_split(input, Self::script())
+    }
+
+    /// Splits the script into smaller parts with the fuzzy split, minimizing
+    /// the largest cost of a shard under the given cost model, with the
+    /// intermediate states of the shards going through the cache if any
+    fn fuzzy_split_with_cost<C: CostModel>(
+        input: Script,
+        split_type: SplitType,
+        cost_model: &C,
+        cache: Option<&ShardCache>,
+    ) -> SplitResult {
+        fuzzy_split_with_cost(input, Self::script(), split_type, cost_model, cache)
+    }
+
+    /// Splits the script into the smaller parts minimizing the largest cost
+    /// of a shard under the given cost model for the given input
+    fn optimal_split_with_cost<C: CostModel>(input: Script, cost_model: &C) -> SplitResult {
+        optimal_split_with_cost(input, Self::script(), cost_model)
+    }
 }
@@ -1,6 +1,19 @@
-use super::{core::split_into_shards, intermediate_state::IntermediateState};
+use std::fs;
+
+use super::{
+    branches::split_into_branch_shards,
+    cache::{ShardCache, FORMAT_VERSION},
+    core::{
+        execute_shards_with_options, form_states_by_reinjection, form_states_from_shards,
+        naive_split, optimal_split, optimal_split_with_cost, split_into_shards, ShardUsage,
+    },
+    cost::{CostModel, StackSizeIndex, StateShape},
+    intermediate_state::IntermediateState,
+    script::{BreakReason, IOPair, SplitableScript},
+};
 use crate::split::core::SplitType;
-use bitcoin_utils::{stack_to_script, treepp::*};
+use bitcoin_scriptexec::{ExecError, Options};
+use bitcoin_utils::{debug::default_options, stack_to_script, treepp::*};
 
 /// Tests whether splitting the script into subprograms (shards)
 /// works properly for the most basic script
@@ -275,3 +288,462 @@ fn test_state_from_state() {
         "z3 altstack should be empty at this point"
     );
 }
+
+/// Tests that the splitter refuses shards exceeding the stack limit,
+/// even though the script itself cleans the stack up
+#[test]
+#[should_panic(expected = "exceeds the tapscript limits")]
+fn test_split_exceeding_stack_limit() {
+    let script = script! {
+        for _ in 0..1001 {
+            OP_1
+        }
+        for _ in 0..1001 {
+            OP_DROP
+        }
+        OP_1
+    };
+
+    naive_split(script! {}, script, SplitType::ByInstructions, 5000);
+}
+
+/// Tests that the splitter counts the stack items of a shard pushed by the
+/// state before it, including the index used to move items to the altstack
+#[test]
+#[should_panic(expected = "Shard 1 exceeds the tapscript limits")]
+fn test_split_exceeding_stack_limit_when_injected() {
+    let script = script! {
+        for _ in 0..1000 {
+            OP_1
+        }
+        OP_TOALTSTACK
+        OP_FROMALTSTACK
+        for _ in 0..1000 {
+            OP_DROP
+        }
+        OP_1
+    };
+
+    // The first shard ends with 1000 items, one on the altstack
+    naive_split(script! {}, script, SplitType::ByInstructions, 1001);
+}
+
+/// Splits the script across branches and checks that executing the shards
+/// one after another ends in the same stacks as executing the whole script
+fn assert_branch_split_equivalent(input: Script, script: Script, chunk_size: usize) {
+    let shards = split_into_branch_shards(&script, chunk_size, SplitType::ByInstructions);
+    for (i, shard) in shards.iter().enumerate() {
+        assert!(
+            shard.instructions().count() <= chunk_size,
+            "Shard {} is too big: {}",
+            i,
+            shard.to_asm_string()
+        );
+    }
+
+    let expected = execute_script(script! {
+        { input.clone() }
+        { script }
+    });
+    assert!(expected.error.is_none(), "Test script failed");
+
+    let intermediate_states = form_states_by_reinjection(shards, input);
+    let last_state = intermediate_states.last().unwrap();
+    assert_eq!(
+        last_state.stack.iter_str().collect::<Vec<_>>(),
+        expected.main_stack.iter_str().collect::<Vec<_>>(),
+    );
+    assert!(
+        last_state.altstack.is_empty(),
+        "Markers were left on the altstack"
+    );
+}
+
+/// Returns the items of the states as bytes, since items pushed back by
+/// [`IntermediateState::inject_script`] are bytes rather than numbers
+fn state_items(states: &[IntermediateState]) -> Vec<(Vec<Vec<u8>>, Vec<Vec<u8>>)> {
+    states
+        .iter()
+        .map(|state| {
+            (
+                state.stack.iter_str().collect(),
+                state.altstack.iter_str().collect(),
+            )
+        })
+        .collect()
+}
+
+/// Tests that forming the states in a single execution gives the same states
+/// as executing the shards separately, including after a failing shard
+#[test]
+fn test_form_states_single_pass() {
+    let input = script! { { 5 } { 3 } };
+    let scripts = [
+        script! {
+            OP_2DUP OP_ADD OP_TOALTSTACK
+            OP_2DUP OP_LESSTHAN
+            OP_IF
+                OP_SWAP OP_FROMALTSTACK OP_DUP OP_TOALTSTACK OP_ADD
+            OP_ELSE
+                OP_SUB OP_NEGATE { 1000 } OP_ADD
+            OP_ENDIF
+            OP_DEPTH OP_1SUB OP_ROLL OP_FROMALTSTACK
+            { 7 } OP_TOALTSTACK OP_ADD OP_FROMALTSTACK OP_SWAP
+        },
+        // Fails in the middle of the script
+        script! {
+            OP_ADD OP_DUP OP_TOALTSTACK { 4 } OP_EQUALVERIFY
+            OP_FROMALTSTACK OP_1ADD OP_DUP
+        },
+    ];
+
+    for script in scripts {
+        let nb_instructions = script.instructions().count();
+        for chunk_size in 1..=nb_instructions {
+            for shards in [
+                split_into_shards(&script, chunk_size, SplitType::ByInstructions),
+                split_into_branch_shards(&script, chunk_size, SplitType::ByInstructions),
+            ] {
+                assert_eq!(
+                    state_items(&form_states_from_shards(
+                        shards.clone(),
+                        input.clone(),
+                        None
+                    )),
+                    state_items(&form_states_by_reinjection(shards, input.clone())),
+                    "States differ for the chunk size {}",
+                    chunk_size
+                );
+            }
+        }
+    }
+}
+
+/// Tests that each shard gets its own validation weight budget when forming
+/// the states in a single execution, as when executing the shards separately
+#[test]
+fn test_form_states_validation_weight() {
+    // Signature checks with an unknown public key type succeed when they are
+    // not discouraged, each one using most of the budget of a leaf with an
+    // empty witness
+    let options = Options {
+        discourage_upgradable_pubkey_type: false,
+        ..default_options()
+    };
+    let shards = vec![
+        script! { OP_1 OP_1 OP_CHECKSIGVERIFY },
+        script! { OP_1 OP_1 OP_CHECKSIG },
+    ];
+
+    let (states, _, _) = execute_shards_with_options(&shards, script! {}, options.clone());
+    let reinjected_states = (0..shards.len())
+        .map(|i| {
+            let from_state = match i {
+                0 => script! {},
+                i => states[i - 1].inject_script(),
+            };
+            let (mut shard_states, _, _) =
+                execute_shards_with_options(&shards[i..i + 1], from_state, options.clone());
+            shard_states.remove(0)
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(state_items(&states), state_items(&reinjected_states));
+    assert_eq!(state_items(&states[1..]), vec![(vec![vec![1]], vec![])]);
+}
+
+/// Tests that the states are stored in the cache, which persists across
+/// instances, and that the stored states are used instead of executing
+/// the shards
+#[test]
+fn test_form_states_with_cache() {
+    let cache_dir =
+        std::env::temp_dir().join(format!("bitcoin-splitter-cache-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&cache_dir);
+
+    let input = script! { { 5 } { 3 } };
+    let script = script! {
+        OP_2DUP OP_ADD OP_TOALTSTACK OP_SUB OP_FROMALTSTACK
+        OP_ADD { 10 } OP_SWAP OP_SUB OP_ABS
+    };
+    let shards = split_into_shards(&script, 3, SplitType::ByInstructions);
+    let expected_states = form_states_from_shards(shards.clone(), input.clone(), None);
+
+    let cache = ShardCache::open(&cache_dir).unwrap();
+    let states = form_states_from_shards(shards.clone(), input.clone(), Some(&cache));
+    assert_eq!(state_items(&states), state_items(&expected_states));
+
+    // The states are found by a new instance
+    let cache = ShardCache::open(&cache_dir).unwrap();
+    let input_state = IntermediateState::from_inject_script(&input);
+    let (first_state, _) = cache
+        .get(&shards[0], &input_state)
+        .expect("The state of the first shard was not stored");
+    assert_eq!(
+        state_items(&[first_state]),
+        state_items(&expected_states[..1])
+    );
+
+    // Replacing the state of the first shard, the next shards are executed
+    // from the stored state
+    let stored_state = IntermediateState::from_inject_script(&script! {
+        { 40 } { 2 } { 8 } OP_TOALTSTACK
+    });
+    let stored_usage = ShardUsage {
+        max_nb_items: 3,
+        max_element_size: 1,
+    };
+    cache
+        .insert(&shards[0], &input_state, &stored_state, &stored_usage)
+        .unwrap();
+    let (_, usage) = cache.get(&shards[0], &input_state).unwrap();
+    assert_eq!(usage, stored_usage);
+    let states = form_states_from_shards(shards.clone(), input, Some(&cache));
+    let mut expected_states = vec![stored_state.clone()];
+    expected_states.extend(form_states_from_shards(
+        shards[1..].to_vec(),
+        stored_state.inject_script(),
+        None,
+    ));
+    assert_eq!(state_items(&states), state_items(&expected_states));
+
+    // The entries are stored under the version of the cache
+    let entries: Vec<_> = fs::read_dir(&cache_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(entries.len(), 1);
+    assert!(entries[0]
+        .to_string_lossy()
+        .starts_with(&format!("v{}-", FORMAT_VERSION)));
+
+    fs::remove_dir_all(&cache_dir).unwrap();
+}
+
+/// Tests that a large conditional is split into several shards
+/// that re-enter the branch taken
+#[test]
+fn test_split_across_branches() {
+    const CHUNK_SIZE: usize = 20;
+
+    let script = script! {
+        OP_IF
+            for _ in 0..30 {
+                { 1 } OP_ADD
+            }
+        OP_ELSE
+            for _ in 0..20 {
+                { 2 } OP_ADD
+            }
+        OP_ENDIF
+    };
+
+    // Counting ifs and endifs keeps the whole block in a single shard
+    let shards = split_into_shards(&script, CHUNK_SIZE, SplitType::ByInstructions);
+    assert_eq!(shards.len(), 1);
+
+    let shards = split_into_branch_shards(&script, CHUNK_SIZE, SplitType::ByInstructions);
+    assert!(shards.len() > 1, "The conditional was not split");
+
+    for condition in [0, 1] {
+        assert_branch_split_equivalent(script! { { 5 } { condition } }, script.clone(), CHUNK_SIZE);
+    }
+}
+
+/// Tests splitting nested conditionals, including OP_NOTIF
+#[test]
+fn test_split_across_nested_branches() {
+    const CHUNK_SIZE: usize = 30;
+
+    let script = script! {
+        OP_IF
+            OP_NOTIF
+                for _ in 0..20 {
+                    { 1 } OP_ADD
+                }
+            OP_ENDIF
+            for _ in 0..10 {
+                OP_DUP OP_TOALTSTACK
+            }
+            for _ in 0..10 {
+                OP_FROMALTSTACK OP_ADD
+            }
+        OP_ELSE
+            OP_DROP
+            { 3 }
+        OP_ENDIF
+        { 7 } OP_ADD
+    };
+
+    for (outer, inner) in [(0, 0), (1, 0), (1, 1)] {
+        assert_branch_split_equivalent(
+            script! { { 5 } { inner } { outer } },
+            script.clone(),
+            CHUNK_SIZE,
+        );
+    }
+}
+
+/// Tests that the optimal split of a script larger than the maximum size
+/// cuts where the state is small, and does at least as well as uniform splits
+#[test]
+fn test_optimal_split() {
+    let input = script! { { 1 } };
+    // Phases during which 50 more elements are on the stack
+    let script = script! {
+        for _ in 0..4 {
+            for i in 0..50 {
+                { i }
+            }
+            for _ in 0..5000 {
+                OP_DUP OP_DROP
+            }
+            for _ in 0..50 {
+                OP_DROP
+            }
+            for _ in 0..5000 {
+                OP_DUP OP_DROP
+            }
+        }
+    };
+
+    let split_result = optimal_split(input.clone(), script.clone());
+    assert!(split_result.len() > 1, "The script was not split");
+    for state in split_result.intermediate_states.iter() {
+        assert_eq!(state.size(), 1, "The script was cut inside a phase");
+    }
+
+    for chunk_size in [5000, 10000, 20000, 40000] {
+        let naive_result = naive_split(
+            input.clone(),
+            script.clone(),
+            SplitType::ByBytes,
+            chunk_size,
+        );
+        assert!(
+            split_result.complexity_index() <= naive_result.complexity_index(),
+            "Chunk size {} gives a better split",
+            chunk_size
+        );
+    }
+}
+
+/// Cost model in which only the elements of the main stack are expensive
+struct MainStackIndex;
+
+impl CostModel for MainStackIndex {
+    fn from_state_cost(&self, from: StateShape, _is_input: bool) -> usize {
+        from.stack * 1000
+    }
+
+    fn to_state_cost(&self, to: StateShape) -> usize {
+        to.stack * 1000
+    }
+}
+
+/// Tests that the optimal split follows the cost model: with only the main
+/// stack being expensive, the script is cut where the elements are kept in
+/// the altstack rather than where the state is the smallest
+#[test]
+fn test_optimal_split_with_cost() {
+    let input = script! {
+        for i in 0..20 {
+            { i }
+        }
+    };
+    // Phases with 50 elements in the altstack, then 20 elements in the stack
+    let script = script! {
+        for _ in 0..4 {
+            for i in 0..31 {
+                { i }
+            }
+            for _ in 0..50 {
+                OP_TOALTSTACK
+            }
+            for _ in 0..5000 {
+                OP_DUP OP_DROP
+            }
+            for _ in 0..50 {
+                OP_FROMALTSTACK
+            }
+            for _ in 0..31 {
+                OP_DROP
+            }
+            for _ in 0..5000 {
+                OP_DUP OP_DROP
+            }
+        }
+    };
+
+    let split_result = optimal_split_with_cost(input.clone(), script.clone(), &MainStackIndex);
+    assert!(split_result.len() > 1, "The script was not split");
+    for state in &split_result.intermediate_states[..split_result.len() - 1] {
+        assert_eq!(
+            state.stack.len(),
+            1,
+            "The script was not cut in an altstack phase"
+        );
+    }
+
+    let default_result = optimal_split(input.clone(), script);
+    assert_eq!(
+        default_result.max_cost(&input, &StackSizeIndex),
+        default_result.complexity_index()
+    );
+    assert!(
+        split_result.max_cost(&input, &MainStackIndex)
+            < default_result.max_cost(&input, &MainStackIndex),
+        "The default cost model gives a better split"
+    );
+}
+
+/// Toy script that adds two numbers whose sum must be below 1000
+struct BoundedAddScript;
+
+impl SplitableScript<2, 1> for BoundedAddScript {
+    fn script() -> Script {
+        script! {
+            OP_2DUP OP_ADD { 1000 } OP_LESSTHAN OP_VERIFY
+            OP_ADD
+        }
+    }
+
+    fn generate_valid_io_pair() -> IOPair<2, 1> {
+        IOPair {
+            input: script! { { 3 } { 4 } },
+            output: script! { { 7 } },
+        }
+    }
+
+    fn generate_invalid_io_pair() -> IOPair<2, 1> {
+        IOPair {
+            input: script! { { 3 } { 4 } },
+            output: script! { { 8 } },
+        }
+    }
+}
+
+#[test]
+fn test_find_breaking_inputs() {
+    assert!(BoundedAddScript::verify_random());
+
+    let breaking_inputs = BoundedAddScript::find_breaking_inputs(&Default::default());
+    assert!(
+        breaking_inputs
+            .iter()
+            .any(|input| input.reason == BreakReason::Failure(ExecError::Verify)),
+        "the bound was not found"
+    );
+
+    // Every input found should indeed break the script
+    for breaking_input in breaking_inputs {
+        let BreakReason::Failure(error) = breaking_input.reason else {
+            panic!("unexpected reason: {:?}", breaking_input.reason);
+        };
+
+        let result = execute_script(script! {
+            { breaking_input.input }
+            { BoundedAddScript::script() }
+        });
+        assert_eq!(result.error, Some(error));
+    }
+}
@@ -42,6 +42,7 @@ impl<const N_BITS: u32, const LIMB_SIZE: u32> BigIntImpl<N_BITS, LIMB_SIZE> {
 #[cfg(test)]
 mod test {
     use crate::bitvm::bigint::{U254, U64};
+    use bitcoin_scriptexec::analysis::analyze;
     use bitcoin_utils::treepp::*;
     use core::ops::{Mul, Rem, Shl};
     use num_bigint::{BigUint, RandomBits};
@@ -84,4 +85,15 @@ mod test {
             run(script);
         }
     }
+
+    #[test]
+    fn test_mul_stack_effect() {
+        let analysis = analyze(&U254::mul()).unwrap();
+        println!("U254.mul: {}", analysis.effect);
+        assert!(analysis.issues.is_empty(), "{:?}", analysis.issues);
+        assert_eq!(analysis.effect.inputs, 2 * U254::N_LIMBS as usize);
+        assert_eq!(analysis.effect.outputs(), Some(U254::N_LIMBS as usize));
+        assert_eq!(analysis.effect.altstack_inputs, 0);
+        assert_eq!(analysis.effect.altstack_outputs(), Some(0));
+    }
 }
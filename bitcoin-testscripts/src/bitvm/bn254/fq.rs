@@ -407,6 +407,7 @@ mod test {
     use crate::bitvm::bn254::utils::fq_push_not_montgomery;
     use ark_ff::{BigInteger, Field, PrimeField};
     use ark_std::UniformRand;
+    use bitcoin_scriptexec::analysis::analyze;
     use bitcoin_utils::treepp::*;
 
     use core::ops::{Add, Mul, Rem, Sub};
@@ -603,6 +604,17 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_square_stack_effect() {
+        let analysis = analyze(&Fq::square()).unwrap();
+        println!("Fq.square: {}", analysis.effect);
+        assert!(analysis.issues.is_empty(), "{:?}", analysis.issues);
+        assert_eq!(analysis.effect.inputs, Fq::N_LIMBS as usize);
+        assert_eq!(analysis.effect.outputs(), Some(Fq::N_LIMBS as usize));
+        // The altstack isn't checked: Fq::sub pops it in branches that depend
+        // on each other, which the analysis doesn't follow.
+    }
+
     #[test]
     fn test_neg() {
         println!("Fq.neg: {} bytes", Fq::neg(0).len());
@@ -160,6 +160,29 @@ mod tests {
         println!("Total size of the states: {} bytes", total_size);
     }
 
+    #[test]
+    fn test_optimal_split() {
+        let IOPair { input, output } = U261MulKaratsubaScript::generate_valid_io_pair();
+
+        let split_result = U261MulKaratsubaScript::optimal_split(input.clone());
+
+        // The last state must be equal to the result of the multiplication
+        let last_state = split_result.must_last_state();
+        assert!(last_state.altstack.is_empty(), "altstack is not empty!");
+        let verification_script = script! {
+            { stack_to_script(&last_state.stack) }
+            { output }
+            { U522::OP_EQUAL(0, 1) }
+        };
+        let result = execute_script(verification_script);
+        assert!(result.success, "verification has failed");
+
+        // The split must be at least as good as the default one
+        let default_result =
+            U261MulKaratsubaScript::default_split(input, SplitType::ByInstructions);
+        assert!(split_result.complexity_index() <= default_result.complexity_index());
+    }
+
     #[test]
     #[ignore = "too-large computation, run separately"]
     fn test_fuzzy_split() {
@@ -109,7 +109,7 @@ impl<const STEPS: usize> SplitableScript<{ INPUT_SIZE }, { OUTPUT_SIZE }>
         }
 
         // Secondly, form the intermediate states and return
-        let intermediate_states = form_states_from_shards(shards.clone(), input);
+        let intermediate_states = form_states_from_shards(shards.clone(), input, None);
         SplitResult {
             shards,
             intermediate_states,
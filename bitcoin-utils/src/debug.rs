@@ -1,6 +1,13 @@
 use crate::treepp;
-use bitcoin::{hashes::Hash, ScriptBuf, TapLeafHash, Transaction};
-use bitcoin_scriptexec::{Exec, ExecCtx, ExecError, ExecStats, Options, Stack, TxTemplate};
+use bitcoin::ScriptBuf;
+use bitcoin_scriptexec::budget::{check_leaf, BudgetReport};
+use bitcoin_scriptexec::profile::{
+    region_begin_marker, region_end_marker, ProfileReport, Profiler,
+};
+use bitcoin_scriptexec::symbolic::{explore, Exploration, Limits};
+use bitcoin_scriptexec::{
+    Exec, ExecCtx, ExecError, ExecObserver, ExecStats, Options, Stack, TxTemplate,
+};
 use core::fmt;
 
 /// Information about the status of the script execution.
@@ -23,7 +30,7 @@ impl fmt::Display for ExecuteInfo {
         }
 
         if let Some(ref error) = self.error {
-            writeln!(f, "Error: {:?}", error)?;
+            writeln!(f, "Error: {} ({})", error, error.name())?;
         }
 
         writeln!(f, "Stats: {:?}", self.stats)?;
@@ -42,29 +49,24 @@ impl fmt::Display for ExecuteInfo {
     }
 }
 
+/// The options all scripts are executed with.
+pub fn default_options() -> Options {
+    Options {
+        // TODO(ZamDimon): Figure our how to optimize stack_to_script function to avoid disabling require_minimal
+        // TODO(ZamDimon): Currently, Winternitz does not work with the stack limit
+        require_minimal: false,
+        enforce_stack_limit: false,
+        ..Default::default()
+    }
+}
+
 /// Executes the given script and returns the result of the execution
 /// (success, error, stack, etc.)
 pub fn execute_script(script: ScriptBuf) -> ExecuteInfo {
     let mut exec = Exec::new(
         ExecCtx::Tapscript,
-        Options {
-            // TODO(ZamDimon): Figure our how to optimize stack_to_script function to avoid disabling require_minimal
-            // TODO(ZamDimon): Currently, Winternitz does not work with the stack limit
-            require_minimal: false,
-            enforce_stack_limit: false,
-            ..Default::default()
-        },
-        TxTemplate {
-            tx: Transaction {
-                version: bitcoin::transaction::Version::TWO,
-                lock_time: bitcoin::locktime::absolute::LockTime::ZERO,
-                input: vec![],
-                output: vec![],
-            },
-            prevouts: vec![],
-            input_idx: 0,
-            taproot_annex_scriptleaf: Some((TapLeafHash::all_zeros(), None)),
-        },
+        default_options(),
+        TxTemplate::dummy(ExecCtx::Tapscript),
         script,
         vec![],
     )
@@ -89,6 +91,39 @@ pub fn execute_script(script: ScriptBuf) -> ExecuteInfo {
     }
 }
 
+/// Executes the given script like [execute_script], reporting every step to
+/// the given observer.
+pub fn execute_script_observed<O: ExecObserver>(
+    script: ScriptBuf,
+    observer: &mut O,
+) -> ExecuteInfo {
+    let mut exec = Exec::new(
+        ExecCtx::Tapscript,
+        default_options(),
+        TxTemplate::dummy(ExecCtx::Tapscript),
+        script,
+        vec![],
+    )
+    .expect("error when creating the execution body");
+
+    // Execute all the opcodes while possible
+    loop {
+        if exec.exec_next_observed(observer).is_err() {
+            break;
+        }
+    }
+
+    let result = exec.result().unwrap();
+
+    ExecuteInfo {
+        success: result.success,
+        error: result.error.clone(),
+        main_stack: exec.stack().clone(),
+        alt_stack: exec.altstack().clone(),
+        stats: exec.stats().clone(),
+    }
+}
+
 pub fn run(script: bitcoin::ScriptBuf) {
     let exec_result = execute_script(script);
     if !exec_result.success {
@@ -116,17 +151,7 @@ pub fn execute_script_no_stack_limit(script: bitcoin::ScriptBuf) -> ExecuteInfo
     let mut exec = Exec::new(
         ExecCtx::Tapscript,
         opts,
-        TxTemplate {
-            tx: Transaction {
-                version: bitcoin::transaction::Version::TWO,
-                lock_time: bitcoin::locktime::absolute::LockTime::ZERO,
-                input: vec![],
-                output: vec![],
-            },
-            prevouts: vec![],
-            input_idx: 0,
-            taproot_annex_scriptleaf: Some((TapLeafHash::all_zeros(), None)),
-        },
+        TxTemplate::dummy(ExecCtx::Tapscript),
         script,
         vec![],
     )
@@ -151,6 +176,82 @@ pub fn execute_script_no_stack_limit(script: bitcoin::ScriptBuf) -> ExecuteInfo
     }
 }
 
+/// Wraps the script in region markers for [profile_script].
+///
+/// The markers don't change the result of the script, but they do increase
+/// its size, so they should only be used while profiling.
+pub fn profile_region(name: &str, script: treepp::Script) -> treepp::Script {
+    let mut bytes = region_begin_marker(name).into_bytes();
+    bytes.extend(script.into_bytes());
+    bytes.extend(region_end_marker(name).into_bytes());
+    treepp::Script::from_bytes(bytes)
+}
+
+/// Executes the given script like [execute_script] and returns the profile
+/// of the execution, with counts per opcode and per [profile_region].
+pub fn profile_script(script: ScriptBuf) -> ProfileReport {
+    let mut profiler = Profiler::new(&script);
+    let mut exec = Exec::new(
+        ExecCtx::Tapscript,
+        default_options(),
+        TxTemplate::dummy(ExecCtx::Tapscript),
+        script,
+        vec![],
+    )
+    .expect("error when creating the execution body");
+
+    // Execute all the opcodes while possible
+    loop {
+        if exec.exec_next_observed(&mut profiler).is_err() {
+            break;
+        }
+    }
+
+    profiler.finish()
+}
+
+/// Explores the paths of the given script, executed like [execute_script],
+/// starting from the given input stack, and returns the paths that were
+/// found, including the ones on which the script fails.
+///
+/// See [bitcoin_scriptexec::symbolic] for which items are treated as
+/// symbols.
+pub fn explore_script(script: ScriptBuf, seed: Vec<Vec<u8>>, limits: &Limits) -> Exploration {
+    explore(
+        ExecCtx::Tapscript,
+        default_options(),
+        TxTemplate::dummy(ExecCtx::Tapscript),
+        script,
+        seed,
+        limits,
+    )
+    .expect("error when creating the execution body")
+}
+
+/// Executes the given leaf script like [execute_script], starting with the
+/// given witness stack, and reports the consensus and policy limits used by
+/// the spend.
+///
+/// See [bitcoin_scriptexec::budget::check_leaf] for the control block size.
+pub fn check_leaf_budget(
+    script: ScriptBuf,
+    witness: Vec<Vec<u8>>,
+    control_block_size: usize,
+) -> BudgetReport {
+    check_leaf(
+        // The stack limit is part of the budget.
+        Options {
+            enforce_stack_limit: true,
+            ..default_options()
+        },
+        TxTemplate::dummy(ExecCtx::Tapscript),
+        script,
+        witness,
+        control_block_size,
+    )
+    .expect("error when creating the execution body")
+}
+
 /// Run the given script and panic if the script execution fails.
 #[allow(dead_code)]
 pub fn run_and_assert(script: treepp::Script) {
@@ -164,7 +265,7 @@ pub fn run_and_assert(script: treepp::Script) {
 
 #[cfg(test)]
 mod test {
-    use super::execute_script_no_stack_limit;
+    use super::{execute_script_no_stack_limit, explore_script, profile_region, profile_script};
     use crate::treepp::*;
 
     #[test]
@@ -179,6 +280,55 @@ mod test {
         assert!(!exec_result.success);
     }
 
+    #[test]
+    fn test_profile_script() {
+        let script = script! {
+            { profile_region("add", script! { 1 2 OP_ADD }) }
+            { profile_region("check", script! {
+                { profile_region("push", script! { 3 }) }
+                OP_EQUAL
+            }) }
+        };
+
+        let report = profile_script(script);
+        assert!(report.error.is_none());
+        assert_eq!(report.executed_count, 5);
+        // The marker pushes are not counted.
+        assert_eq!(report.max_nb_stack_items, 2);
+        assert_eq!(
+            report.to_folded(),
+            "script;add 3\nscript;check 1\nscript;check;push 1\n"
+        );
+    }
+
+    #[test]
+    fn test_explore_script() {
+        // Fails for zero and for inputs above 100.
+        let script = script! {
+            OP_DUP 100 OP_GREATERTHAN
+            OP_IF
+                OP_DROP OP_0
+            OP_ENDIF
+            OP_VERIFY
+            OP_TRUE
+        };
+
+        let exploration = explore_script(script.clone(), vec![vec![5]], &Default::default());
+        assert!(exploration.complete);
+        assert_eq!(exploration.paths.len(), 3);
+        assert_eq!(exploration.failures().count(), 2);
+
+        for failure in exploration.failures() {
+            let input = script! {
+                for item in failure.input.iter() {
+                    { item.clone() }
+                }
+            };
+            let result = execute_script(script! { { input } { script.clone() } });
+            assert!(!result.success);
+        }
+    }
+
     #[test]
     fn test_script_execute_no_stack_limit() {
         let script = script! {
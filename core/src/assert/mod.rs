@@ -13,7 +13,9 @@ use bitcoin::{
     Amount, EcdsaSighashType, OutPoint, Psbt, Sequence, TapSighashType, Transaction, TxIn, TxOut,
     Txid, Witness, XOnlyPublicKey,
 };
+use bitcoin_scriptexec::budget::BudgetReport;
 use bitcoin_splitter::split::script::SplitableScript;
+use bitcoin_utils::debug::check_leaf_budget;
 
 use crate::{
     assert::payout_script::PayoutScript, disprove::form_disprove_scripts_distorted, treepp::*,
@@ -85,14 +87,17 @@ impl<const I: usize, const O: usize, S: SplitableScript<I, O>> AssertTransaction
     ) -> Self {
         let disprove_scripts = form_disprove_scripts::<I, O, S>(input.clone());
         let payout_script = PayoutScript::with_locktime(operator_pubkey, options.payout_locktime);
-        Self {
+        let assert_tx = Self {
             input,
             operator_pubkey,
             amount,
             disprove_scripts,
             payout_script,
             __program: PhantomData,
-        }
+        };
+        assert_tx.assert_within_limits();
+
+        assert_tx
     }
 
     pub fn with_options_distorted(
@@ -103,17 +108,54 @@ impl<const I: usize, const O: usize, S: SplitableScript<I, O>> AssertTransaction
     ) -> (Self, usize) {
         let (disprove_scripts, idx) = form_disprove_scripts_distorted::<I, O, S>(input.clone());
         let payout_script = PayoutScript::with_locktime(operator_pubkey, options.payout_locktime);
-        (
-            Self {
-                input,
-                operator_pubkey,
-                amount,
-                disprove_scripts,
-                payout_script,
-                __program: PhantomData,
-            },
-            idx,
-        )
+        let assert_tx = Self {
+            input,
+            operator_pubkey,
+            amount,
+            disprove_scripts,
+            payout_script,
+            __program: PhantomData,
+        };
+        assert_tx.assert_within_limits();
+
+        (assert_tx, idx)
+    }
+
+    /// Reports the consensus and policy limits used by spending each
+    /// disprove script with its witness, in the order of `disprove_scripts`.
+    pub fn disprove_budgets(&self, ctx: &Secp256k1<All>) -> Vec<BudgetReport> {
+        let taptree = self.form_taptree(ctx);
+
+        self.disprove_scripts
+            .iter()
+            .map(|disprove| {
+                let control_block = taptree
+                    .control_block(&(disprove.script_pubkey.clone(), LeafVersion::TapScript))
+                    .unwrap();
+
+                check_leaf_budget(
+                    disprove.script_pubkey.clone(),
+                    disprove.witness_elements(),
+                    control_block.serialize().len(),
+                )
+            })
+            .collect()
+    }
+
+    /// Panics if spending any of the disprove scripts exceeds a consensus
+    /// or policy limit, as such a leaf can't be used to disprove the
+    /// assertion.
+    fn assert_within_limits(&self) {
+        let ctx = Secp256k1::new();
+
+        for (i, report) in self.disprove_budgets(&ctx).iter().enumerate() {
+            assert!(
+                report.is_standard(),
+                "Disprove script {} exceeds the tapscript limits:\n{}",
+                i,
+                report
+            );
+        }
     }
 
     /// Return partially signed transaction with P2TR output with all disprove
@@ -0,0 +1,102 @@
+//! Exact size of the disprove leaves, used as a cost model by the splitter.
+
+use bitcoin::hashes::{hash160::Hash as Hash160, Hash};
+use bitcoin_splitter::split::cost::{CostModel, StateShape};
+use bitcoin_utils::treepp::*;
+use bitcoin_winternitz::u32::{checksig_verify_script, Message, SecretKey, N};
+use once_cell::sync::Lazy;
+
+use super::DisproveScript;
+
+/// Keys made of the same hashes as any other, the sizes of the scripts
+/// below do not depend on the key nor on the signed element
+static DUMMY_SECRET_KEY: Lazy<SecretKey> = Lazy::new(|| SecretKey::new([Hash160::all_zeros(); N]));
+
+/// Size of the script verifying the signature of a single element and moving
+/// it to the altstack, see
+/// [`SignedIntermediateState::verification_script_toaltstack`](super::signing::SignedIntermediateState::verification_script_toaltstack)
+static ELEMENT_VERIFICATION_SIZE: Lazy<usize> = Lazy::new(|| {
+    script! {
+        { checksig_verify_script(&DUMMY_SECRET_KEY.public_key()) }
+        { Message::recovery_script() }
+        OP_TOALTSTACK
+    }
+    .len()
+});
+
+/// Size of the witness of a single element, see
+/// [`SignedIntermediateState::witness_script`](super::signing::SignedIntermediateState::witness_script)
+static ELEMENT_WITNESS_SIZE: Lazy<usize> = Lazy::new(|| {
+    DUMMY_SECRET_KEY
+        .sign(&Message::from_u32(0))
+        .to_script_sig()
+        .len()
+});
+
+/// Size of a disprove leaf in bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LeafSize {
+    /// Size of the `script_pubkey` of the [`DisproveScript`](super::DisproveScript)
+    pub script: usize,
+    /// Size of the `script_witness` of the [`DisproveScript`](super::DisproveScript)
+    pub witness: usize,
+}
+
+impl LeafSize {
+    /// Returns the total size of the script and the witness
+    pub fn total(&self) -> usize {
+        self.script + self.witness
+    }
+}
+
+/// Cost model measuring a shard by the exact size of its
+/// [`DisproveScript`](super::DisproveScript), without signing the states.
+///
+/// Both the script and the witness of the leaf end up in the witness of the
+/// **Disprove** transaction, so the cost is the sum of their sizes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DisproveSize;
+
+impl DisproveSize {
+    /// Returns the size of the disprove leaf of the shard of `shard_size`
+    /// bytes going from the state `from` to the state `to`
+    pub fn leaf_size(from: StateShape, shard_size: usize, to: StateShape) -> LeafSize {
+        let from_size = Self::from_state_size(from);
+        let to_size = Self::to_state_size(to);
+
+        LeafSize {
+            script: from_size.script + shard_size + to_size.script,
+            witness: from_size.witness + to_size.witness,
+        }
+    }
+
+    /// Part of the leaf verifying the state the shard starts from, whose
+    /// mainstack is then popped from the altstack
+    fn from_state_size(from: StateShape) -> LeafSize {
+        LeafSize {
+            script: from.size() * *ELEMENT_VERIFICATION_SIZE + from.stack,
+            witness: from.size() * *ELEMENT_WITNESS_SIZE,
+        }
+    }
+
+    /// Part of the leaf verifying the state the shard ends in and comparing
+    /// it with the result of the shard
+    fn to_state_size(to: StateShape) -> LeafSize {
+        let comparison = DisproveScript::comparison_script(to.stack, to.altstack);
+
+        LeafSize {
+            script: to.size() * *ELEMENT_VERIFICATION_SIZE + comparison.len(),
+            witness: to.size() * *ELEMENT_WITNESS_SIZE,
+        }
+    }
+}
+
+impl CostModel for DisproveSize {
+    fn from_state_cost(&self, from: StateShape, _is_input: bool) -> usize {
+        Self::from_state_size(from).total()
+    }
+
+    fn to_state_cost(&self, to: StateShape) -> usize {
+        Self::to_state_size(to).total()
+    }
+}
@@ -7,6 +7,7 @@ use bitcoin_splitter::split::{
     core::SplitType, intermediate_state::IntermediateState, script::SplitableScript,
 };
 
+pub mod cost;
 pub mod signing;
 
 #[cfg(test)]
@@ -68,47 +69,63 @@ impl DisproveScript {
             { to_signed.verification_script_toaltstack() } // This leaves z[i+1] in the altstack
             { from_signed.verification_script() } // This leaves z[i].mainstack in the mainstack, while (z[i+1], z[i].altstack) is still in the altstack
 
-            // 2. Applying function and popping "to" state
+            // 2. Applying function
             { function.clone() } // This leaves f[i](z[i]).mainstack in the mainstack and { z[i+1].altstack, f[i](z[i]).altstack } in the altstack
-            for _ in 0..to_signed.altstack.len() {
+
+            // 3. Popping "to" state and checking if z[i+1] == f(z[i])
+            { Self::comparison_script(to_signed.stack.len(), to_signed.altstack.len()) }
+        };
+
+        Self {
+            script_witness,
+            script_pubkey,
+        }
+    }
+
+    /// Script popping the "to" state, left in the altstack by its
+    /// verification, and checking that it differs from the result of the
+    /// function, given the lengths of the stack and altstack of the state.
+    ///
+    /// It is shared with [`cost::DisproveSize`], which measures its size.
+    pub(crate) fn comparison_script(stack_len: usize, altstack_len: usize) -> Script {
+        script! {
+            for _ in 0..altstack_len {
+                OP_FROMALTSTACK
+            }
+            // Popping the mainstack, as in `SignedIntermediateState::verification_script_fromaltstack`.
+            // This leaves z[i+1].mainstack and f[i](z[i]).mainstack in the mainstack, while f[i](z[i]).altstack and z[i+1].alstack is in the altstack
+            for _ in 0..stack_len {
                 OP_FROMALTSTACK
             }
-            { to_signed.verification_script_fromaltstack() } // This leaves z[i+1].mainstack and f[i](z[i]).mainstack in the mainstack, while f[i](z[i]).altstack and z[i+1].alstack is in the altstack
 
             // At tbis point, our stack consists of:
             // { f[i](z[i]).mainstack, f[i](z[i]).altstack, z[i+1].mainstack }
             // while the altstack has z[i+1].altstack.
             // Thus, we have to pick f[i](z[i]).mainstack to the top of the stack
-            for _ in (0..to_signed.stack.len()).rev() {
-                { to_signed.total_len() + to_signed.stack.len() - 1 } OP_ROLL
+            for _ in (0..stack_len).rev() {
+                { altstack_len + 2 * stack_len - 1 } OP_ROLL
             }
 
             // At this point, we should have
             // { f[i](z[i]).altstack, z[i+1].mainstack, f[i](z[i]).mainstack }
 
-            // 3. Checking if z[i+1] == f(z[i])
-            // 3.1. Mainstack verification
-            { OP_LONGNOTEQUAL(to_signed.stack.len()) }
+            // 1. Mainstack verification
+            { OP_LONGNOTEQUAL(stack_len) }
 
-            // 3.2. Altstack verification
-            for _ in 0..to_signed.altstack.len() {
+            // 2. Altstack verification
+            for _ in 0..altstack_len {
                 OP_FROMALTSTACK
             }
 
             // Since currently our stack looks like:
             // { f[i](z[i]).altstack, {bit}, z[i+1].altstack, },
             // we need to push f[i](z[i]).altstack to the top of the stack
-            for _ in 0..to_signed.altstack.len() {
-                { 2*to_signed.altstack.len() } OP_ROLL
+            for _ in 0..altstack_len {
+                { 2 * altstack_len } OP_ROLL
             }
 
-            { OP_LONGNOTEQUAL(to_signed.altstack.len()) }
+            { OP_LONGNOTEQUAL(altstack_len) }
             OP_BOOLOR
-        };
-
-        Self {
-            script_witness,
-            script_pubkey,
         }
     }
 
@@ -8,8 +8,9 @@ use bitcoin::{
 };
 use bitcoin_splitter::split::{
     core::SplitType,
+    cost::StateShape,
     intermediate_state::IntermediateState,
-    script::{IOPair, SplitableScript},
+    script::{IOPair, SplitResult, SplitableScript},
 };
 use bitcoin_testscripts::{
     bitvm::bn254::{fp254impl::Fp254Impl, fq::Fq},
@@ -22,7 +23,9 @@ use bitcoin_window_mul::{bigint::U508, traits::comparable::Comparable};
 use once_cell::sync::Lazy;
 
 use crate::{
-    assert::AssertTransaction, disprove::form_disprove_scripts,
+    assert::AssertTransaction,
+    disprove::cost::{DisproveSize, LeafSize},
+    disprove::form_disprove_scripts,
     disprove::signing::SignedIntermediateState,
 };
 
@@ -497,6 +500,100 @@ pub fn test_disprove_script_batch_correctness() {
     }
 }
 
+/// Returns the sizes of the disprove scripts of the split, formed as in
+/// [`form_disprove_scripts`], together with their sizes under the
+/// [`DisproveSize`] model
+fn leaf_sizes(input: &Script, split_result: &SplitResult) -> Vec<(LeafSize, LeafSize)> {
+    (0..split_result.len())
+        .map(|i| {
+            let from_state = if i == 0 {
+                IntermediateState::from_inject_script(input)
+            } else {
+                split_result.intermediate_states[i - 1].clone()
+            };
+            let to_state = &split_result.intermediate_states[i];
+            let shard = &split_result.shards[i];
+
+            let disprove_script = DisproveScript::new(&from_state, to_state, shard);
+            let actual_size = LeafSize {
+                script: disprove_script.script_pubkey.len(),
+                witness: disprove_script.script_witness.len(),
+            };
+            let model_size = DisproveSize::leaf_size(
+                StateShape::from(&from_state),
+                shard.len(),
+                StateShape::from(to_state),
+            );
+
+            (actual_size, model_size)
+        })
+        .collect()
+}
+
+#[test]
+pub fn test_disprove_size_with_altstack() {
+    // Same setup as in `test_disprove_script_with_altstack_2`
+    let input = script! {
+        OP_1 OP_2 OP_3 OP_4 OP_TOALTSTACK OP_5 OP_TOALTSTACK
+    };
+    let function = script! {
+        OP_FROMALTSTACK OP_ADD OP_TOALTSTACK OP_TOALTSTACK
+    };
+    let state_to = IntermediateState::from_input_script(&input, &function);
+    let split_result = SplitResult::new(vec![function], vec![state_to]);
+
+    for (actual_size, model_size) in leaf_sizes(&input, &split_result) {
+        assert_eq!(actual_size, model_size);
+    }
+}
+
+#[test]
+pub fn test_disprove_size_fibonacci_script() {
+    // The number of steps for the Fibonacci script
+    const STEPS: usize = 64;
+
+    let IOPair { input, output: _ } = SquareFibonacciScript::<STEPS>::generate_valid_io_pair();
+    let split_result =
+        SquareFibonacciScript::<STEPS>::default_split(input.clone(), SplitType::ByInstructions);
+
+    for (i, (actual_size, model_size)) in leaf_sizes(&input, &split_result).into_iter().enumerate()
+    {
+        assert_eq!(actual_size, model_size, "Shard {} is mismeasured", i);
+    }
+}
+
+#[test]
+pub fn test_optimal_split_with_disprove_size() {
+    let IOPair { input, output: _ } = U254MulScript::generate_valid_io_pair();
+
+    let default_result = U254MulScript::default_split(input.clone(), SplitType::ByInstructions);
+    let optimal_result = U254MulScript::optimal_split_with_cost(input.clone(), &DisproveSize);
+
+    let max_leaf_size = |split_result: &SplitResult| {
+        leaf_sizes(&input, split_result)
+            .into_iter()
+            .map(|(actual_size, model_size)| {
+                assert_eq!(actual_size, model_size);
+                actual_size.total()
+            })
+            .max()
+            .unwrap()
+    };
+    let default_size = max_leaf_size(&default_result);
+    let optimal_size = max_leaf_size(&optimal_result);
+    assert_eq!(
+        optimal_size,
+        optimal_result.max_cost(&input, &DisproveSize),
+        "The split is not measured by its largest leaf"
+    );
+    assert!(
+        optimal_size <= default_size,
+        "The default split has smaller leaves: {} > {}",
+        optimal_size,
+        default_size
+    );
+}
+
 static SECKEY: Lazy<SecretKey> = Lazy::new(|| {
     "50c8f972285ad27527d79c80fe4df1b63c1192047713438b45758ea4e110a88b"
         .parse()
@@ -518,6 +615,11 @@ fn test_assert_tx_signing() {
         U254MulScript,
     >::new(input, operator_xonly, Amount::from_sat(70_000));
 
+    // Every disprove script can be spent within the tapscript limits
+    let budgets = assert_tx.disprove_budgets(&ctx);
+    assert_eq!(budgets.len(), assert_tx.disprove_scripts.len());
+    assert!(budgets.iter().all(|report| report.is_standard()));
+
     let operator_script_pubkey =
         Script::new_p2wpkh(&WPubkeyHash::hash(&operator_pubkey.serialize()));
 
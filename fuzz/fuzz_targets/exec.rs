@@ -0,0 +1,61 @@
+//! Executes arbitrary scripts with arbitrary witnesses and checks that the
+//! interpreter never panics, whatever the script does.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use bitcoin::ScriptBuf;
+use bitcoin_scriptexec::{Exec, ExecCtx, Options, TxTemplate};
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Debug, Arbitrary)]
+enum Ctx {
+    Legacy,
+    SegwitV0,
+    Tapscript,
+}
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    ctx: Ctx,
+    consensus: bool,
+    enforce_stack_limit: bool,
+    op_cat: bool,
+    op_ctv: bool,
+    op_csfs: bool,
+    script: Vec<u8>,
+    witness: Vec<Vec<u8>>,
+}
+
+fuzz_target!(|input: Input| {
+    let ctx = match input.ctx {
+        Ctx::Legacy => ExecCtx::Legacy,
+        Ctx::SegwitV0 => ExecCtx::SegwitV0,
+        Ctx::Tapscript => ExecCtx::Tapscript,
+    };
+    let mut opt = if input.consensus {
+        Options::consensus()
+    } else {
+        Options::standardness()
+    };
+    opt.enforce_stack_limit = input.enforce_stack_limit;
+    opt.experimental.op_cat = input.op_cat;
+    opt.experimental.op_ctv = input.op_ctv;
+    opt.experimental.op_csfs = input.op_csfs;
+
+    // The same dummy transaction as the CLI uses without --tx, so checks
+    // against the spending transaction have no input to check.
+    let tx = TxTemplate::dummy(ctx);
+
+    let script = ScriptBuf::from_bytes(input.script);
+    // Scripts that don't parse fail during the execution.
+    let mut exec = Exec::new(ctx, opt, tx, script, input.witness).expect("error creating exec");
+    while exec.exec_next().is_ok() {}
+
+    let result = exec.result().expect("execution is finished");
+    assert!(
+        result.error.is_none() || !result.success,
+        "failed execution reported as successful: {:?}",
+        result
+    );
+});
@@ -0,0 +1,160 @@
+//! Splits arbitrary scripts into shards, with and without cutting inside
+//! conditionals, and checks that executing the shards one after another,
+//! through their intermediate states, ends in the same stacks as executing
+//! the whole script, and that the states formed in a single execution are
+//! the same.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use bitcoin::opcodes::all::*;
+use bitcoin::script::Builder;
+use bitcoin_scriptexec::Stack;
+use bitcoin_splitter::split::{
+    branches::split_into_branch_shards,
+    core::{form_states_by_reinjection, form_states_from_shards, split_into_shards, SplitType},
+};
+use bitcoin_utils::treepp::*;
+use libfuzzer_sys::fuzz_target;
+
+/// The instructions scripts are built from. Scripts made of arbitrary bytes
+/// almost always fail early, so only opcodes that commonly succeed on small
+/// numbers are used.
+#[derive(Debug, Arbitrary)]
+enum Op {
+    Push(i8),
+    Pick(u8),
+    Roll(u8),
+    Add,
+    Sub,
+    Add1,
+    Sub1,
+    Not,
+    Min,
+    Max,
+    LessThan,
+    Equal,
+    Dup,
+    Drop,
+    Swap,
+    Over,
+    Rot,
+    Nip,
+    Tuck,
+    Depth,
+    ToAltStack,
+    FromAltStack,
+    If,
+    NotIf,
+    Else,
+    EndIf,
+}
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    input: Vec<i8>,
+    ops: Vec<Op>,
+    chunk_size: u8,
+    by_bytes: bool,
+}
+
+/// Builds the script, closing the conditionals left open at the end.
+fn build_script(ops: &[Op]) -> Script {
+    let mut builder = Builder::new();
+    let mut open_ifs = 0;
+    for op in ops {
+        builder = match op {
+            Op::Push(n) => builder.push_int(*n as i64),
+            Op::Pick(n) => builder.push_int((n % 8) as i64).push_opcode(OP_PICK),
+            Op::Roll(n) => builder.push_int((n % 8) as i64).push_opcode(OP_ROLL),
+            Op::Add => builder.push_opcode(OP_ADD),
+            Op::Sub => builder.push_opcode(OP_SUB),
+            Op::Add1 => builder.push_opcode(OP_1ADD),
+            Op::Sub1 => builder.push_opcode(OP_1SUB),
+            Op::Not => builder.push_opcode(OP_NOT),
+            Op::Min => builder.push_opcode(OP_MIN),
+            Op::Max => builder.push_opcode(OP_MAX),
+            Op::LessThan => builder.push_opcode(OP_LESSTHAN),
+            Op::Equal => builder.push_opcode(OP_EQUAL),
+            Op::Dup => builder.push_opcode(OP_DUP),
+            Op::Drop => builder.push_opcode(OP_DROP),
+            Op::Swap => builder.push_opcode(OP_SWAP),
+            Op::Over => builder.push_opcode(OP_OVER),
+            Op::Rot => builder.push_opcode(OP_ROT),
+            Op::Nip => builder.push_opcode(OP_NIP),
+            Op::Tuck => builder.push_opcode(OP_TUCK),
+            Op::Depth => builder.push_opcode(OP_DEPTH),
+            Op::ToAltStack => builder.push_opcode(OP_TOALTSTACK),
+            Op::FromAltStack => builder.push_opcode(OP_FROMALTSTACK),
+            Op::If => {
+                open_ifs += 1;
+                builder.push_opcode(OP_IF)
+            }
+            Op::NotIf => {
+                open_ifs += 1;
+                builder.push_opcode(OP_NOTIF)
+            }
+            Op::Else if open_ifs > 0 => builder.push_opcode(OP_ELSE),
+            Op::EndIf if open_ifs > 0 => {
+                open_ifs -= 1;
+                builder.push_opcode(OP_ENDIF)
+            }
+            Op::Else | Op::EndIf => builder,
+        };
+    }
+    for _ in 0..open_ifs {
+        builder = builder.push_opcode(OP_ENDIF);
+    }
+    builder.into_script()
+}
+
+/// The items of the stack as bytes, since items restored from intermediate
+/// states are pushed as bytes rather than numbers.
+fn items(stack: &Stack) -> Vec<Vec<u8>> {
+    stack.iter_str().collect()
+}
+
+fuzz_target!(|input: Input| {
+    let script = build_script(&input.ops);
+    let input_script = script! {
+        for n in input.input {
+            { n as i64 }
+        }
+    };
+
+    // Only executions that succeed up to the end of the script are
+    // preserved, failing shards leave arbitrary states.
+    let full = execute_script(script! {
+        { input_script.clone() }
+        { script.clone() }
+    });
+    if full.error.is_some() {
+        return;
+    }
+
+    let split_type = if input.by_bytes {
+        SplitType::ByBytes
+    } else {
+        SplitType::ByInstructions
+    };
+    let chunk_size = (input.chunk_size as usize).max(1);
+    let shards = split_into_shards(&script, chunk_size, split_type);
+    let joined: Vec<u8> = shards.iter().flat_map(|s| s.to_bytes()).collect();
+    assert_eq!(joined, script.to_bytes(), "shards don't make up the script");
+
+    for shards in [
+        shards,
+        split_into_branch_shards(&script, chunk_size, split_type),
+    ] {
+        let states = form_states_by_reinjection(shards.clone(), input_script.clone());
+        let last = states.last().expect("there is at least one shard");
+        assert_eq!(items(&last.stack), items(&full.main_stack));
+        assert_eq!(items(&last.altstack), items(&full.alt_stack));
+
+        let single_pass_states = form_states_from_shards(shards, input_script.clone(), None);
+        for (state, single_pass_state) in states.iter().zip(&single_pass_states) {
+            assert_eq!(items(&state.stack), items(&single_pass_state.stack));
+            assert_eq!(items(&state.altstack), items(&single_pass_state.altstack));
+        }
+    }
+});
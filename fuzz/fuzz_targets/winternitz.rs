@@ -0,0 +1,46 @@
+//! Checks that the on-chain Winternitz signature verification agrees with the
+//! off-chain [PublicKey::verify] on valid signatures, on signatures of other
+//! messages and on signatures made with other keys.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use bitcoin_utils::treepp::*;
+use bitcoin_winternitz::u32::{checksig_verify_script, Message, SecretKey};
+use libfuzzer_sys::fuzz_target;
+use rand::rngs::SmallRng;
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    seed: [u8; 32],
+    msg: u32,
+    /// The message the signature is checked against, if not the signed one.
+    claimed_msg: Option<u32>,
+    /// The seed of the key that signs, if not the checked one.
+    signer_seed: Option<[u8; 32]>,
+}
+
+fuzz_target!(|input: Input| {
+    // Messages are limited to 31 bits.
+    let msg = input.msg >> 1;
+    let claimed_msg = input.claimed_msg.map_or(msg, |m| m >> 1);
+
+    let public_key = SecretKey::from_seed::<_, SmallRng>(input.seed).public_key();
+    let signer = SecretKey::from_seed::<_, SmallRng>(input.signer_seed.unwrap_or(input.seed));
+    let signature = signer.sign(&Message::from_u32(msg));
+
+    let off_chain = public_key.verify(&Message::from_u32(claimed_msg), &signature);
+
+    let result = execute_script(script! {
+        { signature.to_script_sig() }
+        { checksig_verify_script(&public_key) }
+        { Message::recovery_script() }
+        { claimed_msg }
+        OP_EQUAL
+    });
+    assert_eq!(
+        result.success, off_chain,
+        "on-chain verification disagrees with off-chain verification: {}",
+        result
+    );
+});